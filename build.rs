@@ -49,6 +49,12 @@ fn append_text_to_file<P: AsRef<Path> + Copy, T: AsRef<[u8]>>(file_path: P, text
 }
 
 fn main() {
+    // expose the target triple, used to pick the release artifact when upgrading.
+    println!(
+        "cargo:rustc-env=TARGET={}",
+        std::env::var("TARGET").unwrap()
+    );
+
     if download(
         "https://cdn.jsdelivr.net/gh/pymumu/smartdns/etc/smartdns/smartdns.conf",
         "./etc/smartdns/smartdns.conf",
@@ -0,0 +1,325 @@
+use std::fmt::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use rusqlite::{params, Connection};
+
+use crate::dns_mw_audit::DnsAuditRecord;
+use crate::log::debug;
+
+/// The expired records are purged in this interval.
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The counters are aggregated per hour, the finest granularity of `stats --since`.
+const BUCKET_SECS: i64 = 3600;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS audit (
+    timestamp INTEGER NOT NULL,
+    client TEXT NOT NULL,
+    name TEXT NOT NULL,
+    type TEXT NOT NULL,
+    rcode TEXT NOT NULL,
+    result TEXT NOT NULL,
+    source TEXT NOT NULL,
+    elapsed_ms INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS audit_timestamp ON audit (timestamp);
+CREATE TABLE IF NOT EXISTS domain_stats (
+    hour INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    queries INTEGER NOT NULL,
+    blocked INTEGER NOT NULL,
+    PRIMARY KEY (hour, name)
+);
+CREATE TABLE IF NOT EXISTS client_stats (
+    hour INTEGER NOT NULL,
+    client TEXT NOT NULL,
+    queries INTEGER NOT NULL,
+    blocked INTEGER NOT NULL,
+    PRIMARY KEY (hour, client)
+);
+";
+
+/// The audit-file stored in SQLite rather than rotated text, by the extension `db`.
+pub fn is_db<P: AsRef<Path>>(path: P) -> bool {
+    matches!(path.as_ref().extension(), Some(ext) if ext == "db" || ext == "sqlite")
+}
+
+/// The audit records and their counters per domain and per client, kept for the retention.
+pub struct AuditDb {
+    conn: Connection,
+    retention: Duration,
+    last_purge: Option<Instant>,
+}
+
+impl AuditDb {
+    pub fn open<P: AsRef<Path>>(path: P, retention: Duration) -> rusqlite::Result<Self> {
+        if let Some(dir) = path.as_ref().parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(Self {
+            conn,
+            retention,
+            last_purge: None,
+        })
+    }
+
+    pub fn record(&mut self, records: &[DnsAuditRecord]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut audit =
+                tx.prepare_cached("INSERT INTO audit VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?;
+            let mut domain = tx.prepare_cached(
+                "INSERT INTO domain_stats VALUES (?1, ?2, 1, ?3)
+                 ON CONFLICT (hour, name) DO UPDATE
+                 SET queries = queries + 1, blocked = blocked + excluded.blocked",
+            )?;
+            let mut client = tx.prepare_cached(
+                "INSERT INTO client_stats VALUES (?1, ?2, 1, ?3)
+                 ON CONFLICT (hour, client) DO UPDATE
+                 SET queries = queries + 1, blocked = blocked + excluded.blocked",
+            )?;
+
+            for record in records {
+                let timestamp = record.date().timestamp();
+                let hour = timestamp - timestamp.rem_euclid(BUCKET_SECS);
+                let name = record.query().name().to_string();
+                let client_ip = record.client_ip();
+                let blocked = record.is_blocked() as i64;
+
+                audit.execute(params![
+                    timestamp,
+                    record.client(),
+                    name,
+                    record.query().query_type().to_string(),
+                    format!("{:?}", record.response_code()),
+                    record.fmt_result(),
+                    format!("{:?}", record.lookup_source()),
+                    record.elapsed().as_millis() as i64,
+                ])?;
+                domain.execute(params![hour, name, blocked])?;
+                client.execute(params![hour, client_ip, blocked])?;
+            }
+        }
+        tx.commit()?;
+
+        if self
+            .last_purge
+            .map(|at| at.elapsed() >= PURGE_INTERVAL)
+            .unwrap_or(true)
+        {
+            self.last_purge = Some(Instant::now());
+            let removed = self.purge(Local::now().timestamp() - self.retention.as_secs() as i64)?;
+            debug!("purged {} expired audit records", removed);
+        }
+
+        Ok(())
+    }
+
+    /// Remove the records and the counters older than the timestamp.
+    fn purge(&self, before: i64) -> rusqlite::Result<usize> {
+        let removed = self
+            .conn
+            .execute("DELETE FROM audit WHERE timestamp < ?1", [before])?;
+        self.conn
+            .execute("DELETE FROM domain_stats WHERE hour < ?1", [before])?;
+        self.conn
+            .execute("DELETE FROM client_stats WHERE hour < ?1", [before])?;
+        Ok(removed)
+    }
+
+    /// The most queried domains or clients since the timestamp, with their queries and blocked.
+    pub fn top(&self, of: TopOf, since: i64, limit: usize) -> rusqlite::Result<Vec<TopRow>> {
+        let sql = match of {
+            TopOf::Domains => {
+                "SELECT name, SUM(queries) AS n, SUM(blocked) FROM domain_stats
+                 WHERE hour >= ?1 GROUP BY name ORDER BY n DESC, name LIMIT ?2"
+            }
+            TopOf::Clients => {
+                "SELECT client, SUM(queries) AS n, SUM(blocked) FROM client_stats
+                 WHERE hour >= ?1 GROUP BY client ORDER BY n DESC, client LIMIT ?2"
+            }
+        };
+
+        let since = since - since.rem_euclid(BUCKET_SECS);
+
+        let mut stmt = self.conn.prepare(sql)?;
+        let rows = stmt.query_map(params![since, limit as i64], |row| {
+            Ok(TopRow {
+                key: row.get(0)?,
+                queries: row.get(1)?,
+                blocked: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopOf {
+    Domains,
+    Clients,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopRow {
+    pub key: String,
+    pub queries: u64,
+    pub blocked: u64,
+}
+
+/// Print the most queried domains or clients of the audit database, see `smartdns stats`.
+pub fn print_top<P: AsRef<Path>>(
+    path: P,
+    of: TopOf,
+    since: Duration,
+    limit: usize,
+) -> Result<(), String> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Err(format!(
+            "{} not found, is audit-enable yes and audit-file a .db file?",
+            path.display()
+        ));
+    }
+
+    let since = Local::now()
+        .timestamp()
+        .saturating_sub(since.as_secs().try_into().unwrap_or(i64::MAX));
+
+    let rows = AuditDb::open(path, Duration::MAX)
+        .and_then(|db| db.top(of, since, limit))
+        .map_err(|e| format!("Read {} failed, {}", path.display(), e))?;
+
+    print!("{}", format_top(of, &rows));
+    Ok(())
+}
+
+fn format_top(of: TopOf, rows: &[TopRow]) -> String {
+    let mut out = format!(
+        "{:<48} {:>10} {:>10}\n",
+        match of {
+            TopOf::Domains => "DOMAIN",
+            TopOf::Clients => "CLIENT",
+        },
+        "QUERIES",
+        "BLOCKED"
+    );
+
+    for row in rows {
+        let _ = writeln!(
+            out,
+            "{:<48} {:>10} {:>10}",
+            row.key, row.queries, row.blocked
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::*;
+    use std::str::FromStr;
+    use trust_dns_proto::op::{Query, ResponseCode};
+    use trust_dns_proto::rr::RecordType;
+
+    fn record(name: &str, client: &str, blocked: bool) -> DnsAuditRecord {
+        let query = Query::query(Name::from_str(name).unwrap(), RecordType::A);
+        let (result, source) = if blocked {
+            (
+                Err(ResolveErrorKind::NoRecordsFound {
+                    query: query.clone().into(),
+                    soa: None,
+                    negative_ttl: None,
+                    response_code: ResponseCode::NXDomain,
+                    trusted: true,
+                }
+                .into()),
+                LookupSource::Block,
+            )
+        } else {
+            (
+                Ok(Lookup::from_rdata(
+                    query.clone(),
+                    RData::A("93.184.216.34".parse().unwrap()),
+                )),
+                LookupSource::Cache,
+            )
+        };
+
+        DnsAuditRecord::new(
+            1,
+            Local::now(),
+            client.to_string(),
+            query,
+            result,
+            Duration::from_millis(3),
+            Duration::ZERO,
+            source,
+        )
+    }
+
+    #[test]
+    fn test_audit_db_top() {
+        let path = std::env::temp_dir().join(format!(
+            "smartdns-test-{}-audit.db",
+            Local::now().timestamp_millis()
+        ));
+
+        let mut db = AuditDb::open(&path, Duration::from_secs(86400)).unwrap();
+        db.record(&[
+            record("www.example.com", "192.168.1.5:5353", false),
+            record("www.example.com", "192.168.1.6:5353", false),
+            record("ads.example.com", "192.168.1.5:5354", true),
+        ])
+        .unwrap();
+
+        let since = Local::now().timestamp() - 3600;
+
+        assert_eq!(
+            db.top(TopOf::Domains, since, 10).unwrap(),
+            vec![
+                TopRow {
+                    key: "www.example.com".to_string(),
+                    queries: 2,
+                    blocked: 0
+                },
+                TopRow {
+                    key: "ads.example.com".to_string(),
+                    queries: 1,
+                    blocked: 1
+                }
+            ]
+        );
+        assert_eq!(
+            db.top(TopOf::Clients, since, 1).unwrap(),
+            vec![TopRow {
+                key: "192.168.1.5".to_string(),
+                queries: 2,
+                blocked: 1
+            }]
+        );
+
+        db.purge(Local::now().timestamp() + 2 * BUCKET_SECS)
+            .unwrap();
+        assert!(db.top(TopOf::Domains, since, 10).unwrap().is_empty());
+
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_is_db() {
+        assert!(is_db("/var/log/smartdns/audit.db"));
+        assert!(!is_db("/var/log/smartdns/audit.csv"));
+        assert!(!is_db("/var/log/smartdns/audit.log"));
+    }
+}
@@ -0,0 +1,264 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use trust_dns_client::rr::{domain, LowerName};
+
+use crate::control::ControlServer;
+use crate::log::info;
+
+/// A temporary exemption from blocking, for a client, a domain, or both, until it expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockingOverride {
+    /// seconds since the unix epoch.
+    pub expires: u64,
+    /// all clients if none.
+    pub client: Option<IpAddr>,
+    /// all domains if none, otherwise the domain and its subdomains.
+    pub domain: Option<LowerName>,
+}
+
+impl BlockingOverride {
+    pub fn new(client: Option<IpAddr>, domain: Option<LowerName>, duration: Duration) -> Self {
+        Self {
+            expires: unix_now().saturating_add(duration.as_secs()),
+            client,
+            domain,
+        }
+    }
+
+    #[inline]
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires <= now
+    }
+
+    fn matches(&self, client: IpAddr, name: &LowerName) -> bool {
+        self.client.map(|c| c == client).unwrap_or(true)
+            && self
+                .domain
+                .as_ref()
+                .map(|d| d.zone_of(name))
+                .unwrap_or(true)
+    }
+}
+
+/// `<expires> <client|*> <domain|*>`, as sent by the cli on the control socket.
+impl FromStr for BlockingOverride {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+
+        let expires = parts.next().and_then(|p| p.parse().ok()).ok_or(())?;
+
+        let client = match parts.next().ok_or(())? {
+            "*" => None,
+            client => Some(client.parse().map_err(|_| ())?),
+        };
+
+        let domain = match parts.next().ok_or(())? {
+            "*" => None,
+            domain => {
+                let mut domain = domain::Name::from_str(domain).map_err(|_| ())?;
+                domain.set_fqdn(true);
+                Some(domain.into())
+            }
+        };
+
+        Ok(Self {
+            expires,
+            client,
+            domain,
+        })
+    }
+}
+
+impl fmt::Display for BlockingOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.expires)?;
+        match self.client {
+            Some(client) => write!(f, "{} ", client)?,
+            None => write!(f, "* ")?,
+        }
+        match self.domain.as_ref() {
+            Some(domain) => write!(f, "{}", domain),
+            None => write!(f, "*"),
+        }
+    }
+}
+
+/// The overrides in effect, changed by the cli on the control socket, kept over the reloads.
+#[derive(Debug, Clone, Default)]
+pub struct BlockingOverrides {
+    overrides: Arc<RwLock<Vec<BlockingOverride>>>,
+}
+
+impl BlockingOverrides {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Whether the blocking is paused for the client querying the name.
+    pub fn is_paused(&self, client: IpAddr, name: &LowerName) -> bool {
+        let now = unix_now();
+
+        self.overrides
+            .read()
+            .map(|overrides| {
+                overrides
+                    .iter()
+                    .any(|o| !o.is_expired(now) && o.matches(client, name))
+            })
+            .unwrap_or(false)
+    }
+
+    /// Replace the override of the same client and domain, the expired ones dropped.
+    fn pause(&self, o: BlockingOverride) {
+        let now = unix_now();
+        if let Ok(mut overrides) = self.overrides.write() {
+            overrides
+                .retain(|x| !x.is_expired(now) && (x.client != o.client || x.domain != o.domain));
+            overrides.push(o);
+        }
+    }
+
+    /// Remove the overrides of the client, or all of them if no client given.
+    fn resume(&self, client: Option<IpAddr>) {
+        if let Ok(mut overrides) = self.overrides.write() {
+            overrides.retain(|o| client.is_some() && o.client != client);
+        }
+    }
+
+    /// Answer `blocking-pause <expires> <client|*> <domain|*>` and `blocking-resume [client]` on
+    /// the control socket, see `smartdns blocking`.
+    pub fn register_commands(&self, control: &ControlServer) {
+        let overrides = self.clone();
+        control.register("blocking-pause", move |args| {
+            let o = BlockingOverride::from_str(args)
+                .map_err(|_| format!("invalid blocking override {:?}", args))?;
+
+            let answer = format!(
+                "Blocking paused for {} on {} for {}s\n",
+                o.client
+                    .map(|c| c.to_string())
+                    .unwrap_or_else(|| "all clients".to_string()),
+                o.domain
+                    .as_ref()
+                    .map(|d| d.to_string())
+                    .unwrap_or_else(|| "all domains".to_string()),
+                o.expires.saturating_sub(unix_now())
+            );
+            info!("{}", answer.trim_end());
+
+            overrides.pause(o);
+            Ok(answer)
+        });
+
+        let overrides = self.clone();
+        control.register("blocking-resume", move |args| {
+            let client = match args {
+                "" => None,
+                client => Some(
+                    client
+                        .parse()
+                        .map_err(|_| format!("invalid client {:?}", client))?,
+                ),
+            };
+
+            overrides.resume(client);
+            info!("blocking resumed");
+            Ok("Blocking resumed\n".to_string())
+        });
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> LowerName {
+        domain::Name::from_str(s).unwrap().into()
+    }
+
+    #[test]
+    fn test_parse_blocking_override() {
+        let o = BlockingOverride::from_str("1700000000 192.168.1.5 example.com").unwrap();
+        assert_eq!(o.expires, 1700000000);
+        assert_eq!(o.client, Some("192.168.1.5".parse().unwrap()));
+        assert_eq!(o.domain, Some(name("example.com.")));
+        assert_eq!(o.to_string(), "1700000000 192.168.1.5 example.com.");
+
+        let o = BlockingOverride::from_str("1700000000 * *").unwrap();
+        assert_eq!(o.client, None);
+        assert_eq!(o.domain, None);
+
+        assert!(BlockingOverride::from_str("1700000000 localhost *").is_err());
+        assert!(BlockingOverride::from_str("1700000000").is_err());
+    }
+
+    #[test]
+    fn test_blocking_overrides() {
+        let client = "192.168.1.5".parse().unwrap();
+        let other = "192.168.1.6".parse().unwrap();
+
+        let overrides = BlockingOverrides::new();
+        overrides.pause(BlockingOverride::new(
+            Some(client),
+            None,
+            Duration::from_secs(60),
+        ));
+        overrides.pause(BlockingOverride::new(
+            None,
+            Some(name("ads.com.")),
+            Duration::from_secs(60),
+        ));
+        overrides.pause(BlockingOverride::new(None, None, Duration::ZERO));
+
+        assert!(overrides.is_paused(client, &name("tracker.net.")));
+        assert!(overrides.is_paused(other, &name("www.ads.com.")));
+        // the pause for all is expired.
+        assert!(!overrides.is_paused(other, &name("tracker.net.")));
+    }
+
+    #[test]
+    fn test_blocking_commands() {
+        let client: IpAddr = "192.168.1.5".parse().unwrap();
+        let other: IpAddr = "192.168.1.6".parse().unwrap();
+
+        let overrides = BlockingOverrides::new();
+        let control = ControlServer::new();
+        overrides.register_commands(&control);
+
+        let pause = BlockingOverride::new(Some(client), None, Duration::from_secs(60));
+        assert!(control
+            .execute(&format!("blocking-pause {}", pause))
+            .is_ok());
+        assert!(control.execute("blocking-pause soon * *").is_err());
+        assert!(overrides.is_paused(client, &name("tracker.net.")));
+        assert!(!overrides.is_paused(other, &name("tracker.net.")));
+
+        let pause = BlockingOverride::new(None, None, Duration::from_secs(60));
+        assert!(control
+            .execute(&format!("blocking-pause {}", pause))
+            .is_ok());
+        assert!(overrides.is_paused(other, &name("tracker.net.")));
+
+        // the pause of the other client, of all clients, is kept.
+        assert!(control.execute("blocking-resume 192.168.1.5").is_ok());
+        assert!(overrides.is_paused(other, &name("tracker.net.")));
+
+        assert!(control.execute("blocking-resume localhost").is_err());
+        assert!(control.execute("blocking-resume").is_ok());
+        assert!(!overrides.is_paused(client, &name("tracker.net.")));
+        assert!(!overrides.is_paused(other, &name("tracker.net.")));
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use cfg_if::cfg_if;
+use hyper::header::{ETAG, IF_NONE_MATCH};
+use hyper::{Body, Request, StatusCode};
+use trust_dns_client::rr::LowerName;
+
+use crate::dns_client::DnsClient;
+use crate::dns_conf::{
+    AddressRuleItem, BlocklistFormat, BlocklistUrl, DomainAddress, DomainOrDomainSet,
+};
+use crate::domain_set::DomainSet;
+use crate::infra::http::read_body;
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{debug, info, warn};
+use crate::matcher::DomainAddressMatcher;
+use crate::third_ext::FutureTimeoutExt;
+
+cfg_if! {
+    if #[cfg(target_os = "android")] {
+        pub const CACHE_DIR: &'static str = "/data/data/com.termux/files/usr/var/cache/smartdns/blocklists";
+    } else {
+        pub const CACHE_DIR: &'static str = "/var/cache/smartdns/blocklists";
+    }
+}
+
+/// The lists are large, downloading one may take a while.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The largest list downloaded, the big ones being a few tens of megabytes.
+const MAX_LIST_LEN: usize = 64 * 1024 * 1024;
+
+/// The names in the hosts files mapping the local addresses, not to be blocked.
+const LOCAL_HOSTS: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+/// The domains of the subscribed blocklists, refreshed in background, each download
+/// replacing the domains of its list at once.
+#[derive(Debug, Clone, Default)]
+pub struct Blocklists {
+    matcher: Arc<RwLock<Arc<DomainAddressMatcher>>>,
+    sets: Arc<Mutex<Vec<Arc<DomainSet>>>>,
+}
+
+impl Blocklists {
+    /// Load the cached copies of the lists, then refresh them on their schedule.
+    pub fn spawn<P: AsRef<Path>>(
+        lists: &[BlocklistUrl],
+        cache_dir: P,
+        dns_client: &Arc<DnsClient>,
+        tasks: &BackgroundTasks,
+    ) -> Self {
+        let blocklists = Self::with_lists(lists.len());
+
+        for (idx, list) in lists.iter().enumerate() {
+            let cached = CachedList::new(cache_dir.as_ref(), &list.url);
+            let list = list.clone();
+            let blocklists = blocklists.clone();
+            let dns_client = dns_client.clone();
+
+            // the cached copy first, so that the domains are blocked before downloaded.
+            let mut etag = blocklists.load_cached(idx, &list, &cached);
+
+            tasks.spawn(async move {
+                let mut interval = tokio::time::interval(list.refresh);
+                loop {
+                    interval.tick().await;
+
+                    let (text, new_etag) =
+                        match download(&dns_client, &list.url, etag.as_deref()).await {
+                            Ok(Some(res)) => res,
+                            Ok(None) => {
+                                debug!("blocklist {} not modified", list.url);
+                                continue;
+                            }
+                            Err(err) => {
+                                warn!("download blocklist {} failed, {}", list.url, err);
+                                continue;
+                            }
+                        };
+
+                    let set = parse(&text, list.format);
+                    if set.is_empty() {
+                        warn!("blocklist {} has no domain, kept the previous", list.url);
+                        continue;
+                    }
+
+                    info!("blocklist {} loaded, {} domains", list.url, set.len());
+                    blocklists.update(idx, set);
+
+                    if let Err(err) = cached.store(&text, new_etag.as_deref()) {
+                        warn!("cache blocklist {} failed, {}", list.url, err);
+                    }
+                    etag = new_etag;
+                }
+            });
+        }
+
+        blocklists
+    }
+
+    /// The cached copies of the lists only, e.g. to explain the rules offline.
+    pub fn cached<P: AsRef<Path>>(lists: &[BlocklistUrl], cache_dir: P) -> Self {
+        let blocklists = Self::with_lists(lists.len());
+        for (idx, list) in lists.iter().enumerate() {
+            blocklists.load_cached(idx, list, &CachedList::new(cache_dir.as_ref(), &list.url));
+        }
+        blocklists
+    }
+
+    fn with_lists(len: usize) -> Self {
+        Self {
+            matcher: Default::default(),
+            sets: Arc::new(Mutex::new(vec![Default::default(); len])),
+        }
+    }
+
+    /// Load the cached copy of the list, the ETag of it if any.
+    fn load_cached(&self, idx: usize, list: &BlocklistUrl, cached: &CachedList) -> Option<String> {
+        let text = fs::read_to_string(&cached.path).ok()?;
+        self.update(idx, parse(&text, list.format));
+        fs::read_to_string(&cached.etag_path).ok()
+    }
+
+    /// How the domain is answered, if blocked.
+    pub fn find(&self, name: &LowerName) -> Option<DomainAddress> {
+        self.matcher
+            .read()
+            .ok()
+            .and_then(|matcher| matcher.find(name).copied())
+    }
+
+    /// Replace the domains of the list, and the matcher of all the lists.
+    fn update(&self, idx: usize, set: DomainSet) {
+        let mut sets = match self.sets.lock() {
+            Ok(sets) => sets,
+            Err(_) => return,
+        };
+        sets[idx] = Arc::new(set);
+
+        let domain_sets = sets
+            .iter()
+            .enumerate()
+            .map(|(idx, set)| (format!("blocklist:{}", idx), set.clone()))
+            .collect::<HashMap<_, _>>();
+        let rules = domain_sets
+            .keys()
+            .map(|name| AddressRuleItem {
+                domain: DomainOrDomainSet::DomainSet(name.to_string()),
+                address: DomainAddress::SOA,
+            })
+            .collect::<Vec<_>>();
+
+        let matcher = Arc::new(DomainAddressMatcher::from_rules(&rules, &domain_sets));
+        if let Ok(mut m) = self.matcher.write() {
+            *m = matcher;
+        }
+    }
+}
+
+/// The copy of a list on disk, along with its ETag.
+struct CachedList {
+    path: PathBuf,
+    etag_path: PathBuf,
+}
+
+impl CachedList {
+    fn new(dir: &Path, url: &str) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, url.as_bytes());
+        let name = digest.as_ref()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        Self {
+            path: dir.join(format!("{}.list", name)),
+            etag_path: dir.join(format!("{}.etag", name)),
+        }
+    }
+
+    /// Write to a temporary file then rename, never leaving a partial list behind.
+    fn store(&self, text: &str, etag: Option<&str>) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, &self.path)?;
+
+        match etag {
+            Some(etag) => fs::write(&self.etag_path, etag),
+            None => fs::remove_file(&self.etag_path).or_else(|err| match err.kind() {
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(err),
+            }),
+        }
+    }
+}
+
+/// Download the list, none if not modified since the ETag, its host resolved by the bootstrap
+/// dns so that the download doesn't loop back into this server.
+async fn download(
+    dns_client: &DnsClient,
+    url: &str,
+    etag: Option<&str>,
+) -> Result<Option<(String, Option<String>)>, String> {
+    let parsed = url::Url::parse(url).map_err(|e| e.to_string())?;
+    let client = dns_client.http_client(&parsed).await?;
+
+    let mut request = Request::get(url);
+    if let Some(etag) = etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    let request = request.body(Body::empty()).map_err(|e| e.to_string())?;
+
+    let response = client
+        .request(request)
+        .timeout(FETCH_TIMEOUT)
+        .await
+        .map_err(|_| "timeout".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        return Err(format!("http status {}", response.status()));
+    }
+
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let body = read_body(response, MAX_LIST_LEN)
+        .timeout(FETCH_TIMEOUT)
+        .await
+        .map_err(|_| "timeout".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    Ok(Some((String::from_utf8_lossy(&body).into_owned(), etag)))
+}
+
+/// The domains of the list, the lines not understood are skipped.
+///   hosts: `0.0.0.0 ads.example.com`, the hosts only, not their subdomains.
+///   adblock: `||ads.example.com^`, the domain and its subdomains, the rules with options
+///     or the exceptions are skipped.
+///   domains: one domain per line, along with the prefixes of the domain sets.
+pub fn parse(text: &str, format: BlocklistFormat) -> DomainSet {
+    let mut set = DomainSet::default();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+            continue;
+        }
+
+        match format {
+            BlocklistFormat::Hosts => {
+                let line = line.split('#').next().unwrap_or_default();
+                let mut parts = line.split_whitespace();
+                if parts
+                    .next()
+                    .and_then(|ip| ip.parse::<IpAddr>().ok())
+                    .is_none()
+                {
+                    continue;
+                }
+                for host in parts.filter(|host| !LOCAL_HOSTS.contains(host)) {
+                    set.insert(&format!("full:{}", host));
+                }
+            }
+            BlocklistFormat::Adblock => {
+                if let Some(domain) = line.strip_prefix("||").and_then(|l| l.strip_suffix('^')) {
+                    set.insert(&format!("domain:{}", domain));
+                }
+            }
+            BlocklistFormat::Domains => {
+                set.insert(line);
+            }
+        }
+    }
+
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_client::rr::Name;
+
+    use super::*;
+
+    fn name(s: &str) -> LowerName {
+        Name::from_str(s).unwrap().into()
+    }
+
+    #[test]
+    fn test_parse_blocklist() {
+        let set = parse(
+            "# hosts\n127.0.0.1 localhost\n0.0.0.0 ads.example.com tracker.example.com # ads\n::1 ip6-localhost\nads.example.net\n",
+            BlocklistFormat::Hosts,
+        );
+        assert_eq!(set.len(), 2);
+        assert!(set.full.contains(&name("ads.example.com.")));
+        assert!(set.full.contains(&name("tracker.example.com.")));
+
+        let set = parse(
+            "! adblock\n||ads.example.com^\n||tracker.example.com^$third-party\n@@||cdn.example.com^\n",
+            BlocklistFormat::Adblock,
+        );
+        assert_eq!(set.len(), 1);
+        assert!(set.domains.contains(&name("ads.example.com.")));
+
+        let set = parse(
+            "ads.example.com\nregexp:^ad[0-9]+\\.\n",
+            BlocklistFormat::Domains,
+        );
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_blocklists_update() {
+        let blocklists = Blocklists {
+            matcher: Default::default(),
+            sets: Arc::new(Mutex::new(vec![Default::default(); 2])),
+        };
+        assert_eq!(blocklists.find(&name("ads.example.com.")), None);
+
+        blocklists.update(0, parse("||ads.example.com^", BlocklistFormat::Adblock));
+        blocklists.update(1, parse("0.0.0.0 tracker.net", BlocklistFormat::Hosts));
+
+        assert_eq!(
+            blocklists.find(&name("www.ads.example.com.")),
+            Some(DomainAddress::SOA)
+        );
+        assert_eq!(
+            blocklists.find(&name("tracker.net.")),
+            Some(DomainAddress::SOA)
+        );
+        assert_eq!(blocklists.find(&name("www.tracker.net.")), None);
+
+        // replaced at once.
+        blocklists.update(0, DomainSet::default());
+        assert_eq!(blocklists.find(&name("www.ads.example.com.")), None);
+    }
+}
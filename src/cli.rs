@@ -1,4 +1,4 @@
-use clap::Subcommand;
+use clap::{Args, Subcommand};
 
 pub use clap::Parser;
 
@@ -22,6 +22,29 @@ pub enum Commands {
         /// Turn debugging information on
         #[arg(short = 'd', long)]
         debug: bool,
+
+        #[command(flatten)]
+        overrides: ConfOverrides,
+    },
+
+    /// Print an annotated configuration for this machine, e.g. `smartdns init > smartdns.conf`.
+    Init {
+        /// The kind of the machine, detected if not specified.
+        #[arg(short = 'p', long, value_enum)]
+        profile: Option<crate::init::Profile>,
+    },
+
+    /// Check the configuration, exiting non-zero with the errors found, e.g. before restarting.
+    Check {
+        /// Config file
+        #[arg(short = 'c', long)]
+        conf: Option<std::path::PathBuf>,
+    },
+
+    /// Inspect the configuration, e.g. dump the one in effect.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
     },
 
     /// Manage the Smart-DNS service (install, uninstall, start, stop, restart).
@@ -29,6 +52,221 @@ pub enum Commands {
         #[command(subcommand)]
         command: ServiceCommands,
     },
+
+    /// Upgrade Smart-DNS to the latest (or specified) release, and restart the running server gracefully.
+    Upgrade {
+        /// The release version to upgrade to, e.g. v0.1.5
+        #[arg(short = 'v', long)]
+        version: Option<String>,
+
+        /// The pid file of the running server, the one of its config file if not specified.
+        #[arg(short = 'p', long)]
+        pid_file: Option<std::path::PathBuf>,
+
+        /// Config file of the running server, of its pid file.
+        #[arg(short = 'c', long)]
+        conf: Option<std::path::PathBuf>,
+    },
+
+    /// Pause the blocking temporarily, or resume it.
+    Blocking {
+        #[command(subcommand)]
+        command: BlockingCommands,
+    },
+
+    /// Change the log level of the running server, without restarting it.
+    LogLevel {
+        /// The level, e.g. debug, info, warn, error.
+        #[arg(required_unless_present = "reset")]
+        level: Option<tracing::Level>,
+
+        /// Back to the level the server started with.
+        #[arg(short = 'r', long, conflicts_with = "level")]
+        reset: bool,
+
+        /// Config file of the running server, of its control socket.
+        #[arg(short = 'c', long)]
+        conf: Option<std::path::PathBuf>,
+    },
+
+    /// Print the counters of the running server, e.g. the messages dropped by the limits.
+    Status {
+        /// Config file of the running server, of its control socket.
+        #[arg(short = 'c', long)]
+        conf: Option<std::path::PathBuf>,
+    },
+
+    /// Inspect the upstreams of the running server.
+    Upstream {
+        #[command(subcommand)]
+        command: UpstreamCommands,
+    },
+
+    /// Query the statistics of the audit database, see the sqlite feature.
+    Stats {
+        #[command(subcommand)]
+        command: StatsCommands,
+    },
+
+    /// Manage the rules, e.g. compile the domain sets, or explain which rules a query matches.
+    #[command(alias = "rule")]
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommands,
+    },
+}
+
+/// The options overriding the ones of the config file, so that it's not required.
+#[derive(Args, PartialEq, Eq, Debug, Default)]
+pub struct ConfOverrides {
+    /// Listen on the address for UDP and TCP instead of the binds of the config file, e.g. :5353
+    #[arg(long = "bind", value_name = "ADDR")]
+    pub binds: Vec<String>,
+
+    /// Resolve by the upstream instead of the default group of the config file, e.g. https://dns.google/dns-query
+    #[arg(long = "server", value_name = "URL")]
+    pub servers: Vec<String>,
+
+    /// The number of the answers cached, 0 to disable the cache.
+    #[arg(long)]
+    pub cache_size: Option<usize>,
+
+    /// The log level, e.g. debug, info, warn, error.
+    #[arg(long)]
+    pub log_level: Option<tracing::Level>,
+}
+
+impl ConfOverrides {
+    /// The directives of the options, applied after the config file.
+    pub fn directives(&self) -> Vec<String> {
+        let binds = self
+            .binds
+            .iter()
+            .flat_map(|addr| [format!("bind {}", addr), format!("bind-tcp {}", addr)]);
+        let servers = self.servers.iter().map(|url| format!("server {}", url));
+        let cache_size = self.cache_size.map(|n| format!("cache-size {}", n));
+        let log_level = self
+            .log_level
+            .map(|level| format!("log-level {}", level.to_string().to_lowercase()));
+
+        binds
+            .chain(servers)
+            .chain(cache_size)
+            .chain(log_level)
+            .collect()
+    }
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum ConfigCommands {
+    /// Print the configuration in effect, the defaults and the included files expanded, along
+    /// with the file and line of each directive.
+    Dump {
+        /// Config file
+        #[arg(short = 'c', long)]
+        conf: Option<std::path::PathBuf>,
+
+        /// Print as JSON.
+        #[arg(long)]
+        json: bool,
+
+        #[command(flatten)]
+        overrides: ConfOverrides,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum BlockingCommands {
+    /// Pause the blocking of a client, a domain, or all, e.g. `blocking pause --client 192.168.1.5 --for 15m`.
+    Pause {
+        /// Pause for the client only.
+        #[arg(short = 'c', long)]
+        client: Option<std::net::IpAddr>,
+
+        /// Pause for the domain and its subdomains only.
+        #[arg(short = 'd', long)]
+        domain: Option<String>,
+
+        /// How long to pause, e.g. 30s, 15m, 1h.
+        #[arg(short = 'f', long = "for", default_value = "5m", value_parser = parse_duration)]
+        duration: std::time::Duration,
+
+        /// Config file of the running server, of its control socket.
+        #[arg(long)]
+        conf: Option<std::path::PathBuf>,
+    },
+
+    /// Resume the blocking of a client, or all of them.
+    Resume {
+        /// Resume for the client only.
+        #[arg(short = 'c', long)]
+        client: Option<std::net::IpAddr>,
+
+        /// Config file of the running server, of its control socket.
+        #[arg(long)]
+        conf: Option<std::path::PathBuf>,
+    },
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum UpstreamCommands {
+    /// Print the queries, errors, timeouts, latency and the last error of each upstream.
+    Stats,
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum StatsCommands {
+    /// Print the most queried domains, e.g. `stats top-domains --since 24h`.
+    TopDomains(TopArgs),
+
+    /// Print the clients querying the most, e.g. `stats top-clients --since 7d`.
+    TopClients(TopArgs),
+}
+
+#[derive(Args, PartialEq, Eq, Debug)]
+pub struct TopArgs {
+    /// Count the queries of this period, by the hour, e.g. 1h, 24h, 7d.
+    #[arg(short = 's', long, default_value = "24h", value_parser = parse_duration)]
+    pub since: std::time::Duration,
+
+    /// How many to print.
+    #[arg(short = 'n', long, default_value_t = 10)]
+    pub limit: usize,
+
+    /// Config file, of the audit-file.
+    #[arg(short = 'c', long)]
+    pub conf: Option<std::path::PathBuf>,
+}
+
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+pub enum RulesCommands {
+    /// Compile a domain set into a binary artifact, which is loaded much faster at startup.
+    Compile {
+        /// The domain set file, one domain per line.
+        input: std::path::PathBuf,
+
+        /// The compiled artifact, defaults to the input with the extension `bin`.
+        #[arg(short = 'o', long)]
+        output: Option<std::path::PathBuf>,
+    },
+
+    /// Print the rules the query matches and why, by the configuration, e.g. `rules explain www.example.com A --client 192.168.3.7`.
+    Explain {
+        /// The domain queried.
+        domain: trust_dns_client::rr::Name,
+
+        /// The record type queried.
+        #[arg(default_value = "A")]
+        record_type: trust_dns_client::rr::RecordType,
+
+        /// The client querying, for the client rules.
+        #[arg(long)]
+        client: Option<std::net::IpAddr>,
+
+        /// Config file
+        #[arg(short = 'c', long)]
+        conf: Option<std::path::PathBuf>,
+    },
 }
 
 #[derive(Subcommand, PartialEq, Eq, Debug)]
@@ -56,6 +294,12 @@ pub enum ServiceCommands {
     Status,
 }
 
+/// Parse the duration as in the config file, e.g. 30s, 15m, 1h, 7d.
+fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    smartdns::dns_conf::parse_duration(s)
+        .ok_or_else(|| format!("invalid duration {}, e.g. 30s, 15m, 1h or 7d", s))
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -68,7 +312,8 @@ mod tests {
             cli.command,
             Commands::Run {
                 conf: Some(_),
-                debug: false
+                debug: false,
+                ..
             }
         ));
 
@@ -77,7 +322,8 @@ mod tests {
             cli.command,
             Commands::Run {
                 conf: Some(_),
-                debug: false
+                debug: false,
+                ..
             }
         ));
     }
@@ -89,7 +335,8 @@ mod tests {
             cli.command,
             Commands::Run {
                 conf: Some(_),
-                debug: true
+                debug: true,
+                ..
             }
         ));
 
@@ -98,11 +345,170 @@ mod tests {
             cli.command,
             Commands::Run {
                 conf: Some(_),
-                debug: true
+                debug: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_cli_args_parse_config_dump() {
+        let cli = Cli::parse_from(["smartdns", "config", "dump", "-c", "/etc/smartdns.conf"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Config {
+                command: ConfigCommands::Dump {
+                    conf: Some(_),
+                    json: false,
+                    ..
+                }
+            }
+        ));
+
+        let cli = Cli::parse_from(["smartdns", "config", "dump", "--json", "--cache-size", "0"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Config {
+                command: ConfigCommands::Dump {
+                    conf: None,
+                    json: true,
+                    overrides: ConfOverrides {
+                        cache_size: Some(0),
+                        ..
+                    },
+                }
             }
         ));
     }
 
+    #[test]
+    fn test_cli_args_parse_rules_explain() {
+        let cli = Cli::parse_from([
+            "smartdns",
+            "rule",
+            "explain",
+            "www.example.com",
+            "AAAA",
+            "--client",
+            "192.168.3.7",
+        ]);
+        assert!(matches!(
+            cli.command,
+            Commands::Rules {
+                command: RulesCommands::Explain {
+                    record_type: trust_dns_client::rr::RecordType::AAAA,
+                    client: Some(_),
+                    conf: None,
+                    ..
+                }
+            }
+        ));
+
+        let cli = Cli::parse_from(["smartdns", "rules", "explain", "www.example.com"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Rules {
+                command: RulesCommands::Explain {
+                    record_type: trust_dns_client::rr::RecordType::A,
+                    client: None,
+                    ..
+                }
+            }
+        ));
+    }
+
+    #[test]
+    fn test_cli_args_parse_init() {
+        let cli = Cli::parse_from(["smartdns", "init"]);
+        assert!(matches!(cli.command, Commands::Init { profile: None }));
+
+        let cli = Cli::parse_from(["smartdns", "init", "--profile", "router"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Init {
+                profile: Some(crate::init::Profile::Router)
+            }
+        ));
+    }
+
+    #[test]
+    fn test_cli_args_parse_log_level() {
+        let cli = Cli::parse_from(["smartdns", "log-level", "debug"]);
+        assert!(matches!(
+            cli.command,
+            Commands::LogLevel {
+                level: Some(tracing::Level::DEBUG),
+                reset: false,
+                conf: None
+            }
+        ));
+
+        let cli = Cli::parse_from(["smartdns", "log-level", "--reset"]);
+        assert!(matches!(
+            cli.command,
+            Commands::LogLevel {
+                level: None,
+                reset: true,
+                ..
+            }
+        ));
+
+        assert!(Cli::try_parse_from(["smartdns", "log-level"]).is_err());
+        assert!(Cli::try_parse_from(["smartdns", "log-level", "debug", "--reset"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_args_parse_start_overrides() {
+        let cli = Cli::parse_from([
+            "smartdns",
+            "run",
+            "--bind",
+            ":5353",
+            "--server",
+            "1.1.1.1",
+            "--server",
+            "https://dns.google/dns-query",
+            "--cache-size",
+            "0",
+            "--log-level",
+            "debug",
+        ]);
+
+        let overrides = match cli.command {
+            Commands::Run {
+                conf: None,
+                overrides,
+                ..
+            } => overrides,
+            command => panic!("unexpected {:?}", command),
+        };
+
+        assert_eq!(
+            overrides.directives(),
+            vec![
+                "bind :5353",
+                "bind-tcp :5353",
+                "server 1.1.1.1",
+                "server https://dns.google/dns-query",
+                "cache-size 0",
+                "log-level debug",
+            ]
+        );
+
+        assert!(Cli::try_parse_from(["smartdns", "run", "--log-level", "loud"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_args_parse_check() {
+        let cli = Cli::parse_from(["smartdns", "check", "-c", "/etc/smartdns.conf"]);
+        assert_eq!(
+            cli.command,
+            Commands::Check {
+                conf: Some("/etc/smartdns.conf".into())
+            }
+        );
+    }
+
     #[test]
     fn test_cli_args_parse_install() {
         let cli = Cli::parse_from(["smartdns", "service", "install"]);
@@ -124,4 +530,160 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_cli_args_parse_blocking() {
+        let cli = Cli::parse_from([
+            "smartdns",
+            "blocking",
+            "pause",
+            "--client",
+            "192.168.1.5",
+            "--for",
+            "15m",
+        ]);
+        assert_eq!(
+            cli.command,
+            Commands::Blocking {
+                command: BlockingCommands::Pause {
+                    client: Some("192.168.1.5".parse().unwrap()),
+                    domain: None,
+                    duration: std::time::Duration::from_secs(900),
+                    conf: None,
+                }
+            }
+        );
+
+        let cli = Cli::parse_from(["smartdns", "blocking", "resume"]);
+        assert_eq!(
+            cli.command,
+            Commands::Blocking {
+                command: BlockingCommands::Resume {
+                    client: None,
+                    conf: None,
+                }
+            }
+        );
+
+        let cli = Cli::parse_from([
+            "smartdns",
+            "blocking",
+            "resume",
+            "--conf",
+            "/etc/smartdns/guest.conf",
+        ]);
+        assert_eq!(
+            cli.command,
+            Commands::Blocking {
+                command: BlockingCommands::Resume {
+                    client: None,
+                    conf: Some("/etc/smartdns/guest.conf".into()),
+                }
+            }
+        );
+
+        assert!(Cli::try_parse_from(["smartdns", "blocking", "pause", "--for", "soon"]).is_err());
+        assert!(Cli::try_parse_from([
+            "smartdns",
+            "blocking",
+            "pause",
+            "--for",
+            "99999999999999999999m"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_cli_args_parse_upstream_stats() {
+        let cli = Cli::parse_from(["smartdns", "upstream", "stats"]);
+        assert_eq!(
+            cli.command,
+            Commands::Upstream {
+                command: UpstreamCommands::Stats
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_stats_top() {
+        let cli = Cli::parse_from(["smartdns", "stats", "top-domains", "--since", "7d"]);
+        assert_eq!(
+            cli.command,
+            Commands::Stats {
+                command: StatsCommands::TopDomains(TopArgs {
+                    since: std::time::Duration::from_secs(7 * 86400),
+                    limit: 10,
+                    conf: None
+                })
+            }
+        );
+
+        let cli = Cli::parse_from(["smartdns", "stats", "top-clients", "-n", "3"]);
+        assert_eq!(
+            cli.command,
+            Commands::Stats {
+                command: StatsCommands::TopClients(TopArgs {
+                    since: std::time::Duration::from_secs(86400),
+                    limit: 3,
+                    conf: None
+                })
+            }
+        );
+
+        assert!(Cli::try_parse_from([
+            "smartdns",
+            "stats",
+            "top-domains",
+            "--since",
+            "999999999999999999d"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_cli_args_parse_rules_compile() {
+        let cli = Cli::parse_from(["smartdns", "rules", "compile", "ads.txt", "-o", "ads.bin"]);
+        assert_eq!(
+            cli.command,
+            Commands::Rules {
+                command: RulesCommands::Compile {
+                    input: "ads.txt".into(),
+                    output: Some("ads.bin".into())
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_args_parse_upgrade() {
+        let cli = Cli::parse_from(["smartdns", "upgrade"]);
+        assert_eq!(
+            cli.command,
+            Commands::Upgrade {
+                version: None,
+                pid_file: None,
+                conf: None,
+            }
+        );
+
+        let cli = Cli::parse_from(["smartdns", "upgrade", "-v", "v0.1.5"]);
+        assert_eq!(
+            cli.command,
+            Commands::Upgrade {
+                version: Some("v0.1.5".to_string()),
+                pid_file: None,
+                conf: None,
+            }
+        );
+
+        let cli = Cli::parse_from(["smartdns", "upgrade", "-c", "/etc/smartdns/guest.conf"]);
+        assert_eq!(
+            cli.command,
+            Commands::Upgrade {
+                version: None,
+                pid_file: None,
+                conf: Some("/etc/smartdns/guest.conf".into()),
+            }
+        );
+    }
 }
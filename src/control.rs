@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use cfg_if::cfg_if;
+
+use crate::infra::tasks::BackgroundTasks;
+
+cfg_if! {
+    if #[cfg(target_os = "android")] {
+        pub const RUN_DIR: &'static str = "/data/data/com.termux/files/usr/var/run";
+    } else {
+        pub const RUN_DIR: &'static str = "/var/run";
+    }
+}
+
+/// How long a command is waited for, by the server and by the cli.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest command line read, the rest is ignored.
+const MAX_COMMAND_LEN: u64 = 4096;
+
+type Command = Arc<dyn Fn(&str) -> Result<String, String> + Send + Sync>;
+
+type Section = Arc<dyn Fn() -> String + Send + Sync>;
+
+/// The commands of the running server, answered on its control socket one per connection,
+/// e.g. `log-level debug`, see `smartdns log-level`.
+#[derive(Clone, Default)]
+pub struct ControlServer {
+    commands: Arc<RwLock<HashMap<String, Command>>>,
+    /// the sections of `status`, in the order registered.
+    sections: Arc<RwLock<Vec<(String, Section)>>>,
+}
+
+impl ControlServer {
+    pub fn new() -> Self {
+        let control = Self::default();
+
+        let sections = control.sections.clone();
+        control.register("status", move |_| {
+            let sections = sections.read().map_err(|err| err.to_string())?;
+            Ok(sections.iter().map(|(_, section)| section()).collect())
+        });
+
+        control
+    }
+
+    /// Print the section in `status`, replacing the one of the same name, e.g. the one of the
+    /// middlewares before reloaded.
+    pub fn register_status<F>(&self, name: &str, f: F)
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        if let Ok(mut sections) = self.sections.write() {
+            match sections.iter_mut().find(|(n, _)| n == name) {
+                Some((_, section)) => *section = Arc::new(f),
+                None => sections.push((name.to_string(), Arc::new(f))),
+            }
+        }
+    }
+
+    /// Answer the command by the function, given the arguments following the name.
+    pub fn register<F>(&self, name: &str, f: F)
+    where
+        F: Fn(&str) -> Result<String, String> + Send + Sync + 'static,
+    {
+        if let Ok(mut commands) = self.commands.write() {
+            commands.insert(name.to_string(), Arc::new(f));
+        }
+    }
+
+    /// The answer of the command line, or the error telling why it failed.
+    pub fn execute(&self, line: &str) -> Result<String, String> {
+        let line = line.trim();
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+
+        let command = self
+            .commands
+            .read()
+            .ok()
+            .and_then(|commands| commands.get(name).cloned())
+            .ok_or_else(|| format!("unknown command {:?}", name))?;
+
+        command(args.trim())
+    }
+
+    /// Answer the commands on the socket, replacing the one left by a previous run, accessible
+    /// by the owner only.
+    #[cfg(unix)]
+    pub fn spawn(&self, path: &Path, tasks: &BackgroundTasks) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        use crate::log::{debug, warn};
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+
+        let listener = tokio::net::UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+        let server = self.clone();
+        let conn_tasks = tasks.clone();
+
+        tasks.spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        warn!("accept control connection failed, {}", err);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+
+                let server = server.clone();
+                conn_tasks.spawn(async move {
+                    if let Err(err) = server.serve(stream).await {
+                        debug!("control connection failed, {}", err);
+                    }
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    async fn serve(&self, stream: tokio::net::UnixStream) -> io::Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        use crate::third_ext::FutureTimeoutExt;
+
+        let (reader, mut writer) = stream.into_split();
+
+        let mut line = String::new();
+        BufReader::new(reader.take(MAX_COMMAND_LEN))
+            .read_line(&mut line)
+            .timeout(REQUEST_TIMEOUT)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        let reply = match self.execute(&line) {
+            Ok(answer) => format!("OK\n{}", answer),
+            Err(err) => format!("ERR {}\n", err),
+        };
+
+        writer.write_all(reply.as_bytes()).await?;
+        writer.shutdown().await
+    }
+}
+
+/// The control socket of the instance loading the config file, named after it, so that the
+/// instances of different files don't answer for each other.
+pub fn default_socket(conf_file: Option<&Path>) -> PathBuf {
+    let name = match conf_file
+        .and_then(|path| path.file_stem())
+        .and_then(|stem| stem.to_str())
+    {
+        Some(stem) if stem != "smartdns" => format!("smartdns-{}.sock", stem),
+        _ => "smartdns.sock".to_string(),
+    };
+
+    Path::new(RUN_DIR).join(name)
+}
+
+/// Send the command to the server listening on the socket, its answer or the error it replied.
+#[cfg(unix)]
+pub fn request(path: &Path, command: &str) -> io::Result<Result<String, String>> {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+
+    if let Some(answer) = reply.strip_prefix("OK\n") {
+        Ok(Ok(answer.to_string()))
+    } else if let Some(err) = reply.strip_prefix("ERR ") {
+        Ok(Err(err.trim_end().to_string()))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected reply {:?}", reply),
+        ))
+    }
+}
+
+#[cfg(not(unix))]
+pub fn request(_: &Path, _: &str) -> io::Result<Result<String, String>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the control socket is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo() -> ControlServer {
+        let control = ControlServer::new();
+        control.register("echo", |args| match args {
+            "" => Err("nothing to echo".to_string()),
+            args => Ok(format!("{}\n", args)),
+        });
+        control
+    }
+
+    #[test]
+    fn test_execute() {
+        let control = echo();
+        assert_eq!(control.execute("echo hello\n"), Ok("hello\n".to_string()));
+        assert_eq!(control.execute("echo"), Err("nothing to echo".to_string()));
+        assert!(control.execute("unknown").is_err());
+    }
+
+    #[test]
+    fn test_status() {
+        let control = echo();
+        assert_eq!(control.execute("status"), Ok("".to_string()));
+
+        control.register_status("a", || "a: 1\n".to_string());
+        control.register_status("b", || "b: 1\n".to_string());
+        control.register_status("a", || "a: 2\n".to_string());
+        assert_eq!(control.execute("status"), Ok("a: 2\nb: 1\n".to_string()));
+    }
+
+    #[test]
+    fn test_default_socket() {
+        assert_eq!(
+            default_socket(Some(Path::new("/etc/smartdns/smartdns.conf"))),
+            Path::new(RUN_DIR).join("smartdns.sock")
+        );
+        assert_eq!(
+            default_socket(Some(Path::new("/etc/smartdns/guest.conf"))),
+            Path::new(RUN_DIR).join("smartdns-guest.sock")
+        );
+        assert_eq!(
+            default_socket(None),
+            Path::new(RUN_DIR).join("smartdns.sock")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_request() {
+        let path =
+            std::env::temp_dir().join(format!("smartdns-test-{}-control.sock", std::process::id()));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let tasks = BackgroundTasks::new();
+        {
+            let _guard = runtime.enter();
+            echo().spawn(&path, &tasks).unwrap();
+        }
+
+        assert_eq!(
+            request(&path, "echo hello").unwrap(),
+            Ok("hello\n".to_string())
+        );
+        assert_eq!(
+            request(&path, "echo").unwrap(),
+            Err("nothing to echo".to_string())
+        );
+
+        runtime.block_on(tasks.shutdown());
+        std::fs::remove_file(&path).unwrap();
+
+        // no server answering.
+        assert!(request(&path, "echo hello").is_err());
+    }
+}
@@ -1,11 +1,18 @@
+use std::collections::HashSet;
 use std::fmt::Debug;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::{str::FromStr, sync::Arc, time::Duration};
 
 use trust_dns_proto::rr::rdata::SOA;
 use trust_dns_resolver::error::ResolveError;
 
 use crate::dns_server::Request as OriginRequest;
-use crate::{dns_client::DnsClient, dns_conf::SmartDnsConfig};
+use crate::{
+    dns_client::{DnsClient, UpstreamOptions},
+    dns_conf::{ClientMatch, ClientRule, DomainRule, ServerOpts, SmartDnsConfig, SpeedCheckMode},
+    infra::mac::MacAddr,
+};
 
 pub use trust_dns_proto::{
     op,
@@ -24,6 +31,62 @@ pub struct DnsContext {
     pub client: Arc<DnsClient>,
     pub fastest_speed: Duration,
     pub lookup_source: LookupSource,
+    /// the resolution policy of the listener the request received on.
+    pub server_opts: Arc<ServerOpts>,
+    /// why the answer generated locally failed the resolution, told to the clients.
+    pub extended_error: Option<ExtendedError>,
+    /// the domain-rules of the name queried, matched once for all the stages.
+    pub domain_rule: Option<Arc<DomainRule>>,
+    /// answered by the local sources only, neither the cache nor the upstreams asked, e.g. the
+    /// single-label names expanded under the local domain.
+    pub local_only: bool,
+}
+
+impl DnsContext {
+    /// The speed check modes of the domain rule, else of the conf-group, else the global ones.
+    pub fn speed_check_mode(&self) -> &[SpeedCheckMode] {
+        match self.domain_rule.as_ref() {
+            Some(rule) if !rule.speed_check_mode.is_empty() => &rule.speed_check_mode,
+            _ => self
+                .cfg
+                .speed_check_mode(self.server_opts.conf_group.as_deref()),
+        }
+    }
+
+    /// The speed checks probed, none after `none`, or if the listener has `-no-speed-check`.
+    pub fn speed_checks(&self) -> &[SpeedCheckMode] {
+        if self.server_opts.no_speed_check {
+            return &[];
+        }
+        let modes = self.speed_check_mode();
+        let end = modes
+            .iter()
+            .position(|mode| *mode == SpeedCheckMode::None)
+            .unwrap_or(modes.len());
+        &modes[..end]
+    }
+}
+
+/// An extended dns error (RFC 8914), e.g. of the blocked domains, so that the clients and the
+/// debugging tools see why the resolution failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedError {
+    pub info_code: u16,
+    pub text: String,
+}
+
+impl ExtendedError {
+    pub const OTHER: u16 = 0;
+    pub const BLOCKED: u16 = 15;
+    pub const FILTERED: u16 = 17;
+    pub const PROHIBITED: u16 = 18;
+
+    pub fn new(info_code: u16, text: impl Into<String>) -> Self {
+        Self {
+            info_code,
+            text: text.into(),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -33,6 +96,8 @@ pub enum LookupSource {
     Static,
     Zone(String),
     Server(String),
+    /// Blocked by the address rules.
+    Block,
 }
 
 impl Debug for LookupSource {
@@ -43,6 +108,7 @@ impl Debug for LookupSource {
             Self::Static => write!(f, "Static"),
             Self::Zone(arg0) => write!(f, "Zone: {}", arg0),
             Self::Server(arg0) => write!(f, "Server: {}", arg0),
+            Self::Block => write!(f, "Block"),
         }
     }
 }
@@ -58,15 +124,151 @@ pub type DnsRequest = OriginRequest;
 pub type DnsResponse = Lookup;
 pub type DnsError = ResolveError;
 
+/// The directives of the upstreams, see `SmartDnsConfig::effective_settings`.
+const SERVER_DIRECTIVES: [&str; 6] = [
+    "server",
+    "server-tcp",
+    "server-tls",
+    "server-https",
+    "server-dnscrypt",
+    "server-odoh",
+];
+
 impl SmartDnsConfig {
+    /// The local domain the plain names of the hosts are also answered under, if expanded.
+    pub fn expand_hosts(&self) -> Option<&Name> {
+        self.expand_domain
+            .as_ref()
+            .or(self.domain.as_ref())
+            .filter(|_| self.expand_hosts)
+    }
+
+    /// The rule of the client, of its hardware address if known, else of its subnet, the most
+    /// specific one, none if the listener's policy applies.
+    pub fn client_rule(&self, ip: IpAddr, mac: Option<MacAddr>) -> Option<&ClientRule> {
+        // the ipv4 clients of the dual stack sockets.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+
+        let by_mac = mac.and_then(|mac| {
+            self.client_rules
+                .iter()
+                .filter_map(|rule| match &rule.client {
+                    ClientMatch::Mac(prefix) if prefix.contains(&mac) => {
+                        Some((prefix.prefix(), rule))
+                    }
+                    _ => None,
+                })
+                .max_by_key(|(prefix, _)| *prefix)
+        });
+
+        by_mac
+            .or_else(|| {
+                self.client_rules
+                    .iter()
+                    .filter_map(|rule| match &rule.client {
+                        ClientMatch::Net(net) if net.contains(&ip) => {
+                            Some((net.prefix() as usize, rule))
+                        }
+                        _ => None,
+                    })
+                    .max_by_key(|(prefix, _)| *prefix)
+            })
+            .map(|(_, rule)| rule)
+    }
+
+    /// Whether any client rule is of the hardware addresses, for which the neighbors are read.
+    pub fn has_mac_rules(&self) -> bool {
+        self.client_rules
+            .iter()
+            .any(|rule| matches!(rule.client, ClientMatch::Mac(_)))
+    }
+
+    /// The domain sets blocked by the policy of the listeners or the client rules.
+    pub fn block_sets(&self) -> impl Iterator<Item = &String> {
+        self.binds
+            .iter()
+            .chain(self.binds_tcp.iter())
+            .chain(self.binds_tls.iter())
+            .chain(self.binds_https.iter())
+            .map(|bind| &bind.opts)
+            .chain(self.client_rules.iter().map(|rule| &rule.opts))
+            .flat_map(|opts| opts.block_sets.iter())
+    }
+
     pub fn rr_ttl(&self) -> u64 {
         self.rr_ttl.unwrap_or(300)
     }
 
+    /// The ips answered to the clients at most, none if unlimited.
+    pub fn max_reply_ip_num(&self) -> Option<usize> {
+        self.max_reply_ip_num.filter(|n| *n > 0)
+    }
+
     pub fn cache_size(&self) -> usize {
         self.cache_size.unwrap_or(512)
     }
 
+    /// The udp sockets opened on each bind address, at least one.
+    pub fn num_workers(&self) -> usize {
+        self.num_workers.unwrap_or(1).max(1)
+    }
+
+    /// How long the queries in flight are waited for on shutdown, 5s by default.
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout.unwrap_or(Duration::from_secs(5))
+    }
+
+    /// The udp payload size advertised to the clients, 1232 by default, as the DNS flag day 2020.
+    pub fn edns_packet_max(&self) -> u16 {
+        self.edns_packet_max.unwrap_or(1232).max(512)
+    }
+
+    /// The speed check of the listener, that of its conf-group if configured.
+    pub fn speed_check_mode(&self, conf_group: Option<&str>) -> &[SpeedCheckMode] {
+        conf_group
+            .and_then(|group| self.conf_groups.get(group))
+            .map(|group| group.speed_check_mode.as_slice())
+            .filter(|mode| !mode.is_empty())
+            .unwrap_or(&self.speed_check_mode)
+    }
+
+    /// Off by default, the probes connecting to the sites resolved.
+    pub fn dualstack_ip_selection(&self) -> bool {
+        self.dualstack_ip_selection.unwrap_or(false)
+    }
+
+    pub fn dualstack_ip_selection_threshold(&self) -> Duration {
+        Duration::from_millis(self.dualstack_ip_selection_threshold.unwrap_or(15))
+    }
+
+    /// The control socket of the cli commands, named after the config file unless configured,
+    /// so that the instances of different files don't answer for each other.
+    pub fn control_socket(&self) -> PathBuf {
+        self.control_socket
+            .clone()
+            .unwrap_or_else(|| crate::control::default_socket(self.conf_file.as_deref()))
+    }
+
+    /// The pid file of the server, read by the upgrade to restart it.
+    pub fn pid_file(&self) -> PathBuf {
+        self.pid_file.clone().unwrap_or_else(|| {
+            if cfg!(target_os = "android") {
+                PathBuf::from("/data/data/com.termux/files/usr/var/run/smartdns.pid")
+            } else {
+                PathBuf::from("/var/run/smartdns.pid")
+            }
+        })
+    }
+
+    pub fn audit_file(&self) -> &Path {
+        self.audit_file
+            .as_deref()
+            .unwrap_or_else(|| Path::new("/var/log/smartdns/smartdns-audit.log"))
+    }
+
     pub fn audit_size(&self) -> u64 {
         use byte_unit::n_kb_bytes;
         self.audit_size.unwrap_or(n_kb_bytes(128) as u64)
@@ -75,6 +277,151 @@ impl SmartDnsConfig {
     pub fn audit_num(&self) -> usize {
         self.audit_num.unwrap_or(2)
     }
+
+    pub fn audit_retention(&self) -> Duration {
+        self.audit_retention
+            .unwrap_or(Duration::from_secs(7 * 24 * 3600))
+    }
+
+    pub fn log_size(&self) -> u64 {
+        use byte_unit::n_kb_bytes;
+        self.log_size.unwrap_or(n_kb_bytes(128) as u64)
+    }
+
+    pub fn log_num(&self) -> usize {
+        self.log_num.unwrap_or(2)
+    }
+
+    /// Degrade once the available memory drops below this percent, off unless configured.
+    pub fn memory_pressure_threshold(&self) -> u64 {
+        self.memory_pressure_threshold.unwrap_or(0)
+    }
+
+    /// The domains resolved by multicast dns, none if mdns disabled.
+    pub fn mdns_domains(&self) -> Vec<Name> {
+        if !self.mdns.unwrap_or(true) {
+            vec![]
+        } else if self.mdns_domains.is_empty() {
+            vec![Name::from_str("local.").unwrap()]
+        } else {
+            self.mdns_domains.clone()
+        }
+    }
+
+    /// The countries referenced by `-whitelist-geoip` and `geoip-route`, the ones loaded of a geoip.dat.
+    pub fn geoip_countries(&self) -> HashSet<String> {
+        self.servers
+            .values()
+            .flatten()
+            .flat_map(|server| server.whitelist_geoip.iter().cloned())
+            .chain(self.geoip_route.iter().map(|route| route.country.clone()))
+            .collect()
+    }
+
+    /// The settings in effect, the defaults of the ones not configured included, as directives,
+    /// see `smartdns config dump`.
+    pub fn effective_settings(&self) -> Vec<(&'static str, String)> {
+        let yes_no = |b: bool| if b { "yes" } else { "no" }.to_string();
+        let ms = |d: Duration| format!("{}ms", d.as_millis());
+
+        let mut settings = vec![("server-name", self.server_name.to_string())];
+
+        for (name, binds) in [
+            ("bind", &self.binds),
+            ("bind-tcp", &self.binds_tcp),
+            ("bind-tls", &self.binds_tls),
+            ("bind-https", &self.binds_https),
+        ] {
+            for addr in binds.iter().flat_map(|bind| bind.addr.iter()) {
+                settings.push((name, addr.to_string()));
+            }
+        }
+
+        // the upstreams as written, their options kept and the secrets not interpolated.
+        let servers = self
+            .directives
+            .iter()
+            .filter_map(|directive| {
+                SERVER_DIRECTIVES
+                    .iter()
+                    .find(|name| **name == directive.name)
+                    .map(|name| (*name, directive.options.clone()))
+            })
+            .collect::<Vec<_>>();
+
+        if servers.is_empty() {
+            // the default upstream, none configured.
+            for server in self.servers.values().flatten() {
+                settings.push(("server", server.url.to_string()));
+            }
+        } else {
+            settings.extend(servers);
+        }
+
+        settings.extend([
+            ("cache-size", self.cache_size().to_string()),
+            ("serve-expired", yes_no(self.serve_expired)),
+            ("prefetch-domain", yes_no(self.prefetch_domain)),
+            ("rr-ttl", self.rr_ttl().to_string()),
+            ("num-workers", self.num_workers().to_string()),
+            ("drain-timeout", ms(self.drain_timeout())),
+            ("edns-packet-max", self.edns_packet_max().to_string()),
+            (
+                "dualstack-ip-selection",
+                yes_no(self.dualstack_ip_selection()),
+            ),
+            (
+                "dualstack-ip-selection-threshold",
+                self.dualstack_ip_selection_threshold()
+                    .as_millis()
+                    .to_string(),
+            ),
+            (
+                "memory-pressure-threshold",
+                self.memory_pressure_threshold().to_string(),
+            ),
+            (
+                "log-level",
+                self.log_level.clone().unwrap_or_else(|| "info".to_string()),
+            ),
+            ("audit-enable", yes_no(self.audit_enable)),
+            ("audit-file", self.audit_file().display().to_string()),
+            ("audit-size", self.audit_size().to_string()),
+            ("audit-num", self.audit_num().to_string()),
+            (
+                "audit-retention",
+                format!("{}s", self.audit_retention().as_secs()),
+            ),
+            (
+                "control-socket",
+                self.control_socket().display().to_string(),
+            ),
+            ("pid-file", self.pid_file().display().to_string()),
+            ("log-size", self.log_size().to_string()),
+            ("log-num", self.log_num().to_string()),
+        ]);
+
+        settings
+    }
+
+    pub fn upstream_options(&self) -> UpstreamOptions {
+        let default = UpstreamOptions::default();
+        UpstreamOptions {
+            query_strategy: self.query_strategy,
+            pool_size: self.upstream_pool_size.unwrap_or(default.pool_size),
+            idle_timeout: self
+                .upstream_idle_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(default.idle_timeout),
+            subnet: self.edns_client_subnet,
+            bogus_nxdomain: self.bogus_nxdomain.clone(),
+            ignore_ip: self.ignore_ip.clone(),
+            blacklist_ip: self.blacklist_ip.clone(),
+            whitelist_ip: self.whitelist_ip.clone(),
+            padding: self.edns_padding.unwrap_or_default(),
+            ..default
+        }
+    }
 }
 
 pub trait DefaultSOA {
@@ -1,24 +1,49 @@
-use crate::dns::op::Query;
+use crate::dns::op::{Query, ResponseCode};
 
 use crate::dns::rr::RecordType;
 use crate::dns::DnsError;
 use crate::dns::Lookup;
 use crate::dns::Name;
 use crate::dns::Record;
-use crate::dns_conf::DnsServer;
+use crate::dns_conf::{DnsServer, ForceTransport, QueryPolicy, QueryStrategy, RecordTypeFilter};
+use crate::dns_conn::{
+    is_bogus_answer, is_geoip_discarded, is_ip_filtered, ConnectionOptions, UpstreamConnection,
+    UpstreamConnectionProvider,
+};
+use crate::dns_ecs::ClientSubnet;
+use crate::dns_padding::PaddingPolicy;
 use crate::dns_url::DnsUrl;
-use crate::log::{debug, warn};
-use crate::matcher::DomainNameServerGroupMatcher;
+use crate::dnscrypt::DnsCryptClient;
+use crate::dnstap::DnstapSink;
+use crate::geoip::GeoIp;
+use crate::infra::http::{HttpClient, HttpHost};
+use crate::infra::iface;
+use crate::infra::ipnet::IpNet;
+use crate::infra::metrics::{transport_metrics, LatencyHistogram, TransportMetrics};
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{debug, info, warn};
+use crate::matcher::{DomainForceTransportMatcher, DomainNameServerGroupMatcher};
+use crate::odoh::OdohClient;
 use crate::preset_ns;
+use crate::proxy::{DualStackHost, ProxyConfig, Transport};
 use crate::third_ext::FutureTimeoutExt;
 
-use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
-use std::collections::HashMap;
+use futures::future;
+use rustls::client::{
+    NoClientSessionStorage, ServerCertVerified, ServerCertVerifier, WebPkiVerifier,
+};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
 use std::net::IpAddr;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
 use std::str::FromStr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::Mutex;
 use trust_dns_client::rr::{LowerName, RData};
 use trust_dns_resolver::config::{
@@ -27,14 +52,52 @@ use trust_dns_resolver::config::{
 };
 use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
 use trust_dns_resolver::lookup_ip::LookupIp;
-use trust_dns_resolver::TokioAsyncResolver;
+use trust_dns_resolver::name_server::GenericConnectionProvider;
+use trust_dns_resolver::AsyncResolver;
 use trust_dns_resolver::{IntoName, TokioHandle, TryParseIp};
 
-const LOOKUP_TIMEOUT: u64 = 3;
+/// An upstream is ejected after so many consecutive failures.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// The hostname of nameservers is re-resolved in this interval.
+const NAMESERVER_REFRESH_INTERVAL: Duration = Duration::from_secs(600);
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One in so many queries tries a random upstream first, to keep the others measured.
+const EXPLORE_RATE: u32 = 20;
+
+/// The success rate is kept in parts per million.
+const FULL_SUCCESS_RATE: u64 = 1_000_000;
+
+/// The resolver, whose tcp based connections may go through a proxy,
+/// and whose queries and answers are tuned by the `ConnectionOptions`.
+pub type Resolver = AsyncResolver<UpstreamConnection, UpstreamConnectionProvider>;
+
+fn create_resolver<T: IntoResolverConfig>(config: T) -> Result<Resolver, String> {
+    create_resolver_with_options(config, Default::default(), None)
+}
 
-fn create_resolver<T: IntoResolverConfig>(config: T) -> Result<TokioAsyncResolver, String> {
+/// The resolver of an upstream, timing out as its policy, see `Upstream::query`.
+fn create_resolver_with_options<T: IntoResolverConfig>(
+    config: T,
+    conn_options: ConnectionOptions,
+    policy: Option<&QueryPolicy>,
+) -> Result<Resolver, String> {
     let config = config.into();
+    let options = resolver_opts(&conn_options, policy);
+
+    let conn_provider =
+        UpstreamConnectionProvider::new(GenericConnectionProvider::new(TokioHandle), conn_options);
+
+    let resolver = Resolver::new_with_conn(config, options, conn_provider)
+        .map_err(|e| format!("error constructing new Resolver: {}", e))?;
 
+    Ok(resolver)
+}
+
+fn resolver_opts(conn_options: &ConnectionOptions, policy: Option<&QueryPolicy>) -> ResolverOpts {
     let mut options = {
         let mut opts = ResolverOpts::default();
         opts.cache_size = 0;
@@ -60,10 +123,18 @@ fn create_resolver<T: IntoResolverConfig>(config: T) -> Result<TokioAsyncResolve
         options.preserve_intermediates = true;
     }
 
-    let resolver = TokioAsyncResolver::new(config, options, TokioHandle)
-        .map_err(|e| format!("error constructing new Resolver: {}", e))?;
+    if conn_options.use_edns() {
+        options.edns0 = true;
+    }
 
-    Ok(resolver)
+    // sent once within the timeout of the server, the retries backing off are of the upstream,
+    // rather than sent again at once by the resolver.
+    if let Some(policy) = policy {
+        options.timeout = policy.timeout;
+        options.attempts = 1;
+    }
+
+    options
 }
 
 pub trait IntoResolverConfig: Sized {
@@ -82,203 +153,1097 @@ impl IntoResolverConfig for NameServerConfigGroup {
     }
 }
 
-pub struct DnsClientBuilder {
-    matcher: Option<DomainNameServerGroupMatcher>,
-    servers: HashMap<String, Vec<DnsServer>>,
-
-    server_groups: HashMap<String, NameServerConfigGroup>,
+/// A group of upstreams, which a query fans out to according to the query strategy.
+#[derive(Debug)]
+pub struct ServerGroup {
+    strategy: QueryStrategy,
+    upstreams: Vec<Arc<Upstream>>,
+    /// queried in order, only when all the primary upstreams fail.
+    fallbacks: Vec<Arc<Upstream>>,
+    cursor: AtomicUsize,
 }
 
-impl DnsClientBuilder {
-    pub fn add_server<S: Into<DnsServer>>(mut self, server: S) -> Self {
-        use std::collections::hash_map::Entry::*;
-        let server = server.into();
+impl ServerGroup {
+    fn new(
+        strategy: QueryStrategy,
+        upstreams: Vec<Arc<Upstream>>,
+        fallbacks: Vec<Arc<Upstream>>,
+    ) -> Self {
+        Self {
+            strategy,
+            upstreams,
+            fallbacks,
+            cursor: Default::default(),
+        }
+    }
 
-        let group_name = server
-            .group
-            .as_ref()
-            .map(|s| s.to_string())
-            .unwrap_or("default".to_string());
+    #[inline]
+    pub fn strategy(&self) -> QueryStrategy {
+        self.strategy
+    }
 
-        match self.servers.entry(group_name) {
-            Occupied(mut o) => {
-                o.get_mut().push(server);
+    #[inline]
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    #[inline]
+    pub fn fallbacks(&self) -> &[Arc<Upstream>] {
+        &self.fallbacks
+    }
+
+    /// Query the upstreams accepting the record type, any upstream if the type is none.
+    async fn query<T, F, Fut>(
+        &self,
+        record_type: Option<RecordType>,
+        f: F,
+    ) -> Result<T, ResolveError>
+    where
+        F: Fn(Resolver) -> Fut,
+        Fut: Future<Output = Result<T, ResolveError>>,
+    {
+        let res = self.query_primary(record_type, &f).await;
+
+        match res {
+            Err(err) if !self.fallbacks.is_empty() && is_failure(&err) => {
+                debug!("all primary upstreams failed, {}, query the fallbacks", err);
+                let fallbacks = Self::accepting(self.fallbacks.iter(), record_type);
+                Self::query_in_order(Self::healthy(fallbacks), &f).await
             }
-            Vacant(v) => {
-                v.insert(vec![server]);
+            res => res,
+        }
+    }
+
+    /// Query all the healthy upstreams accepting the record type at once, the answers merged,
+    /// the fallbacks queried in order only when none answered.
+    async fn query_all<F, Fut>(&self, record_type: RecordType, f: F) -> Result<Lookup, ResolveError>
+    where
+        F: Fn(Resolver) -> Fut,
+        Fut: Future<Output = Result<Lookup, ResolveError>>,
+    {
+        let upstreams = Self::healthy(Self::accepting(self.upstreams.iter(), Some(record_type)));
+        let results = future::join_all(upstreams.into_iter().map(|u| u.query(&f))).await;
+
+        let mut lookups = vec![];
+        let mut last_err = None;
+        for res in results {
+            match res {
+                Ok(lookup) => lookups.push(lookup),
+                Err(err) => last_err = Some(err),
             }
-        };
+        }
 
-        self
+        if let Some(lookup) = merge_lookups(lookups) {
+            return Ok(lookup);
+        }
+
+        match last_err {
+            Some(err) if self.fallbacks.is_empty() || !is_failure(&err) => Err(err),
+            Some(err) => {
+                debug!("all primary upstreams failed, {}, query the fallbacks", err);
+                let fallbacks = Self::accepting(self.fallbacks.iter(), Some(record_type));
+                Self::query_in_order(Self::healthy(fallbacks), &f).await
+            }
+            None => Err(ResolveErrorKind::Message("no available upstream").into()),
+        }
     }
 
-    pub fn add_server_group(mut self, server_group: NameServerConfigGroup) -> Self {
-        use std::collections::hash_map::Entry::*;
+    async fn query_primary<T, F, Fut>(
+        &self,
+        record_type: Option<RecordType>,
+        f: &F,
+    ) -> Result<T, ResolveError>
+    where
+        F: Fn(Resolver) -> Fut,
+        Fut: Future<Output = Result<T, ResolveError>>,
+    {
+        if Self::accepting(self.upstreams.iter(), record_type)
+            .next()
+            .is_none()
+        {
+            return Err(ResolveErrorKind::Message("no available upstream").into());
+        }
 
-        match self.server_groups.entry("default".to_string()) {
-            Occupied(mut o) => {
-                *(o.get_mut()) = server_group;
+        match self.strategy {
+            QueryStrategy::First => {
+                let upstreams = Self::healthy(Self::accepting(self.upstreams.iter(), record_type));
+                future::select_ok(upstreams.into_iter().map(|u| Box::pin(u.query(f))))
+                    .await
+                    .map(|(res, _)| res)
             }
-            Vacant(v) => {
-                v.insert(server_group);
+            QueryStrategy::Fastest => {
+                let mut upstreams =
+                    Self::healthy(Self::accepting(self.upstreams.iter(), record_type));
+                let explore = if upstreams.len() > 1 && rand::random::<u32>() % EXPLORE_RATE == 0 {
+                    Some(rand::random::<usize>())
+                } else {
+                    None
+                };
+                Self::sort_by_weighted_rtt(&mut upstreams, explore);
+                Self::query_in_order(upstreams, f).await
+            }
+            QueryStrategy::RoundRobin => {
+                let start = self.cursor.fetch_add(1, Ordering::Relaxed) % self.upstreams.len();
+                let (tail, head) = self.upstreams.split_at(start);
+                let upstreams =
+                    Self::healthy(Self::accepting(head.iter().chain(tail.iter()), record_type));
+                Self::query_in_order(upstreams, f).await
             }
         }
+    }
 
-        self
+    /// The upstreams accepting the record type.
+    fn accepting<'a>(
+        upstreams: impl Iterator<Item = &'a Arc<Upstream>>,
+        record_type: Option<RecordType>,
+    ) -> impl Iterator<Item = &'a Arc<Upstream>> {
+        upstreams.filter(move |u| record_type.map_or(true, |t| u.types.accepts(t)))
     }
 
-    pub fn build(self) -> DnsClient {
-        DnsClient::new(
-            self.matcher.unwrap_or_default(),
-            self.servers,
-            self.server_groups,
-        )
+    /// The healthy upstreams, or all of them if none is healthy.
+    fn healthy<'a>(upstreams: impl Iterator<Item = &'a Arc<Upstream>>) -> Vec<&'a Arc<Upstream>> {
+        let upstreams = upstreams.collect::<Vec<_>>();
+
+        let healthy = upstreams
+            .iter()
+            .filter(|u| u.is_healthy())
+            .copied()
+            .collect::<Vec<_>>();
+
+        if healthy.is_empty() {
+            upstreams
+        } else {
+            healthy
+        }
+    }
+
+    /// Order the upstreams by the response time weighted by the success rate,
+    /// the unmeasured ones come first, so that all upstreams get measured.
+    /// To explore, a random one other than the best is moved to the front.
+    fn sort_by_weighted_rtt(upstreams: &mut Vec<&Arc<Upstream>>, explore: Option<usize>) {
+        upstreams.sort_by_key(|u| u.weighted_rtt());
+
+        if let Some(n) = explore {
+            if upstreams.len() > 1 {
+                let upstream = upstreams.remove(1 + n % (upstreams.len() - 1));
+                upstreams.insert(0, upstream);
+            }
+        }
+    }
+
+    async fn query_in_order<T, F, Fut>(
+        upstreams: Vec<&Arc<Upstream>>,
+        f: &F,
+    ) -> Result<T, ResolveError>
+    where
+        F: Fn(Resolver) -> Fut,
+        Fut: Future<Output = Result<T, ResolveError>>,
+    {
+        let mut last_err = None;
+
+        for upstream in upstreams {
+            match upstream.query(f).await {
+                Ok(res) => return Ok(res),
+                Err(err) if is_answer(&err) => return Err(err),
+                Err(err) => {
+                    debug!("query upstream {} failed, {}", upstream.name, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| ResolveErrorKind::Message("no available upstream").into()))
     }
 }
 
+/// The options applied to the upstreams.
+#[derive(Debug, Clone)]
+pub struct UpstreamOptions {
+    pub query_strategy: QueryStrategy,
+    /// the number of connections kept to each tcp based upstream.
+    pub pool_size: usize,
+    /// the idle connections are closed after this duration.
+    pub idle_timeout: Duration,
+    /// the client subnet attached to queries, unless the upstream overrides it.
+    pub subnet: Option<ClientSubnet>,
+    /// the answers containing these ips are discarded.
+    pub bogus_nxdomain: Vec<IpNet>,
+    /// the records of these ips are dropped from the answers.
+    pub ignore_ip: Vec<IpNet>,
+    /// the ips the answers of the servers with `-blacklist-ip` are discarded for.
+    pub blacklist_ip: Vec<IpNet>,
+    /// the ips the answers of the servers with `-whitelist-ip` are accepted within.
+    pub whitelist_ip: Vec<IpNet>,
+    /// query the tcp based upstreams periodically, so that their connections are kept open.
+    pub heartbeat: Option<Duration>,
+    /// how the queries sent over the encrypted upstreams are padded.
+    pub padding: PaddingPolicy,
+    /// the upstream exchanges are exported to the dnstap collector.
+    pub dnstap: Option<Arc<DnstapSink>>,
+    /// the record types the upstream is queried for.
+    pub types: RecordTypeFilter,
+    /// the countries of the ips, for the servers with `-whitelist-geoip`.
+    pub geoip: Option<Arc<GeoIp>>,
+}
+
+impl Default for UpstreamOptions {
+    fn default() -> Self {
+        Self {
+            query_strategy: Default::default(),
+            pool_size: 1,
+            idle_timeout: Duration::from_secs(120),
+            subnet: None,
+            bogus_nxdomain: vec![],
+            ignore_ip: vec![],
+            blacklist_ip: vec![],
+            whitelist_ip: vec![],
+            heartbeat: None,
+            padding: Default::default(),
+            dnstap: None,
+            types: Default::default(),
+            geoip: None,
+        }
+    }
+}
+
+/// An upstream server, along with its measured response time and health.
 #[derive(Debug)]
-pub struct DnsClient {
-    bootstrap_resolver: TokioAsyncResolver,
-    matcher: DomainNameServerGroupMatcher,
-    servers: HashMap<String, Vec<DnsServer>>,
-    server_groups: Mutex<HashMap<String, NameServerConfigGroup>>,
-    resolvers: Mutex<HashMap<String, Arc<TokioAsyncResolver>>>,
-    nameserver_ip_store: Mutex<HashMap<Name, Vec<IpAddr>>>,
+pub struct Upstream {
+    name: String,
+    config: NameServerConfigGroup,
+    conn_options: ConnectionOptions,
+    policy: QueryPolicy,
+    types: RecordTypeFilter,
+    /// each resolver keeps its own connection to the upstream, queries are pipelined on it.
+    pool: Vec<PoolSlot>,
+    cursor: AtomicUsize,
+    created: Instant,
+    /// smoothed response time in microseconds, zero if not measured yet.
+    srtt: AtomicU64,
+    /// consecutive failures, including timeouts.
+    failures: AtomicU32,
+    /// smoothed rate of the queries answered, in parts per million.
+    success_rate: AtomicU64,
+    /// ejected upstreams are skipped, until a background probe succeeds.
+    ejected: AtomicBool,
+    /// the latency of queries, from sending to the answer received.
+    latency: LatencyHistogram,
+    /// the queries sent, including the retries.
+    queries: AtomicU64,
+    /// the queries not answered, including the timeouts.
+    errors: AtomicU64,
+    timeouts: AtomicU64,
+    last_error: RwLock<Option<(SystemTime, String)>>,
+    tasks: BackgroundTasks,
 }
 
-impl DnsClient {
-    pub fn builder() -> DnsClientBuilder {
-        DnsClientBuilder {
-            matcher: Default::default(),
-            servers: Default::default(),
-            server_groups: Default::default(),
+/// The statistics of an upstream since it was created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamStats {
+    pub name: String,
+    pub queries: u64,
+    pub errors: u64,
+    pub timeouts: u64,
+    pub mean: Option<Duration>,
+    pub p95: Option<Duration>,
+    pub last_error: Option<(SystemTime, String)>,
+}
+
+#[derive(Debug)]
+struct PoolSlot {
+    resolver: RwLock<Resolver>,
+    /// milliseconds since the upstream created, zero if not used since the resolver created.
+    last_used: AtomicU64,
+}
+
+impl Upstream {
+    fn new(
+        name: String,
+        config: NameServerConfigGroup,
+        conn_options: ConnectionOptions,
+        policy: QueryPolicy,
+        options: &UpstreamOptions,
+        tasks: BackgroundTasks,
+    ) -> Result<Arc<Self>, String> {
+        // udp is connectionless, nothing to pool.
+        let is_stream = config.iter().any(|ns| ns.protocol != Protocol::Udp);
+
+        let pool_size = if is_stream {
+            options.pool_size.max(1)
+        } else {
+            1
+        };
+
+        let pool = (0..pool_size)
+            .map(|_| {
+                create_resolver_with_options(config.clone(), conn_options.clone(), Some(&policy))
+                    .map(|resolver| PoolSlot {
+                        resolver: RwLock::new(resolver),
+                        last_used: Default::default(),
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let upstream = Arc::new(Self {
+            name,
+            config,
+            conn_options,
+            policy,
+            types: options.types.clone(),
+            pool,
+            cursor: Default::default(),
+            created: Instant::now(),
+            srtt: Default::default(),
+            failures: Default::default(),
+            success_rate: AtomicU64::new(FULL_SUCCESS_RATE),
+            ejected: Default::default(),
+            latency: Default::default(),
+            queries: Default::default(),
+            errors: Default::default(),
+            timeouts: Default::default(),
+            last_error: Default::default(),
+            tasks,
+        });
+
+        if is_stream {
+            if let Some(interval) = options.heartbeat {
+                upstream.spawn_heartbeat(interval);
+            }
+            upstream.spawn_idle_reaper(options.idle_timeout);
         }
+
+        Ok(upstream)
     }
 
-    pub fn new(
-        matcher: DomainNameServerGroupMatcher,
-        servers: HashMap<String, Vec<DnsServer>>,
-        server_groups: HashMap<String, NameServerConfigGroup>,
-    ) -> Self {
-        use crate::preset_ns::{ALIDNS, CLOUDFLARE, GOOGLE, QUAD9};
+    /// Pick a resolver of the pool in turn.
+    fn resolver(&self) -> Resolver {
+        let slot = &self.pool[self.cursor.fetch_add(1, Ordering::Relaxed) % self.pool.len()];
+        self.use_slot(slot)
+    }
 
-        let bootstrap_servers = NameServerConfigGroup::from_ips_https(
-            preset_ns::find_dns_ips(ALIDNS).unwrap(),
-            443,
-            ALIDNS.to_string(),
-            true,
-        );
+    /// The resolver of the slot, marked used now.
+    fn use_slot(&self, slot: &PoolSlot) -> Resolver {
+        let now = (self.created.elapsed().as_millis() as u64).max(1);
+        slot.last_used.store(now, Ordering::Relaxed);
 
-        let mut nameserver_ips: Mutex<HashMap<Name, Vec<IpAddr>>> = Default::default();
+        slot.resolver
+            .read()
+            .map(|r| r.clone())
+            .unwrap_or_else(|e| e.into_inner().clone())
+    }
 
-        let t = nameserver_ips.get_mut();
+    /// Query through each resolver of the pool periodically, so that the connections are
+    /// neither closed as idle nor dropped by the NAT, and the next query skips the handshakes.
+    fn spawn_heartbeat(self: &Arc<Self>, interval: Duration) {
+        let upstream = Arc::downgrade(self);
 
-        for (name, ips) in [ALIDNS, CLOUDFLARE, GOOGLE, QUAD9].map(|name| {
-            let mut domain_name = Name::from_str(name).unwrap();
-            domain_name.set_fqdn(true);
-            (domain_name, preset_ns::find_dns_ips(name).unwrap())
-        }) {
-            t.insert(name, ips.to_vec());
+        self.tasks.spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            // the first tick completes immediately.
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+
+                let upstream = match upstream.upgrade() {
+                    Some(upstream) => upstream,
+                    None => break,
+                };
+
+                // each slot in turn, the cursor is shared with the queries.
+                for slot in upstream.pool.iter() {
+                    let res = upstream
+                        .use_slot(slot)
+                        .lookup(Name::root(), RecordType::NS)
+                        .timeout(upstream.policy.timeout)
+                        .await;
+
+                    if !matches!(res, Ok(Ok(_))) {
+                        debug!("heartbeat of upstream {} failed", upstream.name);
+                    }
+                }
+            }
+        });
+    }
+
+    fn spawn_idle_reaper(self: &Arc<Self>, idle_timeout: Duration) {
+        let upstream = Arc::downgrade(self);
+        let period = (idle_timeout / 2).max(Duration::from_secs(1));
+
+        self.tasks.spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match upstream.upgrade() {
+                    Some(upstream) => upstream.close_idle(idle_timeout),
+                    None => break,
+                }
+            }
+        });
+    }
+
+    /// Replace the idle resolvers with new ones, so that their connections get closed.
+    fn close_idle(&self, idle_timeout: Duration) {
+        let now = self.created.elapsed().as_millis() as u64;
+        let idle_timeout = idle_timeout.as_millis() as u64;
+
+        for slot in self.pool.iter() {
+            let last_used = slot.last_used.load(Ordering::Relaxed);
+
+            if last_used == 0 || now.saturating_sub(last_used) < idle_timeout {
+                continue;
+            }
+
+            if slot
+                .last_used
+                .compare_exchange(last_used, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+
+            match create_resolver_with_options(
+                self.config.clone(),
+                self.conn_options.clone(),
+                Some(&self.policy),
+            ) {
+                Ok(resolver) => {
+                    if let Ok(mut r) = slot.resolver.write() {
+                        *r = resolver;
+                    }
+                    debug!("idle connection to upstream {} closed", self.name);
+                }
+                Err(err) => warn!("{}", err),
+            }
         }
+    }
 
-        let bootstrap_resolver: TokioAsyncResolver =
-            create_resolver(bootstrap_servers).expect("Create bootstrap resolver failed.");
+    #[inline]
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
 
-        Self {
-            bootstrap_resolver,
-            matcher,
-            servers,
-            server_groups: Mutex::new(server_groups),
-            resolvers: Default::default(),
-            nameserver_ip_store: nameserver_ips,
+    /// The smoothed response time, `None` if not measured yet.
+    pub fn srtt(&self) -> Option<Duration> {
+        match self.srtt.load(Ordering::Relaxed) {
+            0 => None,
+            srtt => Some(Duration::from_micros(srtt)),
         }
     }
 
-    pub fn find_server_group(&self, domain: &LowerName) -> &str {
-        self.matcher
-            .find(domain)
-            .map(|s| s.as_str())
-            .unwrap_or("default")
+    /// The smoothed rate of the queries answered, from 0 to 1.
+    pub fn success_rate(&self) -> f64 {
+        self.success_rate.load(Ordering::Relaxed) as f64 / FULL_SUCCESS_RATE as f64
     }
 
-    pub async fn lookup_nameserver_ip(
-        &self,
-        name: Name,
-        record_type: RecordType,
-    ) -> Option<Lookup> {
-        if let Some(ips) = self.nameserver_ip_store.lock().await.get(&name) {
-            let records = ips
-                .iter()
-                .filter(|ip| {
-                    if record_type == RecordType::A {
-                        ip.is_ipv4()
-                    } else if record_type == RecordType::AAAA {
-                        ip.is_ipv6()
-                    } else {
-                        false
-                    }
-                })
-                .map(|ip| match ip {
-                    IpAddr::V4(ip) => RData::A(*ip),
-                    IpAddr::V6(ip) => RData::AAAA(*ip),
+    /// The smoothed response time divided by the success rate, zero if not measured yet.
+    fn weighted_rtt(&self) -> u64 {
+        let srtt = self.srtt.load(Ordering::Relaxed);
+        let rate = self
+            .success_rate
+            .load(Ordering::Relaxed)
+            .max(FULL_SUCCESS_RATE / 100);
+        srtt.saturating_mul(FULL_SUCCESS_RATE) / rate
+    }
+
+    #[inline]
+    pub fn latency(&self) -> &LatencyHistogram {
+        &self.latency
+    }
+
+    /// The phase latency of the tcp based transports, per nameserver address.
+    pub fn transport_metrics(&self) -> Vec<(SocketAddr, Protocol, Arc<TransportMetrics>)> {
+        self.config
+            .iter()
+            .filter(|ns| ns.protocol != Protocol::Udp)
+            .map(|ns| {
+                (
+                    ns.socket_addr,
+                    ns.protocol,
+                    transport_metrics(ns.socket_addr),
+                )
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub fn is_healthy(&self) -> bool {
+        !self.ejected.load(Ordering::Relaxed)
+    }
+
+    async fn query<T, F, Fut>(self: &Arc<Self>, f: &F) -> Result<T, ResolveError>
+    where
+        F: Fn(Resolver) -> Fut,
+        Fut: Future<Output = Result<T, ResolveError>>,
+    {
+        let mut retry = 0;
+
+        loop {
+            let start = Instant::now();
+            self.queries.fetch_add(1, Ordering::Relaxed);
+
+            let res = f(self.resolver())
+                .timeout(self.policy.timeout)
+                .await
+                .unwrap_or_else(|_| Err(ResolveErrorKind::Timeout.into()));
+
+            // a bogus answer, or one out of the whitelisted countries or ips, is still an
+            // answer, the upstream is not to blame.
+            let answered = match res.as_ref() {
+                Ok(_) => true,
+                Err(err) => {
+                    is_answer(err)
+                        || is_bogus_answer(err)
+                        || is_geoip_discarded(err)
+                        || is_ip_filtered(err)
+                }
+            };
+
+            if answered {
+                let elapsed = start.elapsed();
+                self.latency.record(elapsed);
+                self.update_srtt(elapsed);
+                self.on_success();
+                return res;
+            }
+
+            if let Err(err) = res.as_ref() {
+                self.record_error(err);
+            }
+
+            // penalize the failed upstream, so that the others are preferred.
+            self.update_srtt(self.policy.timeout);
+            self.on_failure();
+
+            if retry >= self.policy.retry || !self.is_healthy() {
+                return res;
+            }
+
+            retry += 1;
+
+            let backoff = self.policy.backoff(retry);
+            debug!(
+                "retry upstream {} after {:?}, {}/{}",
+                self.name, backoff, retry, self.policy.retry
+            );
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    fn update_srtt(&self, rtt: Duration) {
+        let rtt = (rtt.as_micros() as u64).max(1);
+        let _ = self
+            .srtt
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |srtt| {
+                Some(if srtt == 0 { rtt } else { (srtt * 7 + rtt) / 8 })
+            });
+    }
+
+    fn update_success_rate(&self, success: bool) {
+        let _ = self
+            .success_rate
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |rate| {
+                Some(if success {
+                    rate + (FULL_SUCCESS_RATE - rate) / 8
+                } else {
+                    rate - rate / 8
                 })
-                .map(|r| Record::from_rdata(name.clone(), 0, r))
-                .collect();
+            });
+    }
 
-            Some(Lookup::new_with_max_ttl(
-                Query::query(name, record_type),
-                records,
-            ))
-        } else {
-            None
+    #[inline]
+    fn on_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.update_success_rate(true);
+    }
+
+    fn on_failure(self: &Arc<Self>) {
+        self.update_success_rate(false);
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if failures >= MAX_CONSECUTIVE_FAILURES && !self.ejected.swap(true, Ordering::Relaxed) {
+            warn!(
+                "upstream {} ejected after {} consecutive failures",
+                self.name, failures
+            );
+
+            let upstream = Arc::clone(self);
+            self.tasks.spawn(async move { upstream.probe().await });
         }
     }
 
-    /// Performs a dual-stack DNS lookup for the IP for the given hostname.
-    ///
-    /// See the configuration and options parameters for controlling the way in which A(Ipv4) and AAAA(Ipv6) lookups will be performed. For the least expensive query a fully-qualified-domain-name, FQDN, which ends in a final `.`, e.g. `www.example.com.`, will only issue one query. Anything else will always incur the cost of querying the `ResolverConfig::domain` and `ResolverConfig::search`.
-    ///
-    /// # Arguments
-    /// * `host` - string hostname, if this is an invalid hostname, an error will be returned.
-    pub async fn lookup_ip<N: IntoName + TryParseIp + Clone>(
-        &self,
-        host: N,
-    ) -> Result<LookupIp, ResolveError> {
-        if let Ok(name) = host.clone().into_name() {
-            let group_name = self
-                .matcher
-                .find(&name.to_owned().into())
-                .map(|s| s.as_str())
-                .unwrap_or("default");
-
-            self.get_or_create_resolver(group_name)
-                .await
-                .unwrap()
-                .lookup_ip(host)
-                .timeout(Duration::from_secs(LOOKUP_TIMEOUT))
-                .await
-                .unwrap_or(Err(ResolveErrorKind::Timeout.into()))
-        } else {
-            self.get_or_create_resolver("default")
-                .await
-                .unwrap()
-                .lookup_ip(host)
-                .timeout(Duration::from_secs(LOOKUP_TIMEOUT))
-                .await
-                .unwrap_or(Err(ResolveErrorKind::Timeout.into()))
+    fn record_error(&self, err: &ResolveError) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+
+        if matches!(err.kind(), ResolveErrorKind::Timeout) {
+            self.timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if let Ok(mut last_error) = self.last_error.write() {
+            *last_error = Some((SystemTime::now(), err.to_string()));
         }
     }
 
-    /// Lookup any RecordType
-    ///
-    /// *WARNING* this interface may change in the future, see if one of the specializations would be better.
-    ///
-    /// # Arguments
-    ///
-    /// * `name` - name of the record to lookup, if name is not a valid domain name, an error will be returned
-    /// * `record_type` - type of record to lookup, all RecordData responses will be filtered to this type
-    ///
-    /// # Returns
+    pub fn stats(&self) -> UpstreamStats {
+        UpstreamStats {
+            name: self.name.clone(),
+            queries: self.queries.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            mean: self.latency.mean(),
+            p95: self.latency.percentile(0.95),
+            last_error: self.last_error.read().ok().and_then(|e| e.clone()),
+        }
+    }
+
+    /// Probe the ejected upstream with backoff, readmit it once it answers.
+    async fn probe(&self) {
+        let mut interval = PROBE_INTERVAL;
+
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let res = self
+                .resolver()
+                .lookup(Name::root(), RecordType::NS)
+                .timeout(self.policy.timeout)
+                .await;
+
+            match res {
+                Ok(Ok(_)) => break,
+                Ok(Err(err)) if is_answer(&err) => break,
+                _ => {
+                    debug!("probe upstream {} failed", self.name);
+                    interval = (interval * 2).min(MAX_PROBE_INTERVAL);
+                }
+            }
+        }
+
+        self.failures.store(0, Ordering::Relaxed);
+        self.ejected.store(false, Ordering::Relaxed);
+
+        info!("upstream {} readmitted", self.name);
+    }
+}
+
+impl std::fmt::Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: [{}], success rate: {:.1}%",
+            self.name,
+            self.latency,
+            self.success_rate() * 100.0
+        )?;
+        for (addr, protocol, metrics) in self.transport_metrics() {
+            write!(f, ", {} {}: {}", protocol, addr, metrics)?;
+        }
+        Ok(())
+    }
+}
+
+/// Send the queries from the source ip, or the address of the interface of the same family,
+/// the link-local ones skipped, which are unusable without the scope.
+fn apply_bind(mut config: NameServerConfigGroup, server: &DnsServer) -> NameServerConfigGroup {
+    let local_ips = match (server.source_ip, server.interface.as_ref()) {
+        (Some(ip), _) => vec![ip],
+        (None, Some(name)) => match iface::interface_addrs(name).map(without_link_local) {
+            Ok(ips) if !ips.is_empty() => ips,
+            Ok(_) => {
+                warn!("interface {} has no address, server {}", name, server.url);
+                return config;
+            }
+            Err(err) => {
+                warn!("find interface {} failed, {}", name, err);
+                return config;
+            }
+        },
+        (None, None) => return config,
+    };
+
+    for ns in config.iter_mut() {
+        let local_ip = local_ips
+            .iter()
+            .find(|ip| ip.is_ipv4() == ns.socket_addr.is_ipv4())
+            .copied();
+
+        match local_ip {
+            Some(ip) => ns.bind_addr = Some(SocketAddr::new(ip, 0)),
+            None => warn!(
+                "no local address of the same family to reach nameserver {}",
+                ns.socket_addr
+            ),
+        }
+    }
+
+    config
+}
+
+fn without_link_local(ips: Vec<IpAddr>) -> Vec<IpAddr> {
+    ips.into_iter()
+        .filter(|ip| match ip {
+            IpAddr::V4(v4) => !v4.is_link_local(),
+            IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 != 0xfe80,
+        })
+        .collect()
+}
+
+/// Override the transport of the nameservers, `None` if no nameserver is left.
+fn apply_force_transport(
+    mut config: NameServerConfigGroup,
+    force_transport: Option<ForceTransport>,
+) -> Option<NameServerConfigGroup> {
+    match force_transport {
+        Some(ForceTransport::Tcp) => {
+            for ns in config.iter_mut() {
+                if ns.protocol == Protocol::Udp {
+                    ns.protocol = Protocol::Tcp;
+                }
+            }
+        }
+        Some(ForceTransport::Encrypted) => config.retain(|ns| ns.protocol.is_encrypted()),
+        None => (),
+    }
+
+    if config.is_empty() {
+        None
+    } else {
+        Some(config)
+    }
+}
+
+/// Pair each udp nameserver with a tcp one of the same address,
+/// so that the truncated answers are retried over tcp rather than clipped.
+fn with_tcp_fallback(mut config: NameServerConfigGroup) -> NameServerConfigGroup {
+    let fallbacks = config
+        .iter()
+        .filter(|ns| ns.protocol == Protocol::Udp)
+        .filter(|ns| {
+            !config
+                .iter()
+                .any(|n| n.protocol == Protocol::Tcp && n.socket_addr == ns.socket_addr)
+        })
+        .map(|ns| NameServerConfig {
+            protocol: Protocol::Tcp,
+            ..ns.clone()
+        })
+        .collect::<Vec<_>>();
+
+    config.merge(fallbacks.into());
+    config
+}
+
+/// The records of the lookups together, each record once, valid until the earliest expires.
+fn merge_lookups(lookups: Vec<Lookup>) -> Option<Lookup> {
+    let query = lookups.first()?.query().clone();
+    let valid_until = lookups.iter().map(|lookup| lookup.valid_until()).min()?;
+
+    let mut records: Vec<Record> = vec![];
+    for record in lookups.iter().flat_map(|lookup| lookup.records()) {
+        if !records
+            .iter()
+            .any(|r| r.name() == record.name() && r.data() == record.data())
+        {
+            records.push(record.clone());
+        }
+    }
+
+    Some(Lookup::new_with_deadline(
+        query,
+        Arc::from(records),
+        valid_until,
+    ))
+}
+
+/// Whether the error is an answer of the upstream, e.g. NXDOMAIN, rather than a failure to get one.
+#[inline]
+fn is_answer(err: &ResolveError) -> bool {
+    matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+/// All upstreams answered the bogus ips, the name is taken as nonexistent.
+fn bogus_nxdomain(query: Query) -> DnsError {
+    ResolveErrorKind::NoRecordsFound {
+        query: Box::new(query),
+        soa: None,
+        negative_ttl: None,
+        response_code: ResponseCode::NXDomain,
+        trusted: true,
+    }
+    .into()
+}
+
+/// No upstream answered, or they answered SERVFAIL.
+fn is_failure(err: &ResolveError) -> bool {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound { response_code, .. } => {
+            *response_code == ResponseCode::ServFail
+        }
+        _ => true,
+    }
+}
+
+pub struct DnsClientBuilder {
+    matcher: Option<DomainNameServerGroupMatcher>,
+    transport_matcher: Option<DomainForceTransportMatcher>,
+    servers: HashMap<String, Vec<DnsServer>>,
+    proxies: HashMap<String, ProxyConfig>,
+    options: UpstreamOptions,
+    tasks: BackgroundTasks,
+    bootstrap_servers: Vec<DnsServer>,
+
+    server_groups: HashMap<String, NameServerConfigGroup>,
+}
+
+impl DnsClientBuilder {
+    pub fn add_server<S: Into<DnsServer>>(mut self, server: S) -> Self {
+        let server = server.into();
+
+        let mut group_names = server
+            .group
+            .iter()
+            .filter(|g| *g != "default")
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if !server.exclude_default_group || group_names.is_empty() {
+            group_names.push("default".to_string());
+        }
+
+        for group_name in group_names {
+            self.servers
+                .entry(group_name)
+                .or_insert_with(Vec::new)
+                .push(server.clone());
+        }
+
+        self
+    }
+
+    pub fn add_proxy(mut self, name: String, proxy: ProxyConfig) -> Self {
+        self.proxies.insert(name, proxy);
+        self
+    }
+
+    pub fn with_query_strategy(mut self, query_strategy: QueryStrategy) -> Self {
+        self.options.query_strategy = query_strategy;
+        self
+    }
+
+    pub fn with_options(mut self, options: UpstreamOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn with_background_tasks(mut self, tasks: BackgroundTasks) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    pub fn add_server_group(mut self, server_group: NameServerConfigGroup) -> Self {
+        use std::collections::hash_map::Entry::*;
+
+        match self.server_groups.entry("default".to_string()) {
+            Occupied(mut o) => {
+                *(o.get_mut()) = server_group;
+            }
+            Vacant(v) => {
+                v.insert(server_group);
+            }
+        }
+
+        self
+    }
+
+    pub fn add_bootstrap_server<S: Into<DnsServer>>(mut self, server: S) -> Self {
+        self.bootstrap_servers.push(server.into());
+        self
+    }
+
+    pub fn build(self) -> DnsClient {
+        let mut client = DnsClient::new(
+            self.matcher.unwrap_or_default(),
+            self.transport_matcher.unwrap_or_default(),
+            self.servers,
+            &self.bootstrap_servers,
+            self.proxies,
+            self.options,
+            self.tasks,
+        );
+        client.server_groups = self.server_groups;
+        client
+    }
+}
+
+#[derive(Debug)]
+pub struct DnsClient {
+    bootstrap_resolver: Resolver,
+    matcher: DomainNameServerGroupMatcher,
+    transport_matcher: DomainForceTransportMatcher,
+    servers: HashMap<String, Vec<DnsServer>>,
+    proxies: HashMap<String, ProxyConfig>,
+    options: UpstreamOptions,
+    tasks: BackgroundTasks,
+    server_groups: HashMap<String, NameServerConfigGroup>,
+    resolvers: Mutex<HashMap<(String, Option<ForceTransport>), Arc<ServerGroup>>>,
+    nameserver_ip_store: Mutex<HashMap<Name, Vec<IpAddr>>>,
+}
+
+impl DnsClient {
+    pub fn builder() -> DnsClientBuilder {
+        DnsClientBuilder {
+            matcher: Default::default(),
+            transport_matcher: Default::default(),
+            servers: Default::default(),
+            proxies: Default::default(),
+            options: Default::default(),
+            tasks: Default::default(),
+            bootstrap_servers: Default::default(),
+            server_groups: Default::default(),
+        }
+    }
+
+    pub fn new(
+        matcher: DomainNameServerGroupMatcher,
+        transport_matcher: DomainForceTransportMatcher,
+        servers: HashMap<String, Vec<DnsServer>>,
+        bootstrap_servers: &[DnsServer],
+        proxies: HashMap<String, ProxyConfig>,
+        options: UpstreamOptions,
+        tasks: BackgroundTasks,
+    ) -> Self {
+        use crate::preset_ns::{ALIDNS, CLOUDFLARE, GOOGLE, QUAD9};
+
+        let mut nameserver_ips: Mutex<HashMap<Name, Vec<IpAddr>>> = Default::default();
+
+        let t = nameserver_ips.get_mut();
+
+        for (name, ips) in [ALIDNS, CLOUDFLARE, GOOGLE, QUAD9].map(|name| {
+            let mut domain_name = Name::from_str(name).unwrap();
+            domain_name.set_fqdn(true);
+            (domain_name, preset_ns::find_dns_ips(name).unwrap())
+        }) {
+            t.insert(name, ips.to_vec());
+        }
+
+        let bootstrap_resolver: Resolver = create_resolver(bootstrap_config(bootstrap_servers))
+            .expect("Create bootstrap resolver failed.");
+
+        Self {
+            bootstrap_resolver,
+            matcher,
+            transport_matcher,
+            servers,
+            proxies,
+            options,
+            tasks,
+            server_groups: Default::default(),
+            resolvers: Default::default(),
+            nameserver_ip_store: nameserver_ips,
+        }
+    }
+
+    /// The countries of the ips, if the geoip-file loaded.
+    #[inline]
+    pub fn geoip(&self) -> Option<&Arc<GeoIp>> {
+        self.options.geoip.as_ref()
+    }
+
+    pub fn find_server_group(&self, domain: &LowerName) -> &str {
+        self.match_server_group(domain).unwrap_or("default")
+    }
+
+    /// The server group of the nameserver rule matching the domain.
+    #[inline]
+    pub fn match_server_group(&self, domain: &LowerName) -> Option<&str> {
+        self.matcher.find(domain).map(|s| s.as_str())
+    }
+
+    #[inline]
+    pub fn find_force_transport(&self, domain: &LowerName) -> Option<ForceTransport> {
+        self.transport_matcher.find(domain).copied()
+    }
+
+    pub async fn lookup_nameserver_ip(
+        &self,
+        name: Name,
+        record_type: RecordType,
+    ) -> Option<Lookup> {
+        if let Some(ips) = self.nameserver_ip_store.lock().await.get(&name) {
+            let records = ips
+                .iter()
+                .filter(|ip| {
+                    if record_type == RecordType::A {
+                        ip.is_ipv4()
+                    } else if record_type == RecordType::AAAA {
+                        ip.is_ipv6()
+                    } else {
+                        false
+                    }
+                })
+                .map(|ip| match ip {
+                    IpAddr::V4(ip) => RData::A(*ip),
+                    IpAddr::V6(ip) => RData::AAAA(*ip),
+                })
+                .map(|r| Record::from_rdata(name.clone(), 0, r))
+                .collect();
+
+            Some(Lookup::new_with_max_ttl(
+                Query::query(name, record_type),
+                records,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Performs a dual-stack DNS lookup for the IP for the given hostname.
+    ///
+    /// See the configuration and options parameters for controlling the way in which A(Ipv4) and AAAA(Ipv6) lookups will be performed. For the least expensive query a fully-qualified-domain-name, FQDN, which ends in a final `.`, e.g. `www.example.com.`, will only issue one query. Anything else will always incur the cost of querying the `ResolverConfig::domain` and `ResolverConfig::search`.
+    ///
+    /// # Arguments
+    /// * `host` - string hostname, if this is an invalid hostname, an error will be returned.
+    pub async fn lookup_ip<N: IntoName + TryParseIp + Clone>(
+        &self,
+        host: N,
+    ) -> Result<LookupIp, ResolveError> {
+        let (group_name, force_transport) = match host.clone().into_name() {
+            Ok(name) => {
+                let name = name.into();
+                (
+                    self.find_server_group(&name),
+                    self.find_force_transport(&name),
+                )
+            }
+            Err(_) => ("default", None),
+        };
+
+        match self
+            .get_or_create_server_group(group_name, force_transport)
+            .await
+        {
+            Some(group) => {
+                group
+                    .query(None, |resolver| {
+                        let host = host.clone();
+                        async move { resolver.lookup_ip(host).await }
+                    })
+                    .await
+            }
+            None => Err(ResolveErrorKind::Message("no available upstream").into()),
+        }
+    }
+
+    /// Lookup any RecordType
+    ///
+    /// *WARNING* this interface may change in the future, see if one of the specializations would be better.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the record to lookup, if name is not a valid domain name, an error will be returned
+    /// * `record_type` - type of record to lookup, all RecordData responses will be filtered to this type
+    ///
+    /// # Returns
     ///
     //  A future for the returned Lookup RData
     pub async fn lookup<N: IntoName>(
@@ -292,329 +1257,1100 @@ impl DnsClient {
             Err(err) => return Err(err.into()),
         };
 
-        let group_name =
-            group_name.unwrap_or_else(|| self.find_server_group(&name.to_owned().into()));
+        let lower_name = name.to_owned().into();
+        let group_name = group_name.unwrap_or_else(|| self.find_server_group(&lower_name));
+        let force_transport = self.find_force_transport(&lower_name);
+
+        match self
+            .get_or_create_server_group(group_name, force_transport)
+            .await
+        {
+            Some(group) => group
+                .query(Some(record_type), |resolver| {
+                    let name = name.clone();
+                    async move { resolver.lookup(name, record_type).await }
+                })
+                .await
+                .map_err(|err| {
+                    if is_bogus_answer(&err) {
+                        bogus_nxdomain(Query::query(name, record_type))
+                    } else {
+                        err
+                    }
+                }),
+            None => Err(ResolveErrorKind::Message("no available upstream").into()),
+        }
+    }
+
+    /// Resolve by all the upstreams of the group at once, their answers merged, so that the ips
+    /// of all of them are speed checked, see `response-mode fastest-ip`.
+    pub async fn lookup_all<N: IntoName>(
+        &self,
+        name: N,
+        record_type: RecordType,
+        group_name: Option<&str>,
+    ) -> Result<Lookup, DnsError> {
+        let name = match name.into_name() {
+            Ok(name) => name,
+            Err(err) => return Err(err.into()),
+        };
+
+        let lower_name = name.to_owned().into();
+        let group_name = group_name.unwrap_or_else(|| self.find_server_group(&lower_name));
+        let force_transport = self.find_force_transport(&lower_name);
+
+        match self
+            .get_or_create_server_group(group_name, force_transport)
+            .await
+        {
+            Some(group) => group
+                .query_all(record_type, |resolver| {
+                    let name = name.clone();
+                    async move { resolver.lookup(name, record_type).await }
+                })
+                .await
+                .map_err(|err| {
+                    if is_bogus_answer(&err) {
+                        bogus_nxdomain(Query::query(name, record_type))
+                    } else {
+                        err
+                    }
+                }),
+            None => Err(ResolveErrorKind::Message("no available upstream").into()),
+        }
+    }
+
+    /// The server groups created so far, along with their forced transport.
+    pub async fn created_server_groups(
+        &self,
+    ) -> Vec<(String, Option<ForceTransport>, Arc<ServerGroup>)> {
+        self.resolvers
+            .lock()
+            .await
+            .iter()
+            .map(|((name, force_transport), group)| {
+                (name.clone(), *force_transport, Arc::clone(group))
+            })
+            .collect()
+    }
+
+    /// Get the server group, the default one is used if the group not found.
+    pub async fn get_or_create_server_group(
+        &self,
+        group_name: &str,
+        force_transport: Option<ForceTransport>,
+    ) -> Option<Arc<ServerGroup>> {
+        let group_name = if self.servers.contains_key(group_name)
+            || self.server_groups.contains_key(group_name)
+        {
+            group_name
+        } else {
+            debug!("server group {} not found, fallback to default", group_name);
+            "default"
+        };
+
+        let key = (group_name.to_string(), force_transport);
+
+        if let Some(group) = self.resolvers.lock().await.get(&key) {
+            return Some(Arc::clone(group));
+        }
+
+        let bogus_ips = Arc::new(self.options.bogus_nxdomain.clone());
+        let ignore_ips = Arc::new(self.options.ignore_ip.clone());
+        let blacklist_ips = Arc::new(self.options.blacklist_ip.clone());
+        let whitelist_ips = Arc::new(self.options.whitelist_ip.clone());
+
+        let mut upstreams = vec![];
+        let mut fallbacks = vec![];
+
+        if let Some(config) = self.server_groups.get(group_name) {
+            if let Some(config) =
+                apply_force_transport(config.clone(), force_transport).map(with_tcp_fallback)
+            {
+                match Upstream::new(
+                    group_name.to_string(),
+                    config,
+                    ConnectionOptions {
+                        subnet: self.options.subnet,
+                        bogus_nxdomain: bogus_ips.clone(),
+                        ignore_ip: ignore_ips.clone(),
+                        padding: self.options.padding,
+                        dnstap: self.options.dnstap.clone(),
+                        ..Default::default()
+                    },
+                    Default::default(),
+                    &self.options,
+                    self.tasks.clone(),
+                ) {
+                    Ok(upstream) => upstreams.push(upstream),
+                    Err(err) => warn!("{}", err),
+                }
+            }
+        }
+
+        for server in self.servers.get(group_name).into_iter().flatten() {
+            let (config, transport) = match self.create_upstream_config(server).await {
+                Some(upstream) => upstream,
+                None => continue,
+            };
+
+            let config = match apply_force_transport(
+                config,
+                force_transport.or_else(|| server.force_tcp.then_some(ForceTransport::Tcp)),
+            )
+            .map(with_tcp_fallback)
+            {
+                Some(config) => config,
+                None => continue,
+            };
+
+            let transport = Arc::new(transport);
+
+            let odoh = match server.odoh {
+                true => match self.create_odoh_client(server, &config, &transport).await {
+                    Ok(odoh) => Some(Arc::new(odoh)),
+                    Err(err) => {
+                        warn!("{} not used, {}", server.url.to_string(), err);
+                        continue;
+                    }
+                },
+                false => None,
+            };
+
+            let options = UpstreamOptions {
+                idle_timeout: server.idle_timeout.unwrap_or(self.options.idle_timeout),
+                heartbeat: server.heartbeat,
+                types: server.types.clone(),
+                ..self.options.clone()
+            };
+
+            match Upstream::new(
+                server.url.to_string(),
+                config,
+                ConnectionOptions {
+                    subnet: server.client_subnet(self.options.subnet),
+                    check_edns: server.check_edns,
+                    bogus_nxdomain: bogus_ips.clone(),
+                    ignore_ip: ignore_ips.clone(),
+                    blacklist_ip: match server.blacklist_ip {
+                        true => blacklist_ips.clone(),
+                        false => Default::default(),
+                    },
+                    whitelist_ip: match server.whitelist_ip {
+                        true => whitelist_ips.clone(),
+                        false => Default::default(),
+                    },
+                    dnscrypt: server
+                        .url
+                        .dnscrypt()
+                        .map(|stamp| Arc::new(DnsCryptClient::new(stamp.clone()))),
+                    odoh,
+                    padding: self.options.padding,
+                    dnstap: self.options.dnstap.clone(),
+                    geoip: self.options.geoip.clone(),
+                    whitelist_geoip: Arc::new(server.whitelist_geoip.clone()),
+                    transport,
+                },
+                server.policy,
+                &options,
+                self.tasks.clone(),
+            ) {
+                Ok(upstream) if server.fallback => fallbacks.push(upstream),
+                Ok(upstream) => upstreams.push(upstream),
+                Err(err) => warn!("{}", err),
+            }
+        }
+
+        if upstreams.is_empty() && fallbacks.is_empty() {
+            warn!(
+                "no available upstream in server group {}, force transport: {:?}",
+                group_name, force_transport
+            );
+            return None;
+        }
+
+        let group = Arc::new(ServerGroup::new(
+            self.options.query_strategy,
+            upstreams,
+            fallbacks,
+        ));
+
+        self.resolvers.lock().await.insert(key, Arc::clone(&group));
+
+        Some(group)
+    }
+
+    /// The nameservers of the server, and the transport of its connections.
+    async fn create_upstream_config(
+        &self,
+        s: &DnsServer,
+    ) -> Option<(NameServerConfigGroup, Transport)> {
+        if let Some(domain) = s.url.get_domain() {
+            match Name::from_str(domain) {
+                Ok(domain_name) => {
+                    let addrs = self.resolve_nameserver_ips(domain).await;
+
+                    self.nameserver_ip_store
+                        .lock()
+                        .await
+                        .entry(domain_name)
+                        .and_modify(|v| *v = addrs.clone())
+                        .or_insert(addrs.clone());
+
+                    if let Some(c) = self
+                        .create_nameserver_config_group(&s.url, Some(addrs))
+                        .await
+                    {
+                        if !c.is_empty() {
+                            let c = match apply_tls(c, s) {
+                                Ok(c) => apply_bind(c, s),
+                                Err(err) => {
+                                    warn!("{} not used, {}", s.url.to_string(), err);
+                                    return None;
+                                }
+                            };
+                            let transport = match self.transport(s, &c) {
+                                Ok(transport) => Transport {
+                                    dual_stack: dual_stack(&c),
+                                    ..transport
+                                },
+                                Err(err) => {
+                                    warn!("{} not used, {}", s.url.to_string(), err);
+                                    return None;
+                                }
+                            };
+                            return Some((c, transport));
+                        }
+                    }
+                }
+                Err(err) => {
+                    warn!("{:?}", err);
+                }
+            };
+        }
+
+        match self.create_nameserver_config_group(&s.url, None).await {
+            Some(c) if !c.is_empty() => {
+                let c = match apply_tls(c, s) {
+                    Ok(c) => apply_bind(c, s),
+                    Err(err) => {
+                        warn!("{} not used, {}", s.url.to_string(), err);
+                        return None;
+                    }
+                };
+                match self.transport(s, &c) {
+                    Ok(transport) => Some((c, transport)),
+                    Err(err) => {
+                        warn!("{} not used, {}", s.url.to_string(), err);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The odoh client of the server, connecting to the target as its other transports, by the
+    /// addresses resolved, through its proxy and with its tls config, and to the relay likewise.
+    async fn create_odoh_client(
+        &self,
+        server: &DnsServer,
+        config: &NameServerConfigGroup,
+        transport: &Arc<Transport>,
+    ) -> Result<OdohClient, String> {
+        let target = url::Url::parse(&server.url.to_string())
+            .map_err(|err| format!("invalid odoh target, {}", err))?;
+        let target_host = target
+            .host_str()
+            .ok_or_else(|| "odoh target without host".to_string())?
+            .to_string();
+
+        let servers = config
+            .iter()
+            .filter(|ns| ns.protocol == Protocol::Https)
+            .collect::<Vec<_>>();
+
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            target_host,
+            HttpHost {
+                addrs: servers.iter().map(|ns| ns.socket_addr).collect(),
+                tls: servers
+                    .iter()
+                    .find_map(|ns| ns.tls_config.as_ref())
+                    .map(|tls_config| tls_config.0.clone())
+                    .unwrap_or_else(|| Arc::new(webpki_client_config(Protocol::Https))),
+            },
+        );
+
+        if let Some(relay) = server.relay.as_ref() {
+            let (host, addrs) = self.resolve_url_addrs(relay).await?;
+
+            // the pins and the ca-file are of the target.
+            hosts.insert(
+                host,
+                HttpHost {
+                    addrs,
+                    tls: Arc::new(webpki_client_config(Protocol::Https)),
+                },
+            );
+        }
+
+        let bind = servers.iter().find_map(|ns| ns.bind_addr);
+
+        Ok(OdohClient::new(
+            target,
+            server.relay.clone(),
+            HttpClient::new(hosts, bind, transport.clone()),
+        ))
+    }
+
+    /// The http client of the url, e.g. of a blocklist, its host resolved by the bootstrap dns
+    /// rather than the system resolver, which may be this server.
+    pub async fn http_client(&self, url: &url::Url) -> Result<HttpClient, String> {
+        let (host, addrs) = self.resolve_url_addrs(url).await?;
+
+        let mut client_config = webpki_client_config(Protocol::Https);
+        client_config.alpn_protocols.push(b"http/1.1".to_vec());
+
+        let mut hosts = HashMap::new();
+        hosts.insert(
+            host,
+            HttpHost {
+                addrs,
+                tls: Arc::new(client_config),
+            },
+        );
+
+        Ok(HttpClient::new(hosts, None, Default::default()))
+    }
+
+    /// The host of the url and its addresses, the ip of the url itself or the ones resolved as
+    /// a nameserver is.
+    async fn resolve_url_addrs(&self, url: &url::Url) -> Result<(String, Vec<SocketAddr>), String> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| format!("{} without host", url))?;
+        let port = url.port_or_known_default().unwrap_or(443);
+
+        let ips = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(ip) => vec![ip],
+            Err(_) => self.resolve_nameserver_ips(host).await,
+        };
+        if ips.is_empty() {
+            return Err(format!("resolve {} failed", host));
+        }
+
+        let addrs = ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+
+        Ok((host.to_string(), addrs))
+    }
+
+    /// Resolve the hostname of a nameserver, with the server group of the nameserver rule if matched,
+    /// otherwise with the bootstrap dns.
+    async fn resolve_nameserver_ips(&self, domain: &str) -> Vec<IpAddr> {
+        let config = match Name::from_str(domain)
+            .ok()
+            .and_then(|name| self.matcher.find(&LowerName::from(name)))
+            .and_then(|g_name| self.servers.get(g_name))
+        {
+            Some(servers) => future::join_all(
+                servers
+                    .iter()
+                    .map(|c| self.create_nameserver_config_group(&c.url, None)),
+            )
+            .await
+            .into_iter()
+            .flat_map(|x| x.into_iter())
+            .reduce(|mut p, c| {
+                p.merge(c);
+                p
+            }),
+            None => None,
+        };
+
+        match config {
+            Some(c) => {
+                if let Ok(resolver) = create_resolver(c) {
+                    resolver
+                        .lookup_ip(domain)
+                        .await
+                        .map(|r| r.into_iter().collect::<Vec<_>>())
+                        .unwrap_or_default()
+                } else {
+                    Default::default()
+                }
+            }
+            None => self
+                .bootstrap_resolver
+                .lookup_ip(domain)
+                .await
+                .map(|r| r.into_iter().collect::<Vec<_>>())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Re-resolve the hostname of nameservers periodically,
+    /// the server groups are recreated once their addresses changed.
+    pub fn spawn_nameserver_refresh(self: &Arc<Self>) {
+        let client = Arc::downgrade(self);
+
+        self.tasks.spawn(async move {
+            let mut interval = tokio::time::interval(NAMESERVER_REFRESH_INTERVAL);
+            // the first tick completes immediately.
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+                match client.upgrade() {
+                    Some(client) => client.refresh_nameserver_ips().await,
+                    None => break,
+                }
+            }
+        });
+    }
+
+    async fn refresh_nameserver_ips(&self) {
+        let mut changed = HashSet::new();
+
+        let domains = self
+            .servers
+            .values()
+            .flatten()
+            .filter_map(|s| s.url.get_domain())
+            .collect::<HashSet<_>>();
+
+        for domain in domains {
+            let domain_name = match Name::from_str(domain) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+
+            let mut addrs = self.resolve_nameserver_ips(domain).await;
+
+            // keep the known addresses, if failed to resolve.
+            if addrs.is_empty() {
+                continue;
+            }
+
+            addrs.sort();
+
+            let mut store = self.nameserver_ip_store.lock().await;
+
+            let unchanged = store
+                .get(&domain_name)
+                .map(|v| {
+                    let mut v = v.clone();
+                    v.sort();
+                    v == addrs
+                })
+                .unwrap_or(false);
+
+            if !unchanged {
+                info!("nameserver {} addresses changed: {:?}", domain, addrs);
+                store.insert(domain_name, addrs);
+                changed.insert(domain);
+            }
+        }
+
+        if changed.is_empty() {
+            return;
+        }
+
+        let changed = self
+            .servers
+            .values()
+            .flatten()
+            .filter(|s| matches!(s.url.get_domain(), Some(d) if changed.contains(d)))
+            .map(|s| s.url.to_string())
+            .collect::<HashSet<_>>();
+
+        self.resolvers.lock().await.retain(|_, group| {
+            !group
+                .upstreams()
+                .iter()
+                .chain(group.fallbacks())
+                .any(|u| changed.contains(u.name()))
+        });
+    }
+
+    /// The transport of the connections to the server, through its proxy if any, an error
+    /// rather than connecting directly if the proxy is unknown or can not tunnel them.
+    fn transport(
+        &self,
+        server: &DnsServer,
+        config: &NameServerConfigGroup,
+    ) -> Result<Transport, String> {
+        let proxy = match server.proxy.as_ref() {
+            Some(name) => {
+                let proxy = self
+                    .proxies
+                    .get(name)
+                    .ok_or_else(|| format!("proxy server {} not found", name))?;
+                if !server.force_tcp {
+                    if let Some(ns) = config.iter().find(|ns| ns.protocol == Protocol::Udp) {
+                        return Err(format!(
+                            "udp nameserver {} can not connect through proxy",
+                            ns.socket_addr
+                        ));
+                    }
+                }
+                debug!(
+                    "nameserver {} connect through proxy",
+                    server.url.to_string()
+                );
+                Some(proxy.clone())
+            }
+            None => None,
+        };
+
+        Ok(Transport {
+            proxy,
+            device: server.interface.clone(),
+            keepalive: server.tcp_keepalive,
+            dual_stack: None,
+        })
+    }
+
+    pub async fn create_nameserver_config_group(
+        &self,
+        url: &DnsUrl,
+        addrs: Option<Vec<IpAddr>>,
+    ) -> Option<NameServerConfigGroup> {
+        use url::Host;
+
+        let addrs = if let Some(addrs) = addrs {
+            addrs
+        } else {
+            match url.host() {
+                Host::Domain(host) => match preset_ns::find_dns_ips(host) {
+                    Some(ips) => ips.to_vec(),
+                    None => self
+                        .bootstrap_resolver
+                        .lookup_ip(host)
+                        .await
+                        .map(|lo| lo.into_iter().collect::<Vec<IpAddr>>())
+                        .unwrap_or_default(),
+                },
+                Host::Ipv4(ipv4) => vec![(*ipv4).into()],
+                Host::Ipv6(ipv6) => vec![(*ipv6).into()],
+            }
+        };
+
+        nameserver_config_group(url, addrs)
+    }
+}
+
+/// The bootstrap servers must be specified by ip address, or be a preset one.
+fn bootstrap_config(servers: &[DnsServer]) -> NameServerConfigGroup {
+    use crate::preset_ns::ALIDNS;
+    use url::Host;
+
+    let config = servers
+        .iter()
+        .filter_map(|s| {
+            let addrs = match s.url.host() {
+                Host::Domain(domain) => preset_ns::find_dns_ips(domain).map(|ips| ips.to_vec()),
+                Host::Ipv4(ip) => Some(vec![(*ip).into()]),
+                Host::Ipv6(ip) => Some(vec![(*ip).into()]),
+            };
+
+            match addrs {
+                Some(addrs) => nameserver_config_group(&s.url, addrs),
+                None => {
+                    warn!(
+                        "bootstrap dns {} must be specified by ip address",
+                        s.url.to_string()
+                    );
+                    None
+                }
+            }
+        })
+        .filter(|c| !c.is_empty())
+        .reduce(|mut p, c| {
+            p.merge(c);
+            p
+        });
+
+    match config {
+        Some(config) => config,
+        None => NameServerConfigGroup::from_ips_https(
+            preset_ns::find_dns_ips(ALIDNS).unwrap(),
+            443,
+            ALIDNS.to_string(),
+            true,
+        ),
+    }
+}
+
+fn nameserver_config_group(url: &DnsUrl, addrs: Vec<IpAddr>) -> Option<NameServerConfigGroup> {
+    use url::Host;
+
+    let mut host = None;
+
+    if url.proto().is_encrypted() {
+        match url.host() {
+            Host::Ipv4(ip) => {
+                host = preset_ns::find_dns_tls_name(&ip.to_owned().into()).map(|s| s.to_string());
+            }
+            Host::Ipv6(ip) => {
+                host = preset_ns::find_dns_tls_name(&ip.to_owned().into()).map(|s| s.to_string());
+            }
+            Host::Domain(domain) => host = Some(domain.to_string()),
+        }
+
+        if host.is_none() {
+            warn!(
+                "Currently, encrypted dns {} with pure ip not supported!!!",
+                url.to_string()
+            );
+            return None;
+        }
+    }
+
+    let sock_addrs = addrs
+        .into_iter()
+        .map(|ip_addr| (ip_addr, url.port()).to_socket_addrs().ok())
+        .flatten()
+        .flatten()
+        .collect::<Vec<_>>();
+
+    debug!("nameserver {} => addrs: {:?}", url.to_string(), sock_addrs);
+
+    let sock_addrs = sock_addrs.into_iter();
+
+    let mut config: NameServerConfigGroup = match url.proto() {
+        Protocol::Udp => sock_addrs
+            .map(|addr| NameServerConfig {
+                socket_addr: addr,
+                protocol: Protocol::Udp,
+                tls_dns_name: None,
+                tls_config: None,
+                trust_nx_responses: true,
+                bind_addr: None,
+            })
+            .collect::<Vec<_>>(),
+        Protocol::Tcp => sock_addrs
+            .map(|addr| NameServerConfig {
+                socket_addr: addr,
+                protocol: Protocol::Tcp,
+                tls_dns_name: None,
+                tls_config: None,
+                trust_nx_responses: true,
+                bind_addr: None,
+            })
+            .collect::<Vec<_>>(),
+        Protocol::Https => sock_addrs
+            .map(|addr| NameServerConfig {
+                socket_addr: addr,
+                protocol: Protocol::Https,
+                tls_dns_name: host.to_owned(),
+                trust_nx_responses: true,
+                bind_addr: None,
+                tls_config: if let Some(false) = url.enable_sni() {
+                    Some(TlsClientConfig(DOH_TLS_CONFIG.clone()))
+                } else {
+                    None
+                },
+            })
+            .collect::<Vec<_>>(),
+        Protocol::Tls => sock_addrs
+            .map(|addr| NameServerConfig {
+                socket_addr: addr,
+                protocol: Protocol::Tls,
+                tls_dns_name: host.to_owned(),
+                trust_nx_responses: true,
+                bind_addr: None,
+                tls_config: if let Some(false) = url.enable_sni() {
+                    Some(TlsClientConfig(DOT_TLS_CONFIG.clone()))
+                } else {
+                    None
+                },
+            })
+            .collect::<Vec<_>>(),
+        _ => todo!(),
+    }
+    .into();
+
+    if let Some(ns) = config.get(0) {
+        if ns.protocol == Protocol::Tls || ns.protocol == Protocol::Https {
+            let client_cfg = ns.tls_config.as_ref().map(|x| x.0.clone());
+
+            if let Some(x) = client_cfg {
+                config = config.with_client_config(x)
+            }
+        }
+    }
+
+    Some(config)
+}
+
+static DOT_TLS_CONFIG: once_cell::sync::Lazy<Arc<ClientConfig>> =
+    once_cell::sync::Lazy::new(|| {
+        let mut client_config = webpki_client_config(Protocol::Tls);
+        client_config.enable_sni = false;
+        Arc::new(client_config)
+    });
+
+static DOH_TLS_CONFIG: once_cell::sync::Lazy<Arc<ClientConfig>> =
+    once_cell::sync::Lazy::new(|| {
+        let mut client_config = webpki_client_config(Protocol::Https);
+        client_config.enable_sni = false;
+        Arc::new(client_config)
+    });
+
+/// The protocol negotiated with the DoH upstreams, the DoT ones negotiate none.
+const ALPN_H2: &[u8] = b"h2";
+
+/// The webpki roots, the certificate authorities trusted by default.
+fn webpki_root_store() -> RootCertStore {
+    let mut root_store = RootCertStore::empty();
+
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    root_store
+}
+
+/// The client config trusting the webpki roots, with h2 negotiated for DoH.
+fn webpki_client_config(proto: Protocol) -> ClientConfig {
+    client_config(
+        proto,
+        Arc::new(WebPkiVerifier::new(webpki_root_store(), None)),
+    )
+}
+
+/// The client config of the encrypted upstreams verified by the verifier, with h2 negotiated
+/// for DoH, all the upstream configs built by it.
+fn client_config(proto: Protocol, verifier: Arc<dyn ServerCertVerifier>) -> ClientConfig {
+    let mut client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+
+    if proto == Protocol::Https {
+        client_config.alpn_protocols.push(ALPN_H2.to_vec());
+    }
+
+    client_config
+}
+
+/// Use the client config for the nameservers of the server, with its sni option.
+fn with_client_config(
+    mut config: NameServerConfigGroup,
+    server: &DnsServer,
+    mut client_config: ClientConfig,
+) -> NameServerConfigGroup {
+    if let Some(false) = server.url.enable_sni() {
+        client_config.enable_sni = false;
+    }
+
+    let client_config = Arc::new(client_config);
+
+    for ns in config.iter_mut() {
+        ns.tls_config = Some(TlsClientConfig(client_config.clone()));
+    }
+
+    config.with_client_config(client_config)
+}
+
+/// The tls options of the server applied, an error if its certificates can't be verified as
+/// configured, rather than verified by the default roots.
+fn apply_tls(
+    config: NameServerConfigGroup,
+    server: &DnsServer,
+) -> Result<NameServerConfigGroup, String> {
+    let config = apply_spki_pins(apply_tls_verify(config, server)?, server)?;
+    Ok(apply_tls_resumption(config, server))
+}
+
+/// The tls sessions are resumed by default, with the session tickets or ids cached in memory,
+/// disable it for the servers that mishandle the resumption.
+fn apply_tls_resumption(
+    config: NameServerConfigGroup,
+    server: &DnsServer,
+) -> NameServerConfigGroup {
+    if server.tls_resumption || !server.url.proto().is_encrypted() {
+        return config;
+    }
+
+    let mut client_config = match config.iter().find_map(|ns| ns.tls_config.as_ref()) {
+        Some(tls_config) => tls_config.0.as_ref().clone(),
+        None => webpki_client_config(*server.url.proto()),
+    };
 
-        if let Some(resolver) = self.get_or_create_resolver(group_name).await {
-            resolver
-                .lookup(name, record_type)
-                .timeout(Duration::from_secs(LOOKUP_TIMEOUT))
-                .await
-                .unwrap_or(Err(ResolveErrorKind::Timeout.into()))
-        } else {
-            Err(ResolveErrorKind::Message("").into())
-        }
-    }
+    client_config.enable_tickets = false;
+    client_config.session_storage = Arc::new(NoClientSessionStorage {});
 
-    async fn get_or_create_resolver(&self, group_name: &str) -> Option<Arc<TokioAsyncResolver>> {
-        let resolver = async {
-            let resolvers = self.resolvers.lock().await;
-            resolvers.get(group_name).map(|r| Arc::clone(r))
-        }
-        .await;
+    with_client_config(config, server, client_config)
+}
 
-        if resolver.is_some() {
-            return resolver;
-        }
+/// Race the connections to the upstream host resolved to both ipv4 and ipv6 addresses,
+/// rather than timing out on a broken ipv6 path in turn.
+fn dual_stack(config: &NameServerConfigGroup) -> Option<Arc<DualStackHost>> {
+    DualStackHost::new(
+        config
+            .iter()
+            .filter(|ns| ns.protocol != Protocol::Udp)
+            .map(|ns| ns.socket_addr)
+            .collect(),
+    )
+    .map(Arc::new)
+}
 
-        let group_name = if self.servers.contains_key(group_name) {
-            group_name
-        } else {
-            "default"
-        };
+/// Verify the certificates of the encrypted upstreams by the private certificate authorities,
+/// or not at all, the spki pins, if any, take precedence.
+fn apply_tls_verify(
+    config: NameServerConfigGroup,
+    server: &DnsServer,
+) -> Result<NameServerConfigGroup, String> {
+    if !server.url.proto().is_encrypted() {
+        return Ok(config);
+    }
+
+    let verifier: Arc<dyn ServerCertVerifier> = if !server.check_certificate {
+        warn!("certificate of {} is not checked", server.url.to_string());
+        Arc::new(NoCertificateVerifier)
+    } else if let Some(ca_file) = server.ca_file.as_ref() {
+        Arc::new(WebPkiVerifier::new(ca_root_store(ca_file)?, None))
+    } else {
+        return Ok(config);
+    };
 
-        let nameservers = self.get_or_create_nameserver_group(group_name).await;
+    Ok(with_client_config(
+        config,
+        server,
+        client_config(*server.url.proto(), verifier),
+    ))
+}
 
-        if let Some(Ok(resolver)) = nameservers.map(|ss| create_resolver(ss)) {
-            let resolver = Arc::new(resolver);
-            let mut resolvers = self.resolvers.lock().await;
+/// The certificate authorities of the ca-file, checked at load already, but it may have been
+/// removed or replaced since.
+fn ca_root_store(ca_file: &Path) -> Result<RootCertStore, String> {
+    load_root_store(ca_file)
+        .map_err(|err| format!("load ca file {} failed, {}", ca_file.display(), err))
+}
 
-            resolvers.insert(group_name.to_string(), Arc::clone(&resolver));
+/// The certificate authorities in the PEM file.
+pub fn load_root_store(path: &Path) -> Result<RootCertStore, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).map_err(|e| e.to_string())?;
 
-            Some(resolver)
-        } else {
-            None
-        }
+    let mut root_store = RootCertStore::empty();
+    let (added, _) = root_store.add_parsable_certificates(&certs);
+
+    if added == 0 {
+        return Err("no certificate found".to_string());
     }
 
-    async fn get_or_create_nameserver_group(
+    Ok(root_store)
+}
+
+/// Accept any certificate, see `-no-check-certificate`.
+struct NoCertificateVerifier;
+
+impl ServerCertVerifier for NoCertificateVerifier {
+    fn verify_server_cert(
         &self,
-        group_name: &str,
-    ) -> Option<NameServerConfigGroup> {
-        let config = async {
-            let nameservers = self.server_groups.lock().await;
-            nameservers.get(group_name).map(|n| n.clone())
-        }
-        .await;
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
 
-        if config.is_some() {
-            return config;
-        }
+/// Verify the encrypted upstreams by their spki pins, on top of the trusted certificate authorities.
+fn apply_spki_pins(
+    config: NameServerConfigGroup,
+    server: &DnsServer,
+) -> Result<NameServerConfigGroup, String> {
+    if server.spki_pins.is_empty() || !server.url.proto().is_encrypted() {
+        return Ok(config);
+    }
 
-        let mut name_server_cfg_group = NameServerConfigGroup::new();
+    // the chain is verified by the ca-file if any, by the webpki roots otherwise.
+    let verifier = if server.check_certificate {
+        let root_store = match server.ca_file.as_ref() {
+            Some(ca_file) => ca_root_store(ca_file)?,
+            None => webpki_root_store(),
+        };
+        Some(WebPkiVerifier::new(root_store, None))
+    } else {
+        None
+    };
 
-        let ss = self
-            .servers
-            .get(group_name)
-            .expect("default nameserver group not found!!!");
-
-        for s in ss {
-            if let Some(domain) = s.url.get_domain() {
-                match Name::from_str(domain) {
-                    Ok(domain_name) => {
-                        //
-                        let config = if let Some(g_name) =
-                            self.matcher.find(&LowerName::from(domain_name.clone()))
-                        {
-                            use futures::future;
-
-                            let config = self
-                                .servers
-                                .get(g_name)
-                                .expect("default nameserver group not found!!!");
-
-                            let config = future::join_all(
-                                config
-                                    .iter()
-                                    .map(|c| self.create_nameserver_config_group(&c.url, None)),
-                            )
-                            .await
-                            .into_iter()
-                            .flat_map(|x| x.into_iter())
-                            .reduce(|mut p, c| {
-                                p.merge(c);
-                                p
-                            });
-
-                            config
-                        } else {
-                            None
-                        };
-
-                        let addrs = match config {
-                            Some(c) => {
-                                if let Ok(resolver) = create_resolver(c) {
-                                    resolver
-                                        .lookup_ip(domain)
-                                        .await
-                                        .map(|r| r.into_iter().collect::<Vec<_>>())
-                                        .unwrap_or_default()
-                                } else {
-                                    Default::default()
-                                }
-                            }
-                            None => self
-                                .bootstrap_resolver
-                                .lookup_ip(domain)
-                                .await
-                                .map(|r| r.into_iter().collect::<Vec<_>>())
-                                .unwrap_or_default(),
-                        };
-
-                        self.nameserver_ip_store
-                            .lock()
-                            .await
-                            .entry(domain_name)
-                            .and_modify(|v| *v = addrs.clone())
-                            .or_insert(addrs.clone());
-
-                        if let Some(s) = self
-                            .create_nameserver_config_group(&s.url, Some(addrs))
-                            .await
-                        {
-                            if !s.is_empty() {
-                                name_server_cfg_group.merge(s);
-                                continue;
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        warn!("{:?}", err);
-                    }
-                };
-            }
+    let verifier = Arc::new(SpkiPinVerifier {
+        pins: server.spki_pins.clone(),
+        verifier,
+    });
 
-            if let Some(s) = self.create_nameserver_config_group(&s.url, None).await {
-                if !s.is_empty() {
-                    name_server_cfg_group.merge(s);
-                }
+    Ok(with_client_config(
+        config,
+        server,
+        client_config(*server.url.proto(), verifier),
+    ))
+}
+
+/// Accept the certificate chain verified by the certificate authorities, only if any of the
+/// certificates on the path from the end entity matches a pin, that's the base64 encoded sha256
+/// digest of its subject public key info.
+struct SpkiPinVerifier {
+    pins: Vec<String>,
+    /// none for `-no-check-certificate`, then the end entity itself must match a pin, as the
+    /// handshake proves the server holds its key, but nothing about the intermediates.
+    verifier: Option<WebPkiVerifier>,
+}
+
+impl ServerCertVerifier for SpkiPinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let chain = match self.verifier.as_ref() {
+            Some(verifier) => {
+                verifier.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    scts,
+                    ocsp_response,
+                    now,
+                )?;
+                issuer_path(end_entity, intermediates)
             }
-        }
+            None => vec![end_entity],
+        };
 
-        let name_server_cfg_group = if let Some(Some(cfg)) = name_server_cfg_group
+        let matched = chain
             .iter()
-            .map(|n| n.tls_config.clone())
-            .next()
-        {
-            name_server_cfg_group.with_client_config(cfg.0)
-        } else {
-            name_server_cfg_group
-        };
+            .filter_map(|cert| spki_pin(&cert.0))
+            .any(|pin| self.pins.contains(&pin));
 
-        async {
-            self.server_groups
-                .lock()
-                .await
-                .insert(group_name.to_string(), name_server_cfg_group.clone());
+        if matched {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            warn!("spki pin of {:?} mismatched", server_name);
+            Err(rustls::Error::General("spki pin mismatched".to_string()))
         }
-        .await;
-
-        Some(name_server_cfg_group)
     }
+}
 
-    pub async fn create_nameserver_config_group(
-        &self,
-        url: &DnsUrl,
-        addrs: Option<Vec<IpAddr>>,
-    ) -> Option<NameServerConfigGroup> {
-        use url::Host;
+/// The certificates from the end entity up through the intermediates that issued it, the ones
+/// presented but not on the path are left out, so they can't satisfy a pin.
+fn issuer_path<'a>(
+    end_entity: &'a Certificate,
+    intermediates: &'a [Certificate],
+) -> Vec<&'a Certificate> {
+    let mut path = vec![end_entity];
+    let mut remaining = intermediates.iter().collect::<Vec<_>>();
+
+    while let Some(issuer) = path.last().and_then(|cert| tbs_field(&cert.0, ISSUER)) {
+        let found = remaining
+            .iter()
+            .position(|cert| tbs_field(&cert.0, SUBJECT) == Some(issuer));
 
-        let mut host = None;
+        match found {
+            Some(i) => path.push(remaining.swap_remove(i)),
+            None => break,
+        }
+    }
 
-        if url.proto().is_encrypted() {
-            match url.host() {
-                Host::Ipv4(ip) => {
-                    host =
-                        preset_ns::find_dns_tls_name(&ip.to_owned().into()).map(|s| s.to_string());
-                }
-                Host::Ipv6(ip) => {
-                    host =
-                        preset_ns::find_dns_tls_name(&ip.to_owned().into()).map(|s| s.to_string());
-                }
-                Host::Domain(domain) => host = Some(domain.to_string()),
-            }
+    path
+}
 
-            if host.is_none() {
-                warn!(
-                    "Currently, encrypted dns {} with pure ip not supported!!!",
-                    url.to_string()
-                );
-                return None;
-            }
-        }
+/// The base64 encoded sha256 digest of the subject public key info of the DER encoded certificate.
+fn spki_pin(cert: &[u8]) -> Option<String> {
+    use ring::digest::{digest, SHA256};
 
-        let sock_addrs = (if let Some(addrs) = addrs {
-            addrs
-        } else {
-            match url.host() {
-                Host::Domain(host) => match preset_ns::find_dns_ips(host) {
-                    Some(ips) => ips.to_vec(),
-                    None => self
-                        .bootstrap_resolver
-                        .lookup_ip(host)
-                        .await
-                        .map(|lo| lo.into_iter().collect::<Vec<IpAddr>>())
-                        .unwrap_or_default(),
-                },
-                Host::Ipv4(ipv4) => vec![(*ipv4).into()],
-                Host::Ipv6(ipv6) => vec![(*ipv6).into()],
-            }
-        })
-        .into_iter()
-        .map(|ip_addr| (ip_addr, url.port()).to_socket_addrs().ok())
-        .flatten()
-        .flatten()
-        .collect::<Vec<_>>();
+    let spki = subject_public_key_info(cert)?;
+    Some(base64::encode(digest(&SHA256, spki)))
+}
 
-        debug!("nameserver {} => addrs: {:?}", url.to_string(), sock_addrs);
+/// The fields of the TBSCertificate, after the optional version.
+const ISSUER: usize = 2;
+const SUBJECT: usize = 4;
+const SUBJECT_PUBLIC_KEY_INFO: usize = 5;
 
-        let sock_addrs = sock_addrs.into_iter();
+/// Locate the subject public key info in the DER encoded certificate.
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    tbs_field(cert, SUBJECT_PUBLIC_KEY_INFO)
+}
 
-        let mut config: NameServerConfigGroup = match url.proto() {
-            Protocol::Udp => sock_addrs
-                .map(|addr| NameServerConfig {
-                    socket_addr: addr,
-                    protocol: Protocol::Udp,
-                    tls_dns_name: None,
-                    tls_config: None,
-                    trust_nx_responses: true,
-                    bind_addr: None,
-                })
-                .collect::<Vec<_>>(),
-            Protocol::Tcp => sock_addrs
-                .map(|addr| NameServerConfig {
-                    socket_addr: addr,
-                    protocol: Protocol::Tcp,
-                    tls_dns_name: None,
-                    tls_config: None,
-                    trust_nx_responses: true,
-                    bind_addr: None,
-                })
-                .collect::<Vec<_>>(),
-            Protocol::Https => sock_addrs
-                .map(|addr| NameServerConfig {
-                    socket_addr: addr,
-                    protocol: Protocol::Https,
-                    tls_dns_name: host.to_owned(),
-                    trust_nx_responses: true,
-                    bind_addr: None,
-                    tls_config: if let Some(false) = url.enable_sni() {
-                        Some(TlsClientConfig(DOT_TLS_CONFIG.clone()))
-                    } else {
-                        None
-                    },
-                })
-                .collect::<Vec<_>>(),
-            Protocol::Tls => sock_addrs
-                .map(|addr| NameServerConfig {
-                    socket_addr: addr,
-                    protocol: Protocol::Tls,
-                    tls_dns_name: host.to_owned(),
-                    trust_nx_responses: true,
-                    bind_addr: None,
-                    tls_config: if let Some(false) = url.enable_sni() {
-                        Some(TlsClientConfig(DOT_TLS_CONFIG.clone()))
-                    } else {
-                        None
-                    },
-                })
-                .collect::<Vec<_>>(),
-            _ => todo!(),
-        }
-        .into();
+/// Locate the field of the DER encoded certificate, the whole element:
+///   Certificate ::= SEQUENCE { tbsCertificate, ... }
+///   TBSCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature, issuer, validity, subject, subjectPublicKeyInfo, ... }
+fn tbs_field(cert: &[u8], index: usize) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
 
-        if let Some(ns) = config.get(0) {
-            if ns.protocol == Protocol::Tls || ns.protocol == Protocol::Https {
-                let client_cfg = ns.tls_config.as_ref().map(|x| x.0.clone());
+    /// Split the first DER element into (tag, the whole element, its content) and the rest.
+    fn next(der: &[u8]) -> Option<((u8, &[u8], &[u8]), &[u8])> {
+        let tag = *der.first()?;
+        let first = *der.get(1)? as usize;
 
-                if let Some(x) = client_cfg {
-                    config = config.with_client_config(x)
-                }
+        let (header, len) = if first < 0x80 {
+            (2, first)
+        } else {
+            let n = first & 0x7f;
+            if n == 0 || n > 4 {
+                return None;
             }
-        }
+            let len = der
+                .get(2..2 + n)?
+                .iter()
+                .fold(0usize, |len, b| (len << 8) | *b as usize);
+            (2 + n, len)
+        };
 
-        Some(config)
-    }
-}
+        let end = header.checked_add(len)?;
+        let element = der.get(..end)?;
 
-static DOT_TLS_CONFIG: once_cell::sync::Lazy<Arc<ClientConfig>> =
-    once_cell::sync::Lazy::new(|| {
-        const ALPN_H2: &[u8] = b"h2";
+        Some(((tag, element, &element[header..]), &der[end..]))
+    }
 
-        let mut root_store = RootCertStore::empty();
+    let ((tag, _, cert), _) = next(cert)?;
+    if tag != SEQUENCE {
+        return None;
+    }
 
-        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-            OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
+    let ((tag, _, tbs), _) = next(cert)?;
+    if tag != SEQUENCE {
+        return None;
+    }
 
-        let mut client_config = ClientConfig::builder()
-            .with_safe_default_cipher_suites()
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+    let mut rest = tbs;
+    if rest.first() == Some(&VERSION) {
+        rest = next(rest)?.1;
+    }
 
-        client_config.enable_sni = false;
+    for _ in 0..index {
+        rest = next(rest)?.1;
+    }
 
-        client_config.alpn_protocols.push(ALPN_H2.to_vec());
+    let ((tag, field, _), _) = next(rest)?;
+    if tag != SEQUENCE && index != 0 {
+        return None;
+    }
 
-        Arc::new(client_config)
-    });
+    Some(field)
+}
 
 #[cfg(test)]
 mod tests {
@@ -626,6 +2362,23 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_resolver_opts_policy() {
+        let policy = QueryPolicy {
+            timeout: Duration::from_millis(500),
+            retry: 2,
+            ..Default::default()
+        };
+        let opts = resolver_opts(&Default::default(), Some(&policy));
+        assert_eq!(opts.timeout, Duration::from_millis(500));
+        assert_eq!(opts.attempts, 1);
+
+        // the bootstrap resolvers, as trust-dns.
+        let opts = resolver_opts(&Default::default(), None);
+        assert_eq!(opts.timeout, ResolverOpts::default().timeout);
+        assert_eq!(opts.attempts, ResolverOpts::default().attempts);
+    }
+
     async fn assert_google(client: &DnsClient) {
         let name = "dns.google";
         let addrs = client
@@ -658,6 +2411,344 @@ mod tests {
         assert!(addrs.contains("223.5.5.5"));
     }
 
+    #[test]
+    fn test_upstream_ejection() {
+        Runtime::new().unwrap().block_on(async {
+            let tasks = BackgroundTasks::new();
+            let upstreams = ["a", "b"].map(|name| {
+                Upstream::new(
+                    name.to_string(),
+                    NameServerConfigGroup::cloudflare(),
+                    Default::default(),
+                    Default::default(),
+                    &Default::default(),
+                    tasks.clone(),
+                )
+                .unwrap()
+            });
+
+            for _ in 0..MAX_CONSECUTIVE_FAILURES {
+                assert!(upstreams[0].is_healthy());
+                upstreams[0].on_failure();
+            }
+
+            assert!(!upstreams[0].is_healthy());
+            assert!(upstreams[1].is_healthy());
+
+            let healthy = ServerGroup::healthy(upstreams.iter());
+            assert_eq!(healthy.len(), 1);
+            assert_eq!(healthy[0].name(), "b");
+
+            for _ in 0..MAX_CONSECUTIVE_FAILURES {
+                upstreams[1].on_failure();
+            }
+
+            // none is healthy, try all of them.
+            assert_eq!(ServerGroup::healthy(upstreams.iter()).len(), 2);
+
+            tasks.shutdown().await;
+        })
+    }
+
+    #[test]
+    fn test_upstream_record_types() {
+        Runtime::new().unwrap().block_on(async {
+            let tasks = BackgroundTasks::new();
+            let upstreams = [
+                ("corp", vec![RecordType::A, RecordType::AAAA], vec![]),
+                ("public", vec![], vec![RecordType::ANY]),
+            ]
+            .map(|(name, allow, deny)| {
+                Upstream::new(
+                    name.to_string(),
+                    NameServerConfigGroup::cloudflare(),
+                    Default::default(),
+                    Default::default(),
+                    &UpstreamOptions {
+                        types: RecordTypeFilter { allow, deny },
+                        ..Default::default()
+                    },
+                    tasks.clone(),
+                )
+                .unwrap()
+            });
+
+            let names = |record_type| {
+                ServerGroup::accepting(upstreams.iter(), record_type)
+                    .map(|u| u.name())
+                    .collect::<Vec<_>>()
+            };
+
+            assert_eq!(names(Some(RecordType::A)), ["corp", "public"]);
+            assert_eq!(names(Some(RecordType::MX)), ["public"]);
+            assert!(names(Some(RecordType::ANY)).is_empty());
+            assert_eq!(names(None), ["corp", "public"]);
+
+            tasks.shutdown().await;
+        })
+    }
+
+    #[test]
+    fn test_sort_by_weighted_rtt() {
+        Runtime::new().unwrap().block_on(async {
+            let tasks = BackgroundTasks::new();
+            let upstreams = ["a", "b", "c"].map(|name| {
+                Upstream::new(
+                    name.to_string(),
+                    NameServerConfigGroup::cloudflare(),
+                    Default::default(),
+                    Default::default(),
+                    &Default::default(),
+                    tasks.clone(),
+                )
+                .unwrap()
+            });
+
+            upstreams[0].update_srtt(Duration::from_millis(10));
+            upstreams[1].update_srtt(Duration::from_millis(11));
+            upstreams[2].update_srtt(Duration::from_millis(12));
+
+            let names = |explore| {
+                let mut sorted = upstreams.iter().collect::<Vec<_>>();
+                ServerGroup::sort_by_weighted_rtt(&mut sorted, explore);
+                sorted.iter().map(|u| u.name()).collect::<Vec<_>>()
+            };
+
+            assert_eq!(names(None), ["a", "b", "c"]);
+            assert_eq!(names(Some(1)), ["c", "a", "b"]);
+
+            // the fast but flaky upstream is less preferred.
+            for _ in 0..MAX_CONSECUTIVE_FAILURES - 1 {
+                upstreams[0].on_failure();
+            }
+            upstreams[0].on_success();
+            assert!(upstreams[0].success_rate() < 0.8);
+            assert_eq!(names(None), ["b", "c", "a"]);
+
+            tasks.shutdown().await;
+        })
+    }
+
+    #[test]
+    fn test_apply_bind() {
+        let server = DnsServer::from_str("8.8.8.8 -source-ip 192.168.2.10").unwrap();
+        let config = apply_bind(
+            NameServerConfigGroup::from_ips_clear(
+                &[
+                    "8.8.8.8".parse().unwrap(),
+                    "2001:4860:4860::8888".parse().unwrap(),
+                ],
+                53,
+                true,
+            ),
+            &server,
+        );
+
+        for ns in config.iter() {
+            if ns.socket_addr.is_ipv4() {
+                assert_eq!(ns.bind_addr, Some("192.168.2.10:0".parse().unwrap()));
+            } else {
+                assert_eq!(ns.bind_addr, None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_without_link_local() {
+        let ips = ["fe80::1", "169.254.1.2", "2001:db8::1", "192.168.2.10"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect::<Vec<IpAddr>>();
+
+        assert_eq!(without_link_local(ips.clone()), ips[2..]);
+    }
+
+    #[test]
+    fn test_with_tcp_fallback() {
+        let mut config =
+            NameServerConfigGroup::from_ips_clear(&["8.8.8.8".parse().unwrap()], 53, true);
+
+        // udp and tcp already paired.
+        assert_eq!(with_tcp_fallback(config.clone()).len(), 2);
+
+        config.retain(|ns| ns.protocol == Protocol::Udp);
+        let config = with_tcp_fallback(config);
+
+        assert_eq!(
+            config.iter().map(|ns| ns.protocol).collect::<Vec<_>>(),
+            [Protocol::Udp, Protocol::Tcp]
+        );
+
+        // nothing to pair once forced to tcp.
+        let config = apply_force_transport(config, Some(ForceTransport::Tcp))
+            .map(with_tcp_fallback)
+            .unwrap();
+        assert!(config.iter().all(|ns| ns.protocol == Protocol::Tcp));
+    }
+
+    #[test]
+    fn test_is_failure() {
+        let no_records = |response_code| -> ResolveError {
+            ResolveErrorKind::NoRecordsFound {
+                query: Box::new(Query::query(Name::root(), RecordType::A)),
+                soa: None,
+                negative_ttl: None,
+                response_code,
+                trusted: true,
+            }
+            .into()
+        };
+
+        assert!(is_failure(&ResolveErrorKind::Timeout.into()));
+        assert!(is_failure(&no_records(ResponseCode::ServFail)));
+        assert!(!is_failure(&no_records(ResponseCode::NXDomain)));
+    }
+
+    #[test]
+    fn test_upstream_pool() {
+        Runtime::new().unwrap().block_on(async {
+            let tasks = BackgroundTasks::new();
+            let options = UpstreamOptions {
+                pool_size: 3,
+                ..Default::default()
+            };
+
+            let udp = Upstream::new(
+                "udp".to_string(),
+                NameServerConfigGroup::from_ips_clear(&[[1, 1, 1, 1].into()], 53, true),
+                Default::default(),
+                Default::default(),
+                &options,
+                tasks.clone(),
+            )
+            .unwrap();
+            assert_eq!(udp.pool.len(), 1);
+
+            let tls = Upstream::new(
+                "tls".to_string(),
+                NameServerConfigGroup::cloudflare_tls(),
+                Default::default(),
+                Default::default(),
+                &options,
+                tasks.clone(),
+            )
+            .unwrap();
+            assert_eq!(tls.pool.len(), 3);
+
+            for _ in 0..3 {
+                tls.resolver();
+            }
+            assert!(tls
+                .pool
+                .iter()
+                .all(|slot| slot.last_used.load(Ordering::Relaxed) > 0));
+
+            tls.close_idle(Duration::ZERO);
+            assert!(tls
+                .pool
+                .iter()
+                .all(|slot| slot.last_used.load(Ordering::Relaxed) == 0));
+
+            tasks.shutdown().await;
+        })
+    }
+
+    #[test]
+    fn test_subject_public_key_info() {
+        fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+            let mut v = vec![tag];
+            if content.len() < 0x80 {
+                v.push(content.len() as u8);
+            } else {
+                v.extend_from_slice(&[0x82, (content.len() >> 8) as u8, content.len() as u8]);
+            }
+            v.extend_from_slice(content);
+            v
+        }
+
+        let spki = der(
+            0x30,
+            &[der(0x30, &[0x06, 0x01, 0x2a]), der(0x03, &[0; 200])].concat(),
+        );
+
+        let tbs = der(
+            0x30,
+            &[
+                der(0xa0, &der(0x02, &[2])),
+                der(0x02, &[1]),
+                der(0x30, &[]),
+                der(0x30, &[]),
+                der(0x30, &[]),
+                der(0x30, &[]),
+                spki.clone(),
+            ]
+            .concat(),
+        );
+
+        let cert = der(0x30, &[tbs, der(0x30, &[]), der(0x03, &[0])].concat());
+
+        assert_eq!(subject_public_key_info(&cert), Some(spki.as_slice()));
+        assert!(spki_pin(&cert).is_some());
+
+        assert_eq!(subject_public_key_info(&cert[..10]), None);
+        assert_eq!(subject_public_key_info(&[0x02, 0x01, 0x00]), None);
+    }
+
+    #[test]
+    fn test_issuer_path() {
+        fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+            let mut v = vec![tag, content.len() as u8];
+            v.extend_from_slice(content);
+            v
+        }
+
+        let cert = |issuer: u8, subject: u8| {
+            let tbs = der(
+                0x30,
+                &[
+                    der(0x02, &[1]),
+                    der(0x30, &[]),
+                    der(0x30, &der(0x0c, &[issuer])),
+                    der(0x30, &[]),
+                    der(0x30, &der(0x0c, &[subject])),
+                    der(0x30, &der(0x03, &[subject])),
+                ]
+                .concat(),
+            );
+            Certificate(der(0x30, &[tbs, der(0x30, &[]), der(0x03, &[0])].concat()))
+        };
+
+        let leaf = cert(b'i', b'l');
+        let intermediate = cert(b'r', b'i');
+        let unrelated = cert(b'r', b'x');
+
+        let path = issuer_path(&leaf, &[unrelated.clone(), intermediate.clone()]);
+        assert_eq!(path, vec![&leaf, &intermediate]);
+
+        // an intermediate that didn't issue the leaf is off the path, whatever its pin.
+        let attacker = cert(b'a', b'l');
+        assert_eq!(issuer_path(&attacker, &[intermediate]), vec![&attacker]);
+    }
+
+    #[test]
+    fn test_bootstrap_config() {
+        let servers = [
+            "223.5.5.5",
+            "tls://1.1.1.1",
+            "https://dns.example/dns-query",
+        ]
+        .map(|s| DnsServer::from_str(s).unwrap());
+
+        let config = bootstrap_config(&servers);
+
+        assert_eq!(config.len(), 2);
+        assert_eq!(config[0].protocol, Protocol::Udp);
+        assert_eq!(config[1].protocol, Protocol::Tls);
+
+        // fallback to the preset one.
+        assert!(!bootstrap_config(&servers[2..]).is_empty());
+    }
+
     #[test]
     fn test_nameserver_cloudflare_resolve() {
         Runtime::new().unwrap().block_on(async {
@@ -808,4 +2899,39 @@ mod tests {
             assert_alidns(&client).await;
         })
     }
+
+    #[test]
+    fn test_merge_lookups() {
+        let name = Name::from_str("example.com.").unwrap();
+        let query = Query::query(name.clone(), RecordType::A);
+        let now = Instant::now();
+        let lookup = |ips: &[&str], ttl: u64| {
+            let records = ips
+                .iter()
+                .map(|ip| Record::from_rdata(name.clone(), 60, RData::A(ip.parse().unwrap())))
+                .collect::<Vec<_>>();
+            Lookup::new_with_deadline(
+                query.clone(),
+                Arc::from(records),
+                now + Duration::from_secs(ttl),
+            )
+        };
+
+        assert!(merge_lookups(vec![]).is_none());
+
+        let merged = merge_lookups(vec![
+            lookup(&["192.0.2.1", "192.0.2.2"], 60),
+            lookup(&["192.0.2.2", "192.0.2.3"], 30),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            merged
+                .iter()
+                .map(|rdata| rdata.to_string())
+                .collect::<Vec<_>>(),
+            vec!["192.0.2.1", "192.0.2.2", "192.0.2.3"]
+        );
+        assert_eq!(merged.valid_until(), now + Duration::from_secs(30));
+    }
 }
@@ -0,0 +1,359 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt, TryFutureExt};
+use trust_dns_proto::rr::Record;
+use trust_dns_proto::serialize::binary::BinEncodable;
+use trust_dns_proto::xfer::{DnsHandle, DnsRequest, DnsResponse};
+use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverOpts};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::name_server::{
+    ConnectionProvider, GenericConnection, GenericConnectionProvider,
+};
+
+use crate::dns_ecs::ClientSubnet;
+use crate::dns_padding::PaddingPolicy;
+use crate::dnscrypt::DnsCryptClient;
+use crate::dnstap::{DnstapPeer, DnstapSink};
+use crate::geoip::GeoIp;
+use crate::infra::ipnet::IpNet;
+use crate::log::debug;
+use crate::odoh::OdohClient;
+use crate::proxy::{ProxyRuntime, Transport};
+
+/// The error of the answers discarded for the bogus ips, see `ConnectionOptions::bogus_nxdomain`.
+const BOGUS_ANSWER: &'static str = "bogus answer discarded";
+
+/// The error of the answers discarded for the ips out of the whitelisted countries,
+/// see `ConnectionOptions::whitelist_geoip`.
+const GEOIP_DISCARDED: &'static str = "answer out of the whitelisted countries discarded";
+
+/// The error of the answers discarded by ignore-ip, blacklist-ip or whitelist-ip,
+/// see `ConnectionOptions::filter_ips`.
+const IP_FILTERED: &'static str = "answer discarded by the ip filters";
+
+/// How the queries to an upstream are sent and its answers are accepted.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionOptions {
+    /// the client subnet attached to each query.
+    pub subnet: Option<ClientSubnet>,
+    /// discard the answers without an OPT record, which are likely forged by a middlebox.
+    pub check_edns: bool,
+    /// discard the answers containing these ips, typically the hijack pages of the isp.
+    pub bogus_nxdomain: Arc<Vec<IpNet>>,
+    /// drop the records of these ips, the answer discarded if none of its ips left.
+    pub ignore_ip: Arc<Vec<IpNet>>,
+    /// discard the answers containing these ips, if the server filters by blacklist-ip.
+    pub blacklist_ip: Arc<Vec<IpNet>>,
+    /// discard the answers with ips out of these, if the server filters by whitelist-ip.
+    pub whitelist_ip: Arc<Vec<IpNet>>,
+    /// send the queries encrypted by DNSCrypt, instead of the plain connection.
+    pub dnscrypt: Option<Arc<DnsCryptClient>>,
+    /// send the queries encrypted by Oblivious DoH, through the relay if any.
+    pub odoh: Option<Arc<OdohClient>>,
+    /// how the queries are padded, only over the encrypted connections.
+    pub padding: PaddingPolicy,
+    /// the exchanges are exported to the dnstap collector.
+    pub dnstap: Option<Arc<DnstapSink>>,
+    pub geoip: Option<Arc<GeoIp>>,
+    /// discard the answers none of whose ips are of these countries, left to the other upstreams.
+    pub whitelist_geoip: Arc<Vec<String>>,
+    /// how the connections are made, e.g. through the proxy of the server.
+    pub transport: Arc<Transport>,
+}
+
+impl ConnectionOptions {
+    /// Whether the queries must carry an OPT record.
+    #[inline]
+    pub fn use_edns(&self) -> bool {
+        self.subnet.is_some() || self.check_edns
+    }
+
+    fn is_bogus(&self, response: &DnsResponse) -> bool {
+        !self.bogus_nxdomain.is_empty()
+            && response
+                .answers()
+                .iter()
+                .filter_map(|r| r.data().and_then(|d| d.to_ip_addr()))
+                .any(|ip| self.bogus_nxdomain.iter().any(|net| net.contains(&ip)))
+    }
+
+    /// Whether the answer is filtered by the ips of it.
+    fn has_ip_filters(&self) -> bool {
+        !self.ignore_ip.is_empty() || !self.blacklist_ip.is_empty() || !self.whitelist_ip.is_empty()
+    }
+
+    /// The answer without the records of the ignored ips, discarded if it contains the
+    /// blacklisted ips, ones out of the whitelist, or none left of its ips.
+    fn filter_ips(&self, mut response: DnsResponse) -> Result<DnsResponse, ResolveError> {
+        let within = |nets: &[IpNet], ip: &IpAddr| nets.iter().any(|net| net.contains(ip));
+        let ip_of = |record: &Record| record.data().and_then(|data| data.to_ip_addr());
+
+        if !self.ignore_ip.is_empty() {
+            let answers = response.take_answers();
+            let has_ips = answers.iter().any(|record| ip_of(record).is_some());
+
+            let answers = answers
+                .into_iter()
+                .filter(|record| !matches!(ip_of(record), Some(ip) if within(&self.ignore_ip, &ip)))
+                .collect::<Vec<_>>();
+
+            if has_ips && !answers.iter().any(|record| ip_of(record).is_some()) {
+                return Err(ResolveError::from(IP_FILTERED));
+            }
+            response.insert_answers(answers);
+        }
+
+        let discarded = response.answers().iter().filter_map(ip_of).any(|ip| {
+            within(&self.blacklist_ip, &ip)
+                || (!self.whitelist_ip.is_empty() && !within(&self.whitelist_ip, &ip))
+        });
+
+        match discarded {
+            true => Err(ResolveError::from(IP_FILTERED)),
+            false => Ok(response),
+        }
+    }
+
+    /// Whether the answer has ips, but none of the whitelisted countries.
+    fn is_out_of_whitelist(&self, response: &DnsResponse) -> bool {
+        let geoip = match self.geoip.as_ref() {
+            Some(geoip) if !self.whitelist_geoip.is_empty() => geoip,
+            _ => return false,
+        };
+
+        let mut ips = response
+            .answers()
+            .iter()
+            .filter_map(|r| r.data().and_then(|d| d.to_ip_addr()))
+            .peekable();
+
+        ips.peek().is_some() && !ips.any(|ip| geoip.contains_any(ip, &self.whitelist_geoip))
+    }
+}
+
+/// Whether the answer is discarded for the bogus ips.
+pub fn is_bogus_answer(err: &ResolveError) -> bool {
+    matches!(err.kind(), ResolveErrorKind::Message(msg) if *msg == BOGUS_ANSWER)
+}
+
+/// Whether the answer is discarded by the ip filters.
+pub fn is_ip_filtered(err: &ResolveError) -> bool {
+    matches!(err.kind(), ResolveErrorKind::Message(msg) if *msg == IP_FILTERED)
+}
+
+/// Whether the answer is discarded for its ips out of the whitelisted countries.
+pub fn is_geoip_discarded(err: &ResolveError) -> bool {
+    matches!(err.kind(), ResolveErrorKind::Message(msg) if *msg == GEOIP_DISCARDED)
+}
+
+/// The connection provider applying the `ConnectionOptions` to each connection.
+#[derive(Clone)]
+pub struct UpstreamConnectionProvider {
+    inner: GenericConnectionProvider<ProxyRuntime>,
+    options: ConnectionOptions,
+}
+
+impl UpstreamConnectionProvider {
+    pub fn new(inner: GenericConnectionProvider<ProxyRuntime>, options: ConnectionOptions) -> Self {
+        Self { inner, options }
+    }
+}
+
+impl ConnectionProvider for UpstreamConnectionProvider {
+    type Conn = UpstreamConnection;
+    type FutureConn = BoxFuture<'static, Result<Self::Conn, ResolveError>>;
+    type RuntimeProvider = ProxyRuntime;
+
+    fn new_connection(
+        &self,
+        config: &NameServerConfig,
+        options: &ResolverOpts,
+    ) -> Self::FutureConn {
+        let mut conn_options = self.options.clone();
+
+        if !config.protocol.is_encrypted() {
+            conn_options.padding = PaddingPolicy::None;
+        }
+
+        let peer = DnstapPeer {
+            addr: config.socket_addr,
+            protocol: config.protocol,
+            dnscrypt: conn_options.dnscrypt.is_some(),
+        };
+
+        // the queries are posted by the odoh client, the target is never connected directly.
+        let config = if conn_options.odoh.is_some() {
+            NameServerConfig {
+                protocol: Protocol::Udp,
+                ..config.clone()
+            }
+        } else {
+            config.clone()
+        };
+
+        let transport = conn_options.transport.clone();
+
+        transport
+            .scope(self.inner.new_connection(&config, options))
+            .map_ok(move |inner| UpstreamConnection {
+                inner,
+                options: conn_options,
+                peer,
+            })
+            .boxed()
+    }
+}
+
+#[derive(Clone)]
+pub struct UpstreamConnection {
+    inner: GenericConnection,
+    options: ConnectionOptions,
+    peer: DnstapPeer,
+}
+
+impl DnsHandle for UpstreamConnection {
+    type Response = BoxStream<'static, Result<DnsResponse, ResolveError>>;
+    type Error = ResolveError;
+
+    fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(&mut self, request: R) -> Self::Response {
+        let mut request = request.into();
+
+        if let Some(subnet) = self.options.subnet.as_ref() {
+            subnet.apply(&mut request);
+        }
+
+        // the last, so that the padding covers all the other options.
+        self.options.padding.apply(&mut request);
+
+        let dnstap = self.options.dnstap.clone().map(|sink| {
+            let query_time = SystemTime::now();
+            if let Ok(bytes) = request.to_vec() {
+                sink.resolver_query(&self.peer, query_time, &bytes);
+            }
+            (sink, self.peer, query_time)
+        });
+
+        let response = match (self.options.dnscrypt.clone(), self.options.odoh.clone()) {
+            (Some(dnscrypt), _) => async move { dnscrypt.exchange(request).await }
+                .into_stream()
+                .boxed(),
+            (None, Some(odoh)) => async move { odoh.exchange(request).await }
+                .into_stream()
+                .boxed(),
+            (None, None) => self
+                .options
+                .transport
+                .scope(self.inner.send(request))
+                .boxed(),
+        };
+
+        let response = match dnstap {
+            Some((sink, peer, query_time)) => response
+                .inspect(move |res| {
+                    if let Some(bytes) = res.as_ref().ok().and_then(|r| r.to_vec().ok()) {
+                        sink.resolver_response(&peer, query_time, &bytes);
+                    }
+                })
+                .boxed(),
+            None => response,
+        };
+
+        if !self.options.check_edns
+            && self.options.bogus_nxdomain.is_empty()
+            && self.options.whitelist_geoip.is_empty()
+            && !self.options.has_ip_filters()
+        {
+            return response;
+        }
+
+        let options = self.options.clone();
+
+        response
+            .map(move |res| match res {
+                Ok(response) if options.check_edns && response.extensions().is_none() => {
+                    debug!("discard the answer without edns: {:?}", response.queries());
+                    Err(ResolveError::from("answer without edns discarded"))
+                }
+                Ok(response) if options.is_bogus(&response) => {
+                    debug!("discard the bogus answer: {:?}", response.queries());
+                    Err(ResolveError::from(BOGUS_ANSWER))
+                }
+                Ok(response) if options.is_out_of_whitelist(&response) => {
+                    debug!(
+                        "discard the answer out of the whitelisted countries: {:?}",
+                        response.queries()
+                    );
+                    Err(ResolveError::from(GEOIP_DISCARDED))
+                }
+                Ok(response) if options.has_ip_filters() => {
+                    let queries = response.queries().to_vec();
+                    options.filter_ips(response).map_err(|err| {
+                        debug!("discard the answer by the ip filters: {:?}", queries);
+                        err
+                    })
+                }
+                res => res,
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use trust_dns_proto::op::Message;
+    use trust_dns_proto::rr::{Name, RData};
+
+    use super::*;
+
+    fn response(ips: &[&str]) -> DnsResponse {
+        let mut message = Message::new();
+        for ip in ips {
+            message.add_answer(Record::from_rdata(
+                Name::from_ascii("example.com.").unwrap(),
+                60,
+                RData::A(ip.parse().unwrap()),
+            ));
+        }
+        DnsResponse::from(message)
+    }
+
+    fn nets(nets: &[&str]) -> Arc<Vec<IpNet>> {
+        Arc::new(nets.iter().map(|net| net.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn test_filter_ips() {
+        let options = ConnectionOptions {
+            ignore_ip: nets(&["203.0.113.0/24"]),
+            blacklist_ip: nets(&["198.51.100.1"]),
+            ..Default::default()
+        };
+
+        let filtered = options
+            .filter_ips(response(&["203.0.113.7", "192.0.2.1"]))
+            .unwrap();
+        assert_eq!(filtered.answers().len(), 1);
+
+        let err = options.filter_ips(response(&["203.0.113.7"])).unwrap_err();
+        assert!(is_ip_filtered(&err));
+
+        let err = options
+            .filter_ips(response(&["192.0.2.1", "198.51.100.1"]))
+            .unwrap_err();
+        assert!(is_ip_filtered(&err));
+
+        let options = ConnectionOptions {
+            whitelist_ip: nets(&["192.0.2.0/24"]),
+            ..Default::default()
+        };
+        assert!(options.filter_ips(response(&["192.0.2.1"])).is_ok());
+        assert!(options.filter_ips(response(&[])).is_ok());
+        assert!(options
+            .filter_ips(response(&["192.0.2.1", "8.8.8.8"]))
+            .is_err());
+    }
+}
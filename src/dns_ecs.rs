@@ -0,0 +1,128 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use trust_dns_proto::op::Edns;
+use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use trust_dns_proto::xfer::DnsRequest;
+
+/// The EDNS Client Subnet (RFC 7871) attached to the queries sent to upstreams, e.g. `1.2.3.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSubnet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl ClientSubnet {
+    #[inline]
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    #[inline]
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// The ECS option with scope prefix 0, the address is truncated to the source prefix.
+    pub fn to_option(&self) -> EdnsOption {
+        let (family, octets) = match self.addr {
+            IpAddr::V4(ip) => (1u16, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (2u16, ip.octets().to_vec()),
+        };
+
+        let len = (self.prefix as usize + 7) / 8;
+
+        let mut data = Vec::with_capacity(4 + len);
+        data.extend_from_slice(&family.to_be_bytes());
+        data.push(self.prefix);
+        data.push(0);
+        data.extend_from_slice(&octets[..len]);
+
+        // the bits beyond the source prefix must be zero.
+        if self.prefix % 8 != 0 {
+            if let Some(last) = data.last_mut() {
+                *last &= 0xffu8 << (8 - self.prefix % 8);
+            }
+        }
+
+        EdnsOption::Unknown(EdnsCode::Subnet.into(), data)
+    }
+
+    /// Attach the ECS option to the request, replacing the one present.
+    pub fn apply(&self, request: &mut DnsRequest) {
+        let edns = request.extensions_mut().get_or_insert_with(|| {
+            let mut edns = Edns::new();
+            edns.set_max_payload(1232);
+            edns
+        });
+
+        edns.options_mut().insert(self.to_option());
+    }
+}
+
+impl FromStr for ClientSubnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = IpAddr::from_str(addr).map_err(|e| format!("invalid subnet {}, {}", s, e))?;
+
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix)
+                .ok_or_else(|| format!("invalid subnet prefix {}", s))?,
+            None => max_prefix,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for ClientSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_client_subnet() {
+        let subnet = ClientSubnet::from_str("1.2.3.0/24").unwrap();
+        assert_eq!(subnet.addr(), IpAddr::from([1, 2, 3, 0]));
+        assert_eq!(subnet.prefix(), 24);
+        assert_eq!(subnet.to_string(), "1.2.3.0/24");
+
+        assert_eq!(ClientSubnet::from_str("2001:db8::").unwrap().prefix(), 128);
+        assert!(ClientSubnet::from_str("1.2.3.0/33").is_err());
+        assert!(ClientSubnet::from_str("example.com/24").is_err());
+    }
+
+    #[test]
+    fn test_client_subnet_option() {
+        let subnet = ClientSubnet::from_str("1.2.3.4/20").unwrap();
+
+        assert_eq!(
+            subnet.to_option(),
+            EdnsOption::Unknown(8, vec![0, 1, 20, 0, 1, 2, 0])
+        );
+
+        let subnet = ClientSubnet::from_str("2001:db8::/32").unwrap();
+
+        assert_eq!(
+            subnet.to_option(),
+            EdnsOption::Unknown(8, vec![0, 2, 32, 0, 0x20, 0x01, 0x0d, 0xb8])
+        );
+    }
+}
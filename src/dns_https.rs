@@ -0,0 +1,395 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::body::HttpBody;
+use hyper::header::{HeaderMap, HeaderValue, ALT_SVC, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use trust_dns_proto::serialize::binary::BinDecodable;
+use trust_dns_server::authority::MessageRequest;
+use trust_dns_server::server::{Protocol, Request as DnsRequest, RequestHandler};
+
+use crate::dns_conf::TcpListenerOptions;
+use crate::dns_server::MessageLimits;
+use crate::dns_tcp::TcpResponseHandle;
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{debug, warn};
+use crate::third_ext::FutureTimeoutExt;
+
+/// The path the queries are accepted at, the only one.
+pub const DNS_QUERY_PATH: &str = "/dns-query";
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// The messages larger are refused, as they would be over tcp.
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Serve the queries on the https listener, HTTP/2 or HTTP/1.1 as negotiated, see RFC 8484.
+/// The responses advertise HTTP/3 on the port, if served as well.
+pub fn spawn_listener<H: RequestHandler + Clone>(
+    listener: TcpListener,
+    options: TcpListenerOptions,
+    acceptor: TlsAcceptor,
+    h3_port: Option<u16>,
+    limits: MessageLimits,
+    handler: H,
+    tasks: &BackgroundTasks,
+) {
+    let connections = Arc::new(Semaphore::new(options.max_connections));
+    let conn_tasks = tasks.clone();
+    let alt_svc =
+        h3_port.and_then(|port| HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", port)).ok());
+
+    tasks.spawn(async move {
+        loop {
+            // the connections over the limit wait in the backlog.
+            let permit = match connections.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+
+            let (stream, src) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!("accept https connection failed, {}", err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            if let Err(err) = stream.set_nodelay(options.nodelay) {
+                debug!(
+                    "set nodelay of https connection from {} failed, {}",
+                    src, err
+                );
+            }
+
+            let acceptor = acceptor.clone();
+            let handler = handler.clone();
+            let alt_svc = alt_svc.clone();
+            let query_tasks = conn_tasks.clone();
+
+            conn_tasks.spawn(async move {
+                let stream = match acceptor.accept(stream).timeout(options.idle_timeout).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        debug!("https handshake from {} failed, {}", src, err);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("https handshake from {} timed out", src);
+                        return;
+                    }
+                };
+
+                let service = service_fn(move |request: Request<Body>| {
+                    let handler = handler.clone();
+                    let alt_svc = alt_svc.clone();
+                    let query_tasks = query_tasks.clone();
+                    async move {
+                        let (parts, body) = request.into_parts();
+                        let body = match parts.method {
+                            Method::POST => read_body(body).await,
+                            _ => Ok(vec![]),
+                        };
+                        let answer = match body.and_then(|body| {
+                            dns_message(&parts.method, &parts.uri, &parts.headers, body)
+                        }) {
+                            Ok(message) => {
+                                resolve(message, src, limits, handler, &query_tasks).await
+                            }
+                            Err(status) => Err(status),
+                        };
+                        let (parts, body) = response(answer, alt_svc).into_parts();
+                        Ok::<_, Infallible>(Response::from_parts(parts, Body::from(body)))
+                    }
+                });
+
+                if let Err(err) = Http::new()
+                    .http2_max_concurrent_streams(options.max_pipelined as u32)
+                    .serve_connection(stream, service)
+                    .await
+                {
+                    debug!("https connection from {} failed, {}", src, err);
+                }
+                drop(permit);
+            });
+        }
+    });
+}
+
+/// Serve the queries over HTTP/3 on the udp port, see RFC 9114.
+#[cfg(feature = "http3")]
+pub fn spawn_h3_listener<H: RequestHandler + Clone>(
+    addr: SocketAddr,
+    config: rustls::ServerConfig,
+    options: TcpListenerOptions,
+    limits: MessageLimits,
+    handler: H,
+    tasks: &BackgroundTasks,
+) -> std::io::Result<u16> {
+    use bytes::{Buf, Bytes};
+
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(config));
+    if let Some(transport) = Arc::get_mut(&mut server_config.transport) {
+        transport
+            .max_concurrent_bidi_streams(quinn::VarInt::from_u32(options.max_pipelined as u32));
+        transport.max_idle_timeout(options.idle_timeout.try_into().ok());
+    }
+
+    let endpoint = quinn::Endpoint::server(server_config, addr)?;
+    let port = endpoint.local_addr()?.port();
+
+    let connections = Arc::new(Semaphore::new(options.max_connections));
+    let conn_tasks = tasks.clone();
+
+    tasks.spawn(async move {
+        loop {
+            let permit = match connections.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+
+            let connecting = match endpoint.accept().await {
+                Some(connecting) => connecting,
+                None => break,
+            };
+
+            let handler = handler.clone();
+            let query_tasks = conn_tasks.clone();
+
+            conn_tasks.spawn(async move {
+                let src = connecting.remote_address();
+
+                let served = async move {
+                    let conn = connecting.await?;
+                    let mut conn =
+                        h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn))
+                            .await?;
+
+                    while let Some((request, mut stream)) = conn.accept().await? {
+                        let handler = handler.clone();
+                        let tasks = query_tasks.clone();
+
+                        query_tasks.spawn(async move {
+                            let mut body = Ok(vec![]);
+                            while let Ok(Some(mut chunk)) = stream.recv_data().await {
+                                if let Ok(buf) = body.as_mut() {
+                                    if buf.len() + chunk.remaining() > MAX_MESSAGE_SIZE {
+                                        body = Err(StatusCode::PAYLOAD_TOO_LARGE);
+                                        continue;
+                                    }
+                                    while chunk.has_remaining() {
+                                        let n = chunk.chunk().len();
+                                        buf.extend_from_slice(chunk.chunk());
+                                        chunk.advance(n);
+                                    }
+                                }
+                            }
+
+                            let answer = match body.and_then(|body| {
+                                dns_message(
+                                    request.method(),
+                                    request.uri(),
+                                    request.headers(),
+                                    body,
+                                )
+                            }) {
+                                Ok(message) => resolve(message, src, limits, handler, &tasks).await,
+                                Err(status) => Err(status),
+                            };
+
+                            let (parts, body) = response(answer, None).into_parts();
+                            let sent = async {
+                                stream
+                                    .send_response(Response::from_parts(parts, ()))
+                                    .await?;
+                                if !body.is_empty() {
+                                    stream.send_data(Bytes::from(body)).await?;
+                                }
+                                stream.finish().await
+                            };
+                            if let Err(err) = sent.await {
+                                debug!("http3 response to {} failed, {}", src, err);
+                            }
+                        });
+                    }
+
+                    Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+                };
+
+                if let Err(err) = served.await {
+                    debug!("http3 connection from {} failed, {}", src, err);
+                }
+                drop(permit);
+            });
+        }
+    });
+
+    Ok(port)
+}
+
+/// Read the body of POST, refused once larger than a DNS message.
+async fn read_body(mut body: Body) -> Result<Vec<u8>, StatusCode> {
+    let mut buf = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if buf.len() + chunk.len() > MAX_MESSAGE_SIZE {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+/// The DNS message of the request, by the `dns` parameter of GET, or the body of POST.
+fn dns_message(
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: Vec<u8>,
+) -> Result<Vec<u8>, StatusCode> {
+    if uri.path() != DNS_QUERY_PATH {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    match *method {
+        Method::GET => {
+            let dns = uri
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="))
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            base64::decode_config(dns, base64::URL_SAFE_NO_PAD).map_err(|_| StatusCode::BAD_REQUEST)
+        }
+        Method::POST => {
+            let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+            if content_type != Some(DNS_MESSAGE) {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            Ok(body)
+        }
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+/// Answer the query by the handler, the encoded response.
+async fn resolve<H: RequestHandler>(
+    message: Vec<u8>,
+    src: SocketAddr,
+    limits: MessageLimits,
+    handler: H,
+    tasks: &BackgroundTasks,
+) -> Result<Vec<u8>, StatusCode> {
+    if !limits.admit(&message, src) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let message = MessageRequest::from_bytes(&message).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let request = DnsRequest::new(message, src, Protocol::Https);
+
+    // tracked, so that the shutdown waits for it.
+    tasks.spawn(async move {
+        handler
+            .handle_request(&request, TcpResponseHandle(tx))
+            .await;
+    });
+
+    rx.recv().await.ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn response(
+    answer: Result<Vec<u8>, StatusCode>,
+    alt_svc: Option<HeaderValue>,
+) -> Response<Vec<u8>> {
+    let mut builder = Response::builder();
+    if let Some(alt_svc) = alt_svc {
+        builder = builder.header(ALT_SVC, alt_svc);
+    }
+
+    match answer {
+        Ok(message) => builder
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .body(message),
+        Err(status) => builder.status(status).body(vec![]),
+    }
+    .unwrap_or_else(|err| {
+        warn!("build https response failed, {}", err);
+        let mut response = Response::new(vec![]);
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        response
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dns_message_get() {
+        let uri = "/dns-query?ct&dns=AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB"
+            .parse::<Uri>()
+            .unwrap();
+        let message = dns_message(&Method::GET, &uri, &HeaderMap::new(), vec![]).unwrap();
+        assert_eq!(message.len(), 33);
+        assert_eq!(&message[..4], &[0, 0, 1, 0]);
+
+        let uri = "/dns-query?dns=!".parse::<Uri>().unwrap();
+        assert_eq!(
+            dns_message(&Method::GET, &uri, &HeaderMap::new(), vec![]),
+            Err(StatusCode::BAD_REQUEST)
+        );
+
+        let uri = "/resolve?dns=AAAB".parse::<Uri>().unwrap();
+        assert_eq!(
+            dns_message(&Method::GET, &uri, &HeaderMap::new(), vec![]),
+            Err(StatusCode::NOT_FOUND)
+        );
+    }
+
+    #[test]
+    fn test_dns_message_post() {
+        let uri = DNS_QUERY_PATH.parse::<Uri>().unwrap();
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            dns_message(&Method::POST, &uri, &headers, vec![1, 2]),
+            Err(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        );
+
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+        assert_eq!(
+            dns_message(&Method::POST, &uri, &headers, vec![1, 2]),
+            Ok(vec![1, 2])
+        );
+
+        assert_eq!(
+            dns_message(&Method::PUT, &uri, &headers, vec![]),
+            Err(StatusCode::METHOD_NOT_ALLOWED)
+        );
+    }
+
+    #[test]
+    fn test_response_alt_svc() {
+        let alt_svc = HeaderValue::from_static("h3=\":443\"; ma=86400");
+        let ok = response(Ok(vec![0; 12]), Some(alt_svc.clone()));
+
+        assert_eq!(ok.status(), StatusCode::OK);
+        assert_eq!(ok.headers()[CONTENT_TYPE], DNS_MESSAGE);
+        assert_eq!(ok.headers()[ALT_SVC], alt_svc);
+
+        let bad = response(Err(StatusCode::BAD_REQUEST), None);
+        assert_eq!(bad.status(), StatusCode::BAD_REQUEST);
+        assert!(bad.headers().get(ALT_SVC).is_none());
+    }
+}
@@ -1,15 +1,19 @@
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use trust_dns_client::{
     op::ResponseCode,
-    rr::{RData, Record},
+    rr::{LowerName, RData, Record},
 };
 use trust_dns_resolver::error::ResolveErrorKind;
 
 use crate::{
-    dns::{DefaultSOA, DnsContext, DnsError, DnsRequest, DnsResponse},
+    dns::{DefaultSOA, DnsContext, DnsError, DnsRequest, DnsResponse, ExtendedError},
     dns_client::DnsClient,
-    dns_conf::SmartDnsConfig,
+    dns_conf::{ServerOpts, SmartDnsConfig},
+    dns_mw_secondary::DnsSecondaryMiddleware,
+    infra::mac::Neighbors,
+    matcher::DomainRuleMatcher,
     middleware::{Middleware, MiddlewareBuilder, MiddlewareDefaultHandler, MiddlewareHost},
 };
 
@@ -17,31 +21,88 @@ pub struct DnsMiddlewareHandler {
     pub cfg: Arc<SmartDnsConfig>,
     client: Arc<DnsClient>,
     host: MiddlewareHost<DnsContext, DnsRequest, DnsResponse, DnsError>,
+    /// the hardware addresses of the clients, for the client rules of them.
+    neighbors: Option<Neighbors>,
+    domain_rules: DomainRuleMatcher,
+    /// the zones hosted as a secondary, refreshed on the notifies of their primaries.
+    secondary: Option<DnsSecondaryMiddleware>,
 }
 
 impl DnsMiddlewareHandler {
-    pub async fn search(&self, req: &DnsRequest) -> Result<DnsResponse, DnsError> {
+    /// The answer of the request, by the policy of the client if a client rule matches, with
+    /// the extended error of the answers generated locally.
+    pub async fn search(
+        &self,
+        req: &DnsRequest,
+        server_opts: &Arc<ServerOpts>,
+    ) -> (Result<DnsResponse, DnsError>, Option<ExtendedError>) {
+        let mac = self
+            .neighbors
+            .as_ref()
+            .and_then(|neighbors| neighbors.mac_of(req.src().ip()));
+
+        let server_opts = match self.cfg.client_rule(req.src().ip(), mac) {
+            Some(rule) => Arc::new(server_opts.with_client(&rule.opts)),
+            None => server_opts.clone(),
+        };
+
         let mut ctx = DnsContext {
             cfg: self.cfg.clone(),
             client: self.client.clone(),
             fastest_speed: Default::default(),
             lookup_source: Default::default(),
+            server_opts,
+            extended_error: None,
+            domain_rule: self.domain_rules.find(req.query().name()).cloned(),
+            local_only: false,
         };
-        self.host.execute(&mut ctx, req).await
+        let res = self.host.execute(&mut ctx, req).await;
+        (res, ctx.extended_error)
+    }
+
+    /// Whether the notify of the zone is accepted, i.e. of a primary of a secondary zone.
+    pub fn notify(&self, zone: &LowerName, from: IpAddr) -> bool {
+        self.secondary
+            .as_ref()
+            .map_or(false, |secondary| secondary.notify(zone, from))
     }
 }
 
+/// The handling stages, the queries flow through them in the order registered.
 pub struct DnsMiddlewareBuilder {
     builder: MiddlewareBuilder<DnsContext, DnsRequest, DnsResponse, DnsError>,
+    neighbors: Option<Neighbors>,
+    secondary: Option<DnsSecondaryMiddleware>,
+}
+
+impl Default for DnsMiddlewareBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DnsMiddlewareBuilder {
     pub fn new() -> Self {
         Self {
             builder: MiddlewareBuilder::new(DnsDefaultHandler::default()),
+            neighbors: None,
+            secondary: None,
         }
     }
 
+    /// The hardware addresses of the clients, for the client rules of them.
+    pub fn with_neighbors(mut self, neighbors: Neighbors) -> Self {
+        self.neighbors = Some(neighbors);
+        self
+    }
+
+    /// The secondary zones, registered as a stage, and refreshed on the notifies.
+    pub fn with_secondary(mut self, secondary: DnsSecondaryMiddleware) -> Self {
+        self.secondary = Some(secondary.clone());
+        self.with(secondary)
+    }
+
+    /// Register a stage after the ones registered.
     pub fn with<M: Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> + 'static>(
         mut self,
         middleware: M,
@@ -53,8 +114,11 @@ impl DnsMiddlewareBuilder {
     pub fn build(self, cfg: SmartDnsConfig, client: Arc<DnsClient>) -> DnsMiddlewareHandler {
         DnsMiddlewareHandler {
             host: self.builder.build(),
+            domain_rules: DomainRuleMatcher::create(&cfg),
             cfg: Arc::new(cfg),
             client,
+            neighbors: self.neighbors,
+            secondary: self.secondary,
         }
     }
 }
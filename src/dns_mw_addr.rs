@@ -1,25 +1,305 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::Arc;
 
+use trust_dns_proto::rr::rdata::svcb::{IpHint, Mandatory, SvcParamKey, SvcParamValue, SVCB};
+
+use crate::blocking::BlockingOverrides;
+use crate::blocklist::Blocklists;
 use crate::dns::*;
-use crate::dns_conf::SmartDnsConfig;
-use crate::matcher::DomainAddressMatcher;
+use crate::dns_conf::{
+    AddressRuleItem, BlockingMode, DomainAddress, DomainOrDomainSet, DomainRule, ServerOpts,
+    SmartDnsConfig,
+};
+use crate::infra::ipnet::IpNet;
+use crate::matcher::{DomainAddressMatcher, DomainHttpsRecordMatcher};
 use crate::middleware::*;
-use trust_dns_client::rr::{RData, RecordType};
+use trust_dns_client::op::{Query, ResponseCode};
+use trust_dns_client::rr::{LowerName, RData, RecordType};
 use trust_dns_resolver::Name;
 
+/// Answer the domains of the address rules, matched by the longest suffix, before the cache.
 #[derive(Debug)]
 pub struct AddressMiddleware {
     map: DomainAddressMatcher,
+    /// the rules of each conf-group, taking precedence over the global ones.
+    groups: HashMap<String, DomainAddressMatcher>,
+    /// the domain sets blocked by the policy of the clients or listeners, by name.
+    block_sets: HashMap<String, DomainAddressMatcher>,
+    overrides: BlockingOverrides,
+    /// consulted after the rules, so that the rules override the lists.
+    blocklists: Blocklists,
+    /// the rules rewriting or blocking the HTTPS and SVCB records.
+    https: DomainHttpsRecordMatcher,
 }
 
 impl AddressMiddleware {
-    pub fn new(cfg: &SmartDnsConfig) -> Self {
+    pub fn new(cfg: &SmartDnsConfig, overrides: BlockingOverrides, blocklists: Blocklists) -> Self {
         Self {
             map: DomainAddressMatcher::create(cfg),
+            groups: cfg
+                .conf_groups
+                .iter()
+                .map(|(name, group)| {
+                    let map =
+                        DomainAddressMatcher::from_rules(&group.address_rules, &cfg.domain_sets);
+                    (name.to_owned(), map)
+                })
+                .collect(),
+            block_sets: cfg
+                .block_sets()
+                .map(|name| {
+                    let rules = [AddressRuleItem {
+                        domain: DomainOrDomainSet::DomainSet(name.to_owned()),
+                        address: DomainAddress::SOA,
+                    }];
+                    let map = DomainAddressMatcher::from_rules(&rules, &cfg.domain_sets);
+                    (name.to_owned(), map)
+                })
+                .collect(),
+            overrides,
+            blocklists,
+            https: DomainHttpsRecordMatcher::create(cfg),
+        }
+    }
+}
+
+/// Whether the address answers SOA, that's the `#` rules.
+pub(crate) fn is_soa(addr: &DomainAddress) -> bool {
+    matches!(
+        addr,
+        DomainAddress::SOA | DomainAddress::SOAv4 | DomainAddress::SOAv6 | DomainAddress::Block(_)
+    )
+}
+
+/// Whether the address blocks the domain, rather than maps it to an ip.
+fn is_blocking(addr: &DomainAddress) -> bool {
+    match addr {
+        DomainAddress::SOA | DomainAddress::SOAv4 | DomainAddress::SOAv6 => true,
+        DomainAddress::Block(_) => true,
+        DomainAddress::IPv4(ip) => ip.is_unspecified(),
+        DomainAddress::IPv6(ip) => ip.is_unspecified(),
+        _ => false,
+    }
+}
+
+/// The answer of the address rule, none to pass the query through.
+pub(crate) fn address_rdata(addr: &DomainAddress, record_type: RecordType) -> Option<RData> {
+    match (addr, record_type) {
+        (DomainAddress::IPv4(ipv4), RecordType::A) => Some(RData::A(*ipv4)),
+        (DomainAddress::IPv6(ipv6), RecordType::AAAA) => Some(RData::AAAA(*ipv6)),
+        // the domain has an address of the other family only.
+        (DomainAddress::IPv4(_), _) | (DomainAddress::IPv6(_), _) => Some(RData::default_soa()),
+        (DomainAddress::SOA, _) | (DomainAddress::Block(_), _) => Some(RData::default_soa()),
+        (DomainAddress::SOAv4, RecordType::A) => Some(RData::default_soa()),
+        (DomainAddress::SOAv6, RecordType::AAAA) => Some(RData::default_soa()),
+        _ => None,
+    }
+}
+
+/// How the query of the blocked domain is answered, by the mode of the rule or the global one,
+/// none if not blocked.
+pub(crate) fn blocking_mode(
+    addr: &DomainAddress,
+    record_type: RecordType,
+    global: BlockingMode,
+) -> Option<BlockingMode> {
+    match (addr, record_type) {
+        (DomainAddress::Block(mode), _) => Some(*mode),
+        (DomainAddress::SOA, _) => Some(global),
+        // the domain exists, of the other family.
+        (DomainAddress::SOAv4, RecordType::A) | (DomainAddress::SOAv6, RecordType::AAAA) => {
+            match global {
+                BlockingMode::NxDomain => Some(BlockingMode::NoData),
+                mode => Some(mode),
+            }
+        }
+        _ => None,
+    }
+}
+
+fn blocked(query: Query, mode: BlockingMode) -> Result<DnsResponse, DnsError> {
+    let response_code = match (mode, query.query_type()) {
+        (BlockingMode::Ip(Some(ip), _), RecordType::A) => {
+            return Ok(Lookup::from_rdata(query, RData::A(ip)))
+        }
+        (BlockingMode::Ip(_, Some(ip)), RecordType::AAAA) => {
+            return Ok(Lookup::from_rdata(query, RData::AAAA(ip)))
+        }
+        (BlockingMode::NxDomain, _) => ResponseCode::NXDomain,
+        (BlockingMode::Refused, _) => ResponseCode::Refused,
+        _ => return Ok(Lookup::from_rdata(query, RData::default_soa())),
+    };
+
+    Err(ResolveErrorKind::NoRecordsFound {
+        query: query.into(),
+        soa: None,
+        negative_ttl: None,
+        response_code,
+        trusted: true,
+    }
+    .into())
+}
+
+/// The extended error of the blocked query, filtered if the domain has the other family only.
+fn blocked_error(addr: &DomainAddress, record_type: RecordType) -> ExtendedError {
+    match addr {
+        DomainAddress::SOAv4 | DomainAddress::SOAv6 => {
+            ExtendedError::new(ExtendedError::FILTERED, format!("{} filtered", record_type))
+        }
+        _ => ExtendedError::new(ExtendedError::BLOCKED, "blocked by address rule"),
+    }
+}
+
+/// The rule the address of a domain is of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AddressOrigin {
+    /// the address rules of the conf-group.
+    ConfGroup(String),
+    DomainRule,
+    /// the domain set blocked by the policy.
+    BlockSet(String),
+    /// the global address rules.
+    Rule,
+    Blocklist,
+}
+
+impl AddressMiddleware {
+    /// The address of the domain and the rule of it, by the rules of the conf-group, the domain
+    /// rule, the blocked domain sets, the global rules, then the blocklists.
+    pub(crate) fn match_address(
+        &self,
+        opts: &ServerOpts,
+        domain_rule: Option<&DomainRule>,
+        name: &LowerName,
+    ) -> Option<(DomainAddress, AddressOrigin)> {
+        opts.conf_group
+            .as_ref()
+            .and_then(|group| {
+                let addr = self.groups.get(group)?.find(name)?;
+                Some((*addr, AddressOrigin::ConfGroup(group.to_owned())))
+            })
+            .or_else(|| {
+                domain_rule
+                    .and_then(|rule| rule.address)
+                    .map(|addr| (addr, AddressOrigin::DomainRule))
+            })
+            .or_else(|| {
+                opts.block_sets.iter().find_map(|set| {
+                    let addr = self.block_sets.get(set)?.find(name)?;
+                    Some((*addr, AddressOrigin::BlockSet(set.to_owned())))
+                })
+            })
+            .or_else(|| self.map.find(name).map(|addr| (*addr, AddressOrigin::Rule)))
+            .or_else(|| {
+                self.blocklists
+                    .find(name)
+                    .map(|addr| (addr, AddressOrigin::Blocklist))
+            })
+    }
+
+    /// The address of the domain, unless the listener ignores the rules or the blocking is
+    /// paused.
+    fn find_address(&self, ctx: &DnsContext, req: &DnsRequest) -> Option<DomainAddress> {
+        let name = req.query().name();
+        let opts = &ctx.server_opts;
+
+        self.match_address(opts, ctx.domain_rule.as_deref(), name)
+            .map(|(addr, _)| addr)
+            .filter(|_| !opts.no_rule_addr)
+            .filter(|addr| !(opts.no_rule_soa && is_soa(addr)))
+            .filter(|addr| !(is_blocking(addr) && self.overrides.is_paused(req.src().ip(), name)))
+    }
+}
+
+/// How the ip hints of a family follow the A or AAAA answer of the domain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HintRule {
+    /// the upstream answers, the bogus ips dropped.
+    Keep,
+    /// the ip of the address rule.
+    Replace(IpAddr),
+    Drop,
+}
+
+impl HintRule {
+    fn of(addr: Option<&DomainAddress>, record_type: RecordType) -> Self {
+        match addr.and_then(|addr| address_rdata(addr, record_type)) {
+            Some(RData::A(ip)) => HintRule::Replace(ip.into()),
+            Some(RData::AAAA(ip)) => HintRule::Replace(ip.into()),
+            Some(_) => HintRule::Drop,
+            None => HintRule::Keep,
+        }
+    }
+
+    fn apply<T: Copy + Into<IpAddr>>(
+        &self,
+        ips: &[T],
+        bogus: &[IpNet],
+        of_family: fn(IpAddr) -> Option<T>,
+    ) -> Vec<T> {
+        match self {
+            HintRule::Keep => ips
+                .iter()
+                .copied()
+                .filter(|ip| !bogus.iter().any(|net| net.contains(&(*ip).into())))
+                .collect(),
+            HintRule::Replace(ip) => of_family(*ip).into_iter().collect(),
+            HintRule::Drop => vec![],
         }
     }
 }
 
+/// Strip the ech parameter, also from the mandatory keys, and filter the ip hints, the
+/// parameters left empty removed.
+fn rewrite_svcb(
+    svcb: &SVCB,
+    no_ech: bool,
+    hints: Option<&(HintRule, HintRule)>,
+    bogus: &[IpNet],
+) -> SVCB {
+    let params = svcb
+        .svc_params()
+        .iter()
+        .filter_map(|(key, value)| {
+            let value = match (value, hints) {
+                (SvcParamValue::EchConfig(_), _) if no_ech => return None,
+                (SvcParamValue::Mandatory(Mandatory(keys)), _) if no_ech => {
+                    SvcParamValue::Mandatory(Mandatory(
+                        keys.iter()
+                            .filter(|k| **k != SvcParamKey::EchConfig)
+                            .cloned()
+                            .collect(),
+                    ))
+                }
+                (SvcParamValue::Ipv4Hint(IpHint(ips)), Some((v4, _))) => {
+                    SvcParamValue::Ipv4Hint(IpHint(v4.apply(ips, bogus, |ip| match ip {
+                        IpAddr::V4(ip) => Some(ip),
+                        _ => None,
+                    })))
+                }
+                (SvcParamValue::Ipv6Hint(IpHint(ips)), Some((_, v6))) => {
+                    SvcParamValue::Ipv6Hint(IpHint(v6.apply(ips, bogus, |ip| match ip {
+                        IpAddr::V6(ip) => Some(ip),
+                        _ => None,
+                    })))
+                }
+                (value, _) => value.clone(),
+            };
+
+            match &value {
+                SvcParamValue::Ipv4Hint(IpHint(ips)) if ips.is_empty() => None,
+                SvcParamValue::Ipv6Hint(IpHint(ips)) if ips.is_empty() => None,
+                SvcParamValue::Mandatory(Mandatory(keys)) if keys.is_empty() => None,
+                _ => Some((key.clone(), value)),
+            }
+        })
+        .collect();
+
+    SVCB::new(svcb.svc_priority(), svcb.target_name().clone(), params)
+}
+
 #[async_trait::async_trait]
 impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for AddressMiddleware {
     async fn handle(
@@ -32,28 +312,27 @@ impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for AddressMiddle
             // handle AAAA and A only.
             record_type @ (RecordType::AAAA | RecordType::A) => {
                 let name = req.query().name();
-                if let Some(addr) = self.map.find(name) {
-                    let rdata = match addr {
-                        crate::dns_conf::DomainAddress::IPv4(ipv4) => Some(RData::A(*ipv4)),
-                        crate::dns_conf::DomainAddress::IPv6(ipv6) => Some(RData::AAAA(*ipv6)),
-                        crate::dns_conf::DomainAddress::SOA => Some(RData::default_soa()),
-                        crate::dns_conf::DomainAddress::SOAv4
-                            if req.query().query_type() == RecordType::A =>
-                        {
-                            Some(RData::default_soa())
-                        }
-                        crate::dns_conf::DomainAddress::SOAv6
-                            if req.query().query_type() == RecordType::AAAA =>
-                        {
-                            Some(RData::default_soa())
-                        }
-                        crate::dns_conf::DomainAddress::IGN => None,
-                        crate::dns_conf::DomainAddress::IGNv4 => None,
-                        crate::dns_conf::DomainAddress::IGNv6 => None,
-                        _ => None,
-                    };
+                let opts = ctx.server_opts.clone();
 
-                    if let Some(rdata) = rdata {
+                if opts.force_aaaa_soa && record_type == RecordType::AAAA {
+                    ctx.lookup_source = LookupSource::Static;
+                    ctx.extended_error =
+                        Some(ExtendedError::new(ExtendedError::FILTERED, "AAAA filtered"));
+                    return Ok(Lookup::from_rdata(
+                        req.query().original().to_owned(),
+                        RData::default_soa(),
+                    ));
+                }
+
+                let addr = self.find_address(ctx, req);
+
+                if let Some(addr) = addr {
+                    if let Some(mode) = blocking_mode(&addr, record_type, ctx.cfg.blocking_mode) {
+                        ctx.lookup_source = LookupSource::Block;
+                        ctx.extended_error = Some(blocked_error(&addr, record_type));
+                        return blocked(req.query().original().to_owned(), mode);
+                    }
+                    if let Some(rdata) = address_rdata(&addr, record_type) {
                         let lookup = Lookup::from_rdata(req.query().original().to_owned(), rdata);
                         ctx.lookup_source = LookupSource::Static;
                         return Ok(lookup);
@@ -68,6 +347,69 @@ impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for AddressMiddle
                     return Ok(lookup);
                 }
             }
+            RecordType::HTTPS | RecordType::SVCB => {
+                let rule = match self.https.find(req.query().name()) {
+                    Some(rule) => *rule,
+                    None => return next.run(ctx, req).await,
+                };
+
+                if rule.block {
+                    ctx.lookup_source = LookupSource::Block;
+                    ctx.extended_error = Some(ExtendedError::new(
+                        ExtendedError::FILTERED,
+                        format!("{} filtered", req.query().query_type()),
+                    ));
+                    return blocked(req.query().original().to_owned(), ctx.cfg.blocking_mode);
+                }
+
+                let hints = if rule.filter_hints {
+                    let addr = self.find_address(ctx, req);
+                    let v6 = match ctx.server_opts.force_aaaa_soa {
+                        true => HintRule::Drop,
+                        false => HintRule::of(addr.as_ref(), RecordType::AAAA),
+                    };
+                    Some((HintRule::of(addr.as_ref(), RecordType::A), v6))
+                } else {
+                    None
+                };
+
+                let lookup = next.run(ctx, req).await?;
+
+                if !rule.no_ech && hints.is_none() {
+                    return Ok(lookup);
+                }
+
+                let records = lookup
+                    .records()
+                    .iter()
+                    .map(|record| {
+                        let mut record = record.clone();
+                        let rdata = match record.data() {
+                            Some(RData::HTTPS(svcb)) => RData::HTTPS(rewrite_svcb(
+                                svcb,
+                                rule.no_ech,
+                                hints.as_ref(),
+                                &ctx.cfg.bogus_nxdomain,
+                            )),
+                            Some(RData::SVCB(svcb)) => RData::SVCB(rewrite_svcb(
+                                svcb,
+                                rule.no_ech,
+                                hints.as_ref(),
+                                &ctx.cfg.bogus_nxdomain,
+                            )),
+                            _ => return record,
+                        };
+                        record.set_data(Some(rdata));
+                        record
+                    })
+                    .collect::<Vec<_>>();
+
+                return Ok(Lookup::new_with_deadline(
+                    lookup.query().clone(),
+                    Arc::from(records),
+                    lookup.valid_until(),
+                ));
+            }
             RecordType::PTR
                 if req.query().name() == &Name::from_str("whoami").unwrap().into()
                     || req.query().name() == &Name::from_str("smartdns").unwrap().into() =>
@@ -83,3 +425,165 @@ impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for AddressMiddle
         next.run(ctx, req).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use trust_dns_proto::rr::rdata::svcb::EchConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_address_rdata() {
+        let addr = DomainAddress::from_str("1.2.3.4").unwrap();
+        assert_eq!(
+            address_rdata(&addr, RecordType::A),
+            Some(RData::A("1.2.3.4".parse().unwrap()))
+        );
+        assert_eq!(
+            address_rdata(&addr, RecordType::AAAA),
+            Some(RData::default_soa())
+        );
+
+        let addr = DomainAddress::from_str("#4").unwrap();
+        assert_eq!(
+            address_rdata(&addr, RecordType::A),
+            Some(RData::default_soa())
+        );
+        assert_eq!(address_rdata(&addr, RecordType::AAAA), None);
+
+        let addr = DomainAddress::from_str("-").unwrap();
+        assert_eq!(address_rdata(&addr, RecordType::A), None);
+        assert_eq!(address_rdata(&addr, RecordType::AAAA), None);
+    }
+
+    #[test]
+    fn test_blocking_mode() {
+        let zero_ip = BlockingMode::from_str("zero-ip").unwrap();
+
+        let addr = DomainAddress::from_str("#").unwrap();
+        assert_eq!(blocking_mode(&addr, RecordType::A, zero_ip), Some(zero_ip));
+
+        // the rule over the global mode.
+        let addr = DomainAddress::from_str("#:refused").unwrap();
+        assert_eq!(
+            blocking_mode(&addr, RecordType::AAAA, zero_ip),
+            Some(BlockingMode::Refused)
+        );
+
+        let addr = DomainAddress::from_str("#6").unwrap();
+        assert_eq!(
+            blocking_mode(&addr, RecordType::AAAA, BlockingMode::NxDomain),
+            Some(BlockingMode::NoData)
+        );
+        assert_eq!(
+            blocking_mode(&addr, RecordType::A, BlockingMode::NxDomain),
+            None
+        );
+
+        let query = |rtype| Query::query(Name::from_str("ads.example.com.").unwrap(), rtype);
+
+        let lookup = blocked(query(RecordType::AAAA), zero_ip).unwrap();
+        assert_eq!(
+            lookup.iter().next(),
+            Some(&RData::AAAA("::".parse().unwrap()))
+        );
+
+        let page = BlockingMode::from_str("192.168.1.1").unwrap();
+        let lookup = blocked(query(RecordType::AAAA), page).unwrap();
+        assert_eq!(lookup.iter().next(), Some(&RData::default_soa()));
+
+        let err = blocked(query(RecordType::A), BlockingMode::NxDomain).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ResolveErrorKind::NoRecordsFound {
+                response_code: ResponseCode::NXDomain,
+                ..
+            }
+        ));
+
+        // the https records blocked as the others, no ip of the type.
+        let lookup = blocked(query(RecordType::HTTPS), zero_ip).unwrap();
+        assert_eq!(lookup.iter().next(), Some(&RData::default_soa()));
+
+        let err = blocked(query(RecordType::HTTPS), BlockingMode::Refused).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ResolveErrorKind::NoRecordsFound {
+                response_code: ResponseCode::Refused,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_blocked_error() {
+        assert_eq!(
+            blocked_error(&DomainAddress::SOA, RecordType::A).info_code,
+            ExtendedError::BLOCKED
+        );
+        assert_eq!(
+            blocked_error(&DomainAddress::SOAv6, RecordType::AAAA),
+            ExtendedError::new(ExtendedError::FILTERED, "AAAA filtered")
+        );
+    }
+
+    #[test]
+    fn test_rewrite_svcb() {
+        let svcb = SVCB::new(
+            1,
+            Name::root(),
+            vec![
+                (
+                    SvcParamKey::Mandatory,
+                    SvcParamValue::Mandatory(Mandatory(vec![SvcParamKey::EchConfig])),
+                ),
+                (
+                    SvcParamKey::Ipv4Hint,
+                    SvcParamValue::Ipv4Hint(IpHint(vec![
+                        "192.0.2.1".parse().unwrap(),
+                        "198.51.100.1".parse().unwrap(),
+                    ])),
+                ),
+                (
+                    SvcParamKey::EchConfig,
+                    SvcParamValue::EchConfig(EchConfig(vec![1, 2, 3])),
+                ),
+                (
+                    SvcParamKey::Ipv6Hint,
+                    SvcParamValue::Ipv6Hint(IpHint(vec!["2001:db8::1".parse().unwrap()])),
+                ),
+            ],
+        );
+        let bogus = vec![IpNet::from_str("198.51.100.0/24").unwrap()];
+
+        let rewritten = rewrite_svcb(&svcb, true, None, &bogus);
+        assert_eq!(
+            rewritten
+                .svc_params()
+                .iter()
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>(),
+            vec![SvcParamKey::Ipv4Hint, SvcParamKey::Ipv6Hint]
+        );
+
+        let rewritten = rewrite_svcb(
+            &svcb,
+            false,
+            Some(&(HintRule::Keep, HintRule::Drop)),
+            &bogus,
+        );
+        assert_eq!(rewritten.svc_params().len(), 3);
+        assert_eq!(
+            rewritten.svc_params()[1].1,
+            SvcParamValue::Ipv4Hint(IpHint(vec!["192.0.2.1".parse().unwrap()]))
+        );
+
+        let addr = DomainAddress::from_str("10.0.0.1").unwrap();
+        assert_eq!(
+            HintRule::of(Some(&addr), RecordType::A),
+            HintRule::Replace("10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(HintRule::of(Some(&addr), RecordType::AAAA), HintRule::Drop);
+        assert_eq!(HintRule::of(None, RecordType::AAAA), HintRule::Keep);
+    }
+}
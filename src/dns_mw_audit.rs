@@ -1,5 +1,6 @@
 use std::io::Write;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use std::time::Instant;
 
@@ -7,10 +8,16 @@ use chrono::prelude::*;
 use smallvec::SmallVec;
 use tokio::sync::mpsc::{self, Sender};
 
-use trust_dns_proto::op::Query;
+use trust_dns_proto::op::{Query, ResponseCode};
 
+#[cfg(feature = "sqlite")]
+use crate::audit_db::{self, AuditDb};
 use crate::dns::*;
 use crate::infra::mapped_file::MappedFile;
+use crate::infra::tasks::BackgroundTasks;
+use crate::infra::tproxy;
+#[cfg(feature = "sqlite")]
+use crate::log::error;
 use crate::log::warn;
 use crate::middleware::*;
 
@@ -34,10 +41,16 @@ impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsAuditMiddl
 
         let duration = start.elapsed();
 
+        // the resolver the client intended, if redirected to us.
+        let client = match tproxy::original_dst() {
+            Some(dst) => format!("{} -> {}", req.src(), dst),
+            None => req.src().to_string(),
+        };
+
         let audit = DnsAuditRecord::new(
             req.id(),
             now,
-            req.src().to_string(),
+            client,
             req.query().original().to_owned(),
             res.clone(),
             duration,
@@ -57,23 +70,52 @@ impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsAuditMiddl
 }
 
 impl DnsAuditMiddleware {
-    pub fn new<P: AsRef<Path>>(path: P, audit_size: u64, audit_num: usize) -> Self {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        audit_size: u64,
+        audit_num: usize,
+        audit_retention: Duration,
+        tasks: &BackgroundTasks,
+    ) -> Self {
         let audit_file = path.as_ref().to_owned();
 
         let (audit_tx, mut audit_rx) = mpsc::channel::<DnsAuditRecord>(100);
 
-        tokio::spawn(async move {
-            let mut audit_file = MappedFile::open(audit_file, audit_size, Some(audit_num));
+        tasks.spawn_with_token(|token| async move {
+            // the sink is opened and written on the blocking threads, the database commits
+            // and purges may take a while.
+            let mut audit_file = tokio::task::spawn_blocking(move || {
+                AuditSink::open(audit_file, audit_size, audit_num, audit_retention)
+            })
+            .await
+            .unwrap_or(AuditSink::Discard);
 
             const BUF_SIZE: usize = 10;
             let mut buf: SmallVec<[DnsAuditRecord; BUF_SIZE]> = SmallVec::new();
 
-            while let Some(audit) = audit_rx.recv().await {
-                buf.push(audit);
-
-                if buf.len() == BUF_SIZE {
-                    record_audit_to_file(&mut audit_file, buf.as_slice());
-                    buf.clear();
+            loop {
+                let audit = tokio::select! {
+                    audit = audit_rx.recv() => audit,
+                    _ = token.cancelled() => None,
+                };
+
+                match audit {
+                    Some(audit) => {
+                        buf.push(audit);
+
+                        if buf.len() == BUF_SIZE {
+                            audit_file = audit_file
+                                .record_blocking(std::mem::take(&mut buf).into_vec())
+                                .await;
+                        }
+                    }
+                    None => {
+                        // flush the remaining records before exiting.
+                        if !buf.is_empty() {
+                            audit_file.record_blocking(buf.into_vec()).await;
+                        }
+                        break;
+                    }
                 }
             }
         });
@@ -84,6 +126,57 @@ impl DnsAuditMiddleware {
     }
 }
 
+/// Where the audit records go, by the extension of the audit-file.
+enum AuditSink {
+    File(MappedFile),
+    #[cfg(feature = "sqlite")]
+    Db(AuditDb),
+    /// The database failed to open, the records are dropped.
+    Discard,
+}
+
+impl AuditSink {
+    #[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+    fn open(path: PathBuf, size: u64, num: usize, retention: Duration) -> Self {
+        #[cfg(feature = "sqlite")]
+        if audit_db::is_db(&path) {
+            return match AuditDb::open(&path, retention) {
+                Ok(db) => AuditSink::Db(db),
+                Err(err) => {
+                    error!("open audit database {:?} failed, {}", path, err);
+                    AuditSink::Discard
+                }
+            };
+        }
+
+        AuditSink::File(MappedFile::open(path, size, Some(num)))
+    }
+
+    /// Write the records on a blocking thread, the sink given back, discarding if it panicked.
+    async fn record_blocking(mut self, audit_records: Vec<DnsAuditRecord>) -> Self {
+        tokio::task::spawn_blocking(move || {
+            self.record(&audit_records);
+            self
+        })
+        .await
+        .unwrap_or_else(|err| {
+            warn!("write audit failed, {}", err);
+            AuditSink::Discard
+        })
+    }
+
+    fn record(&mut self, audit_records: &[DnsAuditRecord]) {
+        match self {
+            AuditSink::File(file) => record_audit_to_file(file, audit_records),
+            #[cfg(feature = "sqlite")]
+            AuditSink::Db(db) => db
+                .record(audit_records)
+                .unwrap_or_else(|err| warn!("write audit to database failed, {}", err)),
+            AuditSink::Discard => (),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DnsAuditRecord {
     id: u16,
@@ -97,7 +190,7 @@ pub struct DnsAuditRecord {
 }
 
 impl DnsAuditRecord {
-    fn new(
+    pub(crate) fn new(
         id: u16,
         now: DateTime<Local>,
         source_host: String,
@@ -119,7 +212,49 @@ impl DnsAuditRecord {
         }
     }
 
-    fn fmt_result(&self) -> String {
+    pub fn date(&self) -> DateTime<Local> {
+        self.date
+    }
+
+    pub fn client(&self) -> &str {
+        &self.client
+    }
+
+    /// The ip of the client, without the port and the resolver it intended.
+    pub fn client_ip(&self) -> String {
+        let client = self.client.split(" -> ").next().unwrap_or_default();
+        client
+            .parse::<SocketAddr>()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|_| client.to_string())
+    }
+
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn lookup_source(&self) -> &LookupSource {
+        &self.lookup_source
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        matches!(self.lookup_source, LookupSource::Block)
+    }
+
+    /// The response code answered to the client.
+    pub fn response_code(&self) -> ResponseCode {
+        match self.result.as_ref().map_err(|err| err.kind()) {
+            Ok(_) => ResponseCode::NoError,
+            Err(ResolveErrorKind::NoRecordsFound { response_code, .. }) => *response_code,
+            Err(_) => ResponseCode::ServFail,
+        }
+    }
+
+    pub fn fmt_result(&self) -> String {
         self.result
             .as_ref()
             .map(|lookup| lookup.records().to_vec())
@@ -149,13 +284,15 @@ impl DnsAuditRecord {
 
     fn to_string_without_date(&self) -> String {
         format!(
-            "{} query {}, type: {}, elapsed: {:?}, speed: {:?}, result {}",
+            "{} query {}, type: {}, elapsed: {:?}, speed: {:?}, result {}, rcode: {:?}, source: {:?}",
             self.client,
             self.query.name(),
             self.query.query_type(),
             self.elapsed,
             self.speed,
-            self.fmt_result()
+            self.fmt_result(),
+            self.response_code(),
+            self.lookup_source
         )
     }
 }
@@ -163,14 +300,9 @@ impl DnsAuditRecord {
 impl ToString for DnsAuditRecord {
     fn to_string(&self) -> String {
         format!(
-            "[{}] {} query {}, type: {}, elapsed: {:?}, speed: {:?}, result {}",
+            "[{}] {}",
             self.date.format("%Y-%m-%d %H:%M:%S,%3f"),
-            self.client,
-            self.query.name(),
-            self.query.query_type(),
-            self.elapsed,
-            self.speed,
-            self.fmt_result()
+            self.to_string_without_date()
         )
     }
 }
@@ -192,6 +324,7 @@ fn record_audit_to_file(audit_file: &mut MappedFile, audit_records: &[DnsAuditRe
                     "speed",
                     "state",
                     "result",
+                    "rcode",
                     "lookup_source",
                 ])
                 .unwrap();
@@ -217,6 +350,7 @@ fn record_audit_to_file(audit_file: &mut MappedFile, audit_records: &[DnsAuditRe
                         "failed"
                     },
                     audit.fmt_result().as_str(),
+                    format!("{:?}", audit.response_code()).as_str(),
                     format!("{:?}", audit.lookup_source).as_str(),
                 ])
                 .unwrap();
@@ -236,7 +370,7 @@ mod tests {
 
     use std::io::Read;
     use std::str::FromStr;
-    use trust_dns_proto::op::Query;
+    use trust_dns_proto::op::{Query, ResponseCode};
     use trust_dns_proto::rr::{RData, RecordType};
 
     use super::*;
@@ -261,7 +395,7 @@ mod tests {
             LookupSource::Server("default".to_string()),
         );
 
-        assert_eq!(audit.to_string(), format!("[{}] 127.0.0.1 query www.example.com, type: A, elapsed: 10ms, speed: 11ms, result 93.184.216.34 86400 A", now.format("%Y-%m-%d %H:%M:%S,%3f")));
+        assert_eq!(audit.to_string(), format!("[{}] 127.0.0.1 query www.example.com, type: A, elapsed: 10ms, speed: 11ms, result 93.184.216.34 86400 A, rcode: NoError, source: Server: default", now.format("%Y-%m-%d %H:%M:%S,%3f")));
     }
 
     #[test]
@@ -285,7 +419,34 @@ mod tests {
             LookupSource::Server("default".to_string()),
         );
 
-        assert_eq!(audit.to_string_without_date(), "127.0.0.1 query www.example.com, type: A, elapsed: 10ms, speed: 11ms, result 93.184.216.34 86400 A");
+        assert_eq!(audit.to_string_without_date(), "127.0.0.1 query www.example.com, type: A, elapsed: 10ms, speed: 11ms, result 93.184.216.34 86400 A, rcode: NoError, source: Server: default");
+    }
+
+    #[test]
+    fn test_dns_audit_blocked() {
+        let now = "2022-11-11 20:18:11.099966887 +08:00".parse().unwrap();
+        let query = Query::query(Name::from_str("ads.example.com").unwrap(), RecordType::A);
+        let result = Err(ResolveErrorKind::NoRecordsFound {
+            query: query.clone().into(),
+            soa: None,
+            negative_ttl: None,
+            response_code: ResponseCode::NXDomain,
+            trusted: true,
+        }
+        .into());
+
+        let audit = DnsAuditRecord::new(
+            11,
+            now,
+            "127.0.0.1".to_string(),
+            query,
+            result,
+            Duration::from_millis(1),
+            Duration::ZERO,
+            LookupSource::Block,
+        );
+
+        assert_eq!(audit.to_string_without_date(), "127.0.0.1 query ads.example.com, type: A, elapsed: 1ms, speed: 0ns, result query failed, rcode: NXDomain, source: Block");
     }
 
     #[test]
@@ -324,7 +485,7 @@ mod tests {
             .read_to_string(&mut s)
             .unwrap();
 
-        assert_eq!(s, format!("[{}] 127.0.0.1 query www.example.com, type: A, elapsed: 10ms, speed: 11ms, result 93.184.216.34 86400 A\n", now.format("%Y-%m-%d %H:%M:%S,%3f")));
+        assert_eq!(s, format!("[{}] 127.0.0.1 query www.example.com, type: A, elapsed: 10ms, speed: 11ms, result 93.184.216.34 86400 A, rcode: NoError, source: Server: default\n", now.format("%Y-%m-%d %H:%M:%S,%3f")));
 
         std::fs::remove_file(file).unwrap();
 
@@ -376,7 +537,7 @@ mod tests {
             .read_to_string(&mut s)
             .unwrap();
 
-        assert_eq!(s, "id,timestamp,client,name,type,elapsed,speed,state,result,lookup_source\n11,1668169091,127.0.0.1,www.example.com,A,10ms,11ms,success,93.184.216.34 86400 A,Server: default1\n");
+        assert_eq!(s, "id,timestamp,client,name,type,elapsed,speed,state,result,rcode,lookup_source\n11,1668169091,127.0.0.1,www.example.com,A,10ms,11ms,success,93.184.216.34 86400 A,NoError,Server: default1\n");
 
         record_audit_to_file(&mut MappedFile::open(file, 102400, None), &[audit2]);
 
@@ -387,7 +548,7 @@ mod tests {
             .read_to_string(&mut s)
             .unwrap();
 
-        assert_eq!(s, "id,timestamp,client,name,type,elapsed,speed,state,result,lookup_source\n11,1668169091,127.0.0.1,www.example.com,A,10ms,11ms,success,93.184.216.34 86400 A,Server: default1\n12,1668169091,127.0.0.1,www.example.com,A,10ms,11ms,success,93.184.216.34 86400 A,Server: default2\n");
+        assert_eq!(s, "id,timestamp,client,name,type,elapsed,speed,state,result,rcode,lookup_source\n11,1668169091,127.0.0.1,www.example.com,A,10ms,11ms,success,93.184.216.34 86400 A,NoError,Server: default1\n12,1668169091,127.0.0.1,www.example.com,A,10ms,11ms,success,93.184.216.34 86400 A,NoError,Server: default2\n");
 
         std::fs::remove_file(file).unwrap();
 
@@ -1,14 +1,17 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
 use crate::dns::*;
 use crate::dns_client::DnsClient;
-use crate::dns_conf::SmartDnsConfig;
-use crate::log::{debug, error};
+use crate::dns_conf::{ServerOpts, SmartDnsConfig};
+use crate::infra::memory::MemoryPressure;
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{debug, error, info};
 use crate::middleware::*;
 
 use lru::LruCache;
@@ -16,14 +19,91 @@ use tokio::{
     sync::{mpsc, Mutex, Notify},
     time::sleep,
 };
-use trust_dns_proto::op::Query;
+use trust_dns_proto::op::{Message, Query};
+use trust_dns_proto::rr::RecordType;
+use trust_dns_proto::serialize::binary::{BinDecodable, BinEncodable};
+use trust_dns_server::authority::MessageRequest;
 
 pub struct DnsCacheMiddleware {
     cache: Arc<DnsLruCache>,
+    /// the offset the cached ips are answered from, advanced on every hit, see `rr-rotate`.
+    rotation: Option<AtomicUsize>,
+}
+
+/// The answers cached, kept over the reloads of the configuration, see `DnsCacheMiddleware`.
+#[derive(Clone)]
+pub struct DnsCacheStore(Arc<Mutex<LruCache<CacheKey, DnsCacheEntry>>>);
+
+/// The key of a cached answer, the query and the policy of the stages after the cache, so
+/// that the listeners and the clients resolving by different groups never share answers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    query: Query,
+    policy: CachePolicy,
+}
+
+/// The options of `ServerOpts` deciding how a cache miss is resolved.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+struct CachePolicy {
+    group: Option<String>,
+    conf_group: Option<String>,
+    no_rule_nameserver: bool,
+    no_speed_check: bool,
+    no_dualstack_selection: bool,
+}
+
+impl From<&ServerOpts> for CachePolicy {
+    fn from(opts: &ServerOpts) -> Self {
+        Self {
+            group: opts.group.clone(),
+            conf_group: opts.conf_group.clone(),
+            no_rule_nameserver: opts.no_rule_nameserver,
+            no_speed_check: opts.no_speed_check,
+            no_dualstack_selection: opts.no_dualstack_selection,
+        }
+    }
+}
+
+impl CachePolicy {
+    fn key(&self, query: &Query) -> CacheKey {
+        CacheKey {
+            query: query.clone(),
+            policy: self.clone(),
+        }
+    }
+
+    /// The group to prefetch the name by, the one of the listener unless a nameserver rule
+    /// decides it, as `NameServerMiddleware` does.
+    fn prefetch_group<'a>(&'a self, client: &DnsClient, name: &Name) -> Option<&'a str> {
+        self.group.as_deref().filter(|_| {
+            self.no_rule_nameserver || client.match_server_group(&name.into()).is_none()
+        })
+    }
+}
+
+impl DnsCacheStore {
+    pub fn new(cache_size: usize) -> Self {
+        Self(Arc::new(Mutex::new(LruCache::new(
+            NonZeroUsize::new(cache_size.max(1)).unwrap(),
+        ))))
+    }
+
+    /// Set the capacity of the configuration reloaded, the least recent answers dropped if less.
+    pub async fn resize(&self, cache_size: usize) {
+        if let Some(size) = NonZeroUsize::new(cache_size) {
+            self.0.lock().await.resize(size);
+        }
+    }
 }
 
 impl DnsCacheMiddleware {
-    pub fn new(cfg: &SmartDnsConfig, client: Arc<DnsClient>) -> Self {
+    pub fn new(
+        cfg: &SmartDnsConfig,
+        store: DnsCacheStore,
+        client: Arc<DnsClient>,
+        memory: MemoryPressure,
+        tasks: BackgroundTasks,
+    ) -> Self {
         let positive_min_ttl = Some(Duration::from_secs(cfg.rr_ttl_min.unwrap_or(cfg.rr_ttl())));
         let positive_max_ttl = Some(Duration::from_secs(cfg.rr_ttl_max.unwrap_or(cfg.rr_ttl())));
 
@@ -31,18 +111,26 @@ impl DnsCacheMiddleware {
         let negative_max_ttl = None;
 
         let cache = Arc::new(DnsLruCache::new(
+            store,
             cfg.cache_size(),
             positive_min_ttl,
             negative_min_ttl,
             positive_max_ttl,
             negative_max_ttl,
+            memory,
+            tasks,
         ));
 
+        cache.shrink_on_memory_pressure();
+
         if cfg.prefetch_domain {
             cache.prefetch_domain(client);
         }
 
-        Self { cache }
+        Self {
+            cache,
+            rotation: cfg.rr_rotate.then(Default::default),
+        }
     }
 }
 
@@ -56,12 +144,31 @@ impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsCacheMiddl
     ) -> Result<DnsResponse, DnsError> {
         let query = req.query();
 
-        let cached_val = self.cache.get(query.original(), Instant::now()).await;
+        if ctx.server_opts.no_cache || ctx.local_only {
+            return next.run(ctx, req).await;
+        }
+
+        let policy = CachePolicy::from(ctx.server_opts.as_ref());
+
+        let cached_val = self
+            .cache
+            .get(&policy.key(query.original()), Instant::now())
+            .await;
 
-        if cached_val.is_some() {
+        if let Some(cached_val) = cached_val {
             debug!("name: {} using caching", query.name());
             ctx.lookup_source = LookupSource::Cache;
-            return cached_val.unwrap();
+            return match (cached_val, self.rotation.as_ref()) {
+                (Ok(lookup), Some(rotation)) => {
+                    let offset = rotation.fetch_add(1, Ordering::Relaxed);
+                    Ok(Lookup::new_with_deadline(
+                        lookup.query().clone(),
+                        Arc::from(rotate(lookup.records(), offset)),
+                        lookup.valid_until(),
+                    ))
+                }
+                (cached_val, _) => cached_val,
+            };
         }
 
         let res = next.run(ctx, req).await;
@@ -71,6 +178,7 @@ impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsCacheMiddl
                 self.cache
                     .insert_records(
                         query.original().to_owned(),
+                        &policy,
                         lookup.records().to_owned().into_iter(),
                         Instant::now(),
                     )
@@ -85,13 +193,66 @@ impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsCacheMiddl
     }
 }
 
+/// The AAAA query of the A query, and vice versa, from the same client, see
+/// `DnsDualStackMiddleware`.
+pub(crate) fn sibling_request(req: &DnsRequest) -> Option<DnsRequest> {
+    let query = req.query().original();
+
+    let sibling_type = match query.query_type() {
+        RecordType::A => RecordType::AAAA,
+        RecordType::AAAA => RecordType::A,
+        _ => return None,
+    };
+
+    let mut sibling = query.clone();
+    sibling.set_query_type(sibling_type);
+
+    derived_request(req, sibling)
+}
+
+/// The records with the ips rotated by the offset among their own places, the CNAME chain
+/// before them kept in place.
+fn rotate(records: &[Record], offset: usize) -> Vec<Record> {
+    let slots = records
+        .iter()
+        .enumerate()
+        .filter(|(_, record)| matches!(record.record_type(), RecordType::A | RecordType::AAAA))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    let mut rotated = records.to_vec();
+    if slots.len() < 2 {
+        return rotated;
+    }
+
+    for (n, slot) in slots.iter().enumerate() {
+        let from = slots[(n + offset) % slots.len()];
+        rotated[*slot] = records[from].clone();
+    }
+
+    rotated
+}
+
+/// The request of the query instead, from the same client.
+pub(crate) fn derived_request(req: &DnsRequest, query: Query) -> Option<DnsRequest> {
+    let mut message = Message::new();
+    message
+        .set_id(req.id())
+        .set_recursion_desired(true)
+        .add_query(query);
+
+    let message = MessageRequest::from_bytes(&message.to_vec().ok()?).ok()?;
+
+    Some(DnsRequest::new(message, req.src(), req.protocol()))
+}
+
 /// Maximum TTL as defined in https://tools.ietf.org/html/rfc2181, 2147483647
 /// Setting this to a value of 1 day, in seconds
 const MAX_TTL: u32 = 86400_u32;
 
 /// An LRU eviction cache specifically for storing DNS records
 struct DnsLruCache {
-    cache: Arc<Mutex<LruCache<Query, DnsCacheEntry>>>,
+    cache: Arc<Mutex<LruCache<CacheKey, DnsCacheEntry>>>,
     /// A minimum TTL value for positive responses.
     ///
     /// Positive responses with TTLs under `positive_max_ttl` will use
@@ -130,19 +291,26 @@ struct DnsLruCache {
     negative_max_ttl: Duration,
 
     prefetch_notify: Arc<Notify>,
+
+    /// The configured capacity, the cache shrinks to a quarter of it under memory pressure.
+    cache_size: usize,
+    memory: MemoryPressure,
+
+    tasks: BackgroundTasks,
 }
 
 impl DnsLruCache {
     fn new(
+        store: DnsCacheStore,
         cache_size: usize,
         positive_min_ttl: Option<Duration>,
         negative_min_ttl: Option<Duration>,
         positive_max_ttl: Option<Duration>,
         negative_max_ttl: Option<Duration>,
+        memory: MemoryPressure,
+        tasks: BackgroundTasks,
     ) -> Self {
-        let cache = Arc::new(Mutex::new(LruCache::new(
-            NonZeroUsize::new(cache_size).unwrap(),
-        )));
+        let cache = store.0;
         let positive_min_ttl = positive_min_ttl.unwrap_or_else(|| Duration::from_secs(0));
         let negative_min_ttl = negative_min_ttl.unwrap_or_else(|| Duration::from_secs(0));
         let positive_max_ttl =
@@ -157,6 +325,9 @@ impl DnsLruCache {
             positive_max_ttl,
             negative_max_ttl,
             prefetch_notify: Default::default(),
+            cache_size,
+            memory,
+            tasks,
         }
     }
 
@@ -164,9 +335,34 @@ impl DnsLruCache {
         self.cache.lock().await.clear();
     }
 
+    fn shrink_on_memory_pressure(&self) {
+        let cache = self.cache.clone();
+        let cache_size = self.cache_size;
+        let mut memory = self.memory.subscribe();
+
+        self.tasks.spawn(async move {
+            while memory.changed().await.is_ok() {
+                let high = *memory.borrow();
+
+                let size = if high {
+                    (cache_size / 4).max(1)
+                } else {
+                    cache_size
+                };
+
+                let mut cache = cache.lock().await;
+                if let Some(size) = NonZeroUsize::new(size) {
+                    cache.resize(size);
+                }
+
+                info!("Resize dns cache to {} (entries: {})", size, cache.len());
+            }
+        });
+    }
+
     async fn insert(
         &self,
-        query: Query,
+        key: CacheKey,
         records_and_ttl: Vec<(Record, u32)>,
         now: Instant,
     ) -> Lookup {
@@ -188,13 +384,13 @@ impl DnsLruCache {
         let valid_until = now + ttl;
 
         // insert into the LRU
-        let lookup = Lookup::new_with_deadline(query.clone(), Arc::from(records), valid_until);
+        let lookup = Lookup::new_with_deadline(key.query.clone(), Arc::from(records), valid_until);
 
         self.notify_prefetch_domain(ttl);
 
         if let Ok(mut cache) = self.cache.try_lock() {
             cache.put(
-                query,
+                key,
                 DnsCacheEntry {
                     lookup: Ok(lookup.clone()),
                     valid_until,
@@ -213,6 +409,7 @@ impl DnsLruCache {
     /// # Arguments
     ///
     /// * `original_query` - is used for matching the records that should be returned
+    /// * `policy` - the policy the records were resolved by, part of their keys
     /// * `records` - the records will be partitioned by type and name for storage in the cache
     /// * `now` - current time for use in associating TTLs
     ///
@@ -222,6 +419,7 @@ impl DnsLruCache {
     async fn insert_records(
         &self,
         original_query: Query,
+        policy: &CachePolicy,
         records: impl Iterator<Item = Record>,
         now: Instant,
     ) -> Option<Lookup> {
@@ -246,7 +444,7 @@ impl DnsLruCache {
         let mut lookup = None;
         for (query, records_and_ttl) in records {
             let is_query = original_query == query;
-            let inserted = self.insert(query, records_and_ttl, now).await;
+            let inserted = self.insert(policy.key(&query), records_and_ttl, now).await;
 
             if is_query {
                 lookup = Some(inserted)
@@ -272,8 +470,17 @@ impl DnsLruCache {
         // }
     }
 
+    /// Whether the query is cached and current, without touching its recency.
+    async fn contains(&self, key: &CacheKey, now: Instant) -> bool {
+        match self.cache.try_lock() {
+            Ok(cache) => cache.peek(key).map_or(false, |v| v.is_current(now)),
+            // taken as cached, not to query it again.
+            Err(_) => true,
+        }
+    }
+
     /// Based on the query, see if there are any records available
-    async fn get(&self, query: &Query, now: Instant) -> Option<Result<Lookup, DnsError>> {
+    async fn get(&self, key: &CacheKey, now: Instant) -> Option<Result<Lookup, DnsError>> {
         let mut out_of_date = false;
         let mut cache = match self.cache.try_lock() {
             Ok(t) => t,
@@ -282,7 +489,7 @@ impl DnsLruCache {
                 return None;
             }
         };
-        let lookup = cache.get_mut(query).and_then(|value| {
+        let lookup = cache.get_mut(key).and_then(|value| {
             if value.is_current(now) {
                 out_of_date = false;
                 let mut result = value.lookup.clone();
@@ -301,7 +508,7 @@ impl DnsLruCache {
         // this assumes time is always moving forward, this would only not be true in contrived situations where now
         //  is not current time, like tests...
         if out_of_date {
-            cache.pop(query).unwrap();
+            cache.pop(key).unwrap();
         }
 
         lookup
@@ -313,21 +520,22 @@ impl DnsLruCache {
         }
 
         let prefetch_notify = self.prefetch_notify.clone();
-        tokio::spawn(async move {
+        self.tasks.spawn(async move {
             sleep(duration).await;
             prefetch_notify.notify_one();
         });
     }
 
     fn prefetch_domain(&self, client: Arc<DnsClient>) {
-        let (tx, mut rx) = mpsc::channel::<Vec<Query>>(100);
+        let (tx, mut rx) = mpsc::channel::<Vec<CacheKey>>(100);
 
         {
             // prefetch domain.
             let cache = self.cache.clone();
+            let tasks = self.tasks.clone();
 
-            tokio::spawn(async move {
-                let querying: Arc<Mutex<HashSet<Query>>> = Default::default();
+            self.tasks.spawn(async move {
+                let querying: Arc<Mutex<HashSet<CacheKey>>> = Default::default();
 
                 loop {
                     if let Some(queries) = rx.recv().await {
@@ -335,20 +543,24 @@ impl DnsLruCache {
                         let cache = cache.clone();
                         let querying = querying.clone();
 
-                        for query in queries {
-                            if !querying.lock().await.insert(query.clone()) {
+                        for key in queries {
+                            if !querying.lock().await.insert(key.clone()) {
                                 continue;
                             }
 
                             let querying = querying.clone();
                             let cache = cache.clone();
 
-                            let (client, name, typ) =
-                                (client.clone(), query.name().to_owned(), query.query_type());
+                            let (client, name, typ) = (
+                                client.clone(),
+                                key.query.name().to_owned(),
+                                key.query.query_type(),
+                            );
 
-                            tokio::spawn(async move {
+                            tasks.spawn(async move {
                                 let now = Instant::now();
-                                if let Ok(lookup) = client.lookup(name.clone(), typ, None).await {
+                                let group = key.policy.prefetch_group(&client, &name);
+                                if let Ok(lookup) = client.lookup(name.clone(), typ, group).await {
                                     let min_ttl = lookup
                                         .records()
                                         .iter()
@@ -364,7 +576,7 @@ impl DnsLruCache {
                                     );
 
                                     if let Some(min_ttl) = min_ttl {
-                                        if let Some(entry) = cache.lock().await.peek_mut(&query) {
+                                        if let Some(entry) = cache.lock().await.peek_mut(&key) {
                                             entry.valid_until = now + min_ttl;
                                             entry.origin_ttl = min_ttl;
                                             entry.lookup = Ok(lookup);
@@ -372,7 +584,7 @@ impl DnsLruCache {
                                     }
                                 }
 
-                                querying.lock().await.remove(&query);
+                                querying.lock().await.remove(&key);
                             });
                         }
                     }
@@ -388,8 +600,12 @@ impl DnsLruCache {
 
             const MIN_INTERVAL: Duration = Duration::from_secs(1);
             const MIN_TTL: Duration = Duration::from_secs(5);
+            const PRESSURE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
 
-            tokio::spawn(async move {
+            let memory = self.memory.clone();
+            let tasks = self.tasks.clone();
+
+            self.tasks.spawn(async move {
                 let mut last_check = Instant::now();
 
                 loop {
@@ -399,6 +615,17 @@ impl DnsLruCache {
                         continue;
                     }
 
+                    // stop prefetching to save memory, until the pressure relieved.
+                    if memory.is_high() {
+                        debug!("Skip prefetching domains under memory pressure");
+                        let prefetch_notify = prefetch_notify.clone();
+                        tasks.spawn(async move {
+                            sleep(PRESSURE_CHECK_INTERVAL).await;
+                            prefetch_notify.notify_one();
+                        });
+                        continue;
+                    }
+
                     last_check = now;
                     let mut most_recent = Duration::from_secs(MAX_TTL as u64);
 
@@ -411,7 +638,8 @@ impl DnsLruCache {
                             continue;
                         }
 
-                        for (query, entry) in cache.iter_mut() {
+                        for (key, entry) in cache.iter_mut() {
+                            let query = &key.query;
                             // only prefetch query type ip addr
                             if !query.query_type().is_ip_addr() {
                                 continue;
@@ -432,7 +660,7 @@ impl DnsLruCache {
                                 continue;
                             }
 
-                            expired.push(query.to_owned());
+                            expired.push(key.to_owned());
                         }
                         debug!(
                             "Check prefetch domains(total: {}) elapsed {:?}",
@@ -443,7 +671,7 @@ impl DnsLruCache {
 
                     if !expired.is_empty() {
                         let tx = tx.clone();
-                        tokio::spawn(async move {
+                        tasks.spawn(async move {
                             if tx.send(expired).await.is_err() {
                                 error!("Failed to send queries to prefetch domain!",);
                             }
@@ -451,7 +679,7 @@ impl DnsLruCache {
                     }
 
                     let prefetch_notify = prefetch_notify.clone();
-                    tokio::spawn(async move {
+                    tasks.spawn(async move {
                         let dura = most_recent.max(MIN_INTERVAL);
                         debug!("Check domain prefetch after {:?} seconds", dura);
                         sleep(dura).await;
@@ -484,3 +712,116 @@ impl DnsCacheEntry {
         self.origin_ttl
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let name = |s: &str| Name::from_str(s).unwrap();
+        let a = |ip: &str| {
+            Record::from_rdata(name("edge.example.net."), 60, RData::A(ip.parse().unwrap()))
+        };
+
+        let records = vec![
+            Record::from_rdata(
+                name("www.example.com."),
+                60,
+                RData::CNAME(name("edge.example.net.")),
+            ),
+            a("192.0.2.1"),
+            a("192.0.2.2"),
+            a("192.0.2.3"),
+        ];
+
+        assert_eq!(rotate(&records, 0), records);
+        assert_eq!(
+            rotate(&records, 1),
+            vec![
+                records[0].clone(),
+                a("192.0.2.2"),
+                a("192.0.2.3"),
+                a("192.0.2.1")
+            ]
+        );
+        assert_eq!(rotate(&records, 5), rotate(&records, 2));
+    }
+
+    #[test]
+    fn test_cache_key_policy() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let tasks = BackgroundTasks::new();
+            let cache = DnsLruCache::new(
+                DnsCacheStore::new(16),
+                16,
+                None,
+                None,
+                None,
+                None,
+                MemoryPressure::new(),
+                tasks.clone(),
+            );
+
+            let name = Name::from_str("www.example.com.").unwrap();
+            let query = Query::query(name.clone(), RecordType::A);
+            let record = Record::from_rdata(name, 60, RData::A("192.0.2.1".parse().unwrap()));
+
+            let kids = CachePolicy::from(&ServerOpts {
+                group: Some("kids".to_string()),
+                ..Default::default()
+            });
+            let default = CachePolicy::default();
+
+            let now = Instant::now();
+            cache
+                .insert_records(query.clone(), &kids, std::iter::once(record), now)
+                .await;
+
+            assert!(cache.contains(&kids.key(&query), now).await);
+            assert!(!cache.contains(&default.key(&query), now).await);
+            assert!(cache.get(&default.key(&query), now).await.is_none());
+
+            tasks.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn test_cache_key_conf_group() {
+        let query = Query::query(Name::from_str("www.example.com.").unwrap(), RecordType::A);
+
+        let guest = CachePolicy::from(&ServerOpts {
+            conf_group: Some("guest".to_string()),
+            ..Default::default()
+        });
+
+        assert_ne!(guest.key(&query), CachePolicy::default().key(&query));
+    }
+
+    #[test]
+    fn test_cache_key_client_group() {
+        let query = Query::query(Name::from_str("www.example.com.").unwrap(), RecordType::A);
+
+        let listener = ServerOpts::default();
+        let kids = listener.with_client(&ServerOpts {
+            group: Some("kids".to_string()),
+            block_sets: vec!["adult".to_string()],
+            ..Default::default()
+        });
+
+        assert_ne!(
+            CachePolicy::from(&kids).key(&query),
+            CachePolicy::from(&listener).key(&query)
+        );
+        // the blocked sets are matched before the cache, not part of the key.
+        assert_eq!(
+            CachePolicy::from(&kids),
+            CachePolicy::from(&ServerOpts {
+                group: Some("kids".to_string()),
+                ..Default::default()
+            })
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::Arc;
+
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::rr::rdata::TXT;
+use trust_dns_client::rr::{DNSClass, RecordType};
+
+use crate::dns::*;
+use crate::dns_conf::SmartDnsConfig;
+use crate::middleware::*;
+
+/// Answer the CHAOS class TXT queries identifying the resolver, e.g. `dig CH TXT version.bind`,
+/// to the clients the acl allows only.
+pub struct DnsChaosMiddleware;
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsChaosMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let query = req.query().original();
+
+        if query.query_class() != DNSClass::CH {
+            return next.run(ctx, req).await;
+        }
+
+        ctx.lookup_source = LookupSource::Static;
+
+        let text = Some(query)
+            .filter(|query| query.query_type() == RecordType::TXT)
+            .filter(|_| allows(ctx, req))
+            .and_then(|query| chaos_text(&ctx.cfg, &query.name().to_lowercase()));
+
+        match text {
+            Some(text) => {
+                let mut record = Record::from_rdata(
+                    query.name().to_owned(),
+                    0,
+                    RData::TXT(TXT::new(vec![text])),
+                );
+                record.set_dns_class(DNSClass::CH);
+                Ok(Lookup::new_with_max_ttl(
+                    query.to_owned(),
+                    Arc::from(vec![record]),
+                ))
+            }
+            None => Err(ResolveErrorKind::NoRecordsFound {
+                query: query.to_owned().into(),
+                soa: None,
+                negative_ttl: None,
+                response_code: ResponseCode::Refused,
+                trusted: true,
+            }
+            .into()),
+        }
+    }
+}
+
+/// Whether the acl of the listener, else the global one, allows the client, even if the
+/// listener doesn't refuse the others.
+fn allows(ctx: &DnsContext, req: &DnsRequest) -> bool {
+    ctx.server_opts
+        .acl
+        .as_deref()
+        .unwrap_or(&ctx.cfg.acl)
+        .allows(req.src().ip())
+}
+
+/// The text of the names known, see RFC 4892 for `id.server` and `version.server`.
+fn chaos_text(cfg: &SmartDnsConfig, name: &Name) -> Option<String> {
+    let text = match name.to_ascii().trim_end_matches('.') {
+        "version.bind" | "version.server" => {
+            concat!("smartdns-rs ", env!("CARGO_PKG_VERSION")).to_string()
+        }
+        "hostname.bind" | "id.server" => cfg.server_name.to_string(),
+        "cachesize.bind" => cfg.cache_size().to_string(),
+        _ => return None,
+    };
+
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_chaos_text() {
+        let mut cfg = SmartDnsConfig::new();
+        cfg.cache_size = Some(4096);
+
+        let text = |name: &str| chaos_text(&cfg, &Name::from_str(name).unwrap().to_lowercase());
+
+        assert_eq!(
+            text("VERSION.BIND."),
+            Some(format!("smartdns-rs {}", env!("CARGO_PKG_VERSION")))
+        );
+        assert_eq!(text("hostname.bind"), Some("SmartDNS".to_string()));
+        assert_eq!(text("cachesize.bind."), Some("4096".to_string()));
+        assert_eq!(text("authors.bind."), None);
+    }
+}
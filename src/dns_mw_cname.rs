@@ -0,0 +1,196 @@
+use std::sync::Arc;
+
+use trust_dns_client::rr::RecordType;
+
+use crate::dns::*;
+use crate::dns_conf::SmartDnsConfig;
+use crate::matcher::DomainCNameMatcher;
+use crate::middleware::*;
+
+/// Resolve the target of the cname rule instead of the queried name, the answer re-labeled
+/// under the queried name, so that the CNAME chain of a vendor is overridden locally.
+/// The CNAME chains answered for the domains of the `-cname-flatten` rules are left out too,
+/// for the clients mishandling the long chains.
+pub struct DnsCNameMiddleware {
+    matcher: DomainCNameMatcher,
+    flatten: bool,
+}
+
+impl DnsCNameMiddleware {
+    pub fn new(cfg: &SmartDnsConfig) -> Self {
+        Self {
+            matcher: DomainCNameMatcher::create(cfg),
+            flatten: cfg.domain_rules.iter().any(|item| item.rule.cname_flatten),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matcher.is_empty() && !self.flatten
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsCNameMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let cname = match self.matcher.find(req.query().name()) {
+            Some(cname) => cname.clone(),
+            None if should_flatten(ctx, req.query().query_type()) => {
+                let lookup = next.run(ctx, req).await?;
+                return Ok(flatten(lookup));
+            }
+            None => return next.run(ctx, req).await,
+        };
+
+        let query = req.query().original().to_owned();
+
+        // the target is resolved by its own server group.
+        let lookup = ctx.client.lookup(cname, query.query_type(), None).await?;
+
+        ctx.lookup_source = LookupSource::Static;
+
+        Ok(Lookup::new_with_max_ttl(
+            query.clone(),
+            Arc::from(relabel(query.name(), lookup.records())),
+        ))
+    }
+}
+
+fn should_flatten(ctx: &DnsContext, query_type: RecordType) -> bool {
+    matches!(query_type, RecordType::A | RecordType::AAAA)
+        && ctx
+            .domain_rule
+            .as_ref()
+            .map(|rule| rule.cname_flatten)
+            .unwrap_or_default()
+}
+
+/// The records at the end of the CNAME chain under the queried name, expiring with the
+/// chain, the answer kept as is if no chain or nothing at its end, e.g. NODATA.
+fn flatten(lookup: Lookup) -> Lookup {
+    let records = lookup.records();
+
+    let chain_ttl = match records
+        .iter()
+        .filter(|record| record.record_type() == RecordType::CNAME)
+        .map(|record| record.ttl())
+        .min()
+    {
+        Some(ttl) => ttl,
+        None => return lookup,
+    };
+
+    if !records
+        .iter()
+        .any(|record| record.record_type() == lookup.query().query_type())
+    {
+        return lookup;
+    }
+
+    let records = relabel(lookup.query().name(), records)
+        .into_iter()
+        .map(|mut record| {
+            record.set_ttl(record.ttl().min(chain_ttl));
+            record
+        })
+        .collect::<Vec<_>>();
+
+    Lookup::new_with_deadline(
+        lookup.query().clone(),
+        Arc::from(records),
+        lookup.valid_until(),
+    )
+}
+
+/// The records of the target under the name, the CNAME chain to the target left out.
+fn relabel(name: &Name, records: &[Record]) -> Vec<Record> {
+    records
+        .iter()
+        .filter(|record| record.record_type() != RecordType::CNAME)
+        .map(|record| {
+            let mut record = record.clone();
+            record.set_name(name.clone());
+            record
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_client::op::Query;
+
+    use super::*;
+
+    #[test]
+    fn test_relabel() {
+        let name = |s: &str| Name::from_str(s).unwrap();
+
+        let records = vec![
+            Record::from_rdata(
+                name("shop.cdn.example.net."),
+                300,
+                RData::CNAME(name("edge.example.net.")),
+            ),
+            Record::from_rdata(
+                name("edge.example.net."),
+                60,
+                RData::A("192.0.2.1".parse().unwrap()),
+            ),
+        ];
+
+        let records = relabel(&name("shop.example.com."), &records);
+
+        assert_eq!(
+            records,
+            vec![Record::from_rdata(
+                name("shop.example.com."),
+                60,
+                RData::A("192.0.2.1".parse().unwrap())
+            )]
+        );
+    }
+    #[test]
+    fn test_flatten() {
+        let name = |s: &str| Name::from_str(s).unwrap();
+
+        let query = Query::query(name("www.example.com."), RecordType::A);
+        let records = vec![
+            Record::from_rdata(
+                name("www.example.com."),
+                30,
+                RData::CNAME(name("www.cdn.example.net.")),
+            ),
+            Record::from_rdata(
+                name("www.cdn.example.net."),
+                60,
+                RData::A("192.0.2.1".parse().unwrap()),
+            ),
+        ];
+
+        let lookup = flatten(Lookup::new_with_max_ttl(query.clone(), Arc::from(records)));
+
+        assert_eq!(
+            lookup.records(),
+            &[Record::from_rdata(
+                name("www.example.com."),
+                30,
+                RData::A("192.0.2.1".parse().unwrap())
+            )]
+        );
+
+        // NODATA at the end of the chain.
+        let records = vec![Record::from_rdata(
+            name("www.example.com."),
+            30,
+            RData::CNAME(name("www.cdn.example.net.")),
+        )];
+        let lookup = flatten(Lookup::new_with_max_ttl(query, Arc::from(records)));
+        assert_eq!(lookup.records().len(), 1);
+    }
+}
@@ -0,0 +1,520 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use serde_json::Value;
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::rr::{LowerName, RData, Record, RecordType};
+
+use crate::dns::*;
+use crate::dns_conf::{ContainerSource, ContainerZone};
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{debug, warn};
+use crate::middleware::*;
+use crate::third_ext::FutureTimeoutExt;
+
+/// The records of the containers are short lived, as the containers come and go.
+const CONTAINER_TTL: u32 = 10;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest list of the containers read, a runaway endpoint is not buffered without bound.
+const MAX_RESPONSE_SIZE: usize = 8 * 1024 * 1024;
+
+/// The longest header or event line read from the Docker api.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+/// The events of a burst, e.g. of `docker compose up`, are refreshed at once once quiet for this.
+const EVENT_QUIET: Duration = Duration::from_millis(200);
+
+/// The container events changing the names or the ips, the filters of the Docker events api.
+const DOCKER_EVENTS: &str = "/events?filters=%7B%22type%22%3A%5B%22container%22%2C%22network%22%5D%2C%22event%22%3A%5B%22start%22%2C%22die%22%2C%22destroy%22%2C%22rename%22%2C%22connect%22%2C%22disconnect%22%5D%7D";
+
+/// Answer the names of the containers in the configured zones, e.g. `web.container.lan`,
+/// which are refreshed in background on the events of the Docker/Podman api, or polled from a
+/// json endpoint.
+pub struct DnsContainerMiddleware {
+    zones: Vec<PublishedZone>,
+}
+
+struct PublishedZone {
+    zone: LowerName,
+    records: Arc<RwLock<HashMap<LowerName, Vec<IpAddr>>>>,
+}
+
+impl DnsContainerMiddleware {
+    pub fn new(zones: &[ContainerZone], tasks: &BackgroundTasks) -> Self {
+        let zones = zones
+            .iter()
+            .map(|zone| {
+                let published = PublishedZone {
+                    zone: LowerName::from(&zone.zone),
+                    records: Default::default(),
+                };
+
+                let records = published.records.clone();
+                let zone = zone.clone();
+
+                tasks.spawn(async move {
+                    match zone.source.clone() {
+                        ContainerSource::Docker(socket) => {
+                            watch_docker(&zone, &socket, &records).await
+                        }
+                        ContainerSource::Url(_) => {
+                            let mut interval = tokio::time::interval(zone.interval);
+                            loop {
+                                interval.tick().await;
+                                refresh(&zone, &records).await;
+                            }
+                        }
+                    }
+                });
+
+                published
+            })
+            .collect();
+
+        Self { zones }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsContainerMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let name = req.query().name();
+
+        let zone = match self.zones.iter().find(|z| z.zone.zone_of(name)) {
+            Some(zone) => zone,
+            None => return next.run(ctx, req).await,
+        };
+
+        ctx.lookup_source = LookupSource::Zone(zone.zone.to_string());
+
+        let ips = zone
+            .records
+            .read()
+            .ok()
+            .and_then(|records| records.get(name).cloned());
+
+        let query_type = req.query().query_type();
+
+        let records = ips
+            .iter()
+            .flatten()
+            .filter_map(|ip| match (ip, query_type) {
+                (IpAddr::V4(ip), RecordType::A) => Some(RData::A(*ip)),
+                (IpAddr::V6(ip), RecordType::AAAA) => Some(RData::AAAA(*ip)),
+                _ => None,
+            })
+            .map(|rdata| Record::from_rdata(Name::from(name.clone()), CONTAINER_TTL, rdata))
+            .collect::<Vec<_>>();
+
+        if !records.is_empty() {
+            return Ok(Lookup::new_with_max_ttl(
+                req.query().original().to_owned(),
+                Arc::from(records),
+            ));
+        }
+
+        let soa = Record::from_rdata(
+            zone.zone.clone().into(),
+            CONTAINER_TTL,
+            RData::default_soa(),
+        );
+
+        Err(ResolveErrorKind::NoRecordsFound {
+            query: req.query().original().to_owned().into(),
+            soa: Some(Box::new(soa)),
+            negative_ttl: Some(CONTAINER_TTL),
+            response_code: if ips.is_some() {
+                ResponseCode::NoError
+            } else {
+                ResponseCode::NXDomain
+            },
+            trusted: true,
+        }
+        .into())
+    }
+}
+
+type ZoneRecords = RwLock<HashMap<LowerName, Vec<IpAddr>>>;
+
+async fn refresh(zone: &ContainerZone, records: &ZoneRecords) {
+    match fetch_containers(&zone.source).await {
+        Ok(containers) => {
+            let containers = publish(&zone.zone, containers);
+            debug!("{} containers published in {}", containers.len(), zone.zone);
+            if let Ok(mut records) = records.write() {
+                *records = containers;
+            }
+        }
+        Err(err) => warn!("fetch containers for {} failed, {}", zone.zone, err),
+    }
+}
+
+/// List the containers on each event changing them, and once (re)connected to the events, the
+/// ones missed meanwhile included. Polled at the interval while the events are unavailable.
+#[cfg(unix)]
+async fn watch_docker(zone: &ContainerZone, socket: &std::path::Path, records: &ZoneRecords) {
+    loop {
+        let mut events = match docker_request(socket, DOCKER_EVENTS).await {
+            Ok(events) => events,
+            Err(err) => {
+                debug!("watch container events for {} failed, {}", zone.zone, err);
+                refresh(zone, records).await;
+                tokio::time::sleep(zone.interval).await;
+                continue;
+            }
+        };
+
+        refresh(zone, records).await;
+
+        let mut line = String::new();
+        loop {
+            match read_line(&mut events, &mut line).await {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(err) => {
+                    debug!("container events for {} failed, {}", zone.zone, err);
+                    break;
+                }
+            }
+
+            // the rest of the burst, listed at once.
+            while let Ok(Ok(n)) = read_line(&mut events, &mut line).timeout(EVENT_QUIET).await {
+                if n == 0 {
+                    break;
+                }
+            }
+
+            refresh(zone, records).await;
+        }
+
+        tokio::time::sleep(zone.interval).await;
+    }
+}
+
+#[cfg(not(unix))]
+async fn watch_docker(zone: &ContainerZone, _socket: &std::path::Path, records: &ZoneRecords) {
+    let mut interval = tokio::time::interval(zone.interval);
+    loop {
+        interval.tick().await;
+        refresh(zone, records).await;
+    }
+}
+
+/// The names of the containers under the zone, the invalid names are skipped.
+fn publish(zone: &Name, containers: Vec<(String, Vec<IpAddr>)>) -> HashMap<LowerName, Vec<IpAddr>> {
+    let mut records = HashMap::<LowerName, Vec<IpAddr>>::new();
+
+    for (name, ips) in containers {
+        match Name::from_str(&name).and_then(|n| n.append_domain(zone)) {
+            Ok(name) => records
+                .entry(LowerName::from(name))
+                .or_default()
+                .extend(ips),
+            Err(_) => debug!("container name {} is not a valid domain, skipped", name),
+        }
+    }
+
+    records
+}
+
+async fn fetch_containers(source: &ContainerSource) -> Result<Vec<(String, Vec<IpAddr>)>, String> {
+    match source {
+        ContainerSource::Docker(socket) => {
+            let body = docker_get(socket, "/containers/json")
+                .timeout(FETCH_TIMEOUT)
+                .await
+                .map_err(|_| "timeout".to_string())??;
+            parse_docker_containers(&body)
+        }
+        ContainerSource::Url(url) => {
+            let body = http_get(url)
+                .timeout(FETCH_TIMEOUT)
+                .await
+                .map_err(|_| "timeout".to_string())??;
+            parse_generic_containers(&body)
+        }
+    }
+}
+
+/// The body of the response, up to the max response size.
+async fn http_get(url: &str) -> Result<Vec<u8>, String> {
+    let mut res = reqwest::get(url)
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+
+    let mut body = vec![];
+    while let Some(chunk) = res.chunk().await.map_err(|e| e.to_string())? {
+        if body.len() + chunk.len() > MAX_RESPONSE_SIZE {
+            return Err(format!("response larger than {} bytes", MAX_RESPONSE_SIZE));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// The body of a plain HTTP/1.0 request over the unix socket, up to the max response size.
+#[cfg(unix)]
+async fn docker_get(socket: &std::path::Path, path: &str) -> Result<Vec<u8>, String> {
+    use tokio::io::AsyncReadExt;
+
+    let mut body = vec![];
+    docker_request(socket, path)
+        .await?
+        .take(MAX_RESPONSE_SIZE as u64 + 1)
+        .read_to_end(&mut body)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if body.len() > MAX_RESPONSE_SIZE {
+        return Err(format!("response larger than {} bytes", MAX_RESPONSE_SIZE));
+    }
+
+    Ok(body)
+}
+
+#[cfg(not(unix))]
+async fn docker_get(_socket: &std::path::Path, _path: &str) -> Result<Vec<u8>, String> {
+    Err("docker api socket is only supported on unix".to_string())
+}
+
+/// Send a plain HTTP/1.0 request over the unix socket, so that the body is never chunked, the
+/// reader of the body once the status checked.
+#[cfg(unix)]
+async fn docker_request(
+    socket: &std::path::Path,
+    path: &str,
+) -> Result<tokio::io::BufReader<tokio::net::UnixStream>, String> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut stream = tokio::net::UnixStream::connect(socket)
+        .await
+        .map_err(|e| format!("connect {} failed, {}", socket.display(), e))?;
+
+    stream
+        .write_all(format!("GET {} HTTP/1.0\r\nHost: docker\r\n\r\n", path).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut reader = tokio::io::BufReader::new(stream);
+    let mut line = String::new();
+
+    let read = read_line(&mut reader, &mut line)
+        .timeout(FETCH_TIMEOUT)
+        .await
+        .map_err(|_| "timeout".to_string())?;
+    if read.map_err(|e| e.to_string())? == 0 {
+        return Err("malformed http response".to_string());
+    }
+    if line.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("unexpected response {}", line.trim_end()));
+    }
+
+    // the headers, up to the blank line.
+    loop {
+        let read = read_line(&mut reader, &mut line)
+            .timeout(FETCH_TIMEOUT)
+            .await
+            .map_err(|_| "timeout".to_string())?;
+        match read.map_err(|e| e.to_string())? {
+            0 => return Err("malformed http response".to_string()),
+            _ if line.trim_end().is_empty() => return Ok(reader),
+            _ => (),
+        }
+    }
+}
+
+/// Read a line into the buffer cleared, up to the max line length.
+#[cfg(unix)]
+async fn read_line<R>(reader: &mut R, line: &mut String) -> std::io::Result<usize>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    line.clear();
+    let n = reader.take(MAX_LINE_LEN).read_line(line).await?;
+    if n as u64 == MAX_LINE_LEN && !line.ends_with('\n') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("line longer than {} bytes", MAX_LINE_LEN),
+        ));
+    }
+    Ok(n)
+}
+
+/// The containers listed by the Docker api, by the name and the ips of all networks.
+fn parse_docker_containers(body: &[u8]) -> Result<Vec<(String, Vec<IpAddr>)>, String> {
+    let value = serde_json::from_slice::<Value>(body).map_err(|e| e.to_string())?;
+
+    let containers = value
+        .as_array()
+        .ok_or_else(|| "expect an array of containers".to_string())?;
+
+    Ok(containers
+        .iter()
+        .filter_map(|c| {
+            let name = c["Names"]
+                .as_array()?
+                .first()?
+                .as_str()?
+                .trim_start_matches('/');
+
+            let ips = c["NetworkSettings"]["Networks"]
+                .as_object()?
+                .values()
+                .flat_map(|n| [&n["IPAddress"], &n["GlobalIPv6Address"]])
+                .filter_map(|ip| ip.as_str()?.parse::<IpAddr>().ok())
+                .collect::<Vec<_>>();
+
+            Some((name.to_string(), ips))
+        })
+        .collect())
+}
+
+/// The containers listed as `[{"name": "web", "ips": ["172.17.0.2"]}]`.
+fn parse_generic_containers(body: &[u8]) -> Result<Vec<(String, Vec<IpAddr>)>, String> {
+    let value = serde_json::from_slice::<Value>(body).map_err(|e| e.to_string())?;
+
+    let containers = value
+        .as_array()
+        .ok_or_else(|| "expect an array of containers".to_string())?;
+
+    Ok(containers
+        .iter()
+        .filter_map(|c| {
+            let name = c["name"].as_str()?;
+            let ips = c["ips"]
+                .as_array()?
+                .iter()
+                .filter_map(|ip| ip.as_str()?.parse::<IpAddr>().ok())
+                .collect::<Vec<_>>();
+
+            Some((name.to_string(), ips))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_docker_containers() {
+        let body = br#"[{
+            "Id": "8dfafdbc3a40",
+            "Names": ["/web"],
+            "NetworkSettings": {
+                "Networks": {
+                    "bridge": {"IPAddress": "172.17.0.2", "GlobalIPv6Address": ""},
+                    "backend": {"IPAddress": "172.18.0.3", "GlobalIPv6Address": "fd00::3"}
+                }
+            }
+        }, {
+            "Id": "9cd87474be90",
+            "Names": ["/host_net"],
+            "NetworkSettings": {"Networks": {"host": {"IPAddress": ""}}}
+        }]"#;
+
+        let mut containers = parse_docker_containers(body).unwrap();
+        containers[0].1.sort();
+
+        assert_eq!(
+            containers,
+            vec![
+                (
+                    "web".to_string(),
+                    vec![
+                        "172.17.0.2".parse().unwrap(),
+                        "172.18.0.3".parse().unwrap(),
+                        "fd00::3".parse().unwrap()
+                    ]
+                ),
+                ("host_net".to_string(), vec![])
+            ]
+        );
+
+        assert!(parse_docker_containers(b"{}").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_docker_get() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let path = std::env::temp_dir()
+                .join(format!("smartdns-test-{}-docker.sock", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+            tokio::spawn(async move {
+                for response in [
+                    "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n[]".to_string(),
+                    "HTTP/1.0 404 Not Found\r\n\r\n".to_string(),
+                    format!(
+                        "HTTP/1.0 200 OK\r\n\r\n{}",
+                        " ".repeat(MAX_RESPONSE_SIZE + 1)
+                    ),
+                ] {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut request = [0u8; 1024];
+                    let _ = stream.read(&mut request).await.unwrap();
+                    let _ = stream.write_all(response.as_bytes()).await;
+                }
+            });
+
+            assert_eq!(
+                docker_get(&path, "/containers/json").await,
+                Ok(b"[]".to_vec())
+            );
+            assert!(docker_get(&path, "/containers/json").await.is_err());
+            assert!(docker_get(&path, "/containers/json").await.is_err());
+
+            let _ = std::fs::remove_file(&path);
+        })
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_read_line() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut line = String::new();
+
+            let mut reader = &b"{\"status\":\"start\"}\n{}"[..];
+            assert_eq!(read_line(&mut reader, &mut line).await.unwrap(), 19);
+            assert_eq!(read_line(&mut reader, &mut line).await.unwrap(), 2);
+            assert_eq!(line, "{}");
+
+            let long = "x".repeat(MAX_LINE_LEN as usize + 1);
+            let mut reader = long.as_bytes();
+            assert!(read_line(&mut reader, &mut line).await.is_err());
+        })
+    }
+
+    #[test]
+    fn test_publish_containers() {
+        let body = br#"[{"name": "Web", "ips": ["172.17.0.2"]}, {"name": "this-label-is-longer-than-sixty-three-characters-which-is-not-allowed", "ips": []}]"#;
+
+        let zone = Name::from_str("container.lan.").unwrap();
+        let records = publish(&zone, parse_generic_containers(body).unwrap());
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records.get(&LowerName::from(
+                Name::from_str("web.container.lan.").unwrap()
+            )),
+            Some(&vec!["172.17.0.2".parse().unwrap()])
+        );
+    }
+}
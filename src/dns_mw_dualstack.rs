@@ -0,0 +1,280 @@
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use trust_dns_client::rr::RecordType;
+
+use crate::dns::*;
+use crate::dns_conf::{SmartDnsConfig, SpeedCheckMode};
+use crate::dns_mw_cache::sibling_request;
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::debug;
+use crate::middleware::*;
+use crate::speed_check::SpeedChecker;
+
+/// The families are compared by connecting to the web ports, unless the speed check modes
+/// are configured.
+const DEFAULT_MODES: [SpeedCheckMode; 2] = [SpeedCheckMode::Tcp(443), SpeedCheckMode::Tcp(80)];
+
+/// How long the family preferred of a name is kept, probed again after.
+const PREFERENCE_TTL: Duration = Duration::from_secs(600);
+
+/// How long the other queries wait for the probes of a name, rather than probing too.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The names whose preferences are kept at most.
+const MAX_PREFERENCES: usize = 4096;
+
+/// Resolve A and AAAA together, both cached, probe the connectivity of both families in the
+/// background, and answer SOA for the queried family if the other one is faster by the
+/// threshold, so that the clients connect over the faster family. The SOA is answered before
+/// the cache, which keeps the records of both families.
+pub struct DnsDualStackMiddleware {
+    /// the global switch, the domain rules overriding it.
+    enabled: bool,
+    threshold: Duration,
+    checker: Arc<SpeedChecker>,
+    /// the family suppressed of the names probed, until probed again.
+    preferences: Arc<Mutex<LruCache<Name, Preference>>>,
+    tasks: BackgroundTasks,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Preference {
+    /// the slower family, none if neither or still probing.
+    suppressed: Option<RecordType>,
+    until: Instant,
+}
+
+impl DnsDualStackMiddleware {
+    pub fn new(cfg: &SmartDnsConfig, checker: Arc<SpeedChecker>, tasks: &BackgroundTasks) -> Self {
+        Self {
+            enabled: cfg.dualstack_ip_selection(),
+            threshold: cfg.dualstack_ip_selection_threshold(),
+            checker,
+            preferences: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(MAX_PREFERENCES).unwrap(),
+            ))),
+            tasks: tasks.clone(),
+        }
+    }
+
+    /// The preference of the name, if probed or probing.
+    fn preference(&self, name: &Name, now: Instant) -> Option<Preference> {
+        let mut preferences = self.preferences.lock().ok()?;
+        match preferences.get(name) {
+            Some(preference) if preference.until > now => Some(*preference),
+            _ => None,
+        }
+    }
+
+    fn prefer(&self, name: Name, suppressed: Option<RecordType>, ttl: Duration) {
+        prefer(&self.preferences, name, suppressed, ttl)
+    }
+
+    /// Probe both families in the background, the slower one suppressed for the next queries.
+    fn spawn_probe(
+        &self,
+        name: Name,
+        (typ, ips): (RecordType, Vec<IpAddr>),
+        (sibling_typ, sibling_ips): (RecordType, Vec<IpAddr>),
+        modes: Vec<SpeedCheckMode>,
+    ) {
+        let checker = self.checker.clone();
+        let preferences = self.preferences.clone();
+        let threshold = self.threshold;
+
+        self.tasks.spawn(async move {
+            let (rtt, sibling_rtt) = futures::join!(
+                fastest(&checker, &ips, &modes),
+                fastest(&checker, &sibling_ips, &modes)
+            );
+
+            let suppressed = if is_slower(rtt, sibling_rtt, threshold) {
+                Some(typ)
+            } else if is_slower(sibling_rtt, rtt, threshold) {
+                Some(sibling_typ)
+            } else {
+                None
+            };
+
+            debug!(
+                "{} {} {:?}, {} {:?}, suppressed {:?}",
+                name, typ, rtt, sibling_typ, sibling_rtt, suppressed
+            );
+
+            prefer(&preferences, name, suppressed, PREFERENCE_TTL);
+        });
+    }
+}
+
+fn prefer(
+    preferences: &Mutex<LruCache<Name, Preference>>,
+    name: Name,
+    suppressed: Option<RecordType>,
+    ttl: Duration,
+) {
+    if let Ok(mut preferences) = preferences.lock() {
+        preferences.put(
+            name,
+            Preference {
+                suppressed,
+                until: Instant::now() + ttl,
+            },
+        );
+    }
+}
+
+/// The latency of the fastest of the ips, none if none reachable.
+async fn fastest(
+    checker: &SpeedChecker,
+    ips: &[IpAddr],
+    modes: &[SpeedCheckMode],
+) -> Option<Duration> {
+    checker.check(ips, modes).await.into_iter().flatten().min()
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsDualStackMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let enabled = ctx
+            .domain_rule
+            .as_ref()
+            .and_then(|rule| rule.dualstack_ip_selection)
+            .unwrap_or(self.enabled);
+
+        let sibling_req = match sibling_request(req) {
+            Some(sibling_req) if enabled && !ctx.server_opts.no_dualstack_selection => sibling_req,
+            _ => return next.run(ctx, req).await,
+        };
+
+        let name = req.query().original().name().clone();
+        let typ = req.query().query_type();
+
+        if let Some(preference) = self.preference(&name, Instant::now()) {
+            if preference.suppressed != Some(typ) {
+                return next.run(ctx, req).await;
+            }
+
+            debug!("{} {} suppressed, the other family faster", name, typ);
+            return Ok(Lookup::from_rdata(
+                req.query().original().to_owned(),
+                RData::default_soa(),
+            ));
+        }
+
+        // the other queries of the name answered as is, until probed.
+        self.prefer(name.clone(), None, PROBE_TIMEOUT);
+
+        let mut sibling_ctx = DnsContext {
+            cfg: ctx.cfg.clone(),
+            client: ctx.client.clone(),
+            fastest_speed: Default::default(),
+            lookup_source: Default::default(),
+            server_opts: ctx.server_opts.clone(),
+            extended_error: None,
+            domain_rule: ctx.domain_rule.clone(),
+            local_only: ctx.local_only,
+        };
+
+        // the OS stack asks for the sibling right after, it will be a cache hit.
+        let (lookup, sibling_lookup) = futures::join!(
+            next.clone().run(ctx, req),
+            next.run(&mut sibling_ctx, &sibling_req)
+        );
+
+        let lookup = lookup?;
+
+        let ips = answer_ips(&lookup);
+        let sibling_ips = sibling_lookup
+            .map(|lookup| answer_ips(&lookup))
+            .unwrap_or_default();
+
+        if ips.is_empty() || sibling_ips.is_empty() {
+            self.prefer(name, None, PREFERENCE_TTL);
+            return Ok(lookup);
+        }
+
+        let modes = match ctx.speed_check_mode() {
+            [] => DEFAULT_MODES.to_vec(),
+            modes => modes.to_vec(),
+        };
+
+        // the answer not delayed by the probes, the preference applied to the next queries.
+        self.spawn_probe(
+            name,
+            (typ, ips),
+            (sibling_req.query().query_type(), sibling_ips),
+            modes,
+        );
+
+        Ok(lookup)
+    }
+}
+
+fn answer_ips(lookup: &Lookup) -> Vec<IpAddr> {
+    lookup
+        .record_iter()
+        .filter(|record| matches!(record.record_type(), RecordType::A | RecordType::AAAA))
+        .filter_map(|record| record.data().and_then(|data| data.to_ip_addr()))
+        .collect()
+}
+
+/// Whether the family is slower than the sibling by the threshold, or unreachable while the
+/// sibling is reachable.
+fn is_slower(rtt: Option<Duration>, sibling_rtt: Option<Duration>, threshold: Duration) -> bool {
+    match (rtt, sibling_rtt) {
+        (_, None) => false,
+        (None, Some(_)) => true,
+        (Some(rtt), Some(sibling_rtt)) => sibling_rtt + threshold < rtt,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_slower() {
+        let ms = |ms| Some(Duration::from_millis(ms));
+        let threshold = Duration::from_millis(15);
+
+        assert!(is_slower(ms(50), ms(20), threshold));
+        assert!(!is_slower(ms(30), ms(20), threshold));
+        assert!(!is_slower(ms(20), ms(50), threshold));
+        assert!(is_slower(None, ms(50), threshold));
+        assert!(!is_slower(ms(50), None, threshold));
+        assert!(!is_slower(None, None, threshold));
+    }
+
+    #[test]
+    fn test_preference() {
+        let middleware = DnsDualStackMiddleware::new(
+            &SmartDnsConfig::new(),
+            Arc::new(SpeedChecker::default()),
+            &BackgroundTasks::new(),
+        );
+        let name = Name::from_ascii("www.example.com.").unwrap();
+        let now = Instant::now();
+
+        assert!(middleware.preference(&name, now).is_none());
+
+        middleware.prefer(name.clone(), Some(RecordType::AAAA), PREFERENCE_TTL);
+        assert_eq!(
+            middleware.preference(&name, now).unwrap().suppressed,
+            Some(RecordType::AAAA)
+        );
+
+        // probed again once expired.
+        assert!(middleware
+            .preference(&name, now + PREFERENCE_TTL + Duration::from_secs(1))
+            .is_none());
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::rr::{LowerName, RData, Record, RecordType};
+
+use crate::dns::*;
+use crate::dns_conf::{AddressRuleItem, DomainAddress, DomainOrDomainSet, SmartDnsConfig};
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{info, warn};
+use crate::middleware::*;
+
+/// The records follow the hosts files as they change, so cached briefly.
+const HOSTS_TTL: u32 = 10;
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Answer A, AAAA and PTR of the names in the hosts files, e.g. `/etc/hosts`, and the dhcp
+/// leases of dnsmasq, before the cache or the upstreams, the files reloaded once any of them
+/// changed. The ips of the address rules are answered PTR too.
+pub struct DnsHostsMiddleware {
+    hosts: Arc<RwLock<Hosts>>,
+}
+
+impl DnsHostsMiddleware {
+    pub fn new(cfg: &SmartDnsConfig, tasks: &BackgroundTasks) -> Self {
+        let source = HostsSource::new(cfg);
+        let hosts = Arc::new(RwLock::new(source.statics.clone()));
+        let watched = hosts.clone();
+
+        tasks.spawn(async move {
+            let mut modified = vec![None; source.files.len()];
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            loop {
+                interval.tick().await;
+
+                let m = source
+                    .files
+                    .iter()
+                    .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+                    .collect::<Vec<_>>();
+                // the leases expire without the file changed, e.g. the clients gone.
+                let expired = watched
+                    .read()
+                    .ok()
+                    .and_then(|hosts| hosts.next_expiry)
+                    .map_or(false, |expiry| expiry <= unix_now());
+                if m == modified && !expired {
+                    continue;
+                }
+                modified = m;
+
+                let loaded = source.load();
+                info!("{} hosts loaded", loaded.ips.len());
+
+                if let Ok(mut hosts) = watched.write() {
+                    *hosts = loaded;
+                }
+            }
+        });
+
+        Self { hosts }
+    }
+
+    /// Whether there's nothing to answer, no files watched nor address rules of names.
+    pub fn is_empty(cfg: &SmartDnsConfig) -> bool {
+        cfg.hosts_files.is_empty()
+            && cfg.dnsmasq_lease_file.is_none()
+            && !cfg
+                .address_rules
+                .iter()
+                .any(|rule| reverse_of(rule).is_some())
+    }
+}
+
+/// The ip of the address rule of a name answered PTR, e.g. `/nas.lan/192.168.1.20`, not of
+/// the patterns, nor the unspecified ips the blocked names are answered.
+fn reverse_of(rule: &AddressRuleItem) -> Option<(IpAddr, &LowerName)> {
+    let name = match &rule.domain {
+        DomainOrDomainSet::Domain(name) | DomainOrDomainSet::Full(name) => name,
+        _ => return None,
+    };
+    let ip = match rule.address {
+        DomainAddress::IPv4(ip) if !ip.is_unspecified() => IpAddr::V4(ip),
+        DomainAddress::IPv6(ip) if !ip.is_unspecified() => IpAddr::V6(ip),
+        _ => return None,
+    };
+    Some((ip, name))
+}
+
+/// The hosts files, the leases and the names of the address rules of the config.
+struct HostsSource {
+    files: Vec<PathBuf>,
+    lease_file: Option<PathBuf>,
+    expand: Option<Name>,
+    /// the names of the address rules, e.g. `/nas.lan/192.168.1.20`, not the patterns.
+    statics: Hosts,
+}
+
+impl HostsSource {
+    fn new(cfg: &SmartDnsConfig) -> Self {
+        let lease_file = cfg.dnsmasq_lease_file.as_ref().map(PathBuf::from);
+
+        let mut statics = Hosts::default();
+        for (ip, name) in cfg.address_rules.iter().filter_map(reverse_of) {
+            statics.add_reverse(ip, Name::from(name.clone()));
+        }
+
+        Self {
+            files: cfg
+                .hosts_files
+                .iter()
+                .cloned()
+                .chain(lease_file.clone())
+                .collect(),
+            lease_file,
+            expand: cfg.expand_hosts().cloned(),
+            statics,
+        }
+    }
+
+    fn load(&self) -> Hosts {
+        let mut loaded = Hosts::default();
+        for path in self.files.iter() {
+            match fs::read_to_string(path) {
+                Ok(text) if Some(path) == self.lease_file.as_ref() => {
+                    loaded.extend_leases(&text, self.expand.as_ref(), unix_now())
+                }
+                Ok(text) => loaded.extend(&text, self.expand.as_ref()),
+                Err(err) => warn!("read hosts file {:?} failed, {}", path, err),
+            }
+        }
+        loaded.extend_reverse(&self.statics);
+        loaded
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsHostsMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let query_type = req.query().query_type();
+
+        let rdatas = self
+            .hosts
+            .read()
+            .ok()
+            .and_then(|hosts| hosts.lookup(req.query().name(), query_type));
+
+        let rdatas = match rdatas {
+            Some(rdatas) => rdatas,
+            None => return next.run(ctx, req).await,
+        };
+
+        ctx.lookup_source = LookupSource::Static;
+
+        let query = req.query().original().to_owned();
+
+        if rdatas.is_empty() {
+            // the host is known, but not of the type.
+            return Err(ResolveErrorKind::NoRecordsFound {
+                query: query.into(),
+                soa: None,
+                negative_ttl: Some(HOSTS_TTL),
+                response_code: ResponseCode::NoError,
+                trusted: true,
+            }
+            .into());
+        }
+
+        let records = rdatas
+            .into_iter()
+            .map(|rdata| Record::from_rdata(query.name().to_owned(), HOSTS_TTL, rdata))
+            .collect::<Vec<_>>();
+
+        Ok(Lookup::new_with_max_ttl(query, Arc::from(records)))
+    }
+}
+
+/// The names and the addresses of the hosts files.
+#[derive(Debug, Default, Clone)]
+pub struct Hosts {
+    ips: HashMap<LowerName, Vec<IpAddr>>,
+    /// the reverse names, e.g. `1.1.168.192.in-addr.arpa.`, to the first name of the ip.
+    names: HashMap<LowerName, Name>,
+    /// the earliest expiry of the leases added, the hosts reloaded by then.
+    next_expiry: Option<u64>,
+}
+
+impl Hosts {
+    /// The hosts the middleware answers of the config, as loaded now, e.g. to explain the
+    /// rules offline.
+    pub fn load(cfg: &SmartDnsConfig) -> Self {
+        HostsSource::new(cfg).load()
+    }
+
+    /// Add the entries, `[ip] [name] [aliases...]`, the malformed lines are skipped.
+    /// The plain names, e.g. `nas`, are also added under the expand domain, `nas.lan`,
+    /// which is answered to PTR then.
+    pub fn extend(&mut self, text: &str, expand: Option<&Name>) {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut parts = line.split_whitespace();
+
+            // the zone of a link local ipv6, e.g. `fe80::1%lo0`, is dropped.
+            let ip = match parts
+                .next()
+                .and_then(|ip| IpAddr::from_str(ip.split('%').next().unwrap_or(ip)).ok())
+            {
+                Some(ip) => ip,
+                None => continue,
+            };
+
+            for host in parts {
+                self.add(ip, host, expand);
+            }
+        }
+    }
+
+    /// Add the leases of dnsmasq, `[expiry] [mac] [ip] [hostname] [client id]`, the expired
+    /// ones, and the ones without hostname, `*`, skipped.
+    pub fn extend_leases(&mut self, text: &str, expand: Option<&Name>, now: u64) {
+        for line in text.lines() {
+            let parts = line.split_whitespace().collect::<Vec<_>>();
+
+            // the duid line of the dhcpv6 server, the leases following in the same format.
+            let (expiry, ip, host) = match parts.as_slice() {
+                [expiry, _, ip, host, ..] => (expiry, ip, host),
+                _ => continue,
+            };
+
+            // the expiry 0 for the infinite leases.
+            let expiry = match expiry.parse::<u64>() {
+                Ok(expiry) if expiry == 0 || expiry > now => expiry,
+                _ => continue,
+            };
+
+            match IpAddr::from_str(ip) {
+                Ok(ip) if *host != "*" => self.add(ip, host, expand),
+                _ => continue,
+            }
+
+            if expiry > 0 {
+                self.next_expiry = Some(self.next_expiry.map_or(expiry, |next| next.min(expiry)));
+            }
+        }
+    }
+
+    /// Answer the name to PTR of the ip, unless the ip has one already.
+    pub fn add_reverse(&mut self, ip: IpAddr, name: Name) {
+        self.names
+            .entry(LowerName::from(Name::from(ip)))
+            .or_insert(name);
+    }
+
+    fn extend_reverse(&mut self, other: &Hosts) {
+        for (ip, name) in other.names.iter() {
+            self.names.entry(ip.clone()).or_insert_with(|| name.clone());
+        }
+    }
+
+    fn add(&mut self, ip: IpAddr, host: &str, expand: Option<&Name>) {
+        let mut name = match Name::from_str(host) {
+            Ok(name) => name,
+            Err(_) => return,
+        };
+        name.set_fqdn(true);
+
+        let expanded = match expand {
+            Some(domain) if name.num_labels() == 1 => name.clone().append_domain(domain).ok(),
+            _ => None,
+        };
+
+        self.add_reverse(ip, expanded.clone().unwrap_or_else(|| name.clone()));
+
+        for name in std::iter::once(name).chain(expanded) {
+            let ips = self.ips.entry(LowerName::from(name)).or_default();
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+    }
+
+    /// The records of the name, none if not in the hosts files, or empty if not of the type.
+    pub fn lookup(&self, name: &LowerName, query_type: RecordType) -> Option<Vec<RData>> {
+        match query_type {
+            RecordType::A | RecordType::AAAA => self.ips.get(name).map(|ips| {
+                ips.iter()
+                    .filter_map(|ip| match (ip, query_type) {
+                        (IpAddr::V4(ip), RecordType::A) => Some(RData::A(*ip)),
+                        (IpAddr::V6(ip), RecordType::AAAA) => Some(RData::AAAA(*ip)),
+                        _ => None,
+                    })
+                    .collect()
+            }),
+            RecordType::PTR => self
+                .names
+                .get(name)
+                .map(|host| vec![RData::PTR(host.clone())]),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> LowerName {
+        Name::from_str(s).unwrap().into()
+    }
+
+    #[test]
+    fn test_hosts_lookup() {
+        let mut hosts = Hosts::default();
+        hosts.extend(
+            "# comment\n127.0.0.1 localhost\n192.168.1.10 nas storage.home # nas\nfe80::10%eth0 nas\nnot-an-ip host\n",
+            Some(&Name::from_str("lan.").unwrap()),
+        );
+        hosts.extend("192.168.1.11 nas\n", None);
+
+        assert_eq!(
+            hosts.lookup(&name("nas."), RecordType::A),
+            Some(vec![
+                RData::A("192.168.1.10".parse().unwrap()),
+                RData::A("192.168.1.11".parse().unwrap())
+            ])
+        );
+        assert_eq!(
+            hosts.lookup(&name("NAS.lan."), RecordType::AAAA),
+            Some(vec![RData::AAAA("fe80::10".parse().unwrap())])
+        );
+        assert_eq!(
+            hosts.lookup(&name("storage.home."), RecordType::A),
+            Some(vec![RData::A("192.168.1.10".parse().unwrap())])
+        );
+
+        // the host of the other type only, no records.
+        assert_eq!(
+            hosts.lookup(&name("storage.home."), RecordType::AAAA),
+            Some(vec![])
+        );
+        assert_eq!(
+            hosts.lookup(&name("storage.home.lan."), RecordType::A),
+            None
+        );
+        assert_eq!(hosts.lookup(&name("host."), RecordType::A), None);
+        assert_eq!(hosts.lookup(&name("nas."), RecordType::MX), None);
+
+        // the first name of the ip, expanded.
+        assert_eq!(
+            hosts.lookup(&name("10.1.168.192.in-addr.arpa."), RecordType::PTR),
+            Some(vec![RData::PTR(Name::from_str("nas.lan.").unwrap())])
+        );
+        assert_eq!(
+            hosts.lookup(&name("1.0.0.127.in-addr.arpa."), RecordType::PTR),
+            Some(vec![RData::PTR(Name::from_str("localhost.lan.").unwrap())])
+        );
+        assert_eq!(
+            hosts.lookup(&name("11.1.168.192.in-addr.arpa."), RecordType::PTR),
+            Some(vec![RData::PTR(Name::from_str("nas.").unwrap())])
+        );
+    }
+
+    #[test]
+    fn test_hosts_leases() {
+        let mut hosts = Hosts::default();
+        hosts.add_reverse(
+            "192.168.1.30".parse().unwrap(),
+            Name::from_str("printer.lan.").unwrap(),
+        );
+        hosts.extend_leases(
+            "2000 aa:bb:cc:dd:ee:01 192.168.1.20 laptop 01:aa:bb:cc:dd:ee:01\n\
+             500 aa:bb:cc:dd:ee:02 192.168.1.21 phone *\n\
+             1000 aa:bb:cc:dd:ee:03 192.168.1.22 tv *\n\
+             0 aa:bb:cc:dd:ee:04 192.168.1.23 * *\n\
+             duid 00:01:00:01:2c:aa:bb:cc\n\
+             0 1234 fd00::20 desktop 00:01:00:01\n",
+            Some(&Name::from_str("lan.").unwrap()),
+            1000,
+        );
+
+        assert_eq!(
+            hosts.lookup(&name("laptop.lan."), RecordType::A),
+            Some(vec![RData::A("192.168.1.20".parse().unwrap())])
+        );
+        assert_eq!(
+            hosts.lookup(&name("20.1.168.192.in-addr.arpa."), RecordType::PTR),
+            Some(vec![RData::PTR(Name::from_str("laptop.lan.").unwrap())])
+        );
+        assert_eq!(
+            hosts.lookup(&name("desktop."), RecordType::AAAA),
+            Some(vec![RData::AAAA("fd00::20".parse().unwrap())])
+        );
+        // expired.
+        assert_eq!(hosts.lookup(&name("phone.lan."), RecordType::A), None);
+        // reloaded once the laptop's lease expires, the infinite ones never.
+        assert_eq!(hosts.next_expiry, Some(2000));
+        assert_eq!(
+            hosts.lookup(&name("30.1.168.192.in-addr.arpa."), RecordType::PTR),
+            Some(vec![RData::PTR(Name::from_str("printer.lan.").unwrap())])
+        );
+    }
+
+    #[test]
+    fn test_hosts_is_empty() {
+        let mut cfg = SmartDnsConfig::new();
+        assert!(DnsHostsMiddleware::is_empty(&cfg));
+
+        let rule = |domain: &str, address: &str| AddressRuleItem {
+            domain: DomainOrDomainSet::from_str(domain).unwrap(),
+            address: DomainAddress::from_str(address).unwrap(),
+        };
+
+        // blocked by the unspecified ip, no name to answer PTR of.
+        cfg.address_rules.push(rule("ads.example.com", "0.0.0.0"));
+        cfg.address_rules.push(rule("ads.example.com", "::"));
+        cfg.address_rules
+            .push(rule("keyword:tracker", "192.168.1.1"));
+        assert!(DnsHostsMiddleware::is_empty(&cfg));
+        assert!(Hosts::load(&cfg).names.is_empty());
+
+        cfg.address_rules.push(rule("nas.lan", "192.168.1.20"));
+        assert!(!DnsHostsMiddleware::is_empty(&cfg));
+        assert_eq!(
+            Hosts::load(&cfg).lookup(&name("20.1.168.192.in-addr.arpa."), RecordType::PTR),
+            Some(vec![RData::PTR(Name::from_str("nas.lan.").unwrap())])
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::dns::*;
+use crate::dns_conf::SmartDnsConfig;
+use crate::infra::netfilter::Netfilter;
+use crate::log::{debug, warn};
+use crate::matcher::{DomainIpSetMatcher, DomainNftSetMatcher};
+use crate::middleware::*;
+
+/// Add the ips answered for the domains of the ipset and nftset rules to the sets, e.g. for
+/// the policy routing of the destinations, the cached answers adding them again as they expire.
+pub struct DnsIpSetMiddleware {
+    ipsets: DomainIpSetMatcher,
+    ipset_timeout: bool,
+    nftsets: DomainNftSetMatcher,
+    nftset_timeout: bool,
+    netfilter: Option<Netfilter>,
+}
+
+impl DnsIpSetMiddleware {
+    pub fn new(cfg: &SmartDnsConfig) -> Self {
+        let ipsets = DomainIpSetMatcher::create(cfg);
+        let nftsets = DomainNftSetMatcher::create(cfg);
+
+        let netfilter = if ipsets.is_empty() && nftsets.is_empty() {
+            None
+        } else {
+            Netfilter::open()
+                .map_err(|err| {
+                    warn!(
+                        "ipset and nftset rules ignored, netfilter unavailable, {}",
+                        err
+                    )
+                })
+                .ok()
+        };
+
+        Self {
+            ipsets,
+            ipset_timeout: cfg.ipset_timeout,
+            nftsets,
+            nftset_timeout: cfg.nftset_timeout,
+            netfilter,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.netfilter.is_none()
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsIpSetMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let res = next.run(ctx, req).await;
+
+        let netfilter = match self.netfilter.as_ref() {
+            Some(netfilter) if !ctx.server_opts.no_rule_ipset => netfilter,
+            _ => return res,
+        };
+
+        let lookup = match res.as_ref() {
+            Ok(lookup) => lookup,
+            Err(_) => return res,
+        };
+
+        let name = req.query().name();
+        let ipsets = self.ipsets.find(name);
+        let nftsets = self.nftsets.find(name);
+        if ipsets.is_none() && nftsets.is_none() {
+            return res;
+        }
+
+        for record in lookup.record_iter() {
+            let ip = match record.data().and_then(|data| data.to_ip_addr()) {
+                Some(ip) => ip,
+                None => continue,
+            };
+
+            // a timeout of 0 never expires.
+            let ttl = record.ttl().max(1);
+
+            if let Some(set) = ipsets.and_then(|sets| sets.of(ip)) {
+                let timeout = self.ipset_timeout.then_some(ttl);
+                if let Err(err) = netfilter.add_ipset(set, ip, timeout) {
+                    debug!("add {} to ipset {} failed, {}", ip, set, err);
+                }
+            }
+
+            if let Some(set) = nftsets.and_then(|sets| sets.of(ip)) {
+                let timeout = self.nftset_timeout.then_some(ttl);
+                if let Err(err) =
+                    netfilter.add_nftset(&set.family, &set.table, &set.name, ip, timeout)
+                {
+                    debug!(
+                        "add {} to nftset {} {} {} failed, {}",
+                        ip, set.family, set.table, set.name, err
+                    );
+                }
+            }
+        }
+
+        res
+    }
+}
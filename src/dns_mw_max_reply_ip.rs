@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use crate::dns::*;
+use crate::middleware::*;
+
+/// Answer the clients at most so many ips, the fastest ones as sorted by the speed check,
+/// the records without ip, e.g. CNAME, kept. The cached answers keep all the ips.
+pub struct DnsMaxReplyIpMiddleware {
+    max: usize,
+}
+
+impl DnsMaxReplyIpMiddleware {
+    pub fn new(max: usize) -> Self {
+        Self { max }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsMaxReplyIpMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let lookup = next.run(ctx, req).await?;
+
+        let ips = lookup.record_iter().filter(|record| is_ip(record)).count();
+
+        if ips <= self.max {
+            return Ok(lookup);
+        }
+
+        Ok(Lookup::new_with_deadline(
+            lookup.query().clone(),
+            Arc::from(limit_ips(lookup.records(), self.max)),
+            lookup.valid_until(),
+        ))
+    }
+}
+
+fn is_ip(record: &Record) -> bool {
+    record.data().and_then(|data| data.to_ip_addr()).is_some()
+}
+
+/// The first ips of the records, in order, the records without ip kept.
+fn limit_ips(records: &[Record], max: usize) -> Vec<Record> {
+    let mut ips = 0;
+    records
+        .iter()
+        .filter(|record| {
+            if !is_ip(record) {
+                return true;
+            }
+            ips += 1;
+            ips <= max
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_limit_ips() {
+        let name = |s: &str| Name::from_str(s).unwrap();
+        let a = |ip: &str| {
+            Record::from_rdata(name("cdn.example.com."), 60, RData::A(ip.parse().unwrap()))
+        };
+
+        let records = vec![
+            Record::from_rdata(
+                name("www.example.com."),
+                60,
+                RData::CNAME(name("cdn.example.com.")),
+            ),
+            a("192.0.2.3"),
+            a("192.0.2.1"),
+            a("192.0.2.2"),
+        ];
+
+        let limited = limit_ips(&records, 2);
+        assert_eq!(limited, records[..3].to_vec());
+    }
+}
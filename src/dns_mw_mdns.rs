@@ -0,0 +1,316 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future;
+use tokio::net::UdpSocket;
+use trust_dns_client::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_client::rr::{LowerName, Record, RecordType};
+use trust_dns_proto::serialize::binary::{BinDecodable, BinEncodable};
+
+use crate::dns::*;
+use crate::infra::iface;
+use crate::log::debug;
+use crate::middleware::*;
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+/// Every host on the LAN may answer, the first response is waited for in this window.
+const MDNS_WINDOW: Duration = Duration::from_millis(500);
+const MDNS_NEGATIVE_TTL: u32 = 10;
+
+/// Resolve the names in the mDNS domains, e.g. `printer.local`, by multicast queries on the LAN,
+/// rather than leaking them to the unicast upstreams which never know them.
+pub struct DnsMdnsMiddleware {
+    domains: Vec<LowerName>,
+}
+
+impl DnsMdnsMiddleware {
+    pub fn new(domains: &[Name]) -> Self {
+        Self {
+            domains: domains.iter().map(LowerName::from).collect(),
+        }
+    }
+
+    /// The mDNS domain of the name, none if resolved by unicast.
+    pub(crate) fn domain_of(&self, name: &LowerName) -> Option<&LowerName> {
+        self.domains.iter().find(|d| d.zone_of(name))
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsMdnsMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let name = req.query().name();
+
+        if self.domain_of(name).is_none() {
+            return next.run(ctx, req).await;
+        }
+
+        ctx.lookup_source = LookupSource::Server("mdns".to_string());
+
+        let query = req.query().original().to_owned();
+
+        let records = query_mdns(&query).await.unwrap_or_else(|err| {
+            debug!("mdns query {} failed, {}", query, err);
+            vec![]
+        });
+
+        if !records.is_empty() {
+            return Ok(Lookup::new_with_max_ttl(query, Arc::from(records)));
+        }
+
+        Err(ResolveErrorKind::NoRecordsFound {
+            query: query.into(),
+            soa: None,
+            negative_ttl: Some(MDNS_NEGATIVE_TTL),
+            response_code: ResponseCode::NXDomain,
+            trusted: true,
+        }
+        .into())
+    }
+}
+
+/// A one-shot query (RFC 6762, Section 5.1) to both the ipv4 and ipv6 groups, answered by the
+/// first responder, else none once the window passed.
+async fn query_mdns(query: &Query) -> io::Result<Vec<Record>> {
+    let id = rand::random();
+
+    let mut message = Message::new();
+    message
+        .set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(false)
+        .add_query(query.clone());
+
+    let bytes = message
+        .to_bytes()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    // ff02::fb is link-local, sent on each interface by its scope id.
+    let v6_groups = iface::link_local_scopes()
+        .unwrap_or_else(|err| {
+            debug!("mdns query over ipv6 skipped, {}", err);
+            vec![]
+        })
+        .into_iter()
+        .map(|scope| SocketAddr::V6(SocketAddrV6::new(MDNS_V6, MDNS_PORT, 0, scope)))
+        .collect::<Vec<_>>();
+
+    let v4 = query_group(
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        vec![SocketAddr::from((MDNS_V4, MDNS_PORT))],
+        &bytes,
+        id,
+        query,
+    );
+
+    // ipv6 is best effort, the host may have no ipv6 on the LAN.
+    let v6 = query_group(
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        v6_groups,
+        &bytes,
+        id,
+        query,
+    );
+
+    match future::select_ok([Box::pin(v4), Box::pin(v6)]).await {
+        Ok((records, _)) => Ok(records),
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => Ok(vec![]),
+        Err(err) => Err(err),
+    }
+}
+
+/// Send from an ephemeral port, so that the responders answer by unicast, as to a legacy resolver,
+/// echoing the id. The answers of the first response relevant, timed out if none in the window.
+async fn query_group(
+    bind: SocketAddr,
+    groups: Vec<SocketAddr>,
+    bytes: &[u8],
+    id: u16,
+    query: &Query,
+) -> io::Result<Vec<Record>> {
+    let deadline = tokio::time::Instant::now() + MDNS_WINDOW;
+
+    let socket = UdpSocket::bind(bind).await?;
+
+    let mut sent = 0;
+    for group in groups {
+        match socket.send_to(bytes, group).await {
+            Ok(_) => sent += 1,
+            Err(err) => debug!("send mdns query to {} failed, {}", group, err),
+        }
+    }
+
+    if sent == 0 {
+        // nowhere to send, the other family is waited instead.
+        tokio::time::sleep_until(deadline).await;
+        return Err(io::ErrorKind::TimedOut.into());
+    }
+
+    let mut buf = [0u8; 9000];
+
+    loop {
+        let (len, from) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        match Message::from_bytes(&buf[..len]) {
+            Ok(message) if message.message_type() == MessageType::Response => {
+                if message.id() != id {
+                    debug!("mdns response from {} of another query", from);
+                    continue;
+                }
+
+                let mut records = vec![];
+                merge_answers(&mut records, &message, query);
+                if !records.is_empty() {
+                    return Ok(records);
+                }
+            }
+            _ => debug!("invalid mdns response from {}", from),
+        }
+    }
+}
+
+/// Merge the answers relevant to the query, skipping the duplicates.
+fn merge_answers(records: &mut Vec<Record>, message: &Message, query: &Query) {
+    let relevant = message.answers().iter().filter(|r| {
+        r.name() == query.name()
+            && (query.query_type() == RecordType::ANY
+                || r.record_type() == query.query_type()
+                || r.record_type() == RecordType::CNAME)
+    });
+
+    for record in relevant {
+        if !records
+            .iter()
+            .any(|r| r.record_type() == record.record_type() && r.data() == record.data())
+        {
+            records.push(record.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use trust_dns_client::rr::RData;
+
+    #[test]
+    fn test_query_group_first_answer() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let name = Name::from_str("printer.local.").unwrap();
+            let query = Query::query(name.clone(), RecordType::A);
+
+            let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let group = responder.local_addr().unwrap();
+
+            tokio::spawn(async move {
+                let mut buf = [0u8; 512];
+                let (len, from) = responder.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_bytes(&buf[..len]).unwrap();
+
+                for (id, ip) in [
+                    (request.id().wrapping_add(1), "192.168.1.9"),
+                    (request.id(), "192.168.1.5"),
+                ] {
+                    let mut response = Message::new();
+                    response
+                        .set_id(id)
+                        .set_message_type(MessageType::Response)
+                        .add_answer(Record::from_rdata(
+                            name.clone(),
+                            120,
+                            RData::A(ip.parse().unwrap()),
+                        ));
+                    responder
+                        .send_to(&response.to_bytes().unwrap(), from)
+                        .await
+                        .unwrap();
+                }
+            });
+
+            let mut message = Message::new();
+            message.set_id(7).add_query(query.clone());
+
+            let start = std::time::Instant::now();
+            let records = query_group(
+                SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                vec![group],
+                &message.to_bytes().unwrap(),
+                7,
+                &query,
+            )
+            .await
+            .unwrap();
+
+            assert!(start.elapsed() < MDNS_WINDOW);
+            assert_eq!(
+                records.iter().filter_map(|r| r.data()).collect::<Vec<_>>(),
+                vec![&RData::A("192.168.1.5".parse().unwrap())]
+            );
+
+            let err = query_group(
+                SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                vec![],
+                &message.to_bytes().unwrap(),
+                7,
+                &query,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        })
+    }
+
+    #[test]
+    fn test_merge_answers() {
+        let name = Name::from_str("printer.local.").unwrap();
+        let query = Query::query(name.clone(), RecordType::A);
+
+        let response = |ips: &[&str]| {
+            let mut message = Message::new();
+            message.set_message_type(MessageType::Response);
+            for ip in ips {
+                message.add_answer(Record::from_rdata(
+                    name.clone(),
+                    120,
+                    RData::A(ip.parse().unwrap()),
+                ));
+            }
+            message.add_answer(Record::from_rdata(
+                Name::from_str("other.local.").unwrap(),
+                120,
+                RData::A("192.168.1.9".parse().unwrap()),
+            ));
+            message
+        };
+
+        let mut records = vec![];
+        merge_answers(&mut records, &response(&["192.168.1.5"]), &query);
+        merge_answers(
+            &mut records,
+            &response(&["192.168.1.5", "192.168.1.6"]),
+            &query,
+        );
+
+        assert_eq!(
+            records.iter().filter_map(|r| r.data()).collect::<Vec<_>>(),
+            vec![
+                &RData::A("192.168.1.5".parse().unwrap()),
+                &RData::A("192.168.1.6".parse().unwrap())
+            ]
+        );
+    }
+}
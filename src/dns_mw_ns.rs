@@ -1,15 +1,38 @@
-use crate::dns_conf::SmartDnsConfig;
+use std::collections::HashMap;
+
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::rr::RecordType;
+
+use crate::dns_client::DnsClient;
+use crate::dns_conf::{GeoIpRoute, ResponseMode, SmartDnsConfig};
+use crate::geoip::GeoIp;
 
 use crate::dns::*;
 
+use crate::matcher::DomainNameServerGroupMatcher;
 use crate::middleware::*;
 
 #[derive(Debug)]
-pub struct NameServerMiddleware;
+pub struct NameServerMiddleware {
+    /// the nameserver rules of each conf-group, taking precedence over the global ones.
+    groups: HashMap<String, DomainNameServerGroupMatcher>,
+}
 
 impl NameServerMiddleware {
-    pub fn new(_cfg: &SmartDnsConfig) -> Self {
-        Self
+    pub fn new(cfg: &SmartDnsConfig) -> Self {
+        let groups = cfg
+            .conf_groups
+            .iter()
+            .map(|(name, group)| {
+                let map = DomainNameServerGroupMatcher::from_rules(
+                    &group.forward_rules,
+                    &cfg.domain_sets,
+                );
+                (name.to_owned(), map)
+            })
+            .collect();
+
+        Self { groups }
     }
 }
 
@@ -24,8 +47,94 @@ impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for NameServerMid
     ) -> Result<DnsResponse, DnsError> {
         let name = req.query().name();
         let rtype = req.query().query_type();
-        let group_name = ctx.client.find_server_group(name);
+
+        // not answered locally, not to be leaked to the upstreams.
+        if ctx.local_only {
+            return Err(ResolveErrorKind::NoRecordsFound {
+                query: req.query().original().to_owned().into(),
+                soa: None,
+                negative_ttl: None,
+                response_code: ResponseCode::NXDomain,
+                trusted: false,
+            }
+            .into());
+        }
+
+        let opts = &ctx.server_opts;
+        // the nameserver rule of the conf-group, of the domain rule and the global one first,
+        // then the group of the listener.
+        let rule_group = (!opts.no_rule_nameserver)
+            .then(|| {
+                opts.conf_group
+                    .as_ref()
+                    .and_then(|group| self.groups.get(group))
+                    .and_then(|map| map.find(name))
+                    .map(|group| group.as_str())
+                    .or_else(|| {
+                        // "-" means the default group, as of the nameserver rules.
+                        ctx.domain_rule
+                            .as_ref()
+                            .and_then(|rule| rule.nameserver.as_deref())
+                            .map(|group| if group == "-" { "default" } else { group })
+                    })
+                    .or_else(|| ctx.client.match_server_group(name))
+            })
+            .flatten();
+
+        // the geoip route applies to the queries no rule or listener decides the group for.
+        if rule_group.is_none() && opts.group.is_none() {
+            if let (Some(route), Some(geoip)) = (ctx.cfg.geoip_route.as_ref(), ctx.client.geoip()) {
+                if matches!(rtype, RecordType::A | RecordType::AAAA) {
+                    let (group_name, res) = geoip_route(&ctx.client, geoip, route, req).await;
+                    ctx.lookup_source = LookupSource::Server(group_name.to_string());
+                    return res;
+                }
+            }
+        }
+
+        let group_name = rule_group.or(opts.group.as_deref()).unwrap_or("default");
         ctx.lookup_source = LookupSource::Server(group_name.to_string());
+
+        // the ips of all the upstreams are speed checked for the fastest.
+        if ctx.cfg.response_mode == ResponseMode::FastestIp
+            && matches!(rtype, RecordType::A | RecordType::AAAA)
+            && !ctx.speed_checks().is_empty()
+        {
+            return ctx.client.lookup_all(name, rtype, Some(group_name)).await;
+        }
+
         ctx.client.lookup(name, rtype, Some(group_name)).await
     }
 }
+
+/// Resolve by both groups at once, the preferred one answers if its ips are of the country.
+async fn geoip_route<'a>(
+    client: &DnsClient,
+    geoip: &GeoIp,
+    route: &'a GeoIpRoute,
+    req: &DnsRequest,
+) -> (&'a str, Result<DnsResponse, DnsError>) {
+    let name = req.query().name();
+    let rtype = req.query().query_type();
+
+    let (preferred, other) = futures::future::join(
+        client.lookup(name, rtype, Some(route.group.as_str())),
+        client.lookup(name, rtype, Some(route.otherwise.as_str())),
+    )
+    .await;
+
+    match (preferred, other) {
+        (Ok(lookup), _) if is_of_country(geoip, &lookup, &route.country) => {
+            (route.group.as_str(), Ok(lookup))
+        }
+        (preferred, Err(_)) if preferred.is_ok() => (route.group.as_str(), preferred),
+        (_, other) => (route.otherwise.as_str(), other),
+    }
+}
+
+fn is_of_country(geoip: &GeoIp, lookup: &Lookup, country: &str) -> bool {
+    lookup
+        .record_iter()
+        .filter_map(|record| record.data().and_then(|data| data.to_ip_addr()))
+        .any(|ip| geoip.contains(ip, country))
+}
@@ -0,0 +1,324 @@
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future;
+use lru::LruCache;
+use tokio::net::TcpStream;
+use trust_dns_client::rr::RecordType;
+use trust_dns_proto::op::Query;
+
+use crate::dns::*;
+use crate::dns_conf::SmartDnsConfig;
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{debug, info};
+use crate::matcher::DomainPinResultMatcher;
+use crate::middleware::*;
+use crate::third_ext::FutureTimeoutExt;
+
+/// The health of the pinned ips is rechecked after this interval.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+const HEALTH_CHECK_PORTS: [u16; 2] = [443, 80];
+/// The pinned results checked at once.
+const HEALTH_CHECK_CONCURRENCY: usize = 32;
+
+/// The results pinned at most, the least recently answered ones are unpinned beyond it.
+const MAX_PINNED: usize = 4096;
+
+type PinnedResults = Arc<Mutex<LruCache<Query, PinnedResult>>>;
+
+/// Keep answering the ips a domain first resolved to for a period, while they are healthy,
+/// so that the connections to services behind flappy GeoDNS stay stable.
+///
+/// The ips are checked in background, a result is answered once found healthy, and unpinned
+/// once found unhealthy, the queries never waiting for the checks.
+pub struct DnsPinResultMiddleware {
+    matcher: DomainPinResultMatcher,
+    pinned: PinnedResults,
+    tasks: BackgroundTasks,
+}
+
+#[derive(Clone)]
+struct PinnedResult {
+    records: Arc<[Record]>,
+    ips: Vec<IpAddr>,
+    pinned_until: Instant,
+    /// not answered before checked.
+    healthy: bool,
+}
+
+impl DnsPinResultMiddleware {
+    pub fn new(cfg: &SmartDnsConfig, tasks: &BackgroundTasks) -> Self {
+        let middleware = Self {
+            matcher: DomainPinResultMatcher::create(cfg),
+            pinned: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(MAX_PINNED).unwrap(),
+            ))),
+            tasks: tasks.clone(),
+        };
+
+        if !middleware.is_empty() {
+            let pinned = middleware.pinned.clone();
+            tasks.spawn(async move {
+                let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    check_pinned(&pinned).await;
+                }
+            });
+        }
+
+        middleware
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matcher.is_empty()
+    }
+
+    /// The pinned result, if it's still in period and healthy.
+    fn find_pinned(&self, query: &Query, now: Instant) -> Option<Lookup> {
+        let pinned = {
+            let mut pinned = self.pinned.lock().ok()?;
+            match pinned.get(query) {
+                Some(p) if p.pinned_until > now => p.clone(),
+                Some(_) => {
+                    pinned.pop(query);
+                    return None;
+                }
+                None => return None,
+            }
+        };
+
+        if !pinned.healthy {
+            return None;
+        }
+
+        let remaining = pinned.pinned_until.duration_since(now).as_secs() as u32;
+
+        let records = pinned
+            .records
+            .iter()
+            .cloned()
+            .map(|mut r| {
+                r.set_ttl(r.ttl().min(remaining.max(1)));
+                r
+            })
+            .collect::<Vec<_>>();
+
+        Some(Lookup::new_with_max_ttl(query.clone(), Arc::from(records)))
+    }
+
+    /// Pin the result, answered once its ips are found healthy in background.
+    fn pin(&self, query: Query, lookup: &Lookup, period: Duration, now: Instant) {
+        let ips = lookup
+            .records()
+            .iter()
+            .filter_map(|r| r.data().and_then(|d| d.to_ip_addr()))
+            .collect::<Vec<_>>();
+
+        if ips.is_empty() {
+            return;
+        }
+
+        match self.pinned.lock() {
+            // being checked, or pinned already.
+            Ok(pinned) if pinned.contains(&query) => return,
+            Ok(mut pinned) => {
+                pinned.put(
+                    query.clone(),
+                    PinnedResult {
+                        records: Arc::from(lookup.records()),
+                        ips: ips.clone(),
+                        pinned_until: now + period,
+                        healthy: false,
+                    },
+                );
+            }
+            Err(_) => return,
+        }
+
+        let pinned = self.pinned.clone();
+        self.tasks.spawn(async move {
+            let healthy = is_healthy(&ips).await;
+
+            if let Ok(mut pinned) = pinned.lock() {
+                if healthy {
+                    if let Some(p) = pinned.peek_mut(&query) {
+                        debug!(
+                            "pin result of {} {} for {:?}: {:?}",
+                            query.name(),
+                            query.query_type(),
+                            period,
+                            ips
+                        );
+                        p.healthy = true;
+                    }
+                } else {
+                    pinned.pop(&query);
+                }
+            }
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsPinResultMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let query = req.query();
+
+        let period = match query.query_type() {
+            RecordType::A | RecordType::AAAA => self.matcher.find(query.name()).copied(),
+            _ => None,
+        };
+
+        let period = match period {
+            Some(period) => period,
+            None => return next.run(ctx, req).await,
+        };
+
+        let now = Instant::now();
+
+        if let Some(lookup) = self.find_pinned(query.original(), now) {
+            debug!("name: {} using pinned result", query.name());
+            return Ok(lookup);
+        }
+
+        let res = next.run(ctx, req).await;
+
+        if let Ok(lookup) = res.as_ref() {
+            self.pin(query.original().to_owned(), lookup, period, now);
+        }
+
+        res
+    }
+}
+
+/// Check the pinned results again, unpinning the expired and the unhealthy ones.
+async fn check_pinned(pinned: &PinnedResults) {
+    let now = Instant::now();
+
+    let results = match pinned.lock() {
+        Ok(mut pinned) => {
+            let expired = pinned
+                .iter()
+                .filter(|(_, p)| p.pinned_until <= now)
+                .map(|(query, _)| query.clone())
+                .collect::<Vec<_>>();
+            for query in expired {
+                pinned.pop(&query);
+            }
+
+            pinned
+                .iter()
+                .map(|(query, p)| (query.clone(), p.ips.clone()))
+                .collect::<Vec<_>>()
+        }
+        Err(_) => return,
+    };
+
+    for chunk in results.chunks(HEALTH_CHECK_CONCURRENCY) {
+        let checks = chunk
+            .iter()
+            .map(|(query, ips)| async move { (query, is_healthy(ips).await) });
+
+        for (query, healthy) in future::join_all(checks).await {
+            if healthy {
+                continue;
+            }
+            info!(
+                "pinned result of {} {} is unhealthy, unpinned",
+                query.name(),
+                query.query_type()
+            );
+            if let Ok(mut pinned) = pinned.lock() {
+                pinned.pop(query);
+            }
+        }
+    }
+}
+
+/// Any of the ips accepts tcp connections.
+async fn is_healthy(ips: &[IpAddr]) -> bool {
+    if ips.is_empty() {
+        return false;
+    }
+
+    let checks = ips.iter().flat_map(|ip| {
+        HEALTH_CHECK_PORTS.iter().map(|port| {
+            Box::pin(async move {
+                match TcpStream::connect(SocketAddr::new(*ip, *port))
+                    .timeout(HEALTH_CHECK_TIMEOUT)
+                    .await
+                {
+                    Ok(Ok(_)) => Ok(()),
+                    _ => Err(()),
+                }
+            })
+        })
+    });
+
+    future::select_ok(checks).await.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    #[test]
+    fn test_find_pinned() {
+        Runtime::new().unwrap().block_on(async {
+            let middleware =
+                DnsPinResultMiddleware::new(&SmartDnsConfig::new(), &BackgroundTasks::new());
+            let name = Name::from_str("www.example.com.").unwrap();
+            let query = Query::query(name.clone(), RecordType::A);
+
+            let now = Instant::now();
+
+            let record = Record::from_rdata(name, 600, RData::A(Ipv4Addr::new(1, 2, 3, 4)));
+
+            let result = PinnedResult {
+                records: Arc::from(vec![record]),
+                ips: vec![[1, 2, 3, 4].into()],
+                pinned_until: now + Duration::from_secs(60),
+                healthy: false,
+            };
+
+            // not checked yet.
+            middleware
+                .pinned
+                .lock()
+                .unwrap()
+                .put(query.clone(), result.clone());
+            assert!(middleware.find_pinned(&query, now).is_none());
+
+            middleware.pinned.lock().unwrap().put(
+                query.clone(),
+                PinnedResult {
+                    healthy: true,
+                    ..result
+                },
+            );
+
+            let lookup = middleware.find_pinned(&query, now).unwrap();
+            assert_eq!(lookup.records()[0].ttl(), 60);
+
+            // expired.
+            let later = now + Duration::from_secs(61);
+            assert!(middleware.find_pinned(&query, later).is_none());
+            assert!(middleware.pinned.lock().unwrap().is_empty());
+        })
+    }
+}
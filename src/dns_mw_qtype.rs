@@ -0,0 +1,115 @@
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::rr::{LowerName, RecordType};
+
+use crate::dns::*;
+use crate::dns_conf::SmartDnsConfig;
+use crate::log::debug;
+use crate::matcher::DomainQueryTypeMatcher;
+use crate::middleware::*;
+
+/// Answer the queries of the record types denied locally, before any cache or upstream,
+/// NOTIMP for the ones denied globally, e.g. ANY and AXFR, REFUSED for the ones denied by
+/// the rules of the domain.
+pub struct DnsQueryTypeMiddleware {
+    deny: Vec<RecordType>,
+    rules: DomainQueryTypeMatcher,
+}
+
+impl DnsQueryTypeMiddleware {
+    pub fn new(cfg: &SmartDnsConfig) -> Self {
+        Self {
+            deny: cfg.deny_query_types.clone(),
+            rules: DomainQueryTypeMatcher::create(cfg),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deny.is_empty() && self.rules.is_empty()
+    }
+
+    /// The response code of the query denied, none if allowed.
+    fn denied(&self, name: &LowerName, record_type: RecordType) -> Option<ResponseCode> {
+        if self.deny.contains(&record_type) {
+            return Some(ResponseCode::NotImp);
+        }
+
+        self.rules
+            .find(name)
+            .filter(|types| !types.accepts(record_type))
+            .map(|_| ResponseCode::Refused)
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsQueryTypeMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let query = req.query();
+
+        let response_code = match self.denied(query.name(), query.query_type()) {
+            Some(response_code) => response_code,
+            None => return next.run(ctx, req).await,
+        };
+
+        debug!(
+            "{} {} denied, {}",
+            query.name(),
+            query.query_type(),
+            response_code
+        );
+
+        ctx.lookup_source = LookupSource::Static;
+        ctx.extended_error = Some(ExtendedError::new(
+            ExtendedError::FILTERED,
+            format!("query type {} denied", query.query_type()),
+        ));
+
+        Err(ResolveErrorKind::NoRecordsFound {
+            query: query.original().to_owned().into(),
+            soa: None,
+            negative_ttl: None,
+            response_code,
+            trusted: true,
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::dns_conf::{DomainOrDomainSet, QueryTypeRuleItem, RecordTypeFilter};
+
+    #[test]
+    fn test_denied() {
+        let mut cfg = SmartDnsConfig::new();
+        cfg.deny_query_types = vec![RecordType::ANY, RecordType::AXFR];
+        cfg.query_type_rules.push(QueryTypeRuleItem {
+            domain: DomainOrDomainSet::from_str("iot.lan").unwrap(),
+            types: RecordTypeFilter {
+                allow: vec![RecordType::A, RecordType::AAAA],
+                deny: vec![],
+            },
+        });
+
+        let mw = DnsQueryTypeMiddleware::new(&cfg);
+        let name = |s: &str| LowerName::from(Name::from_str(s).unwrap());
+
+        assert_eq!(
+            mw.denied(&name("example.com."), RecordType::AXFR),
+            Some(ResponseCode::NotImp)
+        );
+        assert_eq!(mw.denied(&name("example.com."), RecordType::TXT), None);
+        assert_eq!(
+            mw.denied(&name("cam.iot.lan."), RecordType::TXT),
+            Some(ResponseCode::Refused)
+        );
+        assert_eq!(mw.denied(&name("cam.iot.lan."), RecordType::AAAA), None);
+    }
+}
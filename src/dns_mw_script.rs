@@ -0,0 +1,348 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use trust_dns_client::op::{Query, ResponseCode};
+use trust_dns_client::rr::RecordType;
+
+use crate::dns::*;
+use crate::log::warn;
+use crate::middleware::*;
+
+/// The operations a hook runs at most, so that a looping script doesn't hang the query.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// Where the hooks of a script are invoked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptHook {
+    /// `on_query` before the address rules, and `on_response` once resolved.
+    Query,
+    /// `on_cache_miss` before the queries are forwarded to the upstreams.
+    CacheMiss,
+}
+
+/// What the hook decided, returned by the functions the scripts call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptAction {
+    /// `block()`, answer NXDOMAIN.
+    Block,
+    /// `answer("1.2.3.4")`, answer the ip.
+    Answer(IpAddr),
+    /// `rewrite("example.org")`, resolve the other name, as if a CNAME.
+    Rewrite(Name),
+    /// `forward("office")`, resolve by the server group.
+    Forward(String),
+}
+
+/// Run the hooks of a Rhai script, e.g.
+///
+/// ```text
+/// fn on_query(query) {
+///     if query.name.ends_with(".ads.example.com.") { return block(); }
+///     if query.client.starts_with("192.168.2.") { return forward("guest"); }
+/// }
+///
+/// fn on_response(query, ips) {
+///     if ips.contains("0.0.0.0") { return block(); }
+/// }
+/// ```
+///
+/// The query passed is `#{name, type, client}`, the hooks returning nothing pass it on.
+pub struct DnsScriptMiddleware {
+    script: Arc<Script>,
+    hook: ScriptHook,
+}
+
+impl DnsScriptMiddleware {
+    pub fn new(script: Arc<Script>, hook: ScriptHook) -> Self {
+        Self { script, hook }
+    }
+}
+
+pub struct Script {
+    name: String,
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Self::compile(path.display().to_string(), &source)
+    }
+
+    fn compile(name: String, source: &str) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine
+            .register_type_with_name::<ScriptAction>("Action")
+            .register_fn("block", || ScriptAction::Block)
+            .register_fn("answer", |ip: &str| -> Result<_, Box<EvalAltResult>> {
+                IpAddr::from_str(ip)
+                    .map(ScriptAction::Answer)
+                    .map_err(|err| format!("invalid ip {}, {}", ip, err).into())
+            })
+            .register_fn("rewrite", |name: &str| -> Result<_, Box<EvalAltResult>> {
+                Name::from_str(name)
+                    .map(|mut name| {
+                        name.set_fqdn(true);
+                        ScriptAction::Rewrite(name)
+                    })
+                    .map_err(|err| format!("invalid name {}, {}", name, err).into())
+            })
+            .register_fn("forward", |group: &str| {
+                ScriptAction::Forward(group.to_string())
+            });
+
+        let ast = engine.compile(source).map_err(|err| err.to_string())?;
+
+        Ok(Self { name, engine, ast })
+    }
+
+    /// Whether the script defines the hook.
+    pub fn has_hook(&self, hook: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == hook)
+    }
+
+    /// Call the hook, none if it passes the query on.
+    fn call(&self, hook: &str, args: Vec<Dynamic>) -> Result<Option<ScriptAction>, String> {
+        if !self.has_hook(hook) {
+            return Ok(None);
+        }
+
+        let result = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, hook, args)
+            .map_err(|err| err.to_string())?;
+
+        if result.is_unit() {
+            return Ok(None);
+        }
+
+        result
+            .try_cast::<ScriptAction>()
+            .map(Some)
+            .ok_or_else(|| format!("{} returned neither an action nor nothing", hook))
+    }
+}
+
+fn query_map(req: &DnsRequest) -> Dynamic {
+    let query = req.query();
+    let mut map = Map::new();
+    map.insert("name".into(), query.name().to_string().into());
+    map.insert("type".into(), query.query_type().to_string().into());
+    map.insert("client".into(), req.src().ip().to_string().into());
+    map.into()
+}
+
+fn answer_ips(lookup: &Lookup) -> Dynamic {
+    lookup
+        .record_iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::A(ip)) => Some(ip.to_string().into()),
+            Some(RData::AAAA(ip)) => Some(ip.to_string().into()),
+            _ => None,
+        })
+        .collect::<Array>()
+        .into()
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsScriptMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let hook = match self.hook {
+            ScriptHook::Query => "on_query",
+            ScriptHook::CacheMiss => "on_cache_miss",
+        };
+
+        // the script fails open, the query is resolved as if not hooked.
+        let action = self
+            .script
+            .call(hook, vec![query_map(req)])
+            .unwrap_or_else(|err| {
+                warn!("script {} failed in {}, {}", self.script.name, hook, err);
+                None
+            });
+
+        if let Some(action) = action {
+            return self.apply(ctx, req, action).await;
+        }
+
+        let lookup = next.run(ctx, req).await?;
+
+        if self.hook != ScriptHook::Query {
+            return Ok(lookup);
+        }
+
+        let action = self
+            .script
+            .call("on_response", vec![query_map(req), answer_ips(&lookup)])
+            .unwrap_or_else(|err| {
+                warn!("script {} failed in on_response, {}", self.script.name, err);
+                None
+            });
+
+        match action {
+            Some(action) => self.apply(ctx, req, action).await,
+            None => Ok(lookup),
+        }
+    }
+}
+
+impl DnsScriptMiddleware {
+    async fn apply(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        action: ScriptAction,
+    ) -> Result<DnsResponse, DnsError> {
+        let query = req.query().original().to_owned();
+        let rtype = query.query_type();
+
+        match action {
+            ScriptAction::Block => {
+                ctx.lookup_source = LookupSource::Static;
+                Err(no_records(query, ResponseCode::NXDomain))
+            }
+            ScriptAction::Answer(ip) => {
+                ctx.lookup_source = LookupSource::Static;
+                match (ip, rtype) {
+                    (IpAddr::V4(ip), RecordType::A) => Ok(Lookup::from_rdata(query, RData::A(ip))),
+                    (IpAddr::V6(ip), RecordType::AAAA) => {
+                        Ok(Lookup::from_rdata(query, RData::AAAA(ip)))
+                    }
+                    _ => Err(no_records(query, ResponseCode::NoError)),
+                }
+            }
+            ScriptAction::Rewrite(name) => {
+                let lookup = ctx.client.lookup(name.clone(), rtype, None).await?;
+
+                let cname = Record::from_rdata(
+                    query.name().to_owned(),
+                    lookup.records().iter().map(|r| r.ttl()).min().unwrap_or(0),
+                    RData::CNAME(name),
+                );
+                let records = std::iter::once(cname)
+                    .chain(lookup.records().iter().cloned())
+                    .collect::<Vec<_>>();
+
+                ctx.lookup_source = LookupSource::Static;
+                Ok(Lookup::new_with_max_ttl(query, Arc::from(records)))
+            }
+            ScriptAction::Forward(group) => {
+                let lookup = ctx
+                    .client
+                    .lookup(query.name().to_owned(), rtype, Some(group.as_str()))
+                    .await;
+                ctx.lookup_source = LookupSource::Server(group);
+                lookup
+            }
+        }
+    }
+}
+
+fn no_records(query: Query, response_code: ResponseCode) -> DnsError {
+    ResolveErrorKind::NoRecordsFound {
+        query: query.into(),
+        soa: None,
+        negative_ttl: None,
+        response_code,
+        trusted: true,
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, client: &str) -> Vec<Dynamic> {
+        let mut map = Map::new();
+        map.insert("name".into(), name.to_string().into());
+        map.insert("type".into(), "A".to_string().into());
+        map.insert("client".into(), client.to_string().into());
+        vec![map.into()]
+    }
+
+    #[test]
+    fn test_script_hooks() {
+        let script = Script::compile(
+            "test".to_string(),
+            r#"
+            fn on_query(query) {
+                if query.name.ends_with(".ads.example.com.") { return block(); }
+                if query.name == "nas.lan." { return answer("192.168.1.10"); }
+                if query.name == "www.example.com." { return rewrite("example.org"); }
+                if query.client.starts_with("192.168.2.") { return forward("guest"); }
+            }
+
+            fn on_response(query, ips) {
+                if ips.contains("0.0.0.0") { return block(); }
+            }
+            "#,
+        )
+        .unwrap();
+
+        assert!(script.has_hook("on_query"));
+        assert!(!script.has_hook("on_cache_miss"));
+
+        let call = |name, client| script.call("on_query", query(name, client)).unwrap();
+
+        assert_eq!(
+            call("x.ads.example.com.", "10.0.0.1"),
+            Some(ScriptAction::Block)
+        );
+        assert_eq!(
+            call("nas.lan.", "10.0.0.1"),
+            Some(ScriptAction::Answer("192.168.1.10".parse().unwrap()))
+        );
+        assert_eq!(
+            call("www.example.com.", "10.0.0.1"),
+            Some(ScriptAction::Rewrite(
+                Name::from_str("example.org.").unwrap()
+            ))
+        );
+        assert_eq!(
+            call("example.net.", "192.168.2.7"),
+            Some(ScriptAction::Forward("guest".to_string()))
+        );
+        assert_eq!(call("example.net.", "10.0.0.1"), None);
+
+        let mut args = query("example.net.", "10.0.0.1");
+        args.push(vec![Dynamic::from("0.0.0.0".to_string())].into());
+        assert_eq!(
+            script.call("on_response", args).unwrap(),
+            Some(ScriptAction::Block)
+        );
+
+        assert!(script
+            .call("on_cache_miss", query("a.", "b"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_script_errors() {
+        assert!(Script::compile("test".to_string(), "fn on_query(query) {").is_err());
+
+        let script = Script::compile(
+            "test".to_string(),
+            r#"
+            fn on_query(query) { loop {} }
+            fn on_cache_miss(query) { answer("not an ip") }
+            "#,
+        )
+        .unwrap();
+
+        assert!(script.call("on_query", query("a.", "b")).is_err());
+        assert!(script.call("on_cache_miss", query("a.", "b")).is_err());
+    }
+}
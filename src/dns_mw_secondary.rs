@@ -0,0 +1,678 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use trust_dns_client::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_client::rr::{LowerName, RData, Record, RecordType};
+use trust_dns_proto::serialize::binary::{BinDecodable, BinEncodable};
+
+use crate::dns::*;
+use crate::dns_conf::SecondaryZone;
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{debug, info, warn};
+use crate::middleware::*;
+use crate::third_ext::FutureTimeoutExt;
+
+/// The check of the serial and the transfer are abandoned, if not completed in this duration.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+/// The refresh and retry of the SOA are bounded, against the bogus values.
+const MIN_REFRESH: Duration = Duration::from_secs(5);
+const MAX_REFRESH: Duration = Duration::from_secs(24 * 60 * 60);
+/// The retry until the zone transferred at first.
+const INITIAL_RETRY: Duration = Duration::from_secs(10);
+/// The transfers of more records are abandoned, against an endless one.
+const MAX_ZONE_RECORDS: usize = 1_000_000;
+
+/// Answer the names of the zones hosted as a secondary, transferred from their primaries in
+/// background, refreshed by the timers of their SOA and at once on the NOTIFY of the primaries.
+/// The zones not transferred yet or expired are answered SERVFAIL, rather than leaked to the
+/// upstreams.
+#[derive(Clone)]
+pub struct DnsSecondaryMiddleware {
+    zones: Arc<Vec<Arc<HostedZone>>>,
+}
+
+struct HostedZone {
+    zone: LowerName,
+    primaries: Vec<SocketAddr>,
+    loaded: RwLock<Option<Loaded>>,
+    /// notified on the notifies of the primaries, the serial checked at once.
+    notify: Notify,
+}
+
+struct Loaded {
+    records: ZoneRecords,
+    /// the zone expires, once not refreshed from any primary until then.
+    expires_at: Instant,
+}
+
+/// The records of a zone, by name, along with its SOA.
+#[derive(Debug, Clone, PartialEq)]
+struct ZoneRecords {
+    soa: Record,
+    names: HashMap<LowerName, Vec<Record>>,
+}
+
+impl DnsSecondaryMiddleware {
+    pub fn new(zones: &[SecondaryZone], tasks: &BackgroundTasks) -> Self {
+        let zones = zones
+            .iter()
+            .map(|zone| {
+                let hosted = Arc::new(HostedZone {
+                    zone: LowerName::from(&zone.zone),
+                    primaries: zone.primaries.clone(),
+                    loaded: Default::default(),
+                    notify: Notify::new(),
+                });
+
+                let refreshed = hosted.clone();
+                tasks.spawn(async move {
+                    loop {
+                        let next = refreshed.refresh().await;
+
+                        tokio::select! {
+                            _ = tokio::time::sleep(next) => (),
+                            _ = refreshed.notify.notified() => {
+                                debug!("zone {} notified, checking the serial", refreshed.zone)
+                            }
+                        }
+                    }
+                });
+
+                hosted
+            })
+            .collect();
+
+        Self {
+            zones: Arc::new(zones),
+        }
+    }
+
+    /// Check the serial of the zone at once, if the notify is of one of its primaries,
+    /// see RFC 1996, the others are refused.
+    pub fn notify(&self, zone: &LowerName, from: IpAddr) -> bool {
+        match self
+            .zones
+            .iter()
+            .find(|z| z.zone == *zone && z.primaries.iter().any(|p| p.ip() == from))
+        {
+            Some(hosted) => {
+                hosted.notify.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl HostedZone {
+    /// Check the serial of the primaries in turn, the zone transferred once newer, the next
+    /// check after the refresh of the SOA, or its retry once all the primaries failed.
+    async fn refresh(&self) -> Duration {
+        let current = self.current_soa();
+
+        for primary in self.primaries.iter() {
+            match self
+                .refresh_from(*primary, current.as_ref())
+                .timeout(TRANSFER_TIMEOUT)
+                .await
+            {
+                Ok(Ok(soa)) => return bounded(soa_of(&soa).map(|soa| soa.refresh())),
+                Ok(Err(err)) => warn!(
+                    "refresh zone {} from {} failed, {}",
+                    self.zone, primary, err
+                ),
+                Err(_) => warn!("refresh zone {} from {} timed out", self.zone, primary),
+            }
+        }
+
+        match current {
+            Some(soa) => bounded(soa_of(&soa).map(|soa| soa.retry())),
+            None => INITIAL_RETRY,
+        }
+    }
+
+    /// The SOA of the zone after refreshed from the primary, transferred if the serial is
+    /// newer than the current one.
+    async fn refresh_from(
+        &self,
+        primary: SocketAddr,
+        current: Option<&Record>,
+    ) -> io::Result<Record> {
+        let name = Name::from(self.zone.clone());
+
+        let serial = exchange(primary, &query(name.clone(), RecordType::SOA, None), |_| {
+            true
+        })
+        .await?
+        .iter()
+        .find_map(soa_of)
+        .map(|soa| soa.serial())
+        .ok_or_else(|| invalid_data("no SOA answered"))?;
+
+        let records = match current.and_then(soa_of) {
+            Some(soa) if !is_newer(serial, soa.serial()) => None,
+            _ => {
+                let ixfr_serial = current.and_then(soa_of).map(|soa| soa.serial());
+                let request = match current {
+                    Some(soa) => query(name, RecordType::IXFR, Some(soa.clone())),
+                    None => query(name, RecordType::AXFR, None),
+                };
+                let answers = exchange(primary, &request, |answers| {
+                    is_complete(answers, ixfr_serial)
+                })
+                .await?;
+
+                let current = self
+                    .loaded
+                    .read()
+                    .ok()
+                    .and_then(|loaded| loaded.as_ref().map(|loaded| loaded.records.clone()));
+                apply_transfer(current.as_ref(), answers)?
+            }
+        };
+
+        let mut loaded = self
+            .loaded
+            .write()
+            .map_err(|_| invalid_data("zone lock poisoned"))?;
+
+        if let Some(records) = records {
+            info!(
+                "zone {} transferred from {}, serial {}",
+                self.zone, primary, serial
+            );
+            *loaded = Some(Loaded {
+                records,
+                expires_at: Instant::now(),
+            });
+        }
+
+        match loaded.as_mut() {
+            Some(loaded) => {
+                let expire = soa_of(&loaded.records.soa)
+                    .map(|soa| soa.expire().max(0) as u64)
+                    .unwrap_or_default();
+                loaded.expires_at = Instant::now() + Duration::from_secs(expire);
+                Ok(loaded.records.soa.clone())
+            }
+            None => Err(invalid_data("zone not transferred")),
+        }
+    }
+
+    fn current_soa(&self) -> Option<Record> {
+        self.loaded
+            .read()
+            .ok()
+            .and_then(|loaded| loaded.as_ref().map(|loaded| loaded.records.soa.clone()))
+    }
+
+    /// The records of the name of the type, or its CNAME, the error of the negative answer,
+    /// none if the zone is not transferred yet or expired.
+    fn lookup(
+        &self,
+        name: &LowerName,
+        query_type: RecordType,
+    ) -> Option<Result<Vec<Record>, (Record, bool)>> {
+        let loaded = self.loaded.read().ok()?;
+        let loaded = loaded.as_ref().filter(|l| l.expires_at > Instant::now())?;
+        let zone = &loaded.records;
+
+        let records = match zone.names.get(name) {
+            Some(records) => records,
+            None => {
+                // an empty non-terminal, e.g. of `a.b.corp.lan`, is there without any record.
+                let exists = zone.names.keys().any(|n| name.zone_of(n));
+                return Some(Err((zone.soa.clone(), !exists)));
+            }
+        };
+
+        let records = records
+            .iter()
+            .filter(|r| r.record_type() == query_type || r.record_type() == RecordType::CNAME)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        match records.is_empty() {
+            true => Some(Err((zone.soa.clone(), false))),
+            false => Some(Ok(records)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsSecondaryMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let name = req.query().name();
+
+        let zone = match self.zones.iter().find(|z| z.zone.zone_of(name)) {
+            Some(zone) => zone,
+            None => return next.run(ctx, req).await,
+        };
+
+        ctx.lookup_source = LookupSource::Zone(zone.zone.to_string());
+
+        let query = req.query().original().to_owned();
+
+        let (soa, nx_domain) = match zone.lookup(name, query.query_type()) {
+            Some(Ok(records)) => return Ok(Lookup::new_with_max_ttl(query, Arc::from(records))),
+            Some(Err(negative)) => negative,
+            None => {
+                debug!("zone {} not transferred or expired", zone.zone);
+                return Err(ResolveErrorKind::NoRecordsFound {
+                    query: query.into(),
+                    soa: None,
+                    negative_ttl: None,
+                    response_code: ResponseCode::ServFail,
+                    trusted: true,
+                }
+                .into());
+            }
+        };
+
+        let negative_ttl = soa_of(&soa)
+            .map(|data| data.minimum().min(soa.ttl()))
+            .unwrap_or_default();
+
+        Err(ResolveErrorKind::NoRecordsFound {
+            query: query.into(),
+            soa: Some(Box::new(soa)),
+            negative_ttl: Some(negative_ttl),
+            response_code: if nx_domain {
+                ResponseCode::NXDomain
+            } else {
+                ResponseCode::NoError
+            },
+            trusted: true,
+        }
+        .into())
+    }
+}
+
+fn query(name: Name, query_type: RecordType, soa: Option<Record>) -> Message {
+    let mut message = Message::new();
+    message
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(false)
+        .add_query(Query::query(name, query_type));
+
+    // the version held, the changes since which are asked by IXFR.
+    if let Some(soa) = soa {
+        message.add_name_server(soa);
+    }
+
+    message
+}
+
+/// Send the query to the primary over tcp, the answers of the responses collected until done.
+async fn exchange<F>(primary: SocketAddr, message: &Message, done: F) -> io::Result<Vec<Record>>
+where
+    F: Fn(&[Record]) -> bool,
+{
+    let bytes = message.to_bytes().map_err(invalid_data)?;
+    let len = u16::try_from(bytes.len()).map_err(invalid_data)?;
+
+    let mut stream = TcpStream::connect(primary).await?;
+    stream.write_u16(len).await?;
+    stream.write_all(&bytes).await?;
+
+    let mut answers = vec![];
+
+    loop {
+        let len = stream.read_u16().await? as usize;
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).await?;
+
+        let mut response = Message::from_bytes(&buf).map_err(invalid_data)?;
+
+        if response.id() != message.id() {
+            return Err(invalid_data("response of another query"));
+        }
+        if response.response_code() != ResponseCode::NoError {
+            return Err(invalid_data(format!(
+                "answered {}",
+                response.response_code()
+            )));
+        }
+
+        answers.extend(response.take_answers());
+
+        if answers.len() > MAX_ZONE_RECORDS {
+            return Err(invalid_data("too many records"));
+        }
+        if done(&answers) {
+            return Ok(answers);
+        }
+    }
+}
+
+/// Whether the answers of the IXFR, asked since the serial, or the AXFR are complete,
+/// see RFC 1995, Section 4.
+fn is_complete(answers: &[Record], ixfr_serial: Option<u32>) -> bool {
+    let serial = match answers.first().and_then(soa_of) {
+        Some(soa) => soa.serial(),
+        None => return false,
+    };
+
+    // up to date already.
+    if answers.len() == 1 {
+        return matches!(ixfr_serial, Some(current) if !is_newer(serial, current));
+    }
+
+    // the incremental one ends with the third SOA of the new serial, the one of the last
+    // change included, the whole zone with the second.
+    let ends = match is_incremental(answers) {
+        true => 3,
+        false => 2,
+    };
+
+    answers
+        .iter()
+        .filter_map(soa_of)
+        .filter(|soa| soa.serial() == serial)
+        .count()
+        >= ends
+}
+
+/// Whether the answers are the changes rather than the whole zone, of which the SOA of the
+/// old serial follows the new one.
+fn is_incremental(answers: &[Record]) -> bool {
+    match (
+        answers.first().and_then(soa_of),
+        answers.get(1).and_then(soa_of),
+    ) {
+        (Some(new), Some(old)) => new.serial() != old.serial(),
+        _ => false,
+    }
+}
+
+/// The records of the zone after the transfer, none if up to date.
+fn apply_transfer(
+    current: Option<&ZoneRecords>,
+    answers: Vec<Record>,
+) -> io::Result<Option<ZoneRecords>> {
+    let soa = answers
+        .first()
+        .filter(|r| soa_of(r).is_some())
+        .cloned()
+        .ok_or_else(|| invalid_data("transfer not started by SOA"))?;
+
+    if answers.len() == 1 {
+        return Ok(None);
+    }
+
+    let changes = &answers[1..answers.len() - 1];
+
+    let mut zone = match (current, is_incremental(&answers)) {
+        (Some(current), true) => {
+            let mut zone = current.clone();
+            // the deletions follow the SOA of the old serial, the additions the new one.
+            let mut adding = true;
+            for record in changes {
+                if soa_of(record).is_some() {
+                    adding = !adding;
+                } else if adding {
+                    zone.add(record.clone());
+                } else {
+                    zone.remove(record);
+                }
+            }
+            zone
+        }
+        (None, true) => return Err(invalid_data("changes of a zone never transferred")),
+        (_, false) => {
+            let mut zone = ZoneRecords {
+                soa: soa.clone(),
+                names: Default::default(),
+            };
+            for record in changes.iter().filter(|r| soa_of(r).is_none()) {
+                zone.add(record.clone());
+            }
+            zone
+        }
+    };
+
+    zone.set_soa(soa);
+
+    Ok(Some(zone))
+}
+
+impl ZoneRecords {
+    fn add(&mut self, record: Record) {
+        let records = self
+            .names
+            .entry(LowerName::from(record.name()))
+            .or_default();
+        if !records.iter().any(|r| same_record(r, &record)) {
+            records.push(record);
+        }
+    }
+
+    fn remove(&mut self, record: &Record) {
+        let name = LowerName::from(record.name());
+        if let Some(records) = self.names.get_mut(&name) {
+            records.retain(|r| !same_record(r, record));
+            if records.is_empty() {
+                self.names.remove(&name);
+            }
+        }
+    }
+
+    /// The SOA of the new serial, answered at the apex as well.
+    fn set_soa(&mut self, soa: Record) {
+        let records = self.names.entry(LowerName::from(soa.name())).or_default();
+        records.retain(|r| r.record_type() != RecordType::SOA);
+        records.push(soa.clone());
+        self.soa = soa;
+    }
+}
+
+/// The same record regardless of its ttl.
+fn same_record(a: &Record, b: &Record) -> bool {
+    a.name() == b.name() && a.record_type() == b.record_type() && a.data() == b.data()
+}
+
+fn soa_of(record: &Record) -> Option<&trust_dns_proto::rr::rdata::SOA> {
+    match record.data() {
+        Some(RData::SOA(soa)) => Some(soa),
+        _ => None,
+    }
+}
+
+/// Whether the serial is newer by the serial number arithmetic, see RFC 1982.
+fn is_newer(serial: u32, current: u32) -> bool {
+    serial != current && (serial.wrapping_sub(current) as i32) > 0
+}
+
+fn bounded(seconds: Option<i32>) -> Duration {
+    Duration::from_secs(seconds.unwrap_or_default().max(0) as u64).clamp(MIN_REFRESH, MAX_REFRESH)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use tokio::net::TcpListener;
+    use trust_dns_proto::rr::rdata::SOA;
+
+    use super::*;
+
+    fn soa(serial: u32) -> Record {
+        Record::from_rdata(
+            Name::from_str("corp.lan.").unwrap(),
+            300,
+            RData::SOA(SOA::new(
+                Name::from_str("ns.corp.lan.").unwrap(),
+                Name::from_str("admin.corp.lan.").unwrap(),
+                serial,
+                60,
+                30,
+                3600,
+                120,
+            )),
+        )
+    }
+
+    fn a(name: &str, ip: &str) -> Record {
+        Record::from_rdata(
+            Name::from_str(name).unwrap(),
+            300,
+            RData::A(ip.parse().unwrap()),
+        )
+    }
+
+    fn lower(name: &str) -> LowerName {
+        LowerName::from(Name::from_str(name).unwrap())
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer(2, 1));
+        assert!(!is_newer(1, 1));
+        assert!(!is_newer(1, 2));
+        assert!(is_newer(1, u32::MAX));
+    }
+
+    #[test]
+    fn test_transfer_complete() {
+        let axfr = vec![soa(2), a("www.corp.lan.", "10.0.0.1"), soa(2)];
+        assert!(!is_complete(&axfr[..2], None));
+        assert!(is_complete(&axfr, None));
+
+        assert!(is_complete(&[soa(1)], Some(1)));
+        assert!(!is_complete(&[soa(2)], Some(1)));
+
+        let ixfr = vec![
+            soa(3),
+            soa(1),
+            a("www.corp.lan.", "10.0.0.1"),
+            soa(3),
+            a("www.corp.lan.", "10.0.0.2"),
+            soa(3),
+        ];
+        assert!(!is_complete(&ixfr[..4], Some(1)));
+        assert!(!is_complete(&ixfr[..5], Some(1)));
+        assert!(is_complete(&ixfr, Some(1)));
+    }
+
+    #[test]
+    fn test_apply_transfer() {
+        let zone = apply_transfer(
+            None,
+            vec![
+                soa(1),
+                a("www.corp.lan.", "10.0.0.1"),
+                a("db.corp.lan.", "10.0.0.3"),
+                soa(1),
+            ],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(zone.names.len(), 3);
+
+        assert_eq!(apply_transfer(Some(&zone), vec![soa(1)]).unwrap(), None);
+
+        let zone = apply_transfer(
+            Some(&zone),
+            vec![
+                soa(2),
+                soa(1),
+                a("www.corp.lan.", "10.0.0.1"),
+                a("db.corp.lan.", "10.0.0.3"),
+                soa(2),
+                a("www.corp.lan.", "10.0.0.2"),
+                soa(2),
+            ],
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(soa_of(&zone.soa).unwrap().serial(), 2);
+        assert_eq!(
+            zone.names[&lower("www.corp.lan.")],
+            vec![a("www.corp.lan.", "10.0.0.2")]
+        );
+        assert!(!zone.names.contains_key(&lower("db.corp.lan.")));
+    }
+
+    #[test]
+    fn test_refresh_and_notify() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let primary = listener.local_addr().unwrap();
+
+            // the primary answers the SOA query, then the AXFR.
+            tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let len = stream.read_u16().await.unwrap() as usize;
+                    let mut buf = vec![0; len];
+                    stream.read_exact(&mut buf).await.unwrap();
+
+                    let request = Message::from_bytes(&buf).unwrap();
+                    let answers = match request.queries()[0].query_type() {
+                        RecordType::SOA => vec![soa(7)],
+                        _ => vec![soa(7), a("www.corp.lan.", "10.0.0.1"), soa(7)],
+                    };
+
+                    let mut response = Message::new();
+                    response
+                        .set_id(request.id())
+                        .set_message_type(MessageType::Response)
+                        .insert_answers(answers);
+                    let bytes = response.to_bytes().unwrap();
+                    stream.write_u16(bytes.len() as u16).await.unwrap();
+                    stream.write_all(&bytes).await.unwrap();
+                }
+            });
+
+            let tasks = BackgroundTasks::new();
+            let secondary = DnsSecondaryMiddleware::new(
+                &[SecondaryZone {
+                    zone: Name::from_str("corp.lan.").unwrap(),
+                    primaries: vec![primary],
+                }],
+                &tasks,
+            );
+            let zone = secondary.zones[0].clone();
+
+            assert_eq!(zone.refresh().await, Duration::from_secs(60));
+
+            let www = lower("www.corp.lan.");
+            assert_eq!(
+                zone.lookup(&www, RecordType::A).unwrap().unwrap(),
+                vec![a("www.corp.lan.", "10.0.0.1")]
+            );
+            assert!(matches!(
+                zone.lookup(&lower("ftp.corp.lan."), RecordType::A),
+                Some(Err((_, true)))
+            ));
+            assert!(matches!(
+                zone.lookup(&www, RecordType::AAAA),
+                Some(Err((_, false)))
+            ));
+
+            let name = lower("corp.lan.");
+            assert!(secondary.notify(&name, primary.ip()));
+            assert!(!secondary.notify(&name, "10.9.9.9".parse().unwrap()));
+
+            tasks.shutdown().await;
+        })
+    }
+}
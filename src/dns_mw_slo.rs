@@ -0,0 +1,326 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::dns::*;
+use crate::dns_conf::{LatencySlo, SloScope};
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{info, warn};
+use crate::middleware::*;
+use crate::notify::Notifier;
+
+/// The rolling window is divided into slots, the oldest one is dropped on each rotation.
+const SLOTS: usize = 6;
+/// Too few samples in the window are not evaluated, to avoid false alarms.
+const MIN_SAMPLES: u64 = 20;
+/// The latencies kept per slot, sampled uniformly once more requests recorded.
+const MAX_SAMPLES: usize = 1024;
+
+/// Track the latency of the requests against the objectives, and report the breaches and recoveries.
+pub struct DnsSloMiddleware {
+    trackers: Vec<Arc<SloTracker>>,
+}
+
+impl DnsSloMiddleware {
+    pub fn new(slos: &[LatencySlo], notifier: Notifier, tasks: &BackgroundTasks) -> Self {
+        let trackers = slos
+            .iter()
+            .map(|slo| Arc::new(SloTracker::new(*slo, notifier.clone())))
+            .collect::<Vec<_>>();
+
+        for tracker in trackers.iter() {
+            let tracker = tracker.clone();
+            tasks.spawn(async move {
+                let mut interval = tokio::time::interval(tracker.slot_duration());
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    tracker.rotate();
+                }
+            });
+        }
+
+        Self { trackers }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trackers.is_empty()
+    }
+
+    /// The state of the objectives, printed by `smartdns status`.
+    pub fn status(&self) -> impl Fn() -> String + Send + Sync + 'static {
+        let trackers = self.trackers.clone();
+        move || trackers.iter().map(|tracker| tracker.to_string()).collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsSloMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let start = Instant::now();
+
+        let res = next.run(ctx, req).await;
+
+        let elapsed = start.elapsed();
+
+        for tracker in self.trackers.iter() {
+            tracker.record(&ctx.lookup_source, elapsed);
+        }
+
+        res
+    }
+}
+
+/// The latencies recorded in a slot, a uniform sample of them once too many.
+#[derive(Default)]
+struct Slot {
+    count: u64,
+    samples: Vec<Duration>,
+}
+
+impl Slot {
+    fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        if self.samples.len() < MAX_SAMPLES {
+            self.samples.push(latency);
+        } else {
+            // reservoir sampling, each latency kept with the same probability.
+            let i = rand::thread_rng().gen_range(0..self.count) as usize;
+            if i < MAX_SAMPLES {
+                self.samples[i] = latency;
+            }
+        }
+    }
+}
+
+struct SloTracker {
+    slo: LatencySlo,
+    slots: [Mutex<Slot>; SLOTS],
+    current: AtomicUsize,
+    breached: AtomicBool,
+    notifier: Notifier,
+}
+
+impl SloTracker {
+    fn new(slo: LatencySlo, notifier: Notifier) -> Self {
+        Self {
+            slo,
+            slots: Default::default(),
+            current: Default::default(),
+            breached: Default::default(),
+            notifier,
+        }
+    }
+
+    fn slot_duration(&self) -> Duration {
+        self.slo.window / SLOTS as u32
+    }
+
+    fn record(&self, source: &LookupSource, latency: Duration) {
+        let matched = match self.slo.scope {
+            SloScope::Cache => matches!(source, LookupSource::Cache),
+            SloScope::All => true,
+        };
+
+        if matched {
+            if let Ok(mut slot) = self.slots[self.current.load(Ordering::Relaxed)].lock() {
+                slot.record(latency);
+            }
+        }
+    }
+
+    /// The latency at the percentile over the window, None if too few samples.
+    fn evaluate(&self) -> Option<Duration> {
+        // each sample weighs the requests it stands for in its slot.
+        let mut samples = vec![];
+        let mut count = 0;
+
+        for slot in self.slots.iter() {
+            let slot = match slot.lock() {
+                Ok(slot) => slot,
+                Err(_) => continue,
+            };
+            if slot.samples.is_empty() {
+                continue;
+            }
+            let weight = slot.count as f64 / slot.samples.len() as f64;
+            samples.extend(slot.samples.iter().map(|latency| (*latency, weight)));
+            count += slot.count;
+        }
+
+        if count < MIN_SAMPLES {
+            return None;
+        }
+
+        samples.sort_unstable_by_key(|(latency, _)| *latency);
+
+        let rank = count as f64 * self.slo.percentile as f64 / 100.0;
+        let mut seen = 0.0;
+        for (latency, weight) in samples.iter() {
+            seen += weight;
+            if seen >= rank {
+                return Some(*latency);
+            }
+        }
+
+        samples.last().map(|(latency, _)| *latency)
+    }
+
+    /// Evaluate the window, then drop the oldest slot to record into.
+    fn rotate(&self) {
+        self.check();
+
+        let next = (self.current.load(Ordering::Relaxed) + 1) % SLOTS;
+        if let Ok(mut slot) = self.slots[next].lock() {
+            *slot = Slot::default();
+        }
+        self.current.store(next, Ordering::Relaxed);
+    }
+
+    /// Update the state with the window evaluated, return whether it's breached.
+    fn check(&self) -> bool {
+        let latency = match self.evaluate() {
+            Some(latency) => latency,
+            None => return self.breached.load(Ordering::Relaxed),
+        };
+
+        let breached = latency > self.slo.threshold;
+
+        if self.breached.swap(breached, Ordering::Relaxed) != breached {
+            let message = format!(
+                "{:?} p{} {:?} {} {:?} over {:?}",
+                self.slo.scope,
+                self.slo.percentile,
+                latency,
+                if breached { ">" } else { "<=" },
+                self.slo.threshold,
+                self.slo.window
+            );
+            if breached {
+                warn!("latency slo breached, {}", message);
+                self.notifier.notify("slo-breached", &message);
+            } else {
+                info!("latency slo recovered, {}", message);
+                self.notifier.notify("slo-recovered", &message);
+            }
+        }
+
+        breached
+    }
+}
+
+impl fmt::Display for SloTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scope = match self.slo.scope {
+            SloScope::Cache => "cache",
+            SloScope::All => "all",
+        };
+        write!(
+            f,
+            "latency slo {} p{} {:?} over {:?}: {}",
+            scope,
+            self.slo.percentile,
+            self.slo.threshold,
+            self.slo.window,
+            if self.breached.load(Ordering::Relaxed) {
+                "breached"
+            } else {
+                "ok"
+            }
+        )?;
+        match self.evaluate() {
+            Some(latency) => writeln!(f, ", p{} {:?}", self.slo.percentile, latency),
+            None => writeln!(f, ", too few samples"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_slo(threshold: u64) -> SloTracker {
+        SloTracker::new(
+            LatencySlo {
+                scope: SloScope::Cache,
+                percentile: 95,
+                threshold: Duration::from_millis(threshold),
+                window: Duration::from_secs(60),
+            },
+            Notifier::default(),
+        )
+    }
+
+    #[test]
+    fn test_slo_tracker() {
+        let tracker = cache_slo(30);
+
+        // too few samples.
+        tracker.record(&LookupSource::Cache, Duration::from_millis(100));
+        assert!(!tracker.check());
+
+        for _ in 0..MIN_SAMPLES {
+            tracker.record(&LookupSource::Cache, Duration::from_millis(100));
+            // out of scope.
+            tracker.record(&LookupSource::Static, Duration::from_millis(1));
+        }
+        assert!(tracker.check());
+
+        // recovered once the slow samples are rotated out of the window.
+        for _ in 0..SLOTS {
+            tracker.rotate();
+            for _ in 0..MIN_SAMPLES {
+                tracker.record(&LookupSource::Cache, Duration::from_millis(1));
+            }
+        }
+        assert!(!tracker.check());
+    }
+
+    #[test]
+    fn test_slo_tracker_recorded_latency() {
+        // just over the threshold, no bucket rounding it up or down.
+        let tracker = cache_slo(30);
+        for _ in 0..MIN_SAMPLES {
+            tracker.record(&LookupSource::Cache, Duration::from_millis(31));
+        }
+        assert_eq!(tracker.evaluate(), Some(Duration::from_millis(31)));
+        assert!(tracker.check());
+
+        let tracker = cache_slo(31);
+        for _ in 0..MIN_SAMPLES {
+            tracker.record(&LookupSource::Cache, Duration::from_millis(31));
+        }
+        assert!(!tracker.check());
+
+        // the percentile of the samples, the slow 5% not counted.
+        let tracker = cache_slo(30);
+        for i in 0..100 {
+            let latency = if i < 95 { 10 } else { 500 };
+            tracker.record(&LookupSource::Cache, Duration::from_millis(latency));
+        }
+        assert_eq!(tracker.evaluate(), Some(Duration::from_millis(10)));
+        assert!(!tracker.check());
+        assert_eq!(
+            tracker.to_string(),
+            "latency slo cache p95 30ms over 60s: ok, p95 10ms\n"
+        );
+    }
+
+    #[test]
+    fn test_slot_sampled() {
+        let mut slot = Slot::default();
+        for i in 0..(MAX_SAMPLES * 4) {
+            slot.record(Duration::from_millis(i as u64));
+        }
+        assert_eq!(slot.count, MAX_SAMPLES as u64 * 4);
+        assert_eq!(slot.samples.len(), MAX_SAMPLES);
+    }
+}
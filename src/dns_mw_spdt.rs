@@ -1,7 +1,24 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use trust_dns_client::rr::RecordType;
+
 use crate::dns::*;
+use crate::dns_conf::ResponseMode;
 use crate::middleware::*;
+use crate::speed_check::{sort_by_rtt, SpeedChecker};
 
-pub struct DnsSpeedTestMiddleware;
+/// Probe the ips answered by the upstreams, by the speed check modes of the listener, and
+/// answer the fastest, as the response mode decides.
+pub struct DnsSpeedTestMiddleware {
+    checker: Arc<SpeedChecker>,
+}
+
+impl DnsSpeedTestMiddleware {
+    pub fn new(checker: Arc<SpeedChecker>) -> Self {
+        Self { checker }
+    }
+}
 
 #[async_trait::async_trait]
 impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsSpeedTestMiddleware {
@@ -11,6 +28,55 @@ impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsSpeedTestM
         req: &DnsRequest,
         next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
     ) -> Result<DnsResponse, DnsError> {
-        next.run(ctx, req).await
+        if ctx.speed_checks().is_empty()
+            || ctx.cfg.response_mode == ResponseMode::FastestResponse
+            || !matches!(req.query().query_type(), RecordType::A | RecordType::AAAA)
+        {
+            return next.run(ctx, req).await;
+        }
+
+        let lookup = next.run(ctx, req).await?;
+
+        let ips = lookup
+            .record_iter()
+            .filter_map(|record| record.data().and_then(|data| data.to_ip_addr()))
+            .collect::<Vec<_>>();
+
+        // nothing to choose from.
+        if ips.len() < 2 {
+            return Ok(lookup);
+        }
+
+        let modes = ctx.speed_checks().to_vec();
+
+        let rtts = match ctx.cfg.response_mode {
+            // answered as the upstreams did, not probed.
+            ResponseMode::FastestResponse => return Ok(lookup),
+            // the first reached only, the others not waited for.
+            ResponseMode::FirstPing => match self.checker.first(&ips, &modes).await {
+                Some((ip, rtt)) => HashMap::from([(ip, Some(rtt))]),
+                None => return Ok(lookup),
+            },
+            ResponseMode::FastestIp => {
+                let rtts = self.checker.check(&ips, &modes).await;
+                ips.into_iter().zip(rtts).collect::<HashMap<_, _>>()
+            }
+        };
+
+        if let Some(fastest) = rtts.values().flatten().min() {
+            ctx.fastest_speed = *fastest;
+        }
+
+        let records = sort_by_rtt(
+            lookup.records(),
+            |record| record.data().and_then(|data| data.to_ip_addr()),
+            &rtts,
+        );
+
+        Ok(Lookup::new_with_deadline(
+            lookup.query().clone(),
+            Arc::from(records),
+            lookup.valid_until(),
+        ))
     }
 }
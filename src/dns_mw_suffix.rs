@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use trust_dns_client::op::Query;
+use trust_dns_client::rr::RecordType;
+
+use crate::dns::*;
+use crate::dns_mw_cache::derived_request;
+use crate::middleware::*;
+
+/// Resolve the single-label queries, e.g. `nas`, under the local domain, `nas.lan`, the
+/// answer re-labeled under the queried name, as the clients without a search domain expect.
+/// As dnsmasq, only the local names are expanded, of the hosts, the leases, the zones and the
+/// address rules, the expanded name never sent to the upstreams. The queried name is resolved
+/// as is, if no local name under the local domain.
+pub struct DnsDomainSuffixMiddleware {
+    domain: Name,
+}
+
+impl DnsDomainSuffixMiddleware {
+    pub fn new(domain: Name) -> Self {
+        Self { domain }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsDomainSuffixMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let query = req.query().original();
+
+        if query.name().num_labels() != 1
+            || !matches!(query.query_type(), RecordType::A | RecordType::AAAA)
+        {
+            return next.run(ctx, req).await;
+        }
+
+        let expanded = match query.name().clone().append_domain(&self.domain) {
+            Ok(expanded) => expanded,
+            Err(_) => return next.run(ctx, req).await,
+        };
+
+        let expanded_req =
+            match derived_request(req, Query::query(expanded.clone(), query.query_type())) {
+                Some(expanded_req) => expanded_req,
+                None => return next.run(ctx, req).await,
+            };
+
+        ctx.local_only = true;
+        let expanded_lookup = next.clone().run(ctx, &expanded_req).await;
+        ctx.local_only = false;
+
+        let local = matches!(
+            ctx.lookup_source,
+            LookupSource::Static | LookupSource::Zone(_)
+        );
+
+        match expanded_lookup {
+            Ok(lookup) if local && lookup.records().iter().any(|r| r.data().is_some()) => {
+                Ok(Lookup::new_with_deadline(
+                    query.clone(),
+                    Arc::from(relabel(&expanded, query.name(), lookup.records())),
+                    lookup.valid_until(),
+                ))
+            }
+            _ => {
+                ctx.lookup_source = LookupSource::None;
+                ctx.extended_error = None;
+                next.run(ctx, req).await
+            }
+        }
+    }
+}
+
+/// The records of the expanded name renamed to the queried one, the rest of the chain kept.
+fn relabel(expanded: &Name, name: &Name, records: &[Record]) -> Vec<Record> {
+    records
+        .iter()
+        .map(|record| {
+            let mut record = record.clone();
+            if record.name() == expanded {
+                record.set_name(name.clone());
+            }
+            record
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_relabel() {
+        let name = |s: &str| Name::from_str(s).unwrap();
+
+        let records = vec![
+            Record::from_rdata(name("nas.lan."), 60, RData::CNAME(name("storage.lan."))),
+            Record::from_rdata(
+                name("storage.lan."),
+                60,
+                RData::A("192.168.1.10".parse().unwrap()),
+            ),
+        ];
+
+        let records = relabel(&name("nas.lan."), &name("nas."), &records);
+
+        assert_eq!(records[0].name(), &name("nas."));
+        assert_eq!(records[1].name(), &name("storage.lan."));
+    }
+}
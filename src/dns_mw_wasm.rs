@@ -0,0 +1,302 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use trust_dns_client::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_proto::serialize::binary::BinEncodable;
+use wasmtime::{
+    Config, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+use crate::dns::*;
+use crate::log::warn;
+use crate::middleware::*;
+
+/// The instructions a hook runs at most, so that a looping plugin doesn't hang the query.
+const FUEL: u64 = 10_000_000;
+
+/// The linear memory a plugin grows to at most.
+const MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+/// A plugin hooked into the middleware chain, answering the queries or passing them on, and
+/// rewriting the responses of the ones passed on.
+///
+/// The ABI, the messages in the DNS wire format:
+///   memory: the linear memory exported.
+///   alloc(len: i32) -> i32: reserve the bytes the message is written to.
+///   on_query(ptr: i32, len: i32) -> i64: handle the query, 0 to pass it on,
+///     otherwise `ptr << 32 | len` of the response, e.g. NXDOMAIN to block the domain.
+///   on_response(ptr: i32, len: i32) -> i64: optional, handle the response answered by the
+///     rest of the chain, 0 to keep it, otherwise `ptr << 32 | len` of the replacement.
+///
+/// The hooks run on the blocking threads, each call in a fresh instance.
+pub struct DnsWasmMiddleware {
+    plugin: Arc<Plugin>,
+}
+
+struct Plugin {
+    name: String,
+    engine: Engine,
+    instance: InstancePre<StoreLimits>,
+    response_hook: bool,
+}
+
+impl DnsWasmMiddleware {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let engine = new_engine()?;
+        let module = Module::from_file(&engine, path).map_err(plugin_error)?;
+        Self::new(path.display().to_string(), engine, module)
+    }
+
+    fn new(name: String, engine: Engine, module: Module) -> io::Result<Self> {
+        let response_hook = module.get_export("on_response").is_some();
+        let instance = Linker::new(&engine)
+            .instantiate_pre(&module)
+            .map_err(plugin_error)?;
+
+        Ok(Self {
+            plugin: Arc::new(Plugin {
+                name,
+                engine,
+                instance,
+                response_hook,
+            }),
+        })
+    }
+
+    /// Call the hook off the runtime, the plugin may run for a while.
+    async fn call(&self, hook: &'static str, message: Message) -> io::Result<Option<Message>> {
+        let plugin = self.plugin.clone();
+        let bytes = message.to_bytes().map_err(plugin_error)?;
+
+        let response = tokio::task::spawn_blocking(move || plugin.call(hook, &bytes))
+            .await
+            .map_err(plugin_error)??;
+
+        response
+            .map(|bytes| Message::from_vec(&bytes).map_err(plugin_error))
+            .transpose()
+    }
+}
+
+impl Plugin {
+    /// Call the hook with the message, in a fresh instance.
+    fn call(&self, hook: &str, message: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY)
+            .instances(1)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.add_fuel(FUEL).map_err(plugin_error)?;
+
+        let instance = self
+            .instance
+            .instantiate(&mut store)
+            .map_err(plugin_error)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| plugin_error("memory not exported"))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(plugin_error)?;
+        let hook = instance
+            .get_typed_func::<(i32, i32), i64>(&mut store, hook)
+            .map_err(plugin_error)?;
+
+        let len = message.len() as i32;
+        let ptr = alloc.call(&mut store, len).map_err(plugin_error)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, message)
+            .map_err(plugin_error)?;
+
+        let packed = hook.call(&mut store, (ptr, len)).map_err(plugin_error)?;
+        if packed == 0 {
+            return Ok(None);
+        }
+
+        let (ptr, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+        let mut response = vec![0; len];
+        memory
+            .read(&store, ptr, &mut response)
+            .map_err(plugin_error)?;
+
+        Ok(Some(response))
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsWasmMiddleware {
+    async fn handle(
+        &self,
+        ctx: &mut DnsContext,
+        req: &DnsRequest,
+        next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+    ) -> Result<DnsResponse, DnsError> {
+        let query = req.query().original().to_owned();
+
+        let mut message = Message::new();
+        message
+            .set_id(req.id())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(query.clone());
+
+        // the plugin fails open, the query is resolved as if not hooked.
+        match self.call("on_query", message.clone()).await {
+            Ok(Some(response)) => {
+                ctx.lookup_source = LookupSource::Static;
+                return to_response(query, response);
+            }
+            Ok(None) => (),
+            Err(err) => {
+                warn!("plugin {} failed on {}, {}", self.plugin.name, query, err);
+                return next.run(ctx, req).await;
+            }
+        }
+
+        let lookup = next.run(ctx, req).await?;
+        if !self.plugin.response_hook {
+            return Ok(lookup);
+        }
+
+        message
+            .set_message_type(MessageType::Response)
+            .add_answers(lookup.records().iter().cloned());
+
+        match self.call("on_response", message).await {
+            Ok(Some(response)) => to_response(query, response),
+            Ok(None) => Ok(lookup),
+            Err(err) => {
+                warn!("plugin {} failed on {}, {}", self.plugin.name, query, err);
+                Ok(lookup)
+            }
+        }
+    }
+}
+
+/// The lookup of the response of a plugin.
+fn to_response(query: Query, response: Message) -> Result<DnsResponse, DnsError> {
+    match response.response_code() {
+        ResponseCode::NoError if !response.answers().is_empty() => Ok(Lookup::new_with_max_ttl(
+            query,
+            Arc::from(response.answers().to_vec()),
+        )),
+        response_code => Err(ResolveErrorKind::NoRecordsFound {
+            query: query.into(),
+            soa: None,
+            negative_ttl: None,
+            response_code,
+            trusted: true,
+        }
+        .into()),
+    }
+}
+
+fn new_engine() -> io::Result<Engine> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    Engine::new(&config).map_err(plugin_error)
+}
+
+fn plugin_error<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::serialize::binary::BinDecodable;
+
+    use super::*;
+
+    fn load_wat(wat: &str) -> DnsWasmMiddleware {
+        let engine = new_engine().unwrap();
+        let module = Module::new(&engine, wat).unwrap();
+        DnsWasmMiddleware::new("test".to_string(), engine, module).unwrap()
+    }
+
+    #[test]
+    fn test_plugin_pass() {
+        let plugin = load_wat(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "on_query") (param i32 i32) (result i64) i64.const 0))"#,
+        );
+
+        assert_eq!(plugin.plugin.call("on_query", b"query").unwrap(), None);
+    }
+
+    #[test]
+    fn test_plugin_answer() {
+        let mut response = Message::new();
+        response
+            .set_message_type(MessageType::Response)
+            .add_answer(Record::from_rdata(
+                Name::from_str("example.com.").unwrap(),
+                300,
+                RData::A("192.0.2.1".parse().unwrap()),
+            ));
+        let bytes = response.to_bytes().unwrap();
+        let data = bytes
+            .iter()
+            .map(|b| format!("\\{:02x}", b))
+            .collect::<String>();
+
+        // answers the response in the data segment.
+        let plugin = load_wat(&format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{}")
+                (func (export "alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "on_query") (param i32 i32) (result i64) i64.const {}))"#,
+            data,
+            bytes.len()
+        ));
+
+        let answer = plugin.plugin.call("on_query", b"query").unwrap().unwrap();
+        assert_eq!(
+            Message::from_bytes(&answer).unwrap().answers(),
+            response.answers()
+        );
+
+        // looping forever, out of fuel.
+        let plugin = load_wat(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "on_query") (param i32 i32) (result i64) (loop br 0) i64.const 0))"#,
+        );
+        assert!(plugin.plugin.call("on_query", b"query").is_err());
+    }
+
+    #[test]
+    fn test_plugin_limits() {
+        // 32 MiB of memory, over the limit.
+        let plugin = load_wat(
+            r#"(module
+                (memory (export "memory") 512)
+                (func (export "alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "on_query") (param i32 i32) (result i64) i64.const 0))"#,
+        );
+        assert!(!plugin.plugin.response_hook);
+        assert!(plugin.plugin.call("on_query", b"query").is_err());
+
+        let plugin = load_wat(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "on_query") (param i32 i32) (result i64) i64.const 0)
+                (func (export "on_response") (param i32 i32) (result i64) i64.const 0))"#,
+        );
+        assert!(plugin.plugin.response_hook);
+        assert_eq!(
+            plugin.plugin.call("on_response", b"response").unwrap(),
+            None
+        );
+    }
+}
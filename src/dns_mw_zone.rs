@@ -1,7 +1,40 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use trust_dns_client::rr::{LowerName, RecordType};
+
 use crate::dns::*;
+use crate::dns_conf::SmartDnsConfig;
 use crate::middleware::*;
 
-pub struct DnsZoneMiddleware;
+/// Answer the local records, e.g. `srv-record`, rather than resolved by the upstreams, the
+/// other types of their names passed on, e.g. to the A of an address rule of the same name.
+pub struct DnsZoneMiddleware {
+    records: HashMap<LowerName, Vec<RData>>,
+}
+
+impl DnsZoneMiddleware {
+    pub fn new(cfg: &SmartDnsConfig) -> Self {
+        let mut records = HashMap::<_, Vec<_>>::new();
+        for record in cfg.local_records.iter() {
+            records
+                .entry(LowerName::from(&record.name))
+                .or_default()
+                .push(record.rdata.clone());
+        }
+        Self { records }
+    }
+
+    /// The local records of the name of the type.
+    pub(crate) fn lookup(&self, name: &LowerName, record_type: RecordType) -> Vec<&RData> {
+        self.records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|rdata| rdata.to_record_type() == record_type)
+            .collect()
+    }
+}
 
 #[async_trait::async_trait]
 impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsZoneMiddleware {
@@ -11,6 +44,20 @@ impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for DnsZoneMiddle
         req: &DnsRequest,
         next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
     ) -> Result<DnsResponse, DnsError> {
-        next.run(ctx, req).await
+        let query = req.query().original().to_owned();
+        let ttl = ctx.cfg.rr_ttl() as u32;
+
+        let records = self
+            .lookup(req.query().name(), query.query_type())
+            .into_iter()
+            .map(|rdata| Record::from_rdata(query.name().to_owned(), ttl, rdata.clone()))
+            .collect::<Vec<_>>();
+
+        if records.is_empty() {
+            return next.run(ctx, req).await;
+        }
+
+        ctx.lookup_source = LookupSource::Static;
+        Ok(Lookup::new_with_max_ttl(query, Arc::from(records)))
     }
 }
@@ -0,0 +1,153 @@
+use std::fmt;
+use std::str::FromStr;
+
+use trust_dns_proto::op::Edns;
+use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use trust_dns_proto::serialize::binary::BinEncodable;
+use trust_dns_proto::xfer::DnsRequest;
+
+/// The block length recommended for queries, see RFC 8467, Section 4.1.
+const DEFAULT_BLOCK_LENGTH: u16 = 128;
+
+/// The block length recommended for responses.
+pub const RESPONSE_BLOCK_LENGTH: u16 = 468;
+
+/// How the queries sent over encrypted upstreams are padded (RFC 7830),
+/// so that their sizes leak nothing about the names queried, e.g. `block:128`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    /// send the queries unpadded.
+    None,
+    /// pad the queries to a multiple of the block length.
+    Block(u16),
+}
+
+impl Default for PaddingPolicy {
+    fn default() -> Self {
+        Self::Block(DEFAULT_BLOCK_LENGTH)
+    }
+}
+
+impl PaddingPolicy {
+    /// Attach the padding option to the request, sized after all the other options.
+    pub fn apply(&self, request: &mut DnsRequest) {
+        if *self == Self::None {
+            return;
+        }
+
+        request
+            .extensions_mut()
+            .get_or_insert_with(|| {
+                let mut edns = Edns::new();
+                edns.set_max_payload(1232);
+                edns
+            })
+            .options_mut()
+            .insert(EdnsOption::Unknown(EdnsCode::Padding.into(), vec![]));
+
+        let len = match request.to_vec() {
+            Ok(bytes) => bytes.len(),
+            Err(_) => return,
+        };
+
+        if let Some(edns) = request.extensions_mut().as_mut() {
+            edns.options_mut().insert(EdnsOption::Unknown(
+                EdnsCode::Padding.into(),
+                vec![0; self.padding_len(len)],
+            ));
+        }
+    }
+
+    /// The padding length of the message, which is encoded with an empty padding option.
+    pub fn padding_len(&self, len: usize) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Block(block) => {
+                let block = *block as usize;
+                (block - len % block) % block
+            }
+        }
+    }
+}
+
+impl FromStr for PaddingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (policy, block) = match s.split_once(':') {
+            Some((policy, block)) => (policy, Some(block)),
+            None => (s, None),
+        };
+
+        match (policy.to_ascii_lowercase().as_str(), block) {
+            ("none", None) => Ok(Self::None),
+            ("block", None) => Ok(Self::Block(DEFAULT_BLOCK_LENGTH)),
+            ("block", Some(block)) => block
+                .parse::<u16>()
+                .ok()
+                .filter(|b| *b > 0)
+                .map(Self::Block)
+                .ok_or_else(|| format!("invalid padding block length {}", s)),
+            _ => Err(format!("invalid padding policy {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for PaddingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Block(block) => write!(f, "block:{}", block),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns_proto::op::{Message, Query};
+    use trust_dns_proto::rr::{Name, RecordType};
+    use trust_dns_proto::xfer::DnsRequestOptions;
+
+    fn request(name: &str) -> DnsRequest {
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        DnsRequest::new(message, DnsRequestOptions::default())
+    }
+
+    #[test]
+    fn test_parse_padding_policy() {
+        assert_eq!(PaddingPolicy::from_str("none"), Ok(PaddingPolicy::None));
+        assert_eq!(
+            PaddingPolicy::from_str("block"),
+            Ok(PaddingPolicy::Block(128))
+        );
+        assert_eq!(
+            PaddingPolicy::from_str("block:468"),
+            Ok(PaddingPolicy::Block(468))
+        );
+        assert!(PaddingPolicy::from_str("block:0").is_err());
+        assert!(PaddingPolicy::from_str("random").is_err());
+    }
+
+    #[test]
+    fn test_padding_block() {
+        for name in [
+            "a.com.",
+            "www.example.com.",
+            "a-much-longer-name.example.org.",
+        ] {
+            let mut req = request(name);
+            PaddingPolicy::Block(128).apply(&mut req);
+            assert_eq!(req.to_vec().unwrap().len(), 128);
+
+            // applied again, the padding is resized rather than added.
+            PaddingPolicy::Block(128).apply(&mut req);
+            assert_eq!(req.to_vec().unwrap().len(), 128);
+        }
+
+        let mut req = request("www.example.com.");
+        PaddingPolicy::None.apply(&mut req);
+        assert!(req.extensions().is_none());
+    }
+}
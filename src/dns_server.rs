@@ -1,11 +1,21 @@
 use cfg_if::cfg_if;
 use futures::Future;
 
+use std::fmt;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use tokio::sync::OwnedSemaphorePermit;
 
 use crate::log::{debug, error, info, warn};
-use trust_dns_client::op::{Edns, Header, MessageType, OpCode, ResponseCode};
+use trust_dns_client::op::{Edns, Header, Message, MessageType, OpCode, ResponseCode};
+use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
 use trust_dns_proto::rr::Record;
+use trust_dns_proto::serialize::binary::BinEncodable;
+use trust_dns_resolver::error::ResolveErrorKind;
 pub use trust_dns_server::server::Request;
 pub use trust_dns_server::ServerFuture;
 use trust_dns_server::{
@@ -13,21 +23,381 @@ use trust_dns_server::{
         AuthLookup, EmptyLookup, LookupError, LookupObject, LookupOptions, MessageResponse,
         MessageResponseBuilder, ZoneType,
     },
-    server::{RequestHandler, ResponseHandler, ResponseInfo},
+    server::{Protocol, RequestHandler, ResponseHandler, ResponseInfo},
     store::forwarder::ForwardLookup,
 };
 
-use crate::dns::DnsRequest;
+use crate::dns::{DnsRequest, ExtendedError};
+use crate::dns_conf::{Acl, QueryLimit, RateLimit, RateLimitAction, ServerOpts};
 use crate::dns_mw::DnsMiddlewareHandler;
+use crate::dns_padding::{PaddingPolicy, RESPONSE_BLOCK_LENGTH};
+use crate::infra::concurrency::{ConcurrencyLimiter, Rejected};
+use crate::infra::drain::Drain;
+use crate::infra::rate_limit::RateLimiter;
+
+/// Limits on inbound messages, a message exceeding them is dropped without any response
+/// before it enters the middleware chain, where the cache and upstreams are involved.
+///
+/// The size and the edns options are checked by the listeners on the bytes received, before
+/// parsing, see `admit`, the rest once parsed, see `check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    pub max_message_size: usize,
+    pub max_queries: usize,
+    pub max_labels: u8,
+    pub max_edns_options: usize,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: 4096,
+            max_queries: 1,
+            // enough for the reverse lookup of ipv6, that's 34 labels.
+            max_labels: 40,
+            max_edns_options: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    Oversized,
+    TooManyQueries,
+    TooManyLabels,
+    TooManyEdnsOptions,
+}
+
+impl MessageLimits {
+    /// Check the message as received, the edns options counted as sent, the repeated ones
+    /// too, which the parser keeps only one of.
+    pub fn check_bytes(&self, bytes: &[u8]) -> Result<(), DropReason> {
+        if bytes.len() > self.max_message_size {
+            return Err(DropReason::Oversized);
+        }
+
+        if edns_options(bytes).unwrap_or_default() > self.max_edns_options {
+            return Err(DropReason::TooManyEdnsOptions);
+        }
+
+        Ok(())
+    }
+
+    /// Whether the message received from the client is parsed and answered, dropped and
+    /// counted otherwise.
+    pub fn admit(&self, bytes: &[u8], src: SocketAddr) -> bool {
+        match self.check_bytes(bytes) {
+            Ok(()) => true,
+            Err(reason) => {
+                debug!("drop message from {}, {:?}", src, reason);
+                DROPPED_MESSAGES.inc(reason);
+                false
+            }
+        }
+    }
+
+    pub fn check(&self, request: &Request) -> Result<(), DropReason> {
+        if request.queries().len() > self.max_queries {
+            return Err(DropReason::TooManyQueries);
+        }
+
+        if request
+            .queries()
+            .iter()
+            .any(|q| q.name().num_labels() > self.max_labels)
+        {
+            return Err(DropReason::TooManyLabels);
+        }
+
+        // the distinct ones at least, e.g. of the udp sockets trust-dns answers.
+        if let Some(edns) = request.edns() {
+            if edns.options().as_ref().len() > self.max_edns_options {
+                return Err(DropReason::TooManyEdnsOptions);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The number of the options of the OPT record, none if the message has none or is too
+/// malformed to tell, the parser then rejecting it anyway.
+fn edns_options(bytes: &[u8]) -> Option<usize> {
+    const HEADER_LEN: usize = 12;
+    const OPT: u16 = 41;
+
+    let u16_at = |at: usize| {
+        bytes
+            .get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+
+    let queries = u16_at(4)? as usize;
+    let records = u16_at(6)? as usize + u16_at(8)? as usize;
+    let additionals = u16_at(10)? as usize;
+
+    let mut at = HEADER_LEN;
+    for _ in 0..queries {
+        // the type and the class.
+        at = skip_name(bytes, at)? + 4;
+    }
+
+    for i in 0..records + additionals {
+        at = skip_name(bytes, at)?;
+        let rtype = u16_at(at)?;
+        let rdlength = u16_at(at + 8)? as usize;
+        at += 10;
+
+        if i >= records && rtype == OPT {
+            let rdata = bytes.get(at..at + rdlength)?;
+            let mut count = 0;
+            let mut option = 0;
+            while option + 4 <= rdata.len() {
+                let len = u16::from_be_bytes([rdata[option + 2], rdata[option + 3]]) as usize;
+                option += 4 + len;
+                count += 1;
+            }
+            return Some(count);
+        }
+
+        at += rdlength;
+    }
+
+    None
+}
+
+/// The offset following the name, a pointer ending it.
+fn skip_name(bytes: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        match *bytes.get(at)? as usize {
+            0 => return Some(at + 1),
+            len if len & 0xc0 == 0xc0 => return Some(at + 2),
+            len => at += 1 + len,
+        }
+    }
+}
+
+/// The counters of inbound messages dropped by the limits.
+#[derive(Debug)]
+pub struct DropMetrics {
+    oversized: AtomicU64,
+    too_many_queries: AtomicU64,
+    too_many_labels: AtomicU64,
+    too_many_edns_options: AtomicU64,
+}
+
+pub static DROPPED_MESSAGES: DropMetrics = DropMetrics {
+    oversized: AtomicU64::new(0),
+    too_many_queries: AtomicU64::new(0),
+    too_many_labels: AtomicU64::new(0),
+    too_many_edns_options: AtomicU64::new(0),
+};
+
+impl DropMetrics {
+    fn counter(&self, reason: DropReason) -> &AtomicU64 {
+        match reason {
+            DropReason::Oversized => &self.oversized,
+            DropReason::TooManyQueries => &self.too_many_queries,
+            DropReason::TooManyLabels => &self.too_many_labels,
+            DropReason::TooManyEdnsOptions => &self.too_many_edns_options,
+        }
+    }
 
+    #[inline]
+    fn inc(&self, reason: DropReason) {
+        self.counter(reason).fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counter(reason).load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        [
+            DropReason::Oversized,
+            DropReason::TooManyQueries,
+            DropReason::TooManyLabels,
+            DropReason::TooManyEdnsOptions,
+        ]
+        .into_iter()
+        .map(|reason| self.get(reason))
+        .sum()
+    }
+}
+
+/// The counters as printed by `smartdns status`.
+impl fmt::Display for DropMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "dropped messages: {}", self.total())?;
+        for (reason, name) in [
+            (DropReason::Oversized, "oversized"),
+            (DropReason::TooManyQueries, "too many queries"),
+            (DropReason::TooManyLabels, "too many labels"),
+            (DropReason::TooManyEdnsOptions, "too many edns options"),
+        ] {
+            writeln!(f, "  {:<24}{}", name, self.get(reason))?;
+        }
+        Ok(())
+    }
+}
+
+/// The rate limit of the clients, shared by all the listeners.
+#[derive(Debug)]
+pub struct ClientRateLimit {
+    config: RateLimit,
+    limiter: RateLimiter,
+}
+
+impl ClientRateLimit {
+    pub fn new(config: RateLimit) -> Self {
+        Self {
+            limiter: RateLimiter::new(config.qps, config.burst),
+            config,
+        }
+    }
+
+    fn check(&self, ip: IpAddr) -> bool {
+        self.config.exempt.iter().any(|net| net.contains(&ip))
+            || self.limiter.check(ip, Instant::now())
+    }
+}
+
+/// The middlewares of the configuration, swapped as it's reloaded, shared by all the
+/// listeners. The queries in flight finish with the middlewares they started with.
+#[derive(Clone)]
+pub struct ReloadableHandler(Arc<RwLock<Arc<DnsMiddlewareHandler>>>);
+
+impl ReloadableHandler {
+    fn new(handler: DnsMiddlewareHandler) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(handler))))
+    }
+
+    pub fn current(&self) -> Arc<DnsMiddlewareHandler> {
+        match self.0.read() {
+            Ok(handler) => handler.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn replace(&self, handler: DnsMiddlewareHandler) {
+        let handler = Arc::new(handler);
+        match self.0.write() {
+            Ok(mut current) => *current = handler,
+            Err(poisoned) => *poisoned.into_inner() = handler,
+        }
+    }
+}
+
+/// Cheap to clone, the udp sockets and tcp listeners share the handler.
+#[derive(Clone)]
 pub struct MiddlewareBasedRequestHandler {
-    handler: DnsMiddlewareHandler,
+    handler: ReloadableHandler,
+    limits: MessageLimits,
+    server_opts: Arc<ServerOpts>,
+    /// the clients not allowed are refused, before the middlewares run.
+    acl: Option<Arc<Acl>>,
+    rate_limit: Option<Arc<ClientRateLimit>>,
+    /// the queries answered at once, by all the listeners.
+    query_limit: Option<Arc<ConcurrencyLimiter>>,
+    /// the queries answered at once, by this listener.
+    listener_query_limit: Option<Arc<ConcurrencyLimiter>>,
+    /// the queries in flight, the new ones are dropped once shutting down.
+    drain: Arc<Drain>,
 }
 
 impl MiddlewareBasedRequestHandler {
     pub fn new(handler: DnsMiddlewareHandler) -> Self {
-        Self { handler }
+        Self {
+            handler: ReloadableHandler::new(handler),
+            limits: Default::default(),
+            server_opts: Default::default(),
+            acl: None,
+            rate_limit: None,
+            query_limit: None,
+            listener_query_limit: None,
+            drain: Default::default(),
+        }
+    }
+
+    /// The middlewares of all the listeners, to be replaced on reload.
+    pub fn reloadable(&self) -> ReloadableHandler {
+        self.handler.clone()
+    }
+
+    pub fn with_limits(mut self, limits: MessageLimits) -> Self {
+        self.limits = limits;
+        self
     }
+
+    /// The handler of a listener, sharing the middlewares with the others.
+    pub fn with_server_opts(mut self, server_opts: ServerOpts) -> Self {
+        self.server_opts = Arc::new(server_opts);
+        self
+    }
+
+    pub fn with_acl(mut self, acl: Option<Acl>) -> Self {
+        self.acl = acl.map(Arc::new);
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: Option<RateLimit>) -> Self {
+        self.rate_limit = rate_limit.map(|r| Arc::new(ClientRateLimit::new(r)));
+        self
+    }
+
+    pub fn with_drain(mut self, drain: Arc<Drain>) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    pub fn with_query_limit(mut self, query_limit: Option<QueryLimit>) -> Self {
+        self.query_limit = query_limit.map(concurrency_limiter);
+        self
+    }
+
+    /// The handler of a listener, limited on its own along with the others.
+    pub fn with_listener_query_limit(mut self, query_limit: Option<QueryLimit>) -> Self {
+        self.listener_query_limit = query_limit.map(concurrency_limiter);
+        self
+    }
+
+    /// Wait for the turn of the listener, then the one of all listeners.
+    async fn acquire_permits(&self) -> Result<Vec<OwnedSemaphorePermit>, Rejected> {
+        let mut permits = Vec::with_capacity(2);
+        for limiter in [&self.listener_query_limit, &self.query_limit]
+            .into_iter()
+            .flatten()
+        {
+            permits.push(limiter.acquire().await?);
+        }
+        Ok(permits)
+    }
+}
+
+fn concurrency_limiter(limit: QueryLimit) -> Arc<ConcurrencyLimiter> {
+    Arc::new(ConcurrencyLimiter::new(
+        limit.max_inflight,
+        limit.queue,
+        limit.timeout,
+    ))
+}
+
+/// The option code of the extended dns errors, see RFC 8914.
+const EDNS_EXTENDED_ERROR: u16 = 15;
+
+/// An extended dns error, with the text explaining it to the operators.
+fn extended_error(info_code: u16, text: &str) -> EdnsOption {
+    let mut data = info_code.to_be_bytes().to_vec();
+    data.extend_from_slice(text.as_bytes());
+    EdnsOption::Unknown(EDNS_EXTENDED_ERROR, data)
+}
+
+/// The edns of the answers refused before resolving, telling the extended error only.
+fn extended_error_edns(info_code: u16, text: &str) -> Edns {
+    let mut edns = Edns::new();
+    edns.options_mut().insert(extended_error(info_code, text));
+    edns
 }
 
 #[async_trait::async_trait]
@@ -37,6 +407,111 @@ impl RequestHandler for MiddlewareBasedRequestHandler {
         request: &Request,
         mut response_handle: R,
     ) -> ResponseInfo {
+        // unanswered, so that the clients retry the other resolvers.
+        let _inflight = match self.drain.enter() {
+            Some(inflight) => inflight,
+            None => {
+                debug!(
+                    "drop message {} from {}, shutting down",
+                    request.id(),
+                    request.src()
+                );
+                return ResponseInfo::serve_failed();
+            }
+        };
+
+        // the middlewares of the configuration loaded as the query arrived.
+        let handler = self.handler.current();
+
+        if let Err(reason) = self.limits.check(request) {
+            debug!(
+                "drop message {} from {}, {:?}",
+                request.id(),
+                request.src(),
+                reason
+            );
+            DROPPED_MESSAGES.inc(reason);
+            return ResponseInfo::serve_failed();
+        }
+
+        if let Some(acl) = self.acl.as_ref() {
+            if !acl.allows(request.src().ip()) {
+                debug!("refuse message {} from {}", request.id(), request.src());
+                let mut header = Header::response_from_request(request.header());
+                header.set_response_code(ResponseCode::Refused);
+
+                let mut response = MessageResponseBuilder::from_message_request(request);
+                if request.edns().is_some() {
+                    response.edns(extended_error_edns(
+                        ExtendedError::PROHIBITED,
+                        "client not allowed",
+                    ));
+                }
+                return response_handle
+                    .send_response(response.build_no_records(header))
+                    .await
+                    .unwrap_or_else(|_| ResponseInfo::serve_failed());
+            }
+        }
+
+        if let Some(rate_limit) = self.rate_limit.as_ref() {
+            if !rate_limit.check(request.src().ip()) {
+                debug!("rate limit message {} from {}", request.id(), request.src());
+                let mut header = Header::response_from_request(request.header());
+
+                let mut response = MessageResponseBuilder::from_message_request(request);
+
+                match rate_limit.config.action {
+                    // the real clients retry over tcp, where the answers can't be reflected.
+                    RateLimitAction::Truncate if request.protocol() == Protocol::Udp => {
+                        header.set_truncated(true);
+                    }
+                    _ => {
+                        header.set_response_code(ResponseCode::Refused);
+                        if request.edns().is_some() {
+                            response
+                                .edns(extended_error_edns(ExtendedError::OTHER, "rate limited"));
+                        }
+                    }
+                }
+
+                return response_handle
+                    .send_response(response.build_no_records(header))
+                    .await
+                    .unwrap_or_else(|_| ResponseInfo::serve_failed());
+            }
+        }
+
+        // held until answered.
+        let _permits = match self.acquire_permits().await {
+            Ok(permits) => permits,
+            Err(rejected) => {
+                debug!(
+                    "fail message {} from {}, {:?}",
+                    request.id(),
+                    request.src(),
+                    rejected
+                );
+                let mut header = Header::response_from_request(request.header());
+                header.set_response_code(ResponseCode::ServFail);
+
+                let mut response = MessageResponseBuilder::from_message_request(request);
+                // only the clients understanding edns are told why.
+                if request.edns().is_some() {
+                    let text = match rejected {
+                        Rejected::QueueFull => "query queue full",
+                        Rejected::Timeout => "query queue timeout",
+                    };
+                    response.edns(extended_error_edns(ExtendedError::OTHER, text));
+                }
+
+                return response_handle
+                    .send_response(response.build_no_records(header))
+                    .await
+                    .unwrap_or_else(|_| ResponseInfo::serve_failed());
+            }
+        };
+
         let result = match request.message_type() {
             // TODO think about threading query lookups for multiple lookups, this could be a huge improvement
             //  especially for recursive lookups
@@ -57,7 +532,13 @@ impl RequestHandler for MiddlewareBasedRequestHandler {
                         // TODO: what version are we?
                         let our_version = 0;
                         resp_edns.set_dnssec_ok(true);
-                        resp_edns.set_max_payload(req_edns.max_payload().max(512));
+                        // the udp answers are truncated to it, so the smaller of the client's and ours.
+                        resp_edns.set_max_payload(
+                            req_edns
+                                .max_payload()
+                                .max(512)
+                                .min(handler.cfg.edns_packet_max()),
+                        );
                         resp_edns.set_version(our_version);
                         if req_edns.version() > our_version {
                             warn!(
@@ -112,7 +593,7 @@ impl RequestHandler for MiddlewareBasedRequestHandler {
 
                             let request_header = request.header();
 
-                            let (response_header, sections) = async {
+                            let (response_header, sections, local_error) = async {
                                 let lookup_options = lookup_options_for_edns(request.edns());
 
                                 // log algorithms being requested
@@ -132,11 +613,16 @@ impl RequestHandler for MiddlewareBasedRequestHandler {
 
                                 // let future = self.dns_server.search(request_info, lookup_options);
 
+                                let mut local_error = None;
+
                                 let future = async {
                                     let req: &DnsRequest = request;
 
+                                    let (res, error) = handler.search(req, &self.server_opts).await;
+                                    local_error = error;
+
                                     let lookup_result: Result<Box<dyn LookupObject>, LookupError> =
-                                        match self.handler.search(req).await {
+                                        match res {
                                             Ok(lookup) => Ok(Box::new(ForwardLookup(lookup))),
                                             Err(err) => Err(LookupError::ResolveError(err)),
                                         };
@@ -151,10 +637,25 @@ impl RequestHandler for MiddlewareBasedRequestHandler {
                                 )
                                 .await;
 
-                                (response_header, sections)
+                                (response_header, sections, local_error)
                             }
                             .await;
 
+                            let mut response_edns = response_edns.clone();
+
+                            if let Some(edns) = response_edns.as_mut() {
+                                #[cfg(feature = "dnssec")]
+                                add_supported_algorithms(edns);
+                                if let Some(error) = local_error {
+                                    edns.options_mut()
+                                        .insert(extended_error(error.info_code, &error.text));
+                                }
+                                // the last, so that the padding covers all the other options.
+                                if is_padding_requested(request) {
+                                    pad_response(edns, request, &response_header, &sections);
+                                }
+                            }
+
                             let response = MessageResponseBuilder::from_message_request(request)
                                 .build(
                                     response_header,
@@ -164,12 +665,9 @@ impl RequestHandler for MiddlewareBasedRequestHandler {
                                     sections.additionals.iter(),
                                 );
 
-                            let result = send_response(
-                                response_edns.clone(),
-                                response,
-                                response_handle.clone(),
-                            )
-                            .await;
+                            let result =
+                                send_response(response_edns, response, response_handle.clone())
+                                    .await;
 
                             match result {
                                 Err(e) => {
@@ -192,6 +690,33 @@ impl RequestHandler for MiddlewareBasedRequestHandler {
                     // self.update(request, response_edns, response_handle).await
                     todo!()
                 }
+                OpCode::Notify => {
+                    // the serial of a secondary zone checked at once, if notified by one of
+                    // its primaries, the others refused (RFC 1996).
+                    let zone = request.queries().first().map(|q| q.name());
+                    let accepted =
+                        zone.map_or(false, |zone| handler.notify(zone, request.src().ip()));
+
+                    info!(
+                        "notify received: {} from {}, {:?}, {}",
+                        request.id(),
+                        request.src(),
+                        zone.map(|zone| zone.to_string()),
+                        if accepted { "accepted" } else { "refused" }
+                    );
+                    let response = MessageResponseBuilder::from_message_request(request);
+
+                    response_handle
+                        .send_response(response.error_msg(
+                            request.header(),
+                            if accepted {
+                                ResponseCode::NoError
+                            } else {
+                                ResponseCode::Refused
+                            },
+                        ))
+                        .await
+                }
                 c => {
                     warn!("unimplemented op_code: {:?}", c);
                     let response = MessageResponseBuilder::from_message_request(request);
@@ -246,6 +771,8 @@ async fn send_forwarded_response(
             Err(e) => {
                 if e.is_nx_domain() {
                     response_header.set_response_code(ResponseCode::NXDomain);
+                } else if let Some(response_code) = rejected(&e) {
+                    response_header.set_response_code(response_code);
                 }
                 debug!("error resolving: {}", e);
                 Box::new(EmptyLookup)
@@ -262,6 +789,24 @@ async fn send_forwarded_response(
     }
 }
 
+/// The response code of the query rejected locally, e.g. blocked by the refused blocking
+/// mode, of a record type denied, or of a secondary zone not transferred.
+fn rejected(err: &LookupError) -> Option<ResponseCode> {
+    match err {
+        LookupError::ResolveError(err) => match err.kind() {
+            ResolveErrorKind::NoRecordsFound {
+                response_code:
+                    response_code @ (ResponseCode::Refused
+                    | ResponseCode::NotImp
+                    | ResponseCode::ServFail),
+                ..
+            } => Some(*response_code),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 struct LookupSections {
     answers: Box<dyn LookupObject>,
     ns: Box<dyn LookupObject>,
@@ -269,9 +814,40 @@ struct LookupSections {
     additionals: Box<dyn LookupObject>,
 }
 
+/// Whether the client asks for padded responses, over an encrypted transport, see RFC 7830.
+fn is_padding_requested(request: &Request) -> bool {
+    matches!(request.protocol(), Protocol::Tls | Protocol::Https)
+        && request
+            .edns()
+            .map(|edns| edns.option(EdnsCode::Padding).is_some())
+            .unwrap_or_default()
+}
+
+/// Pad the response to a multiple of the block length recommended, see RFC 8467, Section 4.1.
+/// The response is encoded once with an empty padding option, to measure its length.
+fn pad_response(edns: &mut Edns, request: &Request, header: &Header, sections: &LookupSections) {
+    let mut message = Message::new();
+    message
+        .set_header(*header)
+        .add_queries(request.queries().iter().map(|q| q.original().clone()))
+        .add_answers(sections.answers.iter().cloned())
+        .add_name_servers(sections.ns.iter().chain(sections.soa.iter()).cloned())
+        .add_additionals(sections.additionals.iter().cloned());
+
+    edns.options_mut()
+        .insert(EdnsOption::Unknown(EdnsCode::Padding.into(), vec![]));
+    message.set_edns(edns.clone());
+
+    if let Ok(bytes) = message.to_vec() {
+        let len = PaddingPolicy::Block(RESPONSE_BLOCK_LENGTH).padding_len(bytes.len());
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(EdnsCode::Padding.into(), vec![0; len]));
+    }
+}
+
 async fn send_response<'a, R: ResponseHandler>(
-    _response_edns: Option<Edns>,
-    response: MessageResponse<
+    response_edns: Option<Edns>,
+    mut response: MessageResponse<
         '_,
         'a,
         impl Iterator<Item = &'a Record> + Send + 'a,
@@ -281,28 +857,30 @@ async fn send_response<'a, R: ResponseHandler>(
     >,
     mut response_handle: R,
 ) -> io::Result<ResponseInfo> {
-    #[cfg(feature = "dnssec")]
-    if let Some(mut resp_edns) = response_edns {
-        // set edns DAU and DHU
-        // send along the algorithms which are supported by this authority
-        let mut algorithms = SupportedAlgorithms::default();
-        algorithms.set(Algorithm::RSASHA256);
-        algorithms.set(Algorithm::ECDSAP256SHA256);
-        algorithms.set(Algorithm::ECDSAP384SHA384);
-        algorithms.set(Algorithm::ED25519);
-
-        let dau = EdnsOption::DAU(algorithms);
-        let dhu = EdnsOption::DHU(algorithms);
-
-        resp_edns.options_mut().insert(dau);
-        resp_edns.options_mut().insert(dhu);
-
+    if let Some(resp_edns) = response_edns {
         response.set_edns(resp_edns);
     }
 
     response_handle.send_response(response).await
 }
 
+#[cfg(feature = "dnssec")]
+fn add_supported_algorithms(resp_edns: &mut Edns) {
+    // set edns DAU and DHU
+    // send along the algorithms which are supported by this authority
+    let mut algorithms = SupportedAlgorithms::default();
+    algorithms.set(Algorithm::RSASHA256);
+    algorithms.set(Algorithm::ECDSAP256SHA256);
+    algorithms.set(Algorithm::ECDSAP384SHA384);
+    algorithms.set(Algorithm::ED25519);
+
+    let dau = EdnsOption::DAU(algorithms);
+    let dhu = EdnsOption::DHU(algorithms);
+
+    resp_edns.options_mut().insert(dau);
+    resp_edns.options_mut().insert(dhu);
+}
+
 fn lookup_options_for_edns(edns: Option<&Edns>) -> LookupOptions {
     let _edns = match edns {
         Some(edns) => edns,
@@ -337,3 +915,159 @@ impl ServeFaild for ResponseInfo {
         header.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_client::op::Query;
+    use trust_dns_client::rr::{Name, RecordType};
+    use trust_dns_proto::serialize::binary::BinDecodable;
+    use trust_dns_server::authority::MessageRequest;
+
+    use super::*;
+
+    fn request(name: &str, edns_options: usize) -> Request {
+        let bytes = message(name, edns_options);
+        let message = MessageRequest::from_bytes(&bytes).unwrap();
+
+        Request::new(message, "127.0.0.1:5353".parse().unwrap(), Protocol::Udp)
+    }
+
+    fn message(name: &str, edns_options: usize) -> Vec<u8> {
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+
+        if edns_options > 0 {
+            let mut edns = Edns::new();
+            for i in 0..edns_options {
+                edns.options_mut()
+                    .insert(EdnsOption::Unknown(100 + i as u16, vec![0; 4]));
+            }
+            message.set_edns(edns);
+        }
+
+        message.to_vec().unwrap()
+    }
+
+    #[test]
+    fn test_pad_response() {
+        for name in [
+            "a.com.",
+            "www.example.com.",
+            "a-much-longer-name.example.org.",
+        ] {
+            let request = request(name, 0);
+            let header = Header::response_from_request(request.header());
+            let sections = LookupSections {
+                answers: Box::new(EmptyLookup),
+                ns: Box::new(AuthLookup::default()),
+                soa: Box::new(AuthLookup::default()),
+                additionals: Box::new(AuthLookup::default()),
+            };
+
+            let mut edns = Edns::new();
+            pad_response(&mut edns, &request, &header, &sections);
+
+            let mut message = Message::new();
+            message
+                .set_header(header)
+                .add_query(request.query().original().clone())
+                .set_edns(edns);
+            assert_eq!(message.to_vec().unwrap().len(), 468);
+        }
+    }
+
+    #[test]
+    fn test_extended_error() {
+        let mut edns = Edns::new();
+        edns.options_mut()
+            .insert(extended_error(ExtendedError::OTHER, "query queue full"));
+
+        let mut message = Message::new();
+        message.set_edns(edns);
+        let message = Message::from_vec(&message.to_vec().unwrap()).unwrap();
+
+        let option = message
+            .extensions()
+            .as_ref()
+            .and_then(|edns| edns.option(EdnsCode::from(EDNS_EXTENDED_ERROR)))
+            .cloned();
+        let mut data = vec![0, 0];
+        data.extend_from_slice(b"query queue full");
+        assert_eq!(option, Some(EdnsOption::Unknown(EDNS_EXTENDED_ERROR, data)));
+    }
+
+    #[test]
+    fn test_message_limits_ok() {
+        let limits = MessageLimits::default();
+        assert_eq!(limits.check(&request("www.example.com.", 1)), Ok(()));
+    }
+
+    #[test]
+    fn test_message_limits_labels() {
+        let limits = MessageLimits::default();
+        let name = "a.".repeat(64);
+        assert_eq!(
+            limits.check(&request(name.as_str(), 0)),
+            Err(DropReason::TooManyLabels)
+        );
+    }
+
+    #[test]
+    fn test_message_limits_edns_options() {
+        let limits = MessageLimits::default();
+        assert_eq!(
+            limits.check(&request("www.example.com.", 17)),
+            Err(DropReason::TooManyEdnsOptions)
+        );
+    }
+
+    #[test]
+    fn test_message_limits_size() {
+        let limits = MessageLimits {
+            max_message_size: 32,
+            ..Default::default()
+        };
+        assert_eq!(
+            limits.check_bytes(&message("www.a-long-domain-name-example.com.", 0)),
+            Err(DropReason::Oversized)
+        );
+        assert_eq!(limits.check_bytes(&message("a.com.", 0)), Ok(()));
+    }
+
+    #[test]
+    fn test_message_limits_repeated_edns_options() {
+        let limits = MessageLimits::default();
+
+        let mut bytes = message("www.example.com.", 1);
+        assert_eq!(edns_options(&bytes), Some(1));
+        assert_eq!(limits.check_bytes(&bytes), Ok(()));
+
+        // the same option repeated, the parser keeping only one of them.
+        let option = bytes[bytes.len() - 8..].to_vec();
+        let rdlength = bytes.len() - 10;
+        for _ in 0..16 {
+            bytes.extend_from_slice(&option);
+        }
+        let len = u16::from_be_bytes([bytes[rdlength], bytes[rdlength + 1]]) + 16 * 8;
+        bytes[rdlength..rdlength + 2].copy_from_slice(&len.to_be_bytes());
+
+        assert_eq!(edns_options(&bytes), Some(17));
+        assert_eq!(
+            limits.check_bytes(&bytes),
+            Err(DropReason::TooManyEdnsOptions)
+        );
+
+        assert_eq!(edns_options(&message("www.example.com.", 0)), None);
+        assert_eq!(edns_options(&bytes[..20]), None);
+    }
+
+    #[test]
+    fn test_drop_metrics() {
+        let count = DROPPED_MESSAGES.get(DropReason::TooManyQueries);
+        DROPPED_MESSAGES.inc(DropReason::TooManyQueries);
+        assert_eq!(DROPPED_MESSAGES.get(DropReason::TooManyQueries), count + 1);
+        assert!(DROPPED_MESSAGES.total() > count);
+    }
+}
@@ -0,0 +1,419 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use trust_dns_proto::op::{Header, Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::Record;
+use trust_dns_proto::serialize::binary::{BinDecodable, BinDecoder, BinEncodable, BinEncoder};
+use trust_dns_server::authority::{MessageRequest, MessageResponse};
+use trust_dns_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo};
+
+use crate::dns_conf::TcpListenerOptions;
+use crate::dns_server::MessageLimits;
+use crate::infra::tasks::BackgroundTasks;
+use crate::infra::tproxy::{self, ORIGINAL_DST};
+use crate::log::{debug, warn};
+use crate::third_ext::FutureTimeoutExt;
+
+/// Serve the queries on the tcp listener, within the limits of the options, see RFC 7766.
+pub fn spawn_listener<H: RequestHandler + Clone>(
+    listener: TcpListener,
+    options: TcpListenerOptions,
+    limits: MessageLimits,
+    handler: H,
+    tasks: &BackgroundTasks,
+) {
+    spawn_accept_loop(listener, options, None, limits, handler, tasks)
+}
+
+/// Serve the queries on the tls listener, the same as tcp once handshaked, see RFC 7858.
+pub fn spawn_tls_listener<H: RequestHandler + Clone>(
+    listener: TcpListener,
+    options: TcpListenerOptions,
+    acceptor: TlsAcceptor,
+    limits: MessageLimits,
+    handler: H,
+    tasks: &BackgroundTasks,
+) {
+    spawn_accept_loop(listener, options, Some(acceptor), limits, handler, tasks)
+}
+
+fn spawn_accept_loop<H: RequestHandler + Clone>(
+    listener: TcpListener,
+    options: TcpListenerOptions,
+    acceptor: Option<TlsAcceptor>,
+    limits: MessageLimits,
+    handler: H,
+    tasks: &BackgroundTasks,
+) {
+    let connections = Arc::new(Semaphore::new(options.max_connections));
+    let conn_tasks = tasks.clone();
+    let listener_addr = listener.local_addr();
+
+    tasks.spawn(async move {
+        loop {
+            // the connections over the limit wait in the backlog.
+            let permit = match connections.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+
+            let (stream, src) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!("accept tcp connection failed, {}", err);
+                    // e.g. too many open files, back off a little.
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            if let Err(err) = stream.set_nodelay(options.nodelay) {
+                debug!("set nodelay of tcp connection from {} failed, {}", src, err);
+            }
+
+            let original_dst = listener_addr
+                .as_ref()
+                .ok()
+                .and_then(|addr| tproxy::tcp_original_dst(&stream, *addr));
+            if let Some(dst) = original_dst {
+                debug!("tcp connection from {} redirected, heading to {}", src, dst);
+            }
+
+            let conn = Connection {
+                src,
+                original_dst,
+                options,
+                limits,
+            };
+            let handler = handler.clone();
+            let acceptor = acceptor.clone();
+            let tasks = conn_tasks.clone();
+
+            conn_tasks.spawn(async move {
+                let served = match acceptor {
+                    None => serve_connection(stream, conn, Protocol::Tcp, handler, tasks).await,
+                    Some(acceptor) => {
+                        match acceptor.accept(stream).timeout(options.idle_timeout).await {
+                            Ok(Ok(stream)) => {
+                                serve_connection(stream, conn, Protocol::Tls, handler, tasks).await
+                            }
+                            Ok(Err(err)) => Err(err),
+                            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+                        }
+                    }
+                };
+                if let Err(err) = served {
+                    debug!("tcp connection from {} failed, {}", src, err);
+                }
+                drop(permit);
+            });
+        }
+    });
+}
+
+/// The peer of a connection, and where it was heading if redirected.
+#[derive(Clone, Copy)]
+struct Connection {
+    src: SocketAddr,
+    original_dst: Option<SocketAddr>,
+    options: TcpListenerOptions,
+    limits: MessageLimits,
+}
+
+/// Read the queries of the connection and answer them as they complete, maybe out of order,
+/// each in a task of the group of the listener.
+async fn serve_connection<H, S>(
+    stream: S,
+    conn: Connection,
+    protocol: Protocol,
+    handler: H,
+    tasks: BackgroundTasks,
+) -> io::Result<()>
+where
+    H: RequestHandler + Clone,
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let Connection {
+        src,
+        original_dst,
+        options,
+        limits,
+    } = conn;
+    let (reader, mut writer) = tokio::io::split(stream);
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(options.max_pipelined);
+    let pipeline = Arc::new(Semaphore::new(options.max_pipelined));
+
+    let reading = async move {
+        let mut reader = BufReader::new(reader);
+
+        loop {
+            let len = match reader.read_u16().timeout(options.idle_timeout).await {
+                Ok(Ok(len)) => len as usize,
+                Ok(Err(err)) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(Err(err)) => return Err(err),
+                // not idle, as long as queries are pending.
+                Err(_) if pipeline.available_permits() < options.max_pipelined => continue,
+                Err(_) => {
+                    debug!("tcp connection from {} idle, close it", src);
+                    return Ok(());
+                }
+            };
+
+            let mut buf = vec![0; len];
+            match reader
+                .read_exact(&mut buf)
+                .timeout(options.idle_timeout)
+                .await
+            {
+                Ok(res) => res?,
+                Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+            };
+
+            if !limits.admit(&buf, src) {
+                continue;
+            }
+
+            let message = match MessageRequest::from_bytes(&buf) {
+                Ok(message) => message,
+                Err(err) => {
+                    debug!("malformed message from {}, {}", src, err);
+                    if let Some(response) = format_error(&buf) {
+                        if tx.send(response).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let permit = match pipeline.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return Ok(()),
+            };
+
+            let request = Request::new(message, src, protocol);
+            let response_handle = TcpResponseHandle(tx.clone());
+            let handler = handler.clone();
+
+            tasks.spawn(async move {
+                match original_dst {
+                    Some(dst) => {
+                        ORIGINAL_DST
+                            .scope(dst, handler.handle_request(&request, response_handle))
+                            .await
+                    }
+                    None => handler.handle_request(&request, response_handle).await,
+                };
+                drop(permit);
+            });
+        }
+    };
+
+    // completes once the reading stops and all the pending queries are answered.
+    let writing = async move {
+        while let Some(message) = rx.recv().await {
+            let mut framed = Vec::with_capacity(2 + message.len());
+            framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+            framed.extend_from_slice(&message);
+            writer.write_all(&framed).await?;
+        }
+        writer.shutdown().await
+    };
+
+    let (read, write) = tokio::join!(reading, writing);
+    read.and(write)
+}
+
+/// The FORMERR answer of a malformed query, none if even its header can't be read, or it's
+/// not a query.
+fn format_error(bytes: &[u8]) -> Option<Vec<u8>> {
+    let header = Header::read(&mut BinDecoder::new(bytes)).ok()?;
+    if header.message_type() != MessageType::Query {
+        return None;
+    }
+
+    let mut response = Message::new();
+    response.set_header(Header::response_from_request(&header));
+    response.set_response_code(ResponseCode::FormErr);
+    response.to_vec().ok()
+}
+
+/// Hand the encoded responses over to the writing half of the connection.
+#[derive(Clone)]
+pub(crate) struct TcpResponseHandle(pub(crate) mpsc::Sender<Vec<u8>>);
+
+#[async_trait::async_trait]
+impl ResponseHandler for TcpResponseHandle {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let mut buffer = Vec::with_capacity(512);
+        let header = {
+            let mut encoder = BinEncoder::new(&mut buffer);
+            encoder.set_max_size(u16::MAX);
+            response
+                .destructive_emit(&mut encoder)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+        };
+
+        self.0
+            .send(buffer)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        Ok(header.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use tokio::net::TcpStream;
+    use tokio::runtime::Builder;
+    use trust_dns_proto::op::Query;
+    use trust_dns_proto::rr::{Name, RecordType};
+    use trust_dns_server::authority::MessageResponseBuilder;
+
+    use super::*;
+
+    /// Refuse all the queries.
+    #[derive(Clone)]
+    struct Refused;
+
+    #[async_trait::async_trait]
+    impl RequestHandler for Refused {
+        async fn handle_request<R: ResponseHandler>(
+            &self,
+            request: &Request,
+            mut response_handle: R,
+        ) -> ResponseInfo {
+            let response = MessageResponseBuilder::from_message_request(request)
+                .error_msg(request.header(), ResponseCode::Refused);
+            response_handle.send_response(response).await.unwrap()
+        }
+    }
+
+    fn query(id: u16) -> Vec<u8> {
+        let mut message = Message::new();
+        message.set_id(id);
+        message.add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let message = message.to_vec().unwrap();
+
+        let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&message);
+        framed
+    }
+
+    async fn read_response(stream: &mut TcpStream) -> Message {
+        let len = stream.read_u16().await.unwrap();
+        let mut buf = vec![0; len as usize];
+        stream.read_exact(&mut buf).await.unwrap();
+        Message::from_vec(&buf).unwrap()
+    }
+
+    #[test]
+    fn test_tcp_pipelined() {
+        Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let tasks = BackgroundTasks::new();
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let options = TcpListenerOptions {
+                    idle_timeout: Duration::from_millis(200),
+                    ..Default::default()
+                };
+                spawn_listener(listener, options, Default::default(), Refused, &tasks);
+
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                stream
+                    .write_all(&[query(1), query(2)].concat())
+                    .await
+                    .unwrap();
+
+                let mut ids = vec![];
+                for _ in 0..2 {
+                    let response = read_response(&mut stream).await;
+                    assert_eq!(response.response_code(), ResponseCode::Refused);
+                    ids.push(response.id());
+                }
+                ids.sort();
+                assert_eq!(ids, [1, 2]);
+
+                // closed once idle.
+                let mut buf = [0; 1];
+                let n = stream
+                    .read(&mut buf)
+                    .timeout(Duration::from_secs(2))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(n, 0);
+
+                tasks.shutdown().await;
+            })
+    }
+    #[test]
+    fn test_tcp_malformed() {
+        Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let tasks = BackgroundTasks::new();
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                spawn_listener(
+                    listener,
+                    Default::default(),
+                    Default::default(),
+                    Refused,
+                    &tasks,
+                );
+
+                // the query cut short, the header telling one question.
+                let mut malformed = query(3);
+                malformed.truncate(2 + 16);
+                malformed[..2].copy_from_slice(&16u16.to_be_bytes());
+
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                stream
+                    .write_all(&[malformed, query(4)].concat())
+                    .await
+                    .unwrap();
+
+                let response = read_response(&mut stream).await;
+                assert_eq!(response.id(), 3);
+                assert_eq!(response.response_code(), ResponseCode::FormErr);
+                assert_eq!(response.message_type(), MessageType::Response);
+
+                // the connection still served.
+                let response = read_response(&mut stream).await;
+                assert_eq!(response.id(), 4);
+                assert_eq!(response.response_code(), ResponseCode::Refused);
+
+                tasks.shutdown().await;
+            })
+    }
+}
@@ -0,0 +1,337 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello, NoClientAuth,
+    ResolvesServerCert,
+};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig, SignatureScheme, SupportedProtocolVersion};
+use rustls_pemfile::Item;
+use tokio_rustls::TlsAcceptor;
+
+use crate::dns_client::load_root_store;
+use crate::dns_conf::TlsListenerOptions;
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{info, warn};
+
+/// How often the certificate files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The certificate of the tls listeners, swapped once its files are renewed, e.g. by certbot.
+/// The connections established keep the certificate they were handshaked with.
+pub struct ReloadableCert {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    current: RwLock<(Arc<CertifiedKey>, Option<SystemTime>)>,
+}
+
+impl ReloadableCert {
+    pub fn load<P: AsRef<Path>>(cert_file: P, key_file: P) -> io::Result<Self> {
+        let (cert_file, key_file) = (cert_file.as_ref(), key_file.as_ref());
+        let modified = modified(cert_file, key_file);
+        let key = load_certified_key(cert_file, key_file)?;
+
+        Ok(Self {
+            cert_file: cert_file.to_owned(),
+            key_file: key_file.to_owned(),
+            current: RwLock::new((Arc::new(key), modified)),
+        })
+    }
+
+    /// Reload the certificate if its files changed since loaded, true if reloaded.
+    pub fn reload_if_modified(&self) -> io::Result<bool> {
+        let modified = modified(&self.cert_file, &self.key_file);
+        if modified == self.read().1 {
+            return Ok(false);
+        }
+        self.reload().map(|_| true)
+    }
+
+    /// Reload the certificate, the current one is kept if the files are invalid.
+    pub fn reload(&self) -> io::Result<()> {
+        let modified = modified(&self.cert_file, &self.key_file);
+        let key = load_certified_key(&self.cert_file, &self.key_file)?;
+
+        *match self.current.write() {
+            Ok(current) => current,
+            Err(err) => err.into_inner(),
+        } = (Arc::new(key), modified);
+
+        Ok(())
+    }
+
+    /// Reload the certificate whenever its files change, or on SIGHUP.
+    pub fn spawn_watcher(self: &Arc<Self>, tasks: &BackgroundTasks) {
+        let cert = self.clone();
+
+        tasks.spawn(async move {
+            #[cfg(unix)]
+            let mut hangup = {
+                use tokio::signal::unix::{signal, SignalKind};
+                signal(SignalKind::hangup())
+                    .map_err(|err| warn!("failed to listen SIGHUP, {}", err))
+                    .ok()
+            };
+
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            // the first tick completes immediately, the certificate is loaded already.
+            interval.tick().await;
+
+            loop {
+                #[cfg(unix)]
+                let forced = match hangup.as_mut() {
+                    Some(hangup) => tokio::select! {
+                        _ = interval.tick() => false,
+                        _ = hangup.recv() => true,
+                    },
+                    None => {
+                        interval.tick().await;
+                        false
+                    }
+                };
+                #[cfg(not(unix))]
+                let forced = {
+                    interval.tick().await;
+                    false
+                };
+
+                let reloaded = if forced {
+                    cert.reload().map(|_| true)
+                } else {
+                    cert.reload_if_modified()
+                };
+
+                match reloaded {
+                    Ok(true) => info!("tls certificate {:?} reloaded", cert.cert_file),
+                    Ok(false) => (),
+                    Err(err) => warn!(
+                        "reload tls certificate {:?} failed, keep the current one, {}",
+                        cert.cert_file, err
+                    ),
+                }
+            }
+        });
+    }
+
+    /// The acceptor of the DNS-over-TLS listener, see RFC 7858.
+    pub fn acceptor(self: &Arc<Self>, options: &TlsListenerOptions) -> io::Result<TlsAcceptor> {
+        let config = self.server_config(options, rustls::DEFAULT_VERSIONS, &[b"dot"])?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// The acceptor of the DNS-over-HTTPS listener, HTTP/2 preferred, see RFC 8484.
+    pub fn https_acceptor(
+        self: &Arc<Self>,
+        options: &TlsListenerOptions,
+    ) -> io::Result<TlsAcceptor> {
+        let config =
+            self.server_config(options, rustls::DEFAULT_VERSIONS, &[b"h2", b"http/1.1"])?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// The config of the DNS-over-HTTP/3 listener, TLS 1.3 only as QUIC requires, see RFC 9114.
+    pub fn quic_server_config(
+        self: &Arc<Self>,
+        options: &TlsListenerOptions,
+    ) -> io::Result<ServerConfig> {
+        self.server_config(options, &[&rustls::version::TLS13], &[b"h3"])
+    }
+
+    fn server_config(
+        self: &Arc<Self>,
+        options: &TlsListenerOptions,
+        versions: &[&'static SupportedProtocolVersion],
+        alpn_protocols: &[&[u8]],
+    ) -> io::Result<ServerConfig> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+        let client_auth = match (options.client_ca_file.as_ref(), options.require_client_cert) {
+            (Some(ca_file), require) => {
+                let roots = load_root_store(ca_file)
+                    .map_err(|err| invalid(format!("load {:?} failed, {}", ca_file, err)))?;
+                if require {
+                    AllowAnyAuthenticatedClient::new(roots)
+                } else {
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+                }
+            }
+            (None, true) => {
+                return Err(invalid(
+                    "-require-client-cert requires -client-ca-file".to_string(),
+                ))
+            }
+            (None, false) => NoClientAuth::new(),
+        };
+
+        let mut config = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .map_err(|err| invalid(err.to_string()))?
+            .with_client_cert_verifier(client_auth)
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+
+        Ok(config)
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, (Arc<CertifiedKey>, Option<SystemTime>)> {
+        match self.current.read() {
+            Ok(current) => current,
+            Err(err) => err.into_inner(),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.read().0.clone())
+    }
+}
+
+/// The latest modification of the certificate and its key.
+fn modified(cert_file: &Path, key_file: &Path) -> Option<SystemTime> {
+    [cert_file, key_file]
+        .iter()
+        .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max()
+}
+
+/// Load the certificate chain and its private key from the PEM files.
+fn load_certified_key(cert_file: &Path, key_file: &Path) -> io::Result<CertifiedKey> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_file)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate found in {:?}", cert_file)));
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_file)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(format!("no private key found in {:?}", key_file)))?;
+
+    let key = sign::any_supported_type(&key).map_err(|err| invalid(err.to_string()))?;
+
+    // or every handshake fails, only once the certificate is swapped.
+    if !key_matches(&certs[0], key.as_ref()) {
+        return Err(invalid(format!(
+            "private key {:?} does not match certificate {:?}",
+            key_file, cert_file
+        )));
+    }
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Whether the key signs for the certificate, a message signed by it verified with the public
+/// key of the certificate.
+fn key_matches(cert: &Certificate, key: &dyn sign::SigningKey) -> bool {
+    const MESSAGE: &[u8] = b"smartdns certificate key check";
+
+    let cert = match webpki::EndEntityCert::try_from(cert.0.as_slice()) {
+        Ok(cert) => cert,
+        Err(_) => return false,
+    };
+
+    let signer = match key.choose_scheme(&[
+        SignatureScheme::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::RSA_PKCS1_SHA256,
+    ]) {
+        Some(signer) => signer,
+        None => return false,
+    };
+
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::RSA_PKCS1_SHA256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+        _ => return false,
+    };
+
+    signer.sign(MESSAGE).map_or(false, |signature| {
+        cert.verify_signature(algorithm, MESSAGE, &signature)
+            .is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_invalid_cert() {
+        let dir = std::env::temp_dir().join(format!("smartdns-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let cert_file = dir.join("cert.pem");
+        let key_file = dir.join("key.pem");
+
+        assert!(ReloadableCert::load(&cert_file, &key_file).is_err());
+
+        fs::write(&cert_file, "not a certificate").unwrap();
+        fs::write(&key_file, "not a key").unwrap();
+        let err = ReloadableCert::load(&cert_file, &key_file).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_cert_key_mismatch() {
+        let dir = std::env::temp_dir().join(format!("smartdns-tls-key-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let cert_file = dir.join("cert.pem");
+        let key_file = dir.join("key.pem");
+        fs::copy("tests/test_confs/tls_cert.pem", &cert_file).unwrap();
+        fs::copy("tests/test_confs/tls_key.pem", &key_file).unwrap();
+
+        let cert = Arc::new(ReloadableCert::load(&cert_file, &key_file).unwrap());
+        let current = cert.read().0.clone();
+
+        // the key of another certificate, told at once rather than on the handshakes.
+        fs::copy("tests/test_confs/tls_other_key.pem", &key_file).unwrap();
+        let err = ReloadableCert::load(&cert_file, &key_file).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("does not match"), "{}", err);
+
+        assert!(cert.reload().is_err());
+        assert!(Arc::ptr_eq(&cert.read().0, &current));
+
+        // the DoT, DoH and DoH3 listeners all resolve the certificate reloaded.
+        let options = TlsListenerOptions::default();
+        for (versions, alpn) in [
+            (rustls::DEFAULT_VERSIONS, b"dot".as_slice()),
+            (rustls::DEFAULT_VERSIONS, b"h2".as_slice()),
+        ] {
+            let config = cert.server_config(&options, versions, &[alpn]).unwrap();
+            assert_eq!(
+                Arc::as_ptr(&config.cert_resolver) as *const (),
+                Arc::as_ptr(&cert) as *const ()
+            );
+        }
+        let config = cert.quic_server_config(&options).unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"h3".to_vec()]);
+        assert_eq!(
+            Arc::as_ptr(&config.cert_resolver) as *const (),
+            Arc::as_ptr(&cert) as *const ()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,513 @@
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::Arc;
+
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+use trust_dns_proto::rr::Record;
+use trust_dns_proto::serialize::binary::{BinDecodable, BinEncoder};
+use trust_dns_server::authority::{MessageRequest, MessageResponse};
+use trust_dns_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo};
+
+use crate::dns_server::MessageLimits;
+use crate::infra::iface;
+use crate::infra::tasks::BackgroundTasks;
+use crate::infra::tproxy::ORIGINAL_DST;
+use crate::log::{debug, warn};
+
+/// The udp payload size without edns, see RFC 1035.
+const MIN_PAYLOAD: u16 = 512;
+
+/// Serve the queries on the udp socket bound to a wildcard address, answering each
+/// from the address it arrived on, so that the multi-homed hosts don't answer from
+/// the address of another interface, which the clients drop.
+///
+/// The transparent socket takes the queries redirected by TPROXY too, and answers
+/// them from the destination the clients intended.
+pub fn spawn_listener<H: RequestHandler + Clone>(
+    socket: UdpSocket,
+    max_payload: u16,
+    transparent: bool,
+    limits: MessageLimits,
+    handler: H,
+    tasks: &BackgroundTasks,
+) -> io::Result<()> {
+    enable_pktinfo(&socket)?;
+    if transparent {
+        enable_original_dst(&socket)?;
+    }
+
+    let local_addr = socket.local_addr()?;
+    let socket = Arc::new(socket);
+
+    tasks.spawn(async move {
+        let mut buf = vec![0; u16::MAX as usize];
+
+        loop {
+            let (len, src, pktinfo, original_dst) = match recv(&socket, &mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    warn!("receive udp message failed, {}", err);
+                    continue;
+                }
+            };
+
+            if !limits.admit(&buf[..len], src) {
+                continue;
+            }
+
+            let message = match MessageRequest::from_bytes(&buf[..len]) {
+                Ok(message) => message,
+                Err(err) => {
+                    debug!("malformed message from {}, {}", src, err);
+                    continue;
+                }
+            };
+
+            // the client limit, but ours at most.
+            let max_size = message
+                .edns()
+                .map(|edns| edns.max_payload())
+                .unwrap_or(MIN_PAYLOAD)
+                .max(MIN_PAYLOAD)
+                .min(max_payload.max(MIN_PAYLOAD));
+
+            // heading elsewhere, before redirected.
+            let original_dst =
+                original_dst.filter(|dst| is_redirected(*dst, local_addr, pktinfo.as_ref()));
+            if let Some(dst) = original_dst {
+                debug!("udp message from {} redirected, heading to {}", src, dst);
+            }
+
+            let request = Request::new(message, src, Protocol::Udp);
+            let response_handle = UdpResponseHandle {
+                socket: socket.clone(),
+                dst: src,
+                pktinfo,
+                original_dst,
+                max_size,
+            };
+            let handler = handler.clone();
+
+            tokio::spawn(async move {
+                match original_dst {
+                    Some(dst) => {
+                        ORIGINAL_DST
+                            .scope(dst, handler.handle_request(&request, response_handle))
+                            .await
+                    }
+                    None => handler.handle_request(&request, response_handle).await,
+                };
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// The local address a datagram arrived on, which the answer is sent from.
+#[derive(Clone, Copy)]
+enum PktInfo {
+    V4(libc::in_pktinfo),
+    V6(libc::in6_pktinfo),
+}
+
+impl PktInfo {
+    /// The destination address of the datagram.
+    fn ip(&self) -> IpAddr {
+        match self {
+            PktInfo::V4(info) => IpAddr::from(u32::from_be(info.ipi_spec_dst.s_addr).to_be_bytes()),
+            PktInfo::V6(info) => IpAddr::from(info.ipi6_addr.s6_addr),
+        }
+    }
+}
+
+/// Whether the datagram was heading elsewhere before redirected by TPROXY, rather than to an
+/// address of ours, which the wildcard sockets tell by the destination of the datagram.
+fn is_redirected(
+    original_dst: SocketAddr,
+    local_addr: SocketAddr,
+    pktinfo: Option<&PktInfo>,
+) -> bool {
+    let local_ip = match pktinfo {
+        Some(pktinfo) => pktinfo.ip(),
+        None => local_addr.ip(),
+    };
+
+    original_dst.port() != local_addr.port() || canonical(original_dst.ip()) != canonical(local_ip)
+}
+
+/// The ipv4 address of the ipv4-mapped one, as received on the dual stack sockets.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        ip => ip,
+    }
+}
+
+/// Receive the destination address along with each datagram.
+fn enable_pktinfo(socket: &UdpSocket) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+
+    if socket.local_addr()?.is_ipv4() {
+        setsockopt(fd, libc::IPPROTO_IP, libc::IP_PKTINFO)
+    } else {
+        setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)?;
+        // the ipv4 datagrams of a dual stack socket, if any.
+        let _ = setsockopt(fd, libc::IPPROTO_IP, libc::IP_PKTINFO);
+        Ok(())
+    }
+}
+
+/// Receive the destination address before redirected by TPROXY along with each datagram.
+fn enable_original_dst(socket: &UdpSocket) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+
+    if socket.local_addr()?.is_ipv4() {
+        setsockopt(fd, libc::IPPROTO_IP, libc::IP_RECVORIGDSTADDR)
+    } else {
+        setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVORIGDSTADDR)?;
+        let _ = setsockopt(fd, libc::IPPROTO_IP, libc::IP_RECVORIGDSTADDR);
+        Ok(())
+    }
+}
+
+fn setsockopt(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &enable as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+async fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Received> {
+    loop {
+        socket.readable().await?;
+        match socket.try_io(Interest::READABLE, || recv_msg(socket.as_raw_fd(), buf)) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            res => return res,
+        }
+    }
+}
+
+async fn send(
+    socket: &UdpSocket,
+    buf: &[u8],
+    dst: SocketAddr,
+    pktinfo: Option<PktInfo>,
+) -> io::Result<usize> {
+    loop {
+        socket.writable().await?;
+        match socket.try_io(Interest::WRITABLE, || {
+            send_msg(socket.as_raw_fd(), buf, dst, pktinfo)
+        }) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            res => return res,
+        }
+    }
+}
+
+/// The length and source of a datagram, with the local address it arrived on,
+/// and the destination before redirected if any.
+type Received = (usize, SocketAddr, Option<PktInfo>, Option<SocketAddr>);
+
+/// Send the datagram from the address not local, with a transparent socket bound to it.
+async fn send_from(src: SocketAddr, buf: &[u8], dst: SocketAddr) -> io::Result<usize> {
+    let socket = Socket::new(Domain::for_address(src), Type::DGRAM, None)?;
+    iface::set_transparent(&socket, src.is_ipv6())?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&src.into())?;
+
+    UdpSocket::from_std(socket.into())?.send_to(buf, dst).await
+}
+
+/// The control messages of a datagram, aligned as cmsghdr.
+type ControlBuffer = [u64; 16];
+
+fn recv_msg(fd: RawFd, buf: &mut [u8]) -> io::Result<Received> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control: ControlBuffer = [0; 16];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut storage as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of::<ControlBuffer>() as _;
+
+    let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let src = unsafe { SockAddr::new(storage, msg.msg_namelen) }
+        .as_socket()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unsupported address family"))?;
+
+    let mut pktinfo = None;
+    let mut original_dst = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let info = ptr::read_unaligned(data as *const libc::in_pktinfo);
+                    // answer from the destination address, routed by the kernel.
+                    pktinfo = Some(PktInfo::V4(libc::in_pktinfo {
+                        ipi_ifindex: 0,
+                        ipi_spec_dst: info.ipi_addr,
+                        ipi_addr: libc::in_addr { s_addr: 0 },
+                    }));
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info = ptr::read_unaligned(data as *const libc::in6_pktinfo);
+                    // the interface too, which the link-local addresses are scoped to.
+                    pktinfo = Some(PktInfo::V6(info));
+                }
+                (libc::IPPROTO_IP, libc::IP_ORIGDSTADDR) => {
+                    let addr = ptr::read_unaligned(data as *const libc::sockaddr_in);
+                    original_dst = Some(SocketAddr::from((
+                        u32::from_be(addr.sin_addr.s_addr).to_be_bytes(),
+                        u16::from_be(addr.sin_port),
+                    )));
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_ORIGDSTADDR) => {
+                    let addr = ptr::read_unaligned(data as *const libc::sockaddr_in6);
+                    original_dst = Some(SocketAddr::from((
+                        addr.sin6_addr.s6_addr,
+                        u16::from_be(addr.sin6_port),
+                    )));
+                }
+                _ => (),
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok((len as usize, src, pktinfo, original_dst))
+}
+
+fn send_msg(fd: RawFd, buf: &[u8], dst: SocketAddr, pktinfo: Option<PktInfo>) -> io::Result<usize> {
+    let dst = SockAddr::from(dst);
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control: ControlBuffer = [0; 16];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = dst.as_ptr() as *mut libc::c_void;
+    msg.msg_namelen = dst.len();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    if let Some(pktinfo) = pktinfo.as_ref() {
+        let (level, typ, data, len) = match pktinfo {
+            PktInfo::V4(info) => (
+                libc::IPPROTO_IP,
+                libc::IP_PKTINFO,
+                info as *const _ as *const u8,
+                mem::size_of::<libc::in_pktinfo>(),
+            ),
+            PktInfo::V6(info) => (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_PKTINFO,
+                info as *const _ as *const u8,
+                mem::size_of::<libc::in6_pktinfo>(),
+            ),
+        };
+
+        unsafe {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(len as u32) as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = typ;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(len as u32) as _;
+            ptr::copy_nonoverlapping(data, libc::CMSG_DATA(cmsg), len);
+        }
+    }
+
+    let len = unsafe { libc::sendmsg(fd, &msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+/// Send the encoded responses from the address the queries arrived on.
+#[derive(Clone)]
+struct UdpResponseHandle {
+    socket: Arc<UdpSocket>,
+    dst: SocketAddr,
+    pktinfo: Option<PktInfo>,
+    /// answer from it rather than the socket, if redirected by TPROXY.
+    original_dst: Option<SocketAddr>,
+    max_size: u16,
+}
+
+#[async_trait::async_trait]
+impl ResponseHandler for UdpResponseHandle {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let mut buffer = Vec::with_capacity(512);
+        let header = {
+            let mut encoder = BinEncoder::new(&mut buffer);
+            // the records beyond are left out, and the answer truncated.
+            encoder.set_max_size(self.max_size);
+            response
+                .destructive_emit(&mut encoder)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+        };
+
+        match self.original_dst {
+            Some(src) => send_from(src, &buffer, self.dst).await?,
+            None => send(&self.socket, &buffer, self.dst, self.pktinfo).await?,
+        };
+
+        Ok(header.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use tokio::runtime::Builder;
+    use trust_dns_proto::op::{Message, Query, ResponseCode};
+    use trust_dns_proto::rr::{Name, RecordType};
+    use trust_dns_proto::serialize::binary::BinEncodable;
+    use trust_dns_server::authority::MessageResponseBuilder;
+
+    use super::*;
+    use crate::third_ext::FutureTimeoutExt;
+
+    /// Refuse all the queries.
+    #[derive(Clone)]
+    struct Refused;
+
+    #[async_trait::async_trait]
+    impl RequestHandler for Refused {
+        async fn handle_request<R: ResponseHandler>(
+            &self,
+            request: &Request,
+            mut response_handle: R,
+        ) -> ResponseInfo {
+            let response = MessageResponseBuilder::from_message_request(request)
+                .error_msg(request.header(), ResponseCode::Refused);
+            response_handle.send_response(response).await.unwrap()
+        }
+    }
+
+    #[test]
+    fn test_is_redirected() {
+        let wildcard = SocketAddr::from(([0, 0, 0, 0], 53));
+        let pktinfo = PktInfo::V4(libc::in_pktinfo {
+            ipi_ifindex: 0,
+            ipi_spec_dst: libc::in_addr {
+                s_addr: u32::from_be_bytes([192, 168, 1, 1]).to_be(),
+            },
+            ipi_addr: libc::in_addr { s_addr: 0 },
+        });
+
+        // to our address on the wildcard socket.
+        assert!(!is_redirected(
+            SocketAddr::from(([192, 168, 1, 1], 53)),
+            wildcard,
+            Some(&pktinfo)
+        ));
+        // to another resolver, redirected.
+        assert!(is_redirected(
+            SocketAddr::from(([8, 8, 8, 8], 53)),
+            wildcard,
+            Some(&pktinfo)
+        ));
+        assert!(is_redirected(
+            SocketAddr::from(([192, 168, 1, 1], 5353)),
+            wildcard,
+            Some(&pktinfo)
+        ));
+        // bound to the address.
+        assert!(!is_redirected(
+            SocketAddr::from(([192, 168, 1, 1], 53)),
+            SocketAddr::from(([192, 168, 1, 1], 53)),
+            None
+        ));
+    }
+
+    #[test]
+    fn test_udp_reply_from_destination() {
+        Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let tasks = BackgroundTasks::new();
+                let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+                let port = socket.local_addr().unwrap().port();
+                spawn_listener(socket, 1232, false, Default::default(), Refused, &tasks).unwrap();
+
+                let mut message = Message::new();
+                message.set_id(7);
+                message.add_query(Query::query(
+                    Name::from_str("www.example.com.").unwrap(),
+                    RecordType::A,
+                ));
+
+                let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                client
+                    .send_to(&message.to_vec().unwrap(), ("127.0.0.1", port))
+                    .await
+                    .unwrap();
+
+                let mut buf = [0; 512];
+                let (len, from) = client
+                    .recv_from(&mut buf)
+                    .timeout(Duration::from_secs(2))
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(from, SocketAddr::from(([127, 0, 0, 1], port)));
+                let response = Message::from_vec(&buf[..len]).unwrap();
+                assert_eq!(response.id(), 7);
+                assert_eq!(response.response_code(), ResponseCode::Refused);
+
+                tasks.shutdown().await;
+            })
+    }
+}
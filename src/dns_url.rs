@@ -1,13 +1,17 @@
+use std::net::IpAddr;
 use std::str::FromStr;
 use std::string::ToString;
 use trust_dns_resolver::config::Protocol;
 use url::{Host, Url};
 
+use crate::dnscrypt::DnsCryptStamp;
+
 /// alias: system、google、cloudflare、quad9
 /// udp://8.8.8.8 or 8.8.8.8   => traditional dns server
 /// tcp://8.8.8.8:53           => dns over tcp
 /// tls://8.8.8.8:853          => DOT: dns over tls
 /// https://1.1.1.1/dns-query  => DOH: dns over https
+/// sdns://AQcAAAAAAAAAD...    => DNSCrypt, by the server stamp
 #[derive(Debug, Clone)]
 pub struct DnsUrl {
     proto: Protocol,
@@ -15,6 +19,7 @@ pub struct DnsUrl {
     port: Option<u16>,
     path: Option<String>,
     enable_sni: Option<bool>,
+    dnscrypt: Option<DnsCryptStamp>,
 }
 
 impl DnsUrl {
@@ -54,6 +59,11 @@ impl DnsUrl {
     pub fn enable_sni(&self) -> Option<bool> {
         self.enable_sni
     }
+
+    /// The stamp of the DNSCrypt server, whose queries are sent over udp but encrypted.
+    pub fn dnscrypt(&self) -> Option<&DnsCryptStamp> {
+        self.dnscrypt.as_ref()
+    }
 }
 
 #[derive(Debug)]
@@ -67,6 +77,23 @@ impl FromStr for DnsUrl {
     type Err = DnsUrlParseErr;
 
     fn from_str(url: &str) -> Result<Self, Self::Err> {
+        // the stamp is case sensitive.
+        if url.starts_with("sdns://") {
+            let stamp = DnsCryptStamp::from_str(url).map_err(DnsUrlParseErr::ParseError)?;
+
+            return Ok(Self {
+                proto: Protocol::Udp,
+                host: match stamp.addr.ip() {
+                    IpAddr::V4(ip) => Host::Ipv4(ip),
+                    IpAddr::V6(ip) => Host::Ipv6(ip),
+                },
+                port: Some(stamp.addr.port()),
+                path: None,
+                enable_sni: None,
+                dnscrypt: Some(stamp),
+            });
+        }
+
         let mut url = url.to_lowercase();
         if url.find("://").is_none() {
             url.insert_str(0, "udp://")
@@ -115,12 +142,17 @@ impl FromStr for DnsUrl {
                 Some(url.path().to_string())
             },
             enable_sni,
+            dnscrypt: None,
         })
     }
 }
 
 impl ToString for DnsUrl {
     fn to_string(&self) -> String {
+        if let Some(stamp) = self.dnscrypt.as_ref() {
+            return stamp.to_string();
+        }
+
         if self.is_default_port() {
             match self.proto {
                 Protocol::Udp => format!("udp://{}", self.host),
@@ -176,6 +208,22 @@ mod tests {
         assert_eq!(url.to_string(), "udp://8.8.8.8");
     }
 
+    #[test]
+    fn test_parse_dnscrypt() {
+        let stamp = "sdns://AQcAAAAAAAAADjIxMi40Ny4yMjguMTM2IAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4fHzIuZG5zY3J5cHQtY2VydC5mci5kbnNjcnlwdC5vcmc";
+        let url = DnsUrl::from_str(stamp).unwrap();
+        assert_eq!(url.proto, Protocol::Udp);
+        assert_eq!(url.host.to_string(), "212.47.228.136");
+        assert_eq!(url.port(), 443);
+        assert_eq!(url.to_string(), stamp);
+
+        let url = DnsUrl::from_str(&url.to_string()).unwrap();
+        assert_eq!(
+            url.dnscrypt().map(|s| s.provider_name.as_str()),
+            Some("2.dnscrypt-cert.fr.dnscrypt.org")
+        );
+    }
+
     #[test]
     fn test_parse_udp_1() {
         let url = DnsUrl::from_str("udp://8.8.8.8").unwrap();
@@ -0,0 +1,537 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crypto_box::aead::generic_array::GenericArray;
+use crypto_box::aead::AeadInPlace;
+use crypto_box::{ChaChaBox, PublicKey, SalsaBox, SecretKey};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use trust_dns_proto::op::{Message, MessageType, OpCode, Query};
+use trust_dns_proto::rr::{Name, RData, RecordType};
+use trust_dns_proto::serialize::binary::BinDecodable;
+use trust_dns_proto::xfer::{DnsRequest, DnsResponse};
+use trust_dns_resolver::error::ResolveError;
+
+use crate::log::debug;
+
+const CERT_MAGIC: &[u8; 4] = b"DNSC";
+const RESOLVER_MAGIC: &[u8; 8] = b"r6fnvWj8";
+/// The queries over udp are padded to at least this length, to avoid amplification.
+const MIN_UDP_QUERY_LEN: usize = 256;
+const MAX_UDP_RESPONSE_LEN: usize = 4096;
+const TAG_LEN: usize = 16;
+
+/// A DNSCrypt server stamp, `sdns://` followed by the base64url encoded properties.
+/// See https://dnscrypt.info/stamps-specifications
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsCryptStamp {
+    /// the properties, e.g. dnssec, no logs, as a little endian bit field.
+    pub props: u64,
+    pub addr: SocketAddr,
+    /// the ed25519 key the certificates are signed with.
+    pub provider_pk: [u8; 32],
+    /// e.g. `2.dnscrypt-cert.example.com`.
+    pub provider_name: String,
+}
+
+impl FromStr for DnsCryptStamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s
+            .strip_prefix("sdns://")
+            .ok_or_else(|| "expect sdns://".to_string())?;
+
+        let bytes = base64::decode_config(encoded.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+            .map_err(|e| format!("invalid stamp, {}", e))?;
+
+        let (proto, rest) = bytes
+            .split_first()
+            .ok_or_else(|| "empty stamp".to_string())?;
+
+        if *proto != 0x01 {
+            return Err(format!("stamp of protocol {:#04x} is not dnscrypt", proto));
+        }
+
+        let props = rest.get(..8).ok_or_else(|| "truncated stamp".to_string())?;
+        let props = u64::from_le_bytes(props.try_into().unwrap());
+        let mut rest = &rest[8..];
+
+        let addr = read_lp(&mut rest)?;
+        let provider_pk = read_lp(&mut rest)?;
+        let provider_name = read_lp(&mut rest)?;
+
+        let addr = std::str::from_utf8(addr).map_err(|e| e.to_string())?;
+        let addr = match SocketAddr::from_str(addr) {
+            Ok(addr) => addr,
+            Err(_) => SocketAddr::new(
+                IpAddr::from_str(addr.trim_start_matches('[').trim_end_matches(']'))
+                    .map_err(|e| format!("invalid address {}, {}", addr, e))?,
+                443,
+            ),
+        };
+
+        Ok(Self {
+            props,
+            addr,
+            provider_pk: provider_pk
+                .try_into()
+                .map_err(|_| "invalid provider public key".to_string())?,
+            provider_name: String::from_utf8(provider_name.to_vec()).map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+/// The stamp encoded again, the default port 443 omitted, so that it's parsed back the same.
+impl fmt::Display for DnsCryptStamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addr = match (self.addr.ip(), self.addr.port()) {
+            (IpAddr::V4(ip), 443) => ip.to_string(),
+            (IpAddr::V6(ip), 443) => format!("[{}]", ip),
+            _ => self.addr.to_string(),
+        };
+
+        let mut bytes = vec![0x01];
+        bytes.extend_from_slice(&self.props.to_le_bytes());
+        for field in [
+            addr.as_bytes(),
+            self.provider_pk.as_slice(),
+            self.provider_name.as_bytes(),
+        ] {
+            bytes.push(field.len() as u8);
+            bytes.extend_from_slice(field);
+        }
+
+        write!(
+            f,
+            "sdns://{}",
+            base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+        )
+    }
+}
+
+/// A length prefixed field of the stamp.
+fn read_lp<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let (len, rest) = bytes
+        .split_first()
+        .ok_or_else(|| "truncated stamp".to_string())?;
+    let len = *len as usize;
+
+    if rest.len() < len {
+        return Err("truncated stamp".to_string());
+    }
+
+    let (field, rest) = rest.split_at(len);
+    *bytes = rest;
+    Ok(field)
+}
+
+/// The resolver certificate, signed by the provider.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Certificate {
+    /// 1 for X25519-XSalsa20Poly1305, 2 for X25519-XChacha20Poly1305.
+    es_version: u16,
+    resolver_pk: [u8; 32],
+    client_magic: [u8; 8],
+    serial: u32,
+    ts_start: u32,
+    ts_end: u32,
+}
+
+impl Certificate {
+    fn parse(bytes: &[u8], provider_pk: &[u8; 32]) -> Result<Self, String> {
+        if bytes.len() < 124 || !bytes.starts_with(CERT_MAGIC) {
+            return Err("malformed certificate".to_string());
+        }
+
+        let es_version = u16::from_be_bytes([bytes[4], bytes[5]]);
+        if es_version != 1 && es_version != 2 {
+            return Err(format!("unsupported es version {}", es_version));
+        }
+
+        let (signature, signed) = bytes[8..].split_at(64);
+
+        UnparsedPublicKey::new(&ED25519, provider_pk)
+            .verify(signed, signature)
+            .map_err(|_| "invalid certificate signature".to_string())?;
+
+        let u32_at =
+            |i: usize| u32::from_be_bytes([signed[i], signed[i + 1], signed[i + 2], signed[i + 3]]);
+
+        Ok(Self {
+            es_version,
+            resolver_pk: signed[..32].try_into().unwrap(),
+            client_magic: signed[32..40].try_into().unwrap(),
+            serial: u32_at(40),
+            ts_start: u32_at(44),
+            ts_end: u32_at(48),
+        })
+    }
+
+    #[inline]
+    fn is_valid(&self, now: u32) -> bool {
+        self.ts_start <= now && now <= self.ts_end
+    }
+}
+
+enum Cipher {
+    XSalsa20Poly1305(SalsaBox),
+    XChaCha20Poly1305(ChaChaBox),
+}
+
+impl Cipher {
+    /// The tag followed by the ciphertext, as the NaCl box.
+    fn seal(&self, nonce: &[u8; 24], msg: &[u8]) -> Result<Vec<u8>, ResolveError> {
+        let mut buf = msg.to_vec();
+        let nonce = GenericArray::from_slice(nonce);
+
+        let tag = match self {
+            Self::XSalsa20Poly1305(c) => c.encrypt_in_place_detached(nonce, b"", &mut buf),
+            Self::XChaCha20Poly1305(c) => c.encrypt_in_place_detached(nonce, b"", &mut buf),
+        }
+        .map_err(|_| ResolveError::from("dnscrypt encryption failed"))?;
+
+        let mut sealed = tag.to_vec();
+        sealed.extend(buf);
+        Ok(sealed)
+    }
+
+    fn open(&self, nonce: &[u8; 24], sealed: &[u8]) -> Result<Vec<u8>, ResolveError> {
+        if sealed.len() < TAG_LEN {
+            return Err("truncated dnscrypt response".into());
+        }
+
+        let (tag, msg) = sealed.split_at(TAG_LEN);
+        let mut buf = msg.to_vec();
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = GenericArray::from_slice(tag);
+
+        match self {
+            Self::XSalsa20Poly1305(c) => c.decrypt_in_place_detached(nonce, b"", &mut buf, tag),
+            Self::XChaCha20Poly1305(c) => c.decrypt_in_place_detached(nonce, b"", &mut buf, tag),
+        }
+        .map_err(|_| ResolveError::from("dnscrypt decryption failed"))?;
+
+        Ok(buf)
+    }
+}
+
+/// The keys agreed with the resolver, for the lifetime of its certificate.
+struct Session {
+    cert: Certificate,
+    cipher: Cipher,
+    client_pk: [u8; 32],
+}
+
+impl Session {
+    fn new(cert: Certificate) -> Self {
+        let client_sk = SecretKey::from(rand::random::<[u8; 32]>());
+        let client_pk = *client_sk.public_key().as_bytes();
+        let resolver_pk = PublicKey::from(cert.resolver_pk);
+
+        let cipher = match cert.es_version {
+            2 => Cipher::XChaCha20Poly1305(ChaChaBox::new(&resolver_pk, &client_sk)),
+            _ => Cipher::XSalsa20Poly1305(SalsaBox::new(&resolver_pk, &client_sk)),
+        };
+
+        Self {
+            cert,
+            cipher,
+            client_pk,
+        }
+    }
+
+    /// The encrypted query, along with the client half of the nonce.
+    fn encrypt(&self, query: &[u8], min_len: usize) -> Result<(Vec<u8>, [u8; 12]), ResolveError> {
+        let client_nonce = rand::random::<[u8; 12]>();
+        let mut nonce = [0u8; 24];
+        nonce[..12].copy_from_slice(&client_nonce);
+
+        let sealed = self.cipher.seal(&nonce, &pad(query, min_len))?;
+
+        let mut packet = Vec::with_capacity(8 + 32 + 12 + sealed.len());
+        packet.extend_from_slice(&self.cert.client_magic);
+        packet.extend_from_slice(&self.client_pk);
+        packet.extend_from_slice(&client_nonce);
+        packet.extend(sealed);
+
+        Ok((packet, client_nonce))
+    }
+
+    fn decrypt(&self, packet: &[u8], client_nonce: &[u8; 12]) -> Result<Message, ResolveError> {
+        let rest = packet
+            .strip_prefix(RESOLVER_MAGIC.as_slice())
+            .filter(|rest| rest.len() >= 24)
+            .ok_or_else(|| ResolveError::from("malformed dnscrypt response"))?;
+
+        let (nonce, sealed) = rest.split_at(24);
+        if nonce[..12] != client_nonce[..] {
+            return Err("unexpected dnscrypt response nonce".into());
+        }
+
+        let padded = self.cipher.open(nonce.try_into().unwrap(), sealed)?;
+        let msg = unpad(&padded).ok_or_else(|| ResolveError::from("invalid dnscrypt padding"))?;
+
+        Ok(Message::from_bytes(msg)?)
+    }
+}
+
+/// ISO/IEC 7816-4 padding to a multiple of 64 bytes.
+fn pad(msg: &[u8], min_len: usize) -> Vec<u8> {
+    let len = (msg.len() + 1).max(min_len);
+    let len = (len + 63) / 64 * 64;
+
+    let mut padded = Vec::with_capacity(len);
+    padded.extend_from_slice(msg);
+    padded.push(0x80);
+    padded.resize(len, 0);
+    padded
+}
+
+fn unpad(msg: &[u8]) -> Option<&[u8]> {
+    let end = msg.iter().rposition(|b| *b != 0)?;
+    (msg[end] == 0x80).then(|| &msg[..end])
+}
+
+/// A DNSCrypt v2 client, the certificate is fetched on the first query and whenever it expires.
+/// See https://dnscrypt.info/protocol
+pub struct DnsCryptClient {
+    stamp: DnsCryptStamp,
+    session: RwLock<Option<Arc<Session>>>,
+}
+
+impl DnsCryptClient {
+    pub fn new(stamp: DnsCryptStamp) -> Self {
+        Self {
+            stamp,
+            session: Default::default(),
+        }
+    }
+
+    pub async fn exchange(&self, request: DnsRequest) -> Result<DnsResponse, ResolveError> {
+        let session = self.session().await?;
+        let query = request.to_vec()?;
+
+        let response = self.exchange_udp(&session, &query).await?;
+
+        let response = if response.truncated() {
+            self.exchange_tcp(&session, &query).await?
+        } else {
+            response
+        };
+
+        Ok(DnsResponse::from(response))
+    }
+
+    async fn session(&self) -> Result<Arc<Session>, ResolveError> {
+        let now = unix_now();
+
+        if let Some(session) = self.session.read().ok().and_then(|s| s.clone()) {
+            if session.cert.is_valid(now) {
+                return Ok(session);
+            }
+        }
+
+        let cert = self.fetch_certificate(now).await?;
+        debug!(
+            "dnscrypt certificate of {} fetched, serial {}, es version {}",
+            self.stamp.provider_name, cert.serial, cert.es_version
+        );
+
+        let session = Arc::new(Session::new(cert));
+        if let Ok(mut s) = self.session.write() {
+            *s = Some(session.clone());
+        }
+
+        Ok(session)
+    }
+
+    /// The certificates are published as TXT records of the provider name,
+    /// the valid one of the highest serial is used.
+    async fn fetch_certificate(&self, now: u32) -> Result<Certificate, ResolveError> {
+        let mut name = Name::from_str(&self.stamp.provider_name)?;
+        name.set_fqdn(true);
+
+        let mut message = Message::new();
+        message
+            .set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name, RecordType::TXT));
+
+        let socket = UdpSocket::bind(unspecified(&self.stamp.addr)).await?;
+        socket.connect(self.stamp.addr).await?;
+        socket.send(&message.to_vec()?).await?;
+
+        let mut buf = vec![0u8; MAX_UDP_RESPONSE_LEN];
+        let len = socket.recv(&mut buf).await?;
+        let response = Message::from_bytes(&buf[..len])?;
+
+        response
+            .answers()
+            .iter()
+            .filter_map(|r| match r.data() {
+                Some(RData::TXT(txt)) => Some(txt.txt_data().concat()),
+                _ => None,
+            })
+            .filter_map(
+                |bytes| match Certificate::parse(&bytes, &self.stamp.provider_pk) {
+                    Ok(cert) => Some(cert),
+                    Err(err) => {
+                        debug!("dnscrypt certificate skipped, {}", err);
+                        None
+                    }
+                },
+            )
+            .filter(|cert| cert.is_valid(now))
+            .max_by_key(|cert| (cert.serial, cert.es_version))
+            .ok_or_else(|| ResolveError::from("no valid dnscrypt certificate"))
+    }
+
+    async fn exchange_udp(&self, session: &Session, query: &[u8]) -> Result<Message, ResolveError> {
+        let (packet, client_nonce) = session.encrypt(query, MIN_UDP_QUERY_LEN)?;
+
+        let socket = UdpSocket::bind(unspecified(&self.stamp.addr)).await?;
+        socket.connect(self.stamp.addr).await?;
+        socket.send(&packet).await?;
+
+        let mut buf = vec![0u8; MAX_UDP_RESPONSE_LEN];
+        let len = socket.recv(&mut buf).await?;
+
+        session.decrypt(&buf[..len], &client_nonce)
+    }
+
+    async fn exchange_tcp(&self, session: &Session, query: &[u8]) -> Result<Message, ResolveError> {
+        let (packet, client_nonce) = session.encrypt(query, 0)?;
+
+        let mut stream = TcpStream::connect(self.stamp.addr).await?;
+        stream
+            .write_all(&(packet.len() as u16).to_be_bytes())
+            .await?;
+        stream.write_all(&packet).await?;
+
+        let len = stream.read_u16().await? as usize;
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf).await?;
+
+        session.decrypt(&buf, &client_nonce)
+    }
+}
+
+impl fmt::Debug for DnsCryptClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsCryptClient")
+            .field("stamp", &self.stamp)
+            .finish()
+    }
+}
+
+fn unspecified(addr: &SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    }
+}
+
+fn unix_now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn test_parse_stamp() {
+        let provider_pk = (0..32).collect::<Vec<u8>>();
+
+        let stamp = DnsCryptStamp::from_str(
+            "sdns://AQcAAAAAAAAADjIxMi40Ny4yMjguMTM2IAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4fHzIuZG5zY3J5cHQtY2VydC5mci5kbnNjcnlwdC5vcmc",
+        )
+        .unwrap();
+
+        assert_eq!(stamp.addr, "212.47.228.136:443".parse().unwrap());
+        assert_eq!(stamp.provider_pk.as_slice(), provider_pk.as_slice());
+        assert_eq!(stamp.provider_name, "2.dnscrypt-cert.fr.dnscrypt.org");
+        assert_eq!(stamp.props, 0x07);
+
+        let stamp = DnsCryptStamp::from_str(
+            "sdns://AQAAAAAAAAAADVsyMDAxOmRiODo6MV0gAAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8bMi5kbnNjcnlwdC1jZXJ0LmV4YW1wbGUuY29t",
+        )
+        .unwrap();
+        assert_eq!(stamp.addr, "[2001:db8::1]:443".parse().unwrap());
+        assert_eq!(stamp.props, 0);
+
+        // a doh stamp.
+        assert!(DnsCryptStamp::from_str("sdns://AgAAAAAAAAAA").is_err());
+        assert!(DnsCryptStamp::from_str("https://dns.google").is_err());
+    }
+
+    #[test]
+    fn test_stamp_round_trip() {
+        for s in [
+            "sdns://AQcAAAAAAAAADjIxMi40Ny4yMjguMTM2IAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4fHzIuZG5zY3J5cHQtY2VydC5mci5kbnNjcnlwdC5vcmc",
+            "sdns://AQAAAAAAAAAADVsyMDAxOmRiODo6MV0gAAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8bMi5kbnNjcnlwdC1jZXJ0LmV4YW1wbGUuY29t",
+        ] {
+            let stamp = DnsCryptStamp::from_str(s).unwrap();
+            assert_eq!(stamp.to_string(), s);
+        }
+
+        let stamp = DnsCryptStamp {
+            props: 0x01,
+            addr: "[2001:db8::1]:8443".parse().unwrap(),
+            provider_pk: [7; 32],
+            provider_name: "2.dnscrypt-cert.example.com".to_string(),
+        };
+        assert_eq!(DnsCryptStamp::from_str(&stamp.to_string()), Ok(stamp));
+    }
+
+    #[test]
+    fn test_parse_certificate() {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let provider = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let provider_pk: [u8; 32] = provider.public_key().as_ref().try_into().unwrap();
+
+        let mut signed = vec![7u8; 32];
+        signed.extend_from_slice(b"magic123");
+        signed.extend_from_slice(&3u32.to_be_bytes());
+        signed.extend_from_slice(&100u32.to_be_bytes());
+        signed.extend_from_slice(&200u32.to_be_bytes());
+
+        let mut bytes = CERT_MAGIC.to_vec();
+        bytes.extend_from_slice(&[0, 2, 0, 0]);
+        bytes.extend_from_slice(provider.sign(&signed).as_ref());
+        bytes.extend_from_slice(&signed);
+
+        let cert = Certificate::parse(&bytes, &provider_pk).unwrap();
+        assert_eq!(cert.es_version, 2);
+        assert_eq!(&cert.client_magic, b"magic123");
+        assert_eq!(cert.serial, 3);
+        assert!(cert.is_valid(150));
+        assert!(!cert.is_valid(201));
+
+        // tampered.
+        bytes[80] ^= 1;
+        assert!(Certificate::parse(&bytes, &provider_pk).is_err());
+    }
+
+    #[test]
+    fn test_padding() {
+        let padded = pad(b"query", MIN_UDP_QUERY_LEN);
+        assert_eq!(padded.len(), MIN_UDP_QUERY_LEN);
+        assert_eq!(unpad(&padded), Some(b"query".as_slice()));
+
+        assert_eq!(pad(&[1u8; 64], 0).len(), 128);
+        assert_eq!(unpad(&[1, 2, 0]), None);
+    }
+}
@@ -0,0 +1,398 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use trust_dns_resolver::config::Protocol;
+
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{info, warn};
+
+/// The content type negotiated over the frame streams.
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// The messages queued while the collector is slow or unreachable, the newer ones are dropped.
+const QUEUE_SIZE: usize = 4096;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The frame streams control frame types.
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_STOP: u32 = 3;
+const CONTROL_READY: u32 = 4;
+const CONTROL_FINISH: u32 = 5;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 1;
+const MAX_CONTROL_FRAME: usize = 512;
+
+/// The dnstap message types of the upstream exchanges.
+const RESOLVER_QUERY: u64 = 3;
+const RESOLVER_RESPONSE: u64 = 4;
+
+/// Where the dnstap collector listens, e.g. `unix:/var/run/dnstap.sock` or `tcp:127.0.0.1:6000`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnstapAddr {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl FromStr for DnstapAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+
+        if s.starts_with('/') {
+            return Ok(Self::Unix(PathBuf::from(s)));
+        }
+
+        match s.strip_prefix("tcp:") {
+            Some(addr) if addr.rsplit_once(':').is_some() => Ok(Self::Tcp(addr.to_string())),
+            _ => Err(format!("invalid dnstap address {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for DnstapAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+/// The upstream side of an exchange.
+#[derive(Debug, Clone, Copy)]
+pub struct DnstapPeer {
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+    pub dnscrypt: bool,
+}
+
+impl DnstapPeer {
+    /// The `SocketProtocol` of the dnstap schema.
+    fn socket_protocol(&self) -> u64 {
+        if self.protocol == Protocol::Udp {
+            if self.dnscrypt {
+                5
+            } else {
+                1
+            }
+        } else if self.protocol == Protocol::Tls {
+            3
+        } else if self.protocol == Protocol::Https {
+            4
+        } else if self.dnscrypt {
+            6
+        } else {
+            2
+        }
+    }
+}
+
+/// The producer of the dnstap messages, sent to the collector by a background task over frame streams.
+#[derive(Debug)]
+pub struct DnstapSink {
+    tx: mpsc::Sender<Vec<u8>>,
+    dropped: AtomicU64,
+}
+
+impl DnstapSink {
+    pub fn spawn(addr: DnstapAddr, tasks: &BackgroundTasks) -> Arc<Self> {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tasks.spawn(run(addr, rx));
+        Arc::new(Self {
+            tx,
+            dropped: Default::default(),
+        })
+    }
+
+    /// A query sent to the upstream, the wire format `message`.
+    pub fn resolver_query(&self, peer: &DnstapPeer, query_time: SystemTime, message: &[u8]) {
+        self.send(encode_message(
+            RESOLVER_QUERY,
+            peer,
+            query_time,
+            Some(message),
+            None,
+        ));
+    }
+
+    /// A response received from the upstream, to the query sent at `query_time`.
+    pub fn resolver_response(&self, peer: &DnstapPeer, query_time: SystemTime, message: &[u8]) {
+        self.send(encode_message(
+            RESOLVER_RESPONSE,
+            peer,
+            query_time,
+            None,
+            Some((SystemTime::now(), message)),
+        ));
+    }
+
+    fn send(&self, frame: Vec<u8>) {
+        if self.tx.try_send(frame).is_err() {
+            // report once per thousand, not to flood the log while the collector is away.
+            if self.dropped.fetch_add(1, Ordering::Relaxed) % 1000 == 0 {
+                warn!("dnstap queue full, messages dropped");
+            }
+        }
+    }
+}
+
+trait FrameStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> FrameStream for T {}
+
+async fn run(addr: DnstapAddr, mut rx: mpsc::Receiver<Vec<u8>>) {
+    loop {
+        match connect(&addr).await {
+            Ok(mut stream) => {
+                info!("dnstap connected to {}", addr);
+                match write_frames(&mut stream, &mut rx).await {
+                    Ok(()) => break,
+                    Err(err) => warn!("dnstap to {} disconnected, {}", addr, err),
+                }
+            }
+            Err(err) => warn!("dnstap connect to {} failed, {}", addr, err),
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn connect(addr: &DnstapAddr) -> io::Result<Box<dyn FrameStream>> {
+    match addr {
+        #[cfg(unix)]
+        DnstapAddr::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+        #[cfg(not(unix))]
+        DnstapAddr::Unix(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix socket is not supported on this platform",
+        )),
+        DnstapAddr::Tcp(addr) => Ok(Box::new(tokio::net::TcpStream::connect(addr).await?)),
+    }
+}
+
+/// The bidirectional frame streams: READY, ACCEPT, START, the data frames, then STOP and FINISH.
+async fn write_frames<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    rx: &mut mpsc::Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    stream.write_all(&control_frame(CONTROL_READY)).await?;
+
+    if read_control_frame(stream).await? != CONTROL_ACCEPT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "content type not accepted",
+        ));
+    }
+
+    stream.write_all(&control_frame(CONTROL_START)).await?;
+    stream.flush().await?;
+
+    while let Some(frame) = rx.recv().await {
+        stream
+            .write_all(&(frame.len() as u32).to_be_bytes())
+            .await?;
+        stream.write_all(&frame).await?;
+        stream.flush().await?;
+    }
+
+    stream.write_all(&control_frame(CONTROL_STOP)).await?;
+    stream.flush().await?;
+
+    if read_control_frame(stream).await? != CONTROL_FINISH {
+        warn!("dnstap stopped without finish");
+    }
+
+    Ok(())
+}
+
+/// A control frame, escaped by a zero length, carrying the content type except STOP.
+fn control_frame(control_type: u32) -> Vec<u8> {
+    let mut payload = control_type.to_be_bytes().to_vec();
+    if control_type != CONTROL_STOP {
+        payload.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        payload.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        payload.extend_from_slice(CONTENT_TYPE);
+    }
+
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+async fn read_control_frame<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<u32> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    if stream.read_u32().await? != 0 {
+        return Err(invalid("data frame, while expecting a control frame"));
+    }
+
+    let len = stream.read_u32().await? as usize;
+    if !(4..=MAX_CONTROL_FRAME).contains(&len) {
+        return Err(invalid("invalid control frame length"));
+    }
+
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).await?;
+
+    Ok(u32::from_be_bytes([
+        payload[0], payload[1], payload[2], payload[3],
+    ]))
+}
+
+/// Encode a `Dnstap` protobuf of the `MESSAGE` type, see dnstap.proto.
+fn encode_message(
+    message_type: u64,
+    peer: &DnstapPeer,
+    query_time: SystemTime,
+    query_message: Option<&[u8]>,
+    response: Option<(SystemTime, &[u8])>,
+) -> Vec<u8> {
+    let mut message = vec![];
+    put_varint_field(&mut message, 1, message_type);
+    put_varint_field(&mut message, 2, if peer.addr.is_ipv4() { 1 } else { 2 });
+    put_varint_field(&mut message, 3, peer.socket_protocol());
+
+    match peer.addr {
+        SocketAddr::V4(addr) => put_bytes_field(&mut message, 5, &addr.ip().octets()),
+        SocketAddr::V6(addr) => put_bytes_field(&mut message, 5, &addr.ip().octets()),
+    }
+    put_varint_field(&mut message, 7, peer.addr.port() as u64);
+
+    let (sec, nsec) = unix_time(query_time);
+    put_varint_field(&mut message, 8, sec);
+    put_fixed32_field(&mut message, 9, nsec);
+
+    if let Some(query_message) = query_message {
+        put_bytes_field(&mut message, 10, query_message);
+    }
+
+    if let Some((response_time, response_message)) = response {
+        let (sec, nsec) = unix_time(response_time);
+        put_varint_field(&mut message, 12, sec);
+        put_fixed32_field(&mut message, 13, nsec);
+        put_bytes_field(&mut message, 14, response_message);
+    }
+
+    let mut dnstap = vec![];
+    put_bytes_field(
+        &mut dnstap,
+        2,
+        concat!("smartdns-rs ", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    put_bytes_field(&mut dnstap, 14, &message);
+    put_varint_field(&mut dnstap, 15, 1);
+    dnstap
+}
+
+fn unix_time(time: SystemTime) -> (u64, u32) {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since.as_secs(), since.subsec_nanos())
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, value);
+}
+
+fn put_fixed32_field(buf: &mut Vec<u8>, field: u64, value: u32) {
+    put_varint(buf, field << 3 | 5);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dnstap_addr() {
+        assert_eq!(
+            DnstapAddr::from_str("unix:/var/run/dnstap.sock"),
+            Ok(DnstapAddr::Unix(PathBuf::from("/var/run/dnstap.sock")))
+        );
+        assert_eq!(
+            DnstapAddr::from_str("/var/run/dnstap.sock"),
+            Ok(DnstapAddr::Unix(PathBuf::from("/var/run/dnstap.sock")))
+        );
+        assert_eq!(
+            DnstapAddr::from_str("tcp:127.0.0.1:6000"),
+            Ok(DnstapAddr::Tcp("127.0.0.1:6000".to_string()))
+        );
+        assert!(DnstapAddr::from_str("tcp:127.0.0.1").is_err());
+        assert!(DnstapAddr::from_str("dnstap.sock").is_err());
+    }
+
+    #[test]
+    fn test_control_frame() {
+        let frame = control_frame(CONTROL_READY);
+        assert_eq!(&frame[..4], &[0, 0, 0, 0]);
+        assert_eq!(
+            &frame[4..8],
+            &(12 + CONTENT_TYPE.len() as u32).to_be_bytes()
+        );
+        assert_eq!(&frame[8..12], &CONTROL_READY.to_be_bytes());
+        assert_eq!(&frame[20..], CONTENT_TYPE);
+
+        assert_eq!(
+            control_frame(CONTROL_STOP),
+            vec![0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]
+        );
+    }
+
+    #[test]
+    fn test_encode_message() {
+        let peer = DnstapPeer {
+            addr: "8.8.8.8:853".parse().unwrap(),
+            protocol: Protocol::Tls,
+            dnscrypt: false,
+        };
+        let time = UNIX_EPOCH + Duration::new(300, 7);
+
+        let dnstap = encode_message(RESOLVER_QUERY, &peer, time, Some(&[0xab, 0xcd]), None);
+
+        let message = [
+            0x08, 3, // type
+            0x10, 1, // socket_family
+            0x18, 3, // socket_protocol
+            0x2a, 4, 8, 8, 8, 8, // response_address
+            0x38, 0xd5, 0x06, // response_port
+            0x40, 0xac, 0x02, // query_time_sec
+            0x4d, 7, 0, 0, 0, // query_time_nsec
+            0x52, 2, 0xab, 0xcd, // query_message
+        ];
+
+        let version = concat!("smartdns-rs ", env!("CARGO_PKG_VERSION")).as_bytes();
+        let mut expected = vec![0x12, version.len() as u8];
+        expected.extend_from_slice(version);
+        expected.extend_from_slice(&[0x72, message.len() as u8]);
+        expected.extend_from_slice(&message);
+        expected.extend_from_slice(&[0x78, 1]);
+
+        assert_eq!(dnstap, expected);
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use memmap2::Mmap;
+use trust_dns_client::rr::{domain, LowerName};
+use trust_dns_proto::serialize::binary::BinEncodable;
+
+use crate::dns_conf::{preline, DomainOrDomainSet};
+
+/// The header of the compiled domain set, followed by the sections of the domains, the full
+/// names, the keywords and the regular expressions. Each section is the count, the offsets of
+/// the entries and then the entries, the names in wire format and sorted, so that they are
+/// looked up in place by binary search, with the file mapped into memory rather than read.
+const MAGIC: &[u8; 8] = b"SDNSSET\x03";
+
+/// The domains of a set, one per line, with the same prefixes as the domain rules:
+///   domain:example.com, or no prefix, the domain and its subdomains.
+///   full:example.com, the domain only.
+///   keyword:ads, the domains containing the keyword.
+///   regexp:^ad[0-9]+\., the domains matching the regular expression.
+#[derive(Debug, Default, Clone)]
+pub struct DomainSet {
+    pub domains: HashSet<LowerName>,
+    pub full: HashSet<LowerName>,
+    pub keywords: Vec<String>,
+    pub regexes: Vec<String>,
+    /// the names of the compiled sets, looked up in the mapped files.
+    mapped: Vec<MappedSet>,
+}
+
+impl DomainSet {
+    /// Insert the domain of a line, false if invalid.
+    pub fn insert(&mut self, line: &str) -> bool {
+        match DomainOrDomainSet::from_str(line) {
+            Ok(DomainOrDomainSet::Domain(name)) => self.domains.insert(name),
+            Ok(DomainOrDomainSet::Full(name)) => self.full.insert(name),
+            Ok(DomainOrDomainSet::Keyword(keyword)) => {
+                self.keywords.push(keyword);
+                true
+            }
+            Ok(DomainOrDomainSet::Regex(regex)) => {
+                self.regexes.push(regex);
+                true
+            }
+            Ok(DomainOrDomainSet::DomainSet(_)) | Err(_) => false,
+        }
+    }
+
+    pub fn extend(&mut self, other: DomainSet) {
+        self.domains.extend(other.domains);
+        self.full.extend(other.full);
+        self.keywords.extend(other.keywords);
+        self.regexes.extend(other.regexes);
+        self.mapped.extend(other.mapped);
+    }
+
+    /// Whether the domain, not its subdomains, is in the set.
+    pub fn contains_domain(&self, name: &LowerName) -> bool {
+        self.domains.contains(name) || self.contains_mapped(name, |set| &set.domains)
+    }
+
+    /// Whether the name is in the set as a full name.
+    pub fn contains_full(&self, name: &LowerName) -> bool {
+        self.full.contains(name) || self.contains_mapped(name, |set| &set.full)
+    }
+
+    fn contains_mapped(&self, name: &LowerName, names: fn(&MappedSet) -> &MappedNames) -> bool {
+        if self.mapped.is_empty() {
+            return false;
+        }
+
+        match domain::Name::from(name.clone()).to_bytes() {
+            Ok(key) => self.mapped.iter().any(|set| names(set).contains(&key)),
+            Err(_) => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+            + self.full.len()
+            + self.keywords.len()
+            + self.regexes.len()
+            + self
+                .mapped
+                .iter()
+                .map(|set| set.domains.len() + set.full.len())
+                .sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Read the domain set, either compiled or in plain text with one domain per line.
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<DomainSet> {
+    let mut file = File::open(path)?;
+
+    let mut magic = [0; MAGIC.len()];
+    let compiled = match file.read_exact(&mut magic) {
+        Ok(()) => &magic == MAGIC,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(err) => return Err(err),
+    };
+
+    if compiled {
+        // Safety: the artifact is replaced by renaming, see `compile`, never written in place.
+        let map = unsafe { Mmap::map(&file)? };
+        return decode(Arc::new(map));
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    parse_text(BufReader::new(file)).map(|(set, _)| set)
+}
+
+/// Compile the plain text domain set into the binary artifact,
+/// which is loaded without parsing a domain per line at startup.
+pub fn compile<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> io::Result<usize> {
+    let (set, invalid) = parse_text(BufReader::new(File::open(input)?))?;
+
+    // not dropped silently, as the artifact is loaded without telling.
+    if let Some((line, text)) = invalid.first() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "invalid domain {:?} at line {}, {} invalid in total",
+                text,
+                line,
+                invalid.len()
+            ),
+        ));
+    }
+
+    let bytes = encode(&set)?;
+
+    // write to a temp file first, not to break the artifact being loaded.
+    let output = output.as_ref();
+    let tmp = output.with_extension("tmp");
+    File::create(&tmp)?.write_all(&bytes)?;
+    fs::rename(tmp, output)?;
+
+    Ok(set.len())
+}
+
+/// The domains of the lines, as loaded from the files before compiled, and the invalid lines
+/// skipped with their numbers.
+fn parse_text<R: BufRead>(reader: R) -> io::Result<(DomainSet, Vec<(usize, String)>)> {
+    let mut set = DomainSet::default();
+    let mut invalid = vec![];
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if let Some(line) = preline(&line) {
+            if !set.insert(line) {
+                invalid.push((i + 1, line.to_string()));
+            }
+        }
+    }
+
+    Ok((set, invalid))
+}
+
+fn encode(set: &DomainSet) -> io::Result<Vec<u8>> {
+    let names = |names: &HashSet<LowerName>| {
+        let mut names = names
+            .iter()
+            .map(|name| {
+                domain::Name::from(name.clone())
+                    .to_bytes()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // sorted, to be searched, and so that the artifact is reproducible.
+        names.sort_unstable();
+        Ok::<_, io::Error>(names)
+    };
+
+    let mut bytes = MAGIC.to_vec();
+    encode_section(&names(&set.domains)?, &mut bytes)?;
+    encode_section(&names(&set.full)?, &mut bytes)?;
+    encode_section(&set.keywords, &mut bytes)?;
+    encode_section(&set.regexes, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn encode_section<T: AsRef<[u8]>>(entries: &[T], bytes: &mut Vec<u8>) -> io::Result<()> {
+    let too_large = || io::Error::new(io::ErrorKind::InvalidData, "domain set too large");
+
+    let count = u32::try_from(entries.len()).map_err(|_| too_large())?;
+    bytes.extend_from_slice(&count.to_le_bytes());
+
+    let mut offset = 0u32;
+    bytes.extend_from_slice(&offset.to_le_bytes());
+    for entry in entries {
+        offset = u32::try_from(entry.as_ref().len())
+            .ok()
+            .and_then(|len| offset.checked_add(len))
+            .ok_or_else(too_large)?;
+        bytes.extend_from_slice(&offset.to_le_bytes());
+    }
+
+    for entry in entries {
+        bytes.extend_from_slice(entry.as_ref());
+    }
+
+    Ok(())
+}
+
+fn decode(map: Arc<Mmap>) -> io::Result<DomainSet> {
+    let pos = MAGIC.len();
+    let domains = MappedNames::new(map.clone(), pos)?;
+    let full = MappedNames::new(map.clone(), domains.end())?;
+    let keywords = MappedNames::new(map.clone(), full.end())?;
+    let regexes = MappedNames::new(map.clone(), keywords.end())?;
+
+    // the patterns are compiled into the matchers anyway, so copied.
+    let strings = |section: &MappedNames| {
+        (0..section.len())
+            .map(|i| {
+                std::str::from_utf8(section.get(i))
+                    .map(|s| s.to_string())
+                    .map_err(|_| invalid())
+            })
+            .collect::<io::Result<Vec<_>>>()
+    };
+
+    Ok(DomainSet {
+        keywords: strings(&keywords)?,
+        regexes: strings(&regexes)?,
+        mapped: vec![MappedSet { domains, full }],
+        ..Default::default()
+    })
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed compiled domain set")
+}
+
+#[derive(Debug, Clone)]
+struct MappedSet {
+    domains: MappedNames,
+    full: MappedNames,
+}
+
+/// A section of the compiled domain set, its entries read in the mapped file.
+#[derive(Clone)]
+struct MappedNames {
+    map: Arc<Mmap>,
+    count: usize,
+    /// the position of the offsets.
+    offsets: usize,
+    /// the position of the entries.
+    entries: usize,
+}
+
+impl MappedNames {
+    /// The section at the position, checked once so that the lookups need not.
+    fn new(map: Arc<Mmap>, pos: usize) -> io::Result<Self> {
+        let count = read_u32(&map, pos)? as usize;
+
+        // the count is not trusted for anything before checked against the length.
+        let offsets = pos + 4;
+        let entries = count
+            .checked_add(1)
+            .and_then(|n| n.checked_mul(4))
+            .and_then(|len| offsets.checked_add(len))
+            .filter(|end| *end <= map.len())
+            .ok_or_else(invalid)?;
+
+        let section = Self {
+            map,
+            count,
+            offsets,
+            entries,
+        };
+
+        let mut prev = section.offset(0);
+        if prev != 0 {
+            return Err(invalid());
+        }
+        for i in 1..=count {
+            let offset = section.offset(i);
+            if offset < prev || entries + offset > section.map.len() {
+                return Err(invalid());
+            }
+            prev = offset;
+        }
+
+        Ok(section)
+    }
+
+    fn len(&self) -> usize {
+        self.count
+    }
+
+    /// The position following the section.
+    fn end(&self) -> usize {
+        self.entries + self.offset(self.count)
+    }
+
+    fn offset(&self, i: usize) -> usize {
+        let pos = self.offsets + i * 4;
+        u32::from_le_bytes([
+            self.map[pos],
+            self.map[pos + 1],
+            self.map[pos + 2],
+            self.map[pos + 3],
+        ]) as usize
+    }
+
+    fn get(&self, i: usize) -> &[u8] {
+        &self.map[self.entries + self.offset(i)..self.entries + self.offset(i + 1)]
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        let (mut low, mut high) = (0, self.count);
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.get(mid).cmp(key) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return true,
+            }
+        }
+
+        false
+    }
+}
+
+impl fmt::Debug for MappedNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MappedNames")
+            .field("count", &self.count)
+            .finish()
+    }
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> io::Result<u32> {
+    match bytes.get(pos..pos + 4) {
+        Some([a, b, c, d]) => Ok(u32::from_le_bytes([*a, *b, *c, *d])),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> LowerName {
+        domain::Name::from_str(s).unwrap().into()
+    }
+
+    fn compiled(test: &str, text: &str) -> io::Result<DomainSet> {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("smartdns-test-{}-{}.txt", std::process::id(), test));
+        let output = input.with_extension("bin");
+
+        fs::write(&input, text)?;
+        let compiled = compile(&input, &output).and_then(|_| read(&output));
+
+        let _ = fs::remove_file(&input);
+        let _ = fs::remove_file(&output);
+        compiled
+    }
+
+    #[test]
+    fn test_compile_domain_set() {
+        let text = "# ads\nads1.com\n\n  q.ads2.net # comment\n";
+
+        let (set, invalid) = parse_text(BufReader::new(text.as_bytes())).unwrap();
+        assert_eq!(set.len(), 2);
+        assert!(invalid.is_empty());
+
+        let bytes = encode(&set).unwrap();
+        assert!(bytes.starts_with(MAGIC));
+
+        let compiled = compiled("plain", text).unwrap();
+        assert_eq!(compiled.len(), 2);
+        assert!(compiled.domains.is_empty());
+        assert!(compiled.contains_domain(&name("ads1.com.")));
+        assert!(compiled.contains_domain(&name("q.ads2.net.")));
+        assert!(!compiled.contains_domain(&name("ads2.net.")));
+        assert!(!compiled.contains_full(&name("ads1.com.")));
+    }
+
+    #[test]
+    fn test_compile_domain_set_patterns() {
+        let text =
+            "domain:ads1.com\nfull:ads2.net\nkeyword:tracker\nregexp:^ad[0-9]+\\.\nregexp:(\n";
+
+        let (set, invalid) = parse_text(BufReader::new(text.as_bytes())).unwrap();
+        assert_eq!(set.domains.len(), 1);
+        assert_eq!(set.full.len(), 1);
+        assert_eq!(set.keywords, vec!["tracker".to_string()]);
+        // the invalid regular expression skipped.
+        assert_eq!(set.regexes, vec!["^ad[0-9]+\\.".to_string()]);
+        assert_eq!(invalid, vec![(5, "regexp:(".to_string())]);
+
+        // but not compiled.
+        assert!(compiled("invalid", text).is_err());
+
+        let compiled = compiled("patterns", &text.replace("regexp:(\n", "")).unwrap();
+        assert!(compiled.contains_domain(&name("ads1.com.")));
+        assert!(compiled.contains_full(&name("ads2.net.")));
+        assert_eq!(compiled.keywords, set.keywords);
+        assert_eq!(compiled.regexes, set.regexes);
+    }
+
+    #[test]
+    fn test_compiled_domain_set_malformed() {
+        let (set, _) = parse_text(BufReader::new("ads1.com\nads2.com\n".as_bytes())).unwrap();
+        let bytes = encode(&set).unwrap();
+
+        let path = std::env::temp_dir().join(format!(
+            "smartdns-test-{}-malformed.bin",
+            std::process::id()
+        ));
+
+        // truncated.
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(read(&path).is_err());
+
+        // a count far beyond the length, not allocated for.
+        let mut huge = MAGIC.to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &huge).unwrap();
+        assert!(read(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+use maxminddb::{geoip2, Reader};
+
+use crate::geosite::{invalid, FieldValue, Fields};
+
+/// The marker starting the metadata section at the end of a MaxMind database.
+const MMDB_METADATA: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// The countries of the ips, by a MaxMind `.mmdb`, or by a v2ray/xray `geoip.dat`
+/// whose categories, e.g. `private`, are taken as countries too.
+pub enum GeoIp {
+    Mmdb(Reader<Vec<u8>>),
+    /// the ip ranges of each country, sorted and merged, the ipv4 mapped into ipv6.
+    Dat(HashMap<String, Vec<(u128, u128)>>),
+}
+
+impl GeoIp {
+    /// Open the file, only the countries given, lowercase, are loaded of a geoip.dat.
+    pub fn open<P: AsRef<Path>>(path: P, countries: &HashSet<String>) -> io::Result<Self> {
+        let data = std::fs::read(path)?;
+
+        if data
+            .windows(MMDB_METADATA.len())
+            .rev()
+            .any(|w| w == MMDB_METADATA)
+        {
+            Reader::from_source(data).map(GeoIp::Mmdb).map_err(invalid)
+        } else {
+            parse_dat(&data, countries).map(GeoIp::Dat)
+        }
+    }
+
+    /// Whether the ip is of the country, e.g. `cn`, case insensitive.
+    pub fn contains(&self, ip: IpAddr, country: &str) -> bool {
+        match self {
+            GeoIp::Mmdb(reader) => reader
+                .lookup::<geoip2::Country>(ip)
+                .ok()
+                .and_then(|c| c.country)
+                .and_then(|c| c.iso_code)
+                .map_or(false, |code| code.eq_ignore_ascii_case(country)),
+            GeoIp::Dat(countries) => {
+                let ranges = match countries.get(&country.to_lowercase()) {
+                    Some(ranges) => ranges,
+                    None => return false,
+                };
+                let ip = ip_to_u128(ip);
+                let idx = ranges.partition_point(|(start, _)| *start <= ip);
+                idx > 0 && ranges[idx - 1].1 >= ip
+            }
+        }
+    }
+
+    /// Whether the ip is of any of the countries.
+    pub fn contains_any(&self, ip: IpAddr, countries: &[String]) -> bool {
+        countries.iter().any(|country| self.contains(ip, country))
+    }
+}
+
+impl Debug for GeoIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeoIp::Mmdb(reader) => write!(f, "GeoIp::Mmdb({})", reader.metadata.database_type),
+            GeoIp::Dat(countries) => write!(f, "GeoIp::Dat({:?})", countries.keys()),
+        }
+    }
+}
+
+/// The protobuf encoded `GeoIPList`:
+///   GeoIPList { repeated GeoIP entry = 1; }
+///   GeoIP { string country_code = 1; repeated CIDR cidr = 2; ... }
+///   CIDR { bytes ip = 1; uint32 prefix = 2; }
+fn parse_dat(
+    data: &[u8],
+    countries: &HashSet<String>,
+) -> io::Result<HashMap<String, Vec<(u128, u128)>>> {
+    let mut geoip = HashMap::new();
+
+    for entry in Fields::new(data) {
+        let (field, value) = entry?;
+        if field != 1 {
+            continue;
+        }
+
+        let mut code = None;
+        let mut cidrs = vec![];
+        for geoip_field in Fields::new(value.bytes()?) {
+            match geoip_field? {
+                (1, value) => code = Some(std::str::from_utf8(value.bytes()?).map_err(invalid)?),
+                (2, value) => cidrs.push(value.bytes()?),
+                _ => (),
+            }
+        }
+
+        let code = match code {
+            Some(code) if countries.contains(&code.to_lowercase()) => code.to_lowercase(),
+            _ => continue,
+        };
+
+        let ranges: &mut Vec<_> = geoip.entry(code).or_default();
+        for cidr in cidrs {
+            ranges.push(parse_cidr(cidr)?);
+        }
+    }
+
+    for ranges in geoip.values_mut() {
+        *ranges = merge(std::mem::take(ranges));
+    }
+
+    Ok(geoip)
+}
+
+fn parse_cidr(data: &[u8]) -> io::Result<(u128, u128)> {
+    let mut ip = None;
+    let mut prefix = 0;
+
+    for field in Fields::new(data) {
+        match field? {
+            (1, value) => {
+                ip = Some(match value.bytes()? {
+                    bytes if bytes.len() == 4 => IpAddr::from(<[u8; 4]>::try_from(bytes).unwrap()),
+                    bytes if bytes.len() == 16 => {
+                        IpAddr::from(<[u8; 16]>::try_from(bytes).unwrap())
+                    }
+                    _ => return Err(invalid("invalid cidr ip")),
+                })
+            }
+            (2, FieldValue::Varint(value)) => prefix = value as u32,
+            _ => (),
+        }
+    }
+
+    let ip = ip.ok_or_else(|| invalid("cidr without ip"))?;
+    let prefix = match ip {
+        IpAddr::V4(_) if prefix <= 32 => prefix + 96,
+        IpAddr::V6(_) if prefix <= 128 => prefix,
+        _ => return Err(invalid("invalid cidr prefix")),
+    };
+
+    let host_mask = u128::MAX.checked_shr(prefix).unwrap_or(0);
+    let start = ip_to_u128(ip) & !host_mask;
+    Ok((start, start | host_mask))
+}
+
+/// Sort the ranges and merge the overlapping or adjacent ones.
+fn merge(mut ranges: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn ip_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(field: u64, bytes: &[u8]) -> Vec<u8> {
+        let mut data = vec![(field << 3 | 2) as u8, bytes.len() as u8];
+        data.extend_from_slice(bytes);
+        data
+    }
+
+    fn cidr(ip: &str, prefix: u8) -> Vec<u8> {
+        let ip = match ip.parse::<IpAddr>().unwrap() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let mut data = message(1, &ip);
+        data.extend([2 << 3, prefix]);
+        data
+    }
+
+    fn geoip(code: &str, cidrs: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = message(1, code.as_bytes());
+        for c in cidrs {
+            data.extend(message(2, c));
+        }
+        message(1, &data)
+    }
+
+    #[test]
+    fn test_geoip_dat() {
+        let mut data = geoip(
+            "CN",
+            &[cidr("1.0.1.0", 24), cidr("1.0.2.0", 23), cidr("240e::", 20)],
+        );
+        data.extend(geoip("US", &[cidr("8.8.8.0", 24)]));
+
+        let countries = HashSet::from(["cn".to_string()]);
+        let db = GeoIp::Dat(parse_dat(&data, &countries).unwrap());
+
+        if let GeoIp::Dat(ref ranges) = db {
+            // the adjacent ones merged.
+            assert_eq!(ranges["cn"].len(), 2);
+            assert!(!ranges.contains_key("us"));
+        }
+
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert!(db.contains(ip("1.0.1.1"), "CN"));
+        assert!(db.contains(ip("1.0.3.255"), "cn"));
+        assert!(db.contains(ip("240e:1::1"), "cn"));
+        assert!(!db.contains(ip("1.0.4.0"), "cn"));
+        assert!(!db.contains(ip("1.0.0.255"), "cn"));
+        assert!(!db.contains(ip("8.8.8.8"), "us"));
+        assert!(db.contains_any(ip("1.0.1.1"), &["us".to_string(), "cn".to_string()]));
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+
+use crate::domain_set::DomainSet;
+
+/// The types of `Domain` in the v2ray `geosite.dat`.
+const PLAIN: u64 = 0;
+const REGEX: u64 = 1;
+const DOMAIN: u64 = 2;
+const FULL: u64 = 3;
+
+/// Read the categories of a v2ray/xray `geosite.dat`, e.g. `cn`, or `cn@ads` for the
+/// domains of the category with the attribute, case insensitive.
+///
+/// The file is the protobuf encoded `GeoSiteList`:
+///   GeoSiteList { repeated GeoSite entry = 1; }
+///   GeoSite { string country_code = 1; repeated Domain domain = 2; }
+///   Domain { Type type = 1; string value = 2; repeated Attribute attribute = 3; }
+///   Attribute { string key = 1; ... }
+pub fn read<P: AsRef<Path>>(
+    path: P,
+    categories: &HashSet<String>,
+) -> io::Result<HashMap<String, DomainSet>> {
+    parse(&std::fs::read(path)?, categories)
+}
+
+fn parse(data: &[u8], categories: &HashSet<String>) -> io::Result<HashMap<String, DomainSet>> {
+    let mut sets = HashMap::new();
+
+    for entry in Fields::new(data) {
+        let (field, value) = entry?;
+        if field != 1 {
+            continue;
+        }
+
+        let mut code = None;
+        let mut domains = vec![];
+        for site_field in Fields::new(value.bytes()?) {
+            match site_field? {
+                (1, value) => code = Some(std::str::from_utf8(value.bytes()?).map_err(invalid)?),
+                (2, value) => domains.push(value.bytes()?),
+                _ => (),
+            }
+        }
+
+        let code = match code {
+            Some(code) => code.to_lowercase(),
+            None => continue,
+        };
+
+        for category in categories.iter() {
+            let (name, attribute) = match category.split_once('@') {
+                Some((name, attribute)) => (name, Some(attribute)),
+                None => (category.as_str(), None),
+            };
+            if name != code {
+                continue;
+            }
+
+            let set: &mut DomainSet = sets.entry(category.to_string()).or_default();
+            for domain in domains.iter() {
+                insert_domain(set, domain, attribute)?;
+            }
+        }
+    }
+
+    Ok(sets)
+}
+
+fn insert_domain(set: &mut DomainSet, data: &[u8], attribute: Option<&str>) -> io::Result<()> {
+    let mut typ = PLAIN;
+    let mut value = "";
+    let mut attributes = vec![];
+
+    for field in Fields::new(data) {
+        match field? {
+            (1, FieldValue::Varint(v)) => typ = v,
+            (2, v) => value = std::str::from_utf8(v.bytes()?).map_err(invalid)?,
+            (3, v) => {
+                for attr_field in Fields::new(v.bytes()?) {
+                    if let (1, key) = attr_field? {
+                        attributes.push(std::str::from_utf8(key.bytes()?).map_err(invalid)?);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if let Some(attribute) = attribute {
+        if !attributes.iter().any(|a| a.eq_ignore_ascii_case(attribute)) {
+            return Ok(());
+        }
+    }
+
+    let prefix = match typ {
+        PLAIN => "keyword:",
+        REGEX => "regexp:",
+        DOMAIN => "domain:",
+        FULL => "full:",
+        _ => return Ok(()),
+    };
+
+    // the invalid ones, e.g. the regular expressions of the go syntax only, are skipped.
+    set.insert(&format!("{}{}", prefix, value));
+
+    Ok(())
+}
+
+pub(crate) enum FieldValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+impl<'a> FieldValue<'a> {
+    pub fn bytes(self) -> io::Result<&'a [u8]> {
+        match self {
+            FieldValue::Bytes(bytes) => Ok(bytes),
+            _ => Err(invalid("expect a length delimited field")),
+        }
+    }
+}
+
+/// The fields of a protobuf message, by the field number, also of the geoip.dat.
+pub(crate) struct Fields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for (i, b) in self.data.iter().enumerate().take(10) {
+            value |= ((b & 0x7f) as u64) << (7 * i);
+            if b & 0x80 == 0 {
+                self.data = &self.data[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(invalid("truncated varint"))
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(invalid("truncated field"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn field(&mut self) -> io::Result<(u64, FieldValue<'a>)> {
+        let key = self.varint()?;
+        let value = match key & 0x07 {
+            0 => FieldValue::Varint(self.varint()?),
+            1 => self.take(8).map(|_| FieldValue::Fixed)?,
+            2 => {
+                let len = self.varint()? as usize;
+                FieldValue::Bytes(self.take(len)?)
+            }
+            5 => self.take(4).map(|_| FieldValue::Fixed)?,
+            typ => return Err(invalid(format!("unsupported wire type {}", typ))),
+        };
+        Ok((key >> 3, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = io::Result<(u64, FieldValue<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            // stop at the malformed field.
+            self.data = &[];
+        }
+        Some(field)
+    }
+}
+
+pub(crate) fn invalid<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_client::rr::{LowerName, Name};
+
+    use super::*;
+
+    fn message(field: u64, bytes: &[u8]) -> Vec<u8> {
+        let mut data = vec![(field << 3 | 2) as u8, bytes.len() as u8];
+        data.extend_from_slice(bytes);
+        data
+    }
+
+    fn domain(typ: u8, value: &str, attribute: Option<&str>) -> Vec<u8> {
+        let mut data = vec![1 << 3, typ];
+        data.extend(message(2, value.as_bytes()));
+        if let Some(attribute) = attribute {
+            let mut attr = message(1, attribute.as_bytes());
+            attr.extend([2 << 3, 1]);
+            data.extend(message(3, &attr));
+        }
+        data
+    }
+
+    fn site(code: &str, domains: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = message(1, code.as_bytes());
+        for d in domains {
+            data.extend(message(2, d));
+        }
+        message(1, &data)
+    }
+
+    fn name(s: &str) -> LowerName {
+        Name::from_str(s).unwrap().into()
+    }
+
+    #[test]
+    fn test_parse_geosite() {
+        let mut data = site(
+            "CN",
+            &[
+                domain(2, "cn", None),
+                domain(3, "www.qq.com", None),
+                domain(0, "baidu", Some("ads")),
+                domain(1, "^ad[0-9]+\\.", None),
+            ],
+        );
+        data.extend(site("google", &[domain(2, "google.com", None)]));
+
+        let categories = HashSet::from(["cn".to_string(), "cn@ads".to_string()]);
+        let sets = parse(&data, &categories).unwrap();
+
+        assert_eq!(sets.len(), 2);
+
+        let cn = &sets["cn"];
+        assert!(cn.domains.contains(&name("cn.")));
+        assert!(cn.full.contains(&name("www.qq.com.")));
+        assert_eq!(cn.keywords, vec!["baidu".to_string()]);
+        assert_eq!(cn.regexes, vec!["^ad[0-9]+\\.".to_string()]);
+
+        let ads = &sets["cn@ads"];
+        assert_eq!(ads.len(), 1);
+        assert_eq!(ads.keywords, vec!["baidu".to_string()]);
+
+        assert!(parse(&data[..data.len() - 3], &categories).is_err());
+    }
+}
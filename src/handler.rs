@@ -0,0 +1,568 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[cfg(feature = "script")]
+use crate::dns_mw_script::{self, DnsScriptMiddleware, ScriptHook};
+#[cfg(feature = "wasm-plugin")]
+use crate::dns_mw_wasm;
+use crate::{
+    blocking::BlockingOverrides,
+    blocklist::{self, Blocklists},
+    control::ControlServer,
+    dns::{DnsContext, DnsError, DnsRequest, DnsResponse},
+    dns_client::{DnsClient, UpstreamOptions},
+    dns_conf::{PluginStage, SmartDnsConfig},
+    dns_mw::{DnsMiddlewareBuilder, DnsMiddlewareHandler},
+    dns_mw_addr::AddressMiddleware,
+    dns_mw_audit::DnsAuditMiddleware,
+    dns_mw_cache::{DnsCacheMiddleware, DnsCacheStore},
+    dns_mw_chaos::DnsChaosMiddleware,
+    dns_mw_cname::DnsCNameMiddleware,
+    dns_mw_container::DnsContainerMiddleware,
+    dns_mw_dualstack::DnsDualStackMiddleware,
+    dns_mw_hosts::DnsHostsMiddleware,
+    dns_mw_ipset::DnsIpSetMiddleware,
+    dns_mw_max_reply_ip::DnsMaxReplyIpMiddleware,
+    dns_mw_mdns::DnsMdnsMiddleware,
+    dns_mw_ns::NameServerMiddleware,
+    dns_mw_pin::DnsPinResultMiddleware,
+    dns_mw_qtype::DnsQueryTypeMiddleware,
+    dns_mw_secondary::DnsSecondaryMiddleware,
+    dns_mw_slo::DnsSloMiddleware,
+    dns_mw_spdt::DnsSpeedTestMiddleware,
+    dns_mw_suffix::DnsDomainSuffixMiddleware,
+    dns_mw_zone::DnsZoneMiddleware,
+    dnstap::DnstapSink,
+    geoip::GeoIp,
+    infra::{self, memory::MemoryPressure, middleware::Middleware, tasks::BackgroundTasks},
+    log::{error, warn},
+    matcher::{DomainForceTransportMatcher, DomainNameServerGroupMatcher},
+    notify::Notifier,
+    speed_check::SpeedChecker,
+    upstream_stats,
+};
+
+/// The state kept over the builds, e.g. on reload of the configuration, so that the cached
+/// answers and the blocking paused survive.
+#[derive(Clone)]
+pub struct Retained {
+    /// the cached answers.
+    cache: DnsCacheStore,
+    /// the blocking paused by the cli, see `smartdns blocking`.
+    blocking: BlockingOverrides,
+}
+
+impl Retained {
+    pub fn new(cfg: &SmartDnsConfig) -> Self {
+        Self {
+            cache: DnsCacheStore::new(cfg.cache_size()),
+            blocking: BlockingOverrides::new(),
+        }
+    }
+
+    /// Resize the cache to the one of the configuration reloaded.
+    pub async fn resize(&self, cfg: &SmartDnsConfig) {
+        self.cache.resize(cfg.cache_size()).await;
+    }
+
+    /// Answer the commands changing the state on the control socket, e.g. `blocking-pause`.
+    pub fn register_commands(&self, control: &ControlServer) {
+        self.blocking.register_commands(control);
+    }
+}
+
+/// The handler of the queries, with the stages of the configuration as run by the smartdns
+/// binary, after the ones registered by [`HandlerBuilder::with`], see the [crate] docs.
+pub struct HandlerBuilder {
+    cfg: SmartDnsConfig,
+    stages: DnsMiddlewareBuilder,
+    retained: Option<Retained>,
+    control: Option<ControlServer>,
+    tasks: Option<BackgroundTasks>,
+}
+
+impl HandlerBuilder {
+    pub fn new(cfg: SmartDnsConfig) -> Self {
+        Self {
+            cfg,
+            stages: DnsMiddlewareBuilder::new(),
+            retained: None,
+            control: None,
+            tasks: None,
+        }
+    }
+
+    /// Register a stage before the ones of the configuration, in the order registered.
+    pub fn with<M: Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> + 'static>(
+        mut self,
+        middleware: M,
+    ) -> Self {
+        self.stages = self.stages.with(middleware);
+        self
+    }
+
+    /// The state of a previous build, new if not given.
+    pub fn with_retained(mut self, retained: &Retained) -> Self {
+        self.retained = Some(retained.clone());
+        self
+    }
+
+    /// The control server the stages print their status on, none if not given.
+    pub fn with_control(mut self, control: &ControlServer) -> Self {
+        self.control = Some(control.clone());
+        self
+    }
+
+    /// The group the background tasks of the stages are spawned into, stopped along with it,
+    /// e.g. once replaced on reload. A group of its own if not given.
+    pub fn with_tasks(mut self, tasks: &BackgroundTasks) -> Self {
+        self.tasks = Some(tasks.clone());
+        self
+    }
+
+    /// Build the stages, their background tasks spawned on the current runtime.
+    pub fn build(self) -> DnsMiddlewareHandler {
+        let Self {
+            cfg,
+            stages,
+            retained,
+            control,
+            tasks,
+        } = self;
+
+        let retained = &retained.unwrap_or_else(|| Retained::new(&cfg));
+        let control = &control.unwrap_or_else(ControlServer::new);
+        let tasks = &tasks.unwrap_or_default();
+
+        let force_aaaa_soa = cfg
+            .binds
+            .iter()
+            .chain(cfg.binds_tcp.iter())
+            .chain(cfg.binds_tls.iter())
+            .chain(cfg.binds_https.iter())
+            .any(|s| s.opts.force_aaaa_soa);
+
+        let dns_client = Arc::new(DnsClient::new(
+            DomainNameServerGroupMatcher::create(&cfg),
+            DomainForceTransportMatcher::create(&cfg),
+            cfg.servers.clone(),
+            &cfg.bootstrap_servers,
+            cfg.proxy_servers.clone(),
+            UpstreamOptions {
+                geoip: cfg.geoip_file.as_ref().and_then(|path| {
+                    match GeoIp::open(path, &cfg.geoip_countries()) {
+                        Ok(geoip) => Some(Arc::new(geoip)),
+                        Err(err) => {
+                            error!("load geoip file {:?} failed, {}", path, err);
+                            None
+                        }
+                    }
+                }),
+                dnstap: cfg
+                    .dnstap
+                    .as_ref()
+                    .map(|addr| DnstapSink::spawn(addr.clone(), tasks)),
+                ..cfg.upstream_options()
+            },
+            tasks.child(),
+        ));
+
+        dns_client.spawn_nameserver_refresh();
+        control.register_status(
+            "upstreams",
+            upstream_stats::spawn_writer(&dns_client, upstream_stats::STATS_FILE, tasks),
+        );
+
+        let memory = MemoryPressure::new();
+        memory.spawn_monitor(cfg.memory_pressure_threshold(), tasks);
+
+        let mut middleware_builder = stages;
+
+        // check if audit enabled.
+        if cfg.audit_enable {
+            #[cfg(not(feature = "sqlite"))]
+            if matches!(cfg.audit_file().extension(), Some(ext) if ext == "db" || ext == "sqlite") {
+                warn!(
+                    "audit database {:?} written as text, built without the sqlite feature",
+                    cfg.audit_file()
+                );
+            }
+            middleware_builder = middleware_builder.with(DnsAuditMiddleware::new(
+                cfg.audit_file(),
+                cfg.audit_size(),
+                cfg.audit_num(),
+                cfg.audit_retention(),
+                tasks,
+            ));
+        }
+
+        // check if any latency objective defined, its state replacing the previous one on status.
+        let slo = DnsSloMiddleware::new(
+            &cfg.latency_slos,
+            Notifier::new(cfg.notify_command.clone()),
+            tasks,
+        );
+        control.register_status("slo", slo.status());
+        if !slo.is_empty() {
+            middleware_builder = middleware_builder.with(slo);
+        }
+
+        // check if the ips answered limited.
+        if let Some(max) = cfg.max_reply_ip_num() {
+            middleware_builder = middleware_builder.with(DnsMaxReplyIpMiddleware::new(max));
+        }
+
+        if cfg.enable_chaos {
+            middleware_builder = middleware_builder.with(DnsChaosMiddleware);
+        }
+
+        // check if any record type denied.
+        let query_type = DnsQueryTypeMiddleware::new(&cfg);
+        if !query_type.is_empty() {
+            middleware_builder = middleware_builder.with(query_type);
+        }
+
+        // check if any local domain the single-label queries resolved under.
+        if let Some(domain) = cfg.domain.clone() {
+            middleware_builder = middleware_builder.with(DnsDomainSuffixMiddleware::new(domain));
+        }
+
+        middleware_builder = middleware_builder.with(DnsZoneMiddleware::new(&cfg));
+
+        // check if any zone hosted as a secondary.
+        if !cfg.secondary_zones.is_empty() {
+            middleware_builder = middleware_builder
+                .with_secondary(DnsSecondaryMiddleware::new(&cfg.secondary_zones, tasks));
+        }
+
+        // check if any container zone published.
+        if !cfg.container_zones.is_empty() {
+            middleware_builder =
+                middleware_builder.with(DnsContainerMiddleware::new(&cfg.container_zones, tasks));
+        }
+
+        #[cfg(feature = "script")]
+        let script = cfg.script_file.as_ref().and_then(|path| {
+            dns_mw_script::Script::load(path)
+                .map(Arc::new)
+                .map_err(|err| error!("load script {:?} failed, {}", path, err))
+                .ok()
+        });
+        #[cfg(not(feature = "script"))]
+        if let Some(path) = cfg.script_file.as_ref() {
+            warn!(
+                "script {:?} ignored, built without the script feature",
+                path
+            );
+        }
+
+        #[cfg(feature = "script")]
+        if let Some(script) = script.as_ref() {
+            if script.has_hook("on_query") || script.has_hook("on_response") {
+                middleware_builder = middleware_builder
+                    .with(DnsScriptMiddleware::new(script.clone(), ScriptHook::Query));
+            }
+        }
+
+        if !DnsHostsMiddleware::is_empty(&cfg) {
+            middleware_builder = middleware_builder.with(DnsHostsMiddleware::new(&cfg, tasks));
+        }
+
+        middleware_builder = with_plugins(middleware_builder, &cfg, PluginStage::Address);
+
+        let group_address_rules = cfg
+            .conf_groups
+            .values()
+            .any(|group| !group.address_rules.is_empty());
+        let domain_rule_address = cfg
+            .domain_rules
+            .iter()
+            .any(|item| item.rule.address.is_some());
+        if cfg.address_rules.len() > 0
+            || group_address_rules
+            || domain_rule_address
+            || force_aaaa_soa
+            || !cfg.blocklists.is_empty()
+            || !cfg.https_record_rules.is_empty()
+            || cfg.block_sets().next().is_some()
+        {
+            let blocklists =
+                Blocklists::spawn(&cfg.blocklists, blocklist::CACHE_DIR, &dns_client, tasks);
+            middleware_builder = middleware_builder.with(AddressMiddleware::new(
+                &cfg,
+                retained.blocking.clone(),
+                blocklists,
+            ));
+        }
+
+        // check if mdns enabled.
+        let mdns_domains = cfg.mdns_domains();
+        if !mdns_domains.is_empty() {
+            middleware_builder = middleware_builder.with(DnsMdnsMiddleware::new(&mdns_domains));
+        }
+
+        // check if any ipset or nftset rule, the cached answers added too.
+        let ipset = DnsIpSetMiddleware::new(&cfg);
+        if !ipset.is_empty() {
+            middleware_builder = middleware_builder.with(ipset);
+        }
+
+        middleware_builder = with_plugins(middleware_builder, &cfg, PluginStage::Cache);
+
+        // the latencies probed are shared by the dualstack selection and the speed check.
+        let speed_checker = Arc::new(SpeedChecker::default());
+
+        // check if dualstack ip selection enabled, globally or for any domain, before the cache
+        // that keeps the records of the family suppressed.
+        if cfg.dualstack_ip_selection()
+            || cfg
+                .domain_rules
+                .iter()
+                .any(|item| item.rule.dualstack_ip_selection == Some(true))
+        {
+            middleware_builder = middleware_builder.with(DnsDualStackMiddleware::new(
+                &cfg,
+                speed_checker.clone(),
+                tasks,
+            ));
+        }
+
+        // check if cache enabled.
+        if cfg.cache_size() > 0 {
+            middleware_builder = middleware_builder.with(DnsCacheMiddleware::new(
+                &cfg,
+                retained.cache.clone(),
+                dns_client.clone(),
+                memory.clone(),
+                tasks.child(),
+            ));
+        }
+
+        // check if any result pinned.
+        let pin_result = DnsPinResultMiddleware::new(&cfg, tasks);
+        if !pin_result.is_empty() {
+            middleware_builder = middleware_builder.with(pin_result);
+        }
+
+        // check if speed_check enabled, globally or for any conf-group or domain.
+        if !cfg.speed_check_mode.is_empty()
+            || cfg
+                .conf_groups
+                .values()
+                .any(|group| !group.speed_check_mode.is_empty())
+            || cfg
+                .domain_rules
+                .iter()
+                .any(|item| !item.rule.speed_check_mode.is_empty())
+        {
+            middleware_builder =
+                middleware_builder.with(DnsSpeedTestMiddleware::new(speed_checker));
+        }
+
+        #[cfg(feature = "script")]
+        if let Some(script) = script.as_ref() {
+            if script.has_hook("on_cache_miss") {
+                middleware_builder = middleware_builder.with(DnsScriptMiddleware::new(
+                    script.clone(),
+                    ScriptHook::CacheMiss,
+                ));
+            }
+        }
+
+        // check if any cname rule or cname flattened.
+        let cname = DnsCNameMiddleware::new(&cfg);
+        if !cname.is_empty() {
+            middleware_builder = middleware_builder.with(cname);
+        }
+
+        middleware_builder = with_plugins(middleware_builder, &cfg, PluginStage::NameServer);
+        middleware_builder = middleware_builder.with(NameServerMiddleware::new(&cfg));
+
+        if cfg.has_mac_rules() {
+            let lease_file = cfg.dnsmasq_lease_file.as_ref().map(PathBuf::from);
+            middleware_builder =
+                middleware_builder.with_neighbors(infra::mac::Neighbors::spawn(lease_file, tasks));
+        }
+
+        middleware_builder.build(cfg, dns_client)
+    }
+}
+
+/// Hook the plugins invoked before the stage.
+fn with_plugins(
+    mut builder: DnsMiddlewareBuilder,
+    cfg: &SmartDnsConfig,
+    stage: PluginStage,
+) -> DnsMiddlewareBuilder {
+    for plugin in cfg.wasm_plugins.iter().filter(|p| p.stage == stage) {
+        #[cfg(feature = "wasm-plugin")]
+        match dns_mw_wasm::DnsWasmMiddleware::load(&plugin.path) {
+            Ok(middleware) => builder = builder.with(middleware),
+            Err(err) => error!("load plugin {:?} failed, {}", plugin.path, err),
+        }
+        #[cfg(not(feature = "wasm-plugin"))]
+        warn!(
+            "plugin {:?} ignored, built without the wasm-plugin feature",
+            plugin.path
+        );
+    }
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use tokio::net::{TcpListener, UdpSocket};
+    use trust_dns_client::op::{Message, MessageType, Query};
+    use trust_dns_client::rr::{Name, RData, Record, RecordType};
+    use trust_dns_proto::serialize::binary::{BinDecodable, BinEncodable};
+    use trust_dns_server::{authority::MessageRequest, server::Protocol};
+
+    use super::*;
+    use crate::{
+        dns::{DefaultSOA, ExtendedError},
+        dns_conf::ServerOpts,
+    };
+
+    fn config(lines: &[&str]) -> SmartDnsConfig {
+        let mut cfg = SmartDnsConfig::new();
+        cfg.apply_overrides(
+            &lines
+                .iter()
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>(),
+        );
+        assert!(cfg.diagnostics.is_empty(), "{:?}", cfg.diagnostics);
+        cfg
+    }
+
+    fn request(name: &str, src: &str) -> DnsRequest {
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        let message = MessageRequest::from_bytes(&message.to_bytes().unwrap()).unwrap();
+
+        DnsRequest::new(message, SocketAddr::from_str(src).unwrap(), Protocol::Udp)
+    }
+
+    /// An upstream answering the ips to every query.
+    async fn upstream(ips: &'static [&'static str]) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let request = Message::from_bytes(&buf[..len]).unwrap();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_desired(true)
+                    .set_recursion_available(true)
+                    .add_queries(request.queries().to_vec());
+                for ip in ips {
+                    response.add_answer(Record::from_rdata(
+                        request.queries()[0].name().clone(),
+                        60,
+                        RData::A(ip.parse().unwrap()),
+                    ));
+                }
+                let _ = socket.send_to(&response.to_bytes().unwrap(), from).await;
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn test_block_set_of_client() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let handler = HandlerBuilder::new(config(&[
+                "domain-set -name adult -file tests/test_confs/block-list.txt",
+                "client-rules 192.168.3.0/24 -group kids -block-set adult",
+            ]))
+            .build();
+
+            let (res, error) = handler
+                .search(
+                    &request("ads1.com.", "192.168.3.7:5353"),
+                    &Arc::new(ServerOpts::default()),
+                )
+                .await;
+
+            let lookup = res.unwrap();
+            assert_eq!(
+                lookup
+                    .record_iter()
+                    .filter_map(|r| r.data())
+                    .collect::<Vec<_>>(),
+                vec![&RData::default_soa()]
+            );
+            assert_eq!(
+                error.map(|error| error.info_code),
+                Some(ExtendedError::BLOCKED)
+            );
+        });
+    }
+    #[test]
+    fn test_address_of_domain_rule() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let handler =
+                HandlerBuilder::new(config(&["domain-rules /blocked.example/ -address #"])).build();
+
+            let (res, error) = handler
+                .search(
+                    &request("www.blocked.example.", "127.0.0.1:5353"),
+                    &Arc::new(ServerOpts::default()),
+                )
+                .await;
+
+            let lookup = res.unwrap();
+            assert_eq!(
+                lookup
+                    .record_iter()
+                    .filter_map(|r| r.data())
+                    .collect::<Vec<_>>(),
+                vec![&RData::default_soa()]
+            );
+            assert_eq!(
+                error.map(|error| error.info_code),
+                Some(ExtendedError::BLOCKED)
+            );
+        });
+    }
+
+    #[test]
+    fn test_speed_check_of_domain_rule() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            // only the first ip reached, nothing listening on the other.
+            let upstream = upstream(&["127.0.0.2", "127.0.0.1"]).await;
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+
+            let handler = HandlerBuilder::new(config(&[
+                &format!("server {}", upstream),
+                &format!("domain-rules /fast.example/ -speed-check-mode tcp:{}", port),
+            ]))
+            .build();
+
+            let (res, _) = handler
+                .search(
+                    &request("www.fast.example.", "127.0.0.1:5353"),
+                    &Arc::new(ServerOpts::default()),
+                )
+                .await;
+
+            let lookup = res.unwrap();
+            assert_eq!(
+                lookup
+                    .record_iter()
+                    .filter_map(|r| r.data())
+                    .collect::<Vec<_>>(),
+                vec![&RData::A("127.0.0.1".parse().unwrap())]
+            );
+        });
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::third_ext::FutureTimeoutExt;
+
+/// Why a task was not let in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    /// too many tasks waiting already.
+    QueueFull,
+    /// waited until the deadline.
+    Timeout,
+}
+
+/// The tasks run at once, the others wait in a bounded queue until the deadline.
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    max_queued: usize,
+    timeout: Duration,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max_running: usize, max_queued: usize, timeout: Duration) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_running.max(1))),
+            queued: AtomicUsize::new(0),
+            max_queued,
+            timeout,
+        }
+    }
+
+    /// Wait for a turn, the task runs as long as the permit is held.
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, Rejected> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        // dequeued however the wait ends, cancelled as well.
+        let queued = Queued::enter(&self.queued);
+        if queued.position >= self.max_queued {
+            return Err(Rejected::QueueFull);
+        }
+
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .timeout(self.timeout)
+            .await;
+
+        match permit {
+            Ok(Ok(permit)) => Ok(permit),
+            // never closed.
+            Ok(Err(_)) | Err(_) => Err(Rejected::Timeout),
+        }
+    }
+
+    /// The tasks waiting for a turn.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+}
+
+/// A place in the queue, left when dropped.
+struct Queued<'a> {
+    queued: &'a AtomicUsize,
+    position: usize,
+}
+
+impl<'a> Queued<'a> {
+    fn enter(queued: &'a AtomicUsize) -> Self {
+        let position = queued.fetch_add(1, Ordering::AcqRel);
+        Self { queued, position }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Builder;
+
+    use super::*;
+
+    #[test]
+    fn test_concurrency_limiter() {
+        Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let limiter = Arc::new(ConcurrencyLimiter::new(1, 1, Duration::from_millis(100)));
+
+                let running = limiter.acquire().await.unwrap();
+
+                // the second one queues, and gets its turn once the first completes.
+                let queued = tokio::spawn({
+                    let limiter = limiter.clone();
+                    async move { limiter.acquire().await.map(|_| ()) }
+                });
+                tokio::task::yield_now().await;
+                assert_eq!(limiter.queued(), 1);
+
+                // the third one overflows the queue.
+                assert_eq!(limiter.acquire().await.err(), Some(Rejected::QueueFull));
+
+                drop(running);
+                assert_eq!(queued.await.unwrap(), Ok(()));
+
+                // waits until the deadline.
+                let _running = limiter.acquire().await.unwrap();
+                assert_eq!(limiter.acquire().await.err(), Some(Rejected::Timeout));
+                assert_eq!(limiter.queued(), 0);
+
+                // dequeued once the waiting is cancelled.
+                let waiting = tokio::spawn({
+                    let limiter = limiter.clone();
+                    async move { limiter.acquire().await.map(|_| ()) }
+                });
+                tokio::task::yield_now().await;
+                assert_eq!(limiter.queued(), 1);
+
+                waiting.abort();
+                assert!(waiting.await.is_err());
+                assert_eq!(limiter.queued(), 0);
+            })
+    }
+}
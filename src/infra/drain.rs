@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+/// The queries in flight, waited for on shutdown while the new ones are turned away.
+#[derive(Debug, Default)]
+pub struct Drain {
+    draining: AtomicBool,
+    inflight: AtomicUsize,
+    idle: Notify,
+}
+
+impl Drain {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Count a query in flight until the guard dropped, none once draining.
+    pub fn enter(self: &Arc<Self>) -> Option<DrainGuard> {
+        self.inflight.fetch_add(1, Ordering::SeqCst);
+        let guard = DrainGuard(self.clone());
+
+        if self.draining.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(guard)
+    }
+
+    /// Turn away the new queries, and wait for the ones in flight.
+    pub async fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+
+        loop {
+            // notified of the guards dropped from now on.
+            let idle = self.idle.notified();
+            if self.inflight.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    #[inline]
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn inflight(&self) -> usize {
+        self.inflight.load(Ordering::SeqCst)
+    }
+}
+
+pub struct DrainGuard(Arc<Drain>);
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        if self.0.inflight.fetch_sub(1, Ordering::SeqCst) == 1 && self.0.is_draining() {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::runtime::Builder;
+
+    use super::*;
+    use crate::third_ext::FutureTimeoutExt;
+
+    #[test]
+    fn test_drain() {
+        Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let drain = Arc::new(Drain::new());
+
+                let inflight = drain.enter().unwrap();
+                assert_eq!(drain.inflight(), 1);
+
+                let draining = tokio::spawn({
+                    let drain = drain.clone();
+                    async move { drain.drain().await }
+                });
+                tokio::task::yield_now().await;
+
+                // turned away, and the inflight one waited for.
+                assert!(drain.enter().is_none());
+                assert!(!draining.is_finished());
+
+                drop(inflight);
+                draining
+                    .timeout(Duration::from_secs(1))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(drain.inflight(), 0);
+            })
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::{Body, Request, Response, Uri};
+use rustls::{ClientConfig, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use trust_dns_proto::iocompat::AsyncIoStdAsTokio;
+
+use crate::proxy::{ProxyTcpStream, Transport};
+
+/// An http client over the transport of the upstreams: the connections are made to the
+/// addresses resolved already, e.g. by the bootstrap dns rather than the system resolver,
+/// which may be this server, by the transport of their upstream, and verified by the tls
+/// config of each host, e.g. with its spki pins.
+#[derive(Clone)]
+pub struct HttpClient {
+    client: hyper::Client<HttpConnector, Body>,
+}
+
+/// The addresses a host is connected to, and its tls config.
+#[derive(Clone)]
+pub struct HttpHost {
+    pub addrs: Vec<SocketAddr>,
+    pub tls: Arc<ClientConfig>,
+}
+
+impl HttpClient {
+    /// The client of the hosts, the others not resolved, bound to the local address if any,
+    /// connected by the transport, e.g. through the proxy of the upstream.
+    pub fn new(
+        hosts: HashMap<String, HttpHost>,
+        bind: Option<SocketAddr>,
+        transport: Arc<Transport>,
+    ) -> Self {
+        let hosts = hosts
+            .into_iter()
+            .map(|(host, addrs)| (host_key(&host).to_string(), addrs))
+            .collect();
+
+        Self {
+            client: hyper::Client::builder().build(HttpConnector {
+                hosts: Arc::new(hosts),
+                bind,
+                transport,
+            }),
+        }
+    }
+
+    pub async fn request(&self, request: Request<Body>) -> io::Result<Response<Body>> {
+        self.client
+            .request(request)
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+}
+
+/// Read the body, an error once it's longer than the limit, without buffering the rest.
+pub async fn read_body(response: Response<Body>, limit: usize) -> io::Result<Bytes> {
+    let mut body = response.into_body();
+    let mut bytes = vec![];
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+        if bytes.len() + chunk.len() > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("body longer than {} bytes", limit),
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes.into())
+}
+
+/// The host of the url without the brackets of an ipv6 address.
+fn host_key(host: &str) -> &str {
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[derive(Clone)]
+struct HttpConnector {
+    hosts: Arc<HashMap<String, HttpHost>>,
+    bind: Option<SocketAddr>,
+    transport: Arc<Transport>,
+}
+
+impl HttpConnector {
+    async fn connect(self, uri: Uri) -> io::Result<HttpStream> {
+        let name = host_key(uri.host().unwrap_or_default());
+
+        let host = self.hosts.get(name).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} not resolved", name))
+        })?;
+
+        let mut last_err =
+            io::Error::new(io::ErrorKind::NotFound, format!("no address of {}", name));
+
+        // the first address connected, as the upstreams do.
+        for addr in host.addrs.iter() {
+            let stream = match ProxyTcpStream::connect_by(&self.transport, *addr, self.bind).await {
+                Ok(stream) => AsyncIoStdAsTokio(stream),
+                Err(err) => {
+                    last_err = err;
+                    continue;
+                }
+            };
+
+            if uri.scheme_str() != Some("https") {
+                return Ok(HttpStream::Plain(stream));
+            }
+
+            let server_name = ServerName::try_from(name)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+            return TlsConnector::from(host.tls.clone())
+                .connect(server_name, stream)
+                .await
+                .map(|stream| HttpStream::Tls(Box::new(stream)));
+        }
+
+        Err(last_err)
+    }
+}
+
+impl Service<Uri> for HttpConnector {
+    type Response = HttpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<HttpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        Box::pin(self.clone().connect(uri))
+    }
+}
+
+type TcpStream = AsyncIoStdAsTokio<ProxyTcpStream>;
+
+enum HttpStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection for HttpStream {
+    fn connected(&self) -> Connected {
+        match self {
+            HttpStream::Tls(stream) if stream.get_ref().1.alpn_protocol() == Some(b"h2") => {
+                Connected::new().negotiated_h2()
+            }
+            _ => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for HttpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HttpStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            HttpStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for HttpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            HttpStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            HttpStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HttpStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            HttpStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HttpStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            HttpStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_body_limit() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let response = || Response::new(Body::from(vec![0u8; 100]));
+
+            assert_eq!(read_body(response(), 100).await.unwrap().len(), 100);
+            assert!(read_body(response(), 99).await.is_err());
+        })
+    }
+
+    #[test]
+    fn test_unresolved_host() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let client = HttpClient::new(HashMap::new(), None, Default::default());
+            let request = Request::get("https://example.com/")
+                .body(Body::empty())
+                .unwrap();
+            assert!(client.request(request).await.is_err());
+        })
+    }
+}
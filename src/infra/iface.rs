@@ -0,0 +1,274 @@
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The addresses assigned to the network interface.
+#[cfg(unix)]
+pub fn interface_addrs(name: &str) -> io::Result<Vec<IpAddr>> {
+    use std::ffi::CStr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut ifaddrs = std::ptr::null_mut();
+
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addrs = vec![];
+    let mut cursor = ifaddrs;
+
+    while !cursor.is_null() {
+        let ifa = unsafe { &*cursor };
+        cursor = ifa.ifa_next;
+
+        if ifa.ifa_addr.is_null() || ifa.ifa_name.is_null() {
+            continue;
+        }
+
+        if unsafe { CStr::from_ptr(ifa.ifa_name) }.to_bytes() != name.as_bytes() {
+            continue;
+        }
+
+        match unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    addr.sin_addr.s_addr,
+                ))));
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                addrs.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+            }
+            _ => (),
+        }
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    Ok(addrs)
+}
+
+#[cfg(not(unix))]
+pub fn interface_addrs(name: &str) -> io::Result<Vec<IpAddr>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "binding to interface {} is not supported on this platform",
+            name
+        ),
+    ))
+}
+
+/// The scope ids of the multicast interfaces with an ipv6 link-local address, so that the
+/// link-local groups, e.g. ff02::fb of mDNS, are reached on each of them.
+#[cfg(unix)]
+pub fn link_local_scopes() -> io::Result<Vec<u32>> {
+    let mut ifaddrs = std::ptr::null_mut();
+
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut scopes = vec![];
+    let mut cursor = ifaddrs;
+
+    while !cursor.is_null() {
+        let ifa = unsafe { &*cursor };
+        cursor = ifa.ifa_next;
+
+        if ifa.ifa_addr.is_null()
+            || ifa.ifa_flags & libc::IFF_LOOPBACK as libc::c_uint != 0
+            || ifa.ifa_flags & libc::IFF_MULTICAST as libc::c_uint == 0
+            || unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int != libc::AF_INET6
+        {
+            continue;
+        }
+
+        let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+        let link_local =
+            addr.sin6_addr.s6_addr[0] == 0xfe && addr.sin6_addr.s6_addr[1] & 0xc0 == 0x80;
+
+        if link_local && addr.sin6_scope_id != 0 && !scopes.contains(&addr.sin6_scope_id) {
+            scopes.push(addr.sin6_scope_id);
+        }
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    Ok(scopes)
+}
+
+#[cfg(not(unix))]
+pub fn link_local_scopes() -> io::Result<Vec<u32>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "listing the interfaces is not supported on this platform",
+    ))
+}
+
+/// Bind the socket to the network interface with SO_BINDTODEVICE, so that the policy routing
+/// of the interface applies, rather than only the source address.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn bind_device<S: std::os::unix::io::AsRawFd>(socket: &S, name: &str) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Bind the socket to the network interface with IP_BOUND_IF, or IPV6_BOUND_IF by the family.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub fn bind_device<S: std::os::unix::io::AsRawFd>(socket: &S, name: &str) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+
+    let name = std::ffi::CString::new(name)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret =
+        unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if addr.ss_family as libc::c_int == libc::AF_INET6 {
+        setsockopt_int(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_BOUND_IF,
+            index as libc::c_int,
+        )
+    } else {
+        setsockopt_int(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_BOUND_IF,
+            index as libc::c_int,
+        )
+    }
+}
+
+/// Elsewhere, binding to the address of the interface is the best effort.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+pub fn bind_device<S>(_socket: &S, _name: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// Enable the tcp keepalive, probing once the connection idle for the duration,
+/// so that the idle connections to upstreams survive the NAT and firewall timeouts.
+#[cfg(unix)]
+pub fn set_keepalive<S: std::os::unix::io::AsRawFd>(socket: &S, idle: Duration) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    let idle = idle.as_secs().clamp(1, i32::MAX as u64) as libc::c_int;
+
+    setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle)?;
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, idle)?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_keepalive<S>(_socket: &S, _idle: Duration) -> io::Result<()> {
+    Ok(())
+}
+
+/// Enable IP_TRANSPARENT, so that the socket takes the traffic of other addresses
+/// redirected by TPROXY, and answers from them, requires CAP_NET_ADMIN.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_transparent<S: std::os::unix::io::AsRawFd>(socket: &S, ipv6: bool) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+
+    if ipv6 {
+        setsockopt_int(fd, libc::SOL_IPV6, libc::IPV6_TRANSPARENT, 1)?;
+        // the ipv4 traffic of a dual stack socket, if any.
+        let _ = setsockopt_int(fd, libc::SOL_IP, libc::IP_TRANSPARENT, 1);
+        Ok(())
+    } else {
+        setsockopt_int(fd, libc::SOL_IP, libc::IP_TRANSPARENT, 1)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_transparent<S>(_socket: &S, _ipv6: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent proxy is not supported on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn setsockopt_int(
+    fd: std::os::unix::io::RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_interface_addrs() {
+        let lo = if cfg!(target_os = "linux") {
+            "lo"
+        } else {
+            "lo0"
+        };
+        let addrs = interface_addrs(lo).unwrap();
+        assert!(addrs.iter().any(|ip| ip.is_loopback()));
+
+        assert!(interface_addrs("no-such-iface").unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_link_local_scopes() {
+        let scopes = link_local_scopes().unwrap();
+        assert!(!scopes.contains(&0));
+    }
+}
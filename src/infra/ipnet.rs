@@ -0,0 +1,95 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An ip network, e.g. `203.0.113.0/24`, a single ip is a network of the full prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    #[inline]
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    #[inline]
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = IpAddr::from_str(addr).map_err(|e| format!("invalid ip {}, {}", s, e))?;
+
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix)
+                .ok_or_else(|| format!("invalid ip prefix {}", s))?,
+            None => max_prefix,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_net_contains() {
+        let net = IpNet::from_str("203.0.113.0/24").unwrap();
+        assert!(net.contains(&"203.0.113.7".parse().unwrap()));
+        assert!(!net.contains(&"203.0.114.7".parse().unwrap()));
+        assert!(!net.contains(&"::1".parse().unwrap()));
+
+        let ip = IpNet::from_str("1.2.3.4").unwrap();
+        assert_eq!(ip.prefix(), 32);
+        assert!(ip.contains(&"1.2.3.4".parse().unwrap()));
+        assert!(!ip.contains(&"1.2.3.5".parse().unwrap()));
+
+        let any = IpNet::from_str("0.0.0.0/0").unwrap();
+        assert!(any.contains(&"8.8.8.8".parse().unwrap()));
+
+        let net = IpNet::from_str("2001:db8::/32").unwrap();
+        assert!(net.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!net.contains(&"2001:db9::1".parse().unwrap()));
+
+        assert!(IpNet::from_str("1.2.3.0/33").is_err());
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::infra::tasks::BackgroundTasks;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::log::debug;
+use crate::log::warn;
+
+/// The neighbor table and the leases are read again after this long, as the clients come
+/// and go.
+const NEIGHBORS_TTL: Duration = Duration::from_secs(10);
+
+/// A hardware address, e.g. `00:11:22:33:44:55`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddr([u8; 6]);
+
+impl FromStr for MacAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_octets(s) {
+            Some(octets) if octets.len() == 6 => {
+                let mut addr = [0; 6];
+                addr.copy_from_slice(&octets);
+                Ok(Self(addr))
+            }
+            _ => Err(format!("invalid mac {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// The leading octets of the hardware addresses, e.g. the vendor `00:11:22`, a full address
+/// matching itself only.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MacPrefix(Vec<u8>);
+
+impl MacPrefix {
+    /// The octets of the prefix, the longer the more specific.
+    #[inline]
+    pub fn prefix(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn contains(&self, mac: &MacAddr) -> bool {
+        mac.0.starts_with(&self.0)
+    }
+}
+
+impl FromStr for MacPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_octets(s) {
+            Some(octets) if (1..=6).contains(&octets.len()) => Ok(Self(octets)),
+            _ => Err(format!("invalid mac prefix {}", s)),
+        }
+    }
+}
+
+/// The octets separated by `:` or `-`, each of 2 hex digits.
+fn parse_octets(s: &str) -> Option<Vec<u8>> {
+    s.split(|c| c == ':' || c == '-')
+        .map(|octet| match octet.len() {
+            2 => u8::from_str_radix(octet, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The hardware addresses of the clients on the local networks, by the neighbor table of the
+/// kernel, linux only, then the unexpired leases of dnsmasq, the ipv6 clients of the leases
+/// with a duid rather than a hardware address unknown. Read in background, as the clients
+/// come and go.
+#[derive(Debug, Clone, Default)]
+pub struct Neighbors {
+    table: Arc<RwLock<Arc<HashMap<IpAddr, MacAddr>>>>,
+}
+
+impl Neighbors {
+    pub fn spawn(lease_file: Option<PathBuf>, tasks: &BackgroundTasks) -> Self {
+        let neighbors = Self::default();
+
+        tasks.spawn({
+            let table = neighbors.table.clone();
+            async move {
+                let mut interval = tokio::time::interval(NEIGHBORS_TTL);
+                loop {
+                    interval.tick().await;
+
+                    let lease_file = lease_file.clone();
+                    match tokio::task::spawn_blocking(move || read(lease_file.as_deref())).await {
+                        Ok(neighbors) => {
+                            if let Ok(mut table) = table.write() {
+                                *table = Arc::new(neighbors);
+                            }
+                        }
+                        Err(err) => warn!("read the neighbors failed, {}", err),
+                    }
+                }
+            }
+        });
+
+        neighbors
+    }
+
+    /// The hardware address of the ip, none if not a neighbor.
+    pub fn mac_of(&self, ip: IpAddr) -> Option<MacAddr> {
+        // the ipv4 clients of the dual stack sockets.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+
+        let table = self.table.read().ok()?.clone();
+        table.get(&ip).copied()
+    }
+}
+
+fn read(lease_file: Option<&Path>) -> HashMap<IpAddr, MacAddr> {
+    let mut neighbors = HashMap::new();
+
+    if let Some(text) = lease_file.and_then(|file| fs::read_to_string(file).ok()) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        neighbors.extend(parse_leases(&text, now));
+    }
+
+    // the current neighbors over the leases, which may be stale.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    match neighbor_table() {
+        Ok(table) => neighbors.extend(table),
+        Err(err) => debug!("read the neighbor table failed, {}", err),
+    }
+
+    neighbors
+}
+
+/// see linux/netlink.h, linux/rtnetlink.h and linux/neighbour.h.
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const RTM_NEWNEIGH: u16 = 28;
+const RTM_GETNEIGH: u16 = 30;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_DUMP: u16 = 0x300;
+const NDA_DST: u16 = 1;
+const NDA_LLADDR: u16 = 2;
+/// the neighbors not resolved, or not resolving anymore.
+const NUD_INCOMPLETE: u16 = 0x01;
+const NUD_FAILED: u16 = 0x20;
+const NUD_NOARP: u16 = 0x40;
+
+/// The neighbors of the kernel, ipv4 and ipv6, dumped over the netlink of the routes.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn neighbor_table() -> io::Result<Vec<(IpAddr, MacAddr)>> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    use std::io::Read;
+
+    let socket = Socket::new(
+        Domain::from(libc::AF_NETLINK),
+        Type::RAW,
+        Some(Protocol::from(libc::NETLINK_ROUTE)),
+    )?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    socket.send(&getneigh_request())?;
+
+    let mut neighbors = vec![];
+    let mut buf = vec![0u8; 32 * 1024];
+    loop {
+        let len = (&socket).read(&mut buf)?;
+        if parse_neighbors(&buf[..len], &mut neighbors)? {
+            return Ok(neighbors);
+        }
+    }
+}
+
+/// The dump request of the neighbors of all the families, nlmsghdr then ndmsg.
+fn getneigh_request() -> Vec<u8> {
+    let mut buf = Vec::with_capacity(28);
+    buf.extend_from_slice(&28u32.to_ne_bytes());
+    buf.extend_from_slice(&RTM_GETNEIGH.to_ne_bytes());
+    buf.extend_from_slice(&(NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    buf.extend_from_slice(&1u32.to_ne_bytes()); // seq
+    buf.extend_from_slice(&0u32.to_ne_bytes()); // port id, assigned by the kernel.
+    buf.extend_from_slice(&[0u8; 12]); // ndmsg, AF_UNSPEC for all the families.
+    buf
+}
+
+/// The resolved neighbors of the datagram of the dump, true once done.
+fn parse_neighbors(buf: &[u8], neighbors: &mut Vec<(IpAddr, MacAddr)>) -> io::Result<bool> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid neighbor message");
+
+    let mut buf = buf;
+    while buf.len() >= 16 {
+        let len = u32::from_ne_bytes(buf[0..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(buf[4..6].try_into().unwrap());
+        if len < 16 || len > buf.len() {
+            return Err(invalid());
+        }
+
+        match kind {
+            NLMSG_DONE => return Ok(true),
+            NLMSG_ERROR => {
+                let errno = buf
+                    .get(16..20)
+                    .map(|b| i32::from_ne_bytes(b.try_into().unwrap()))
+                    .ok_or_else(invalid)?;
+                return Err(io::Error::from_raw_os_error(-errno));
+            }
+            RTM_NEWNEIGH => neighbors.extend(parse_neighbor(&buf[16..len])),
+            _ => (),
+        }
+
+        buf = &buf[align(len).min(buf.len())..];
+    }
+
+    Ok(false)
+}
+
+/// The ip and the hardware address of ndmsg and its attributes, if resolved.
+fn parse_neighbor(msg: &[u8]) -> Option<(IpAddr, MacAddr)> {
+    // ndmsg: family, padding, ifindex, state, flags and type.
+    let state = u16::from_ne_bytes(msg.get(8..10)?.try_into().ok()?);
+    if state & (NUD_INCOMPLETE | NUD_FAILED | NUD_NOARP) != 0 {
+        return None;
+    }
+
+    let (mut ip, mut mac) = (None, None);
+    let mut attrs = msg.get(12..)?;
+    while attrs.len() >= 4 {
+        let len = u16::from_ne_bytes(attrs[0..2].try_into().ok()?) as usize;
+        let kind = u16::from_ne_bytes(attrs[2..4].try_into().ok()?);
+        let data = attrs.get(4..len)?;
+
+        match (kind, data.len()) {
+            (NDA_DST, 4) => ip = Some(IpAddr::from(<[u8; 4]>::try_from(data).ok()?)),
+            (NDA_DST, 16) => ip = Some(IpAddr::from(<[u8; 16]>::try_from(data).ok()?)),
+            (NDA_LLADDR, 6) => mac = Some(MacAddr(data.try_into().ok()?)),
+            _ => (),
+        }
+
+        attrs = &attrs[align(len).min(attrs.len())..];
+    }
+
+    Some((ip?, mac?))
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// The leases of dnsmasq, `[expiry] [mac] [ip] [hostname] [client id]`, the expired ones, the
+/// duid line and the dhcpv6 leases skipped, the second field of the last two isn't a hardware
+/// address. An expiry of 0 never expires.
+fn parse_leases(text: &str, now: u64) -> impl Iterator<Item = (IpAddr, MacAddr)> + '_ {
+    text.lines().filter_map(move |line| {
+        let mut parts = line.split_whitespace();
+        let expiry = parts.next()?.parse::<u64>().ok()?;
+        if expiry != 0 && expiry < now {
+            return None;
+        }
+        let mac = parts.next()?.parse().ok()?;
+        let ip = parts.next()?.parse().ok()?;
+        Some((ip, mac))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mac_prefix() {
+        let mac = MacAddr::from_str("00:11:22:AA:bb:cc").unwrap();
+        assert_eq!(mac.to_string(), "00:11:22:aa:bb:cc");
+        assert!(MacAddr::from_str("00:11:22").is_err());
+
+        let vendor = MacPrefix::from_str("00-11-22").unwrap();
+        assert_eq!(vendor.prefix(), 3);
+        assert!(vendor.contains(&mac));
+        assert!(!MacPrefix::from_str("00:11:23").unwrap().contains(&mac));
+        assert!(MacPrefix::from_str("00:11:22:aa:bb:cc")
+            .unwrap()
+            .contains(&mac));
+
+        assert!(MacPrefix::from_str("192.168.1.1").is_err());
+        assert!(MacPrefix::from_str("0:11").is_err());
+    }
+
+    /// nlmsghdr, ndmsg of the state, then the ip and the mac attributes.
+    fn neighbor_msg(state: u16, ip: IpAddr, mac: &[u8]) -> Vec<u8> {
+        let ip = match ip {
+            IpAddr::V4(v4) => v4.octets().to_vec(),
+            IpAddr::V6(v6) => v6.octets().to_vec(),
+        };
+
+        let mut msg = vec![0u8; 16];
+        msg[4..6].copy_from_slice(&RTM_NEWNEIGH.to_ne_bytes());
+        msg.extend_from_slice(&[0u8; 8]);
+        msg.extend_from_slice(&state.to_ne_bytes());
+        msg.extend_from_slice(&[0u8; 2]);
+        for (kind, data) in [(NDA_DST, ip.as_slice()), (NDA_LLADDR, mac)] {
+            msg.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+            msg.extend_from_slice(&kind.to_ne_bytes());
+            msg.extend_from_slice(data);
+            msg.resize(align(msg.len()), 0);
+        }
+
+        let len = msg.len() as u32;
+        msg[0..4].copy_from_slice(&len.to_ne_bytes());
+        msg
+    }
+
+    #[test]
+    fn test_parse_neighbors() {
+        const NUD_REACHABLE: u16 = 0x02;
+        const NUD_STALE: u16 = 0x04;
+
+        let mac = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55];
+
+        let mut dump = neighbor_msg(NUD_REACHABLE, "192.168.1.10".parse().unwrap(), &mac);
+        dump.extend(neighbor_msg(NUD_STALE, "fe80::10".parse().unwrap(), &mac));
+        dump.extend(neighbor_msg(
+            NUD_INCOMPLETE,
+            "192.168.1.11".parse().unwrap(),
+            &[0; 6],
+        ));
+
+        let mut neighbors = vec![];
+        assert!(!parse_neighbors(&dump, &mut neighbors).unwrap());
+        assert_eq!(
+            neighbors,
+            vec![
+                (
+                    "192.168.1.10".parse().unwrap(),
+                    "00:11:22:33:44:55".parse().unwrap()
+                ),
+                (
+                    "fe80::10".parse().unwrap(),
+                    "00:11:22:33:44:55".parse().unwrap()
+                )
+            ]
+        );
+
+        let mut done = vec![0u8; 16];
+        done[0..4].copy_from_slice(&16u32.to_ne_bytes());
+        done[4..6].copy_from_slice(&NLMSG_DONE.to_ne_bytes());
+        assert!(parse_neighbors(&done, &mut neighbors).unwrap());
+
+        let leases = "\
+1700000000 66:77:88:99:aa:bb 192.168.1.20 tablet *
+0 66:77:88:99:aa:cc 192.168.1.21 printer *
+1600000000 66:77:88:99:aa:dd 192.168.1.22 phone *
+duid 00:01:00:01:2a:2b:2c:2d:00:11:22:33:44:55
+1700000000 1234 2001:db8::20 tablet 00:01:00:01:2a:2b
+";
+        let neighbors = parse_leases(leases, 1650000000).collect::<Vec<_>>();
+        assert_eq!(
+            neighbors,
+            vec![
+                (
+                    "192.168.1.20".parse().unwrap(),
+                    "66:77:88:99:aa:bb".parse().unwrap()
+                ),
+                (
+                    "192.168.1.21".parse().unwrap(),
+                    "66:77:88:99:aa:cc".parse().unwrap()
+                )
+            ]
+        );
+    }
+}
@@ -64,6 +64,11 @@ impl MappedFile {
         }
     }
 
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn mapped_files(&self) -> io::Result<Vec<PathBuf>> {
         match (
             self.path
@@ -94,6 +99,10 @@ impl MappedFile {
         self.num = num;
     }
 
+    pub fn set_size(&mut self, size: u64) {
+        self.size = size;
+    }
+
     pub fn remove_files(&mut self) -> io::Result<()> {
         if let Some(mut file) = self.file.take() {
             file.flush()?;
@@ -106,6 +115,17 @@ impl MappedFile {
         Ok(())
     }
 
+    /// Close the file and open the one at the path again, appended to by its length, e.g. once
+    /// moved by an external rotator.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        self.len = 0;
+        self.get_active_file()?;
+        Ok(())
+    }
+
     fn is_full(&self) -> bool {
         self.len() >= self.size
     }
@@ -229,4 +249,52 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    pub fn test_reopen_file() -> io::Result<()> {
+        let file_path = format!("./logs/reopen-{:#x}.txt", Local::now().timestamp());
+        let moved_path = format!("{}.1", file_path);
+
+        let mut file = MappedFile::open(&file_path, 1024, Some(3));
+        file.write_all(b"aa")?;
+
+        // moved by an external rotator, the writes go to the moved one until reopened.
+        fs::rename(&file_path, &moved_path)?;
+        file.write_all(b"bb")?;
+        file.reopen()?;
+        file.write_all(b"cc")?;
+
+        assert_eq!(fs::read(&moved_path)?, b"aabb");
+        assert_eq!(fs::read(&file_path)?, b"cc");
+
+        fs::remove_file(moved_path)?;
+        file.remove_files()?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_reopen_existing_file() -> io::Result<()> {
+        let file_path = format!("./logs/reopen-existing-{:#x}.txt", Local::now().timestamp());
+
+        let mut file = MappedFile::open(&file_path, 6, Some(3));
+        file.write_all(b"aa")?;
+
+        // not moved, or replaced by another one, the writes append to the one at the path.
+        fs::write(&file_path, b"xyz")?;
+        file.reopen()?;
+        assert_eq!(file.len(), 3);
+        file.write_all(b"cc")?;
+        assert_eq!(fs::read(&file_path)?, b"xyzcc");
+        assert_eq!(file.mapped_files()?.len(), 1);
+
+        // full by the length of the existing one, rotated by the next write.
+        file.write_all(b"dd")?;
+        file.write_all(b"ee")?;
+        assert_eq!(file.mapped_files()?.len(), 2);
+
+        file.remove_files()?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,221 @@
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::{debug, info, warn};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The memory available to this process, limited by the cgroup if any, otherwise the whole system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub total: u64,
+    pub available: u64,
+}
+
+impl MemoryUsage {
+    pub fn read() -> Option<Self> {
+        let system = fs::read_to_string("/proc/meminfo")
+            .ok()
+            .and_then(|s| parse_meminfo(&s));
+
+        let cgroup = read_cgroup_v2().or_else(read_cgroup_v1);
+
+        match (system, cgroup) {
+            (Some(system), Some(cgroup)) if cgroup.total < system.total => Some(MemoryUsage {
+                total: cgroup.total,
+                available: cgroup.available.min(system.available),
+            }),
+            (Some(system), _) => Some(system),
+            (None, cgroup) => cgroup,
+        }
+    }
+
+    /// The available memory in percent of the total.
+    pub fn available_percent(&self) -> u64 {
+        match self.total {
+            0 => 100,
+            total => self.available.saturating_mul(100) / total,
+        }
+    }
+}
+
+fn parse_meminfo(s: &str) -> Option<MemoryUsage> {
+    let field = |name: &str| {
+        s.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    };
+
+    Some(MemoryUsage {
+        total: field("MemTotal")?,
+        available: field("MemAvailable").or_else(|| field("MemFree"))?,
+    })
+}
+
+fn parse_cgroup(limit: &str, usage: &str) -> Option<MemoryUsage> {
+    // "max" in cgroup v2, or a huge number in cgroup v1 means unlimited.
+    let total = limit.trim().parse::<u64>().ok().filter(|n| *n < 1 << 60)?;
+    let usage = usage.trim().parse::<u64>().ok()?;
+
+    Some(MemoryUsage {
+        total,
+        available: total.saturating_sub(usage),
+    })
+}
+
+fn read_cgroup_v2() -> Option<MemoryUsage> {
+    parse_cgroup(
+        &fs::read_to_string("/sys/fs/cgroup/memory.max").ok()?,
+        &fs::read_to_string("/sys/fs/cgroup/memory.current").ok()?,
+    )
+}
+
+fn read_cgroup_v1() -> Option<MemoryUsage> {
+    parse_cgroup(
+        &fs::read_to_string("/sys/fs/cgroup/memory/memory.limit_in_bytes").ok()?,
+        &fs::read_to_string("/sys/fs/cgroup/memory/memory.usage_in_bytes").ok()?,
+    )
+}
+
+/// Whether the memory is under pressure, shared by the subsystems to degrade themselves,
+/// e.g. the cache stops prefetching and shrinks.
+#[derive(Debug, Clone)]
+pub struct MemoryPressure {
+    state: Arc<watch::Sender<bool>>,
+}
+
+impl Default for MemoryPressure {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl MemoryPressure {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    #[inline]
+    pub fn is_high(&self) -> bool {
+        *self.state.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.state.subscribe()
+    }
+
+    /// Check the memory usage periodically, the pressure is high once the available memory
+    /// drops below `threshold` percent, and is relieved above twice of it.
+    pub fn spawn_monitor(&self, threshold: u64, tasks: &BackgroundTasks) {
+        if threshold == 0 {
+            return;
+        }
+
+        if MemoryUsage::read().is_none() {
+            debug!("memory usage is unavailable on this system, memory pressure check disabled");
+            return;
+        }
+
+        let pressure = self.clone();
+
+        tasks.spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Some(usage) = MemoryUsage::read() {
+                    pressure.update(usage, threshold);
+                }
+            }
+        });
+    }
+
+    fn update(&self, usage: MemoryUsage, threshold: u64) {
+        let available = usage.available_percent();
+
+        let high = if self.is_high() {
+            available < threshold.saturating_mul(2)
+        } else {
+            available < threshold
+        };
+
+        if high == self.is_high() {
+            return;
+        }
+
+        if high {
+            warn!(
+                "memory pressure is high, available {}% ({} of {} bytes), degrading prefetch and cache",
+                available, usage.available, usage.total
+            );
+        } else {
+            info!(
+                "memory pressure relieved, available {}% ({} of {} bytes)",
+                available, usage.available, usage.total
+            );
+        }
+
+        self.state.send_replace(high);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:         255024 kB\nMemFree:           10240 kB\nMemAvailable:      20480 kB\nBuffers:            1024 kB\n";
+
+        assert_eq!(
+            parse_meminfo(meminfo),
+            Some(MemoryUsage {
+                total: 255024 * 1024,
+                available: 20480 * 1024
+            })
+        );
+
+        assert_eq!(parse_meminfo("Buffers: 1024 kB"), None);
+    }
+
+    #[test]
+    fn test_parse_cgroup() {
+        assert_eq!(
+            parse_cgroup("1048576\n", "786432\n"),
+            Some(MemoryUsage {
+                total: 1048576,
+                available: 262144
+            })
+        );
+        assert_eq!(parse_cgroup("max\n", "786432\n"), None);
+        assert_eq!(parse_cgroup("9223372036854771712\n", "786432\n"), None);
+    }
+
+    #[test]
+    fn test_memory_pressure() {
+        let pressure = MemoryPressure::new();
+        let usage = |available| MemoryUsage {
+            total: 100,
+            available,
+        };
+
+        pressure.update(usage(50), 10);
+        assert!(!pressure.is_high());
+
+        pressure.update(usage(5), 10);
+        assert!(pressure.is_high());
+
+        // keep degraded until relieved enough.
+        pressure.update(usage(15), 10);
+        assert!(pressure.is_high());
+
+        pressure.update(usage(30), 10);
+        assert!(!pressure.is_high());
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+/// The upper bounds of the buckets are 1us, 2us, 4us ... ~33.5s, the last one takes the rest.
+const BUCKETS: usize = 27;
+
+/// A latency histogram with exponential buckets, cheap enough to be recorded on the hot path.
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: Default::default(),
+            count: Default::default(),
+            sum_micros: Default::default(),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+
+        let idx = match micros {
+            0 | 1 => 0,
+            n => ((64 - (n - 1).leading_zeros()) as usize).min(BUCKETS - 1),
+        };
+
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Add the samples of the other histogram to this one.
+    pub fn merge(&self, other: &LatencyHistogram) {
+        for (bucket, other) in self.buckets.iter().zip(other.buckets.iter()) {
+            bucket.fetch_add(other.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        self.count
+            .fetch_add(other.count.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(other.sum_micros.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.sum_micros.store(0, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            count => Some(Duration::from_micros(
+                self.sum_micros.load(Ordering::Relaxed) / count,
+            )),
+        }
+    }
+
+    /// The upper bound of the bucket the percentile falls in, e.g. `percentile(0.95)`.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let count = self.count();
+
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((count as f64) * p.clamp(0.0, 1.0)).ceil().max(1.0) as u64;
+
+        let mut seen = 0;
+        for (idx, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Some(Duration::from_micros(1 << idx));
+            }
+        }
+
+        Some(Duration::from_micros(1 << (BUCKETS - 1)))
+    }
+}
+
+impl fmt::Display for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.mean(), self.percentile(0.95)) {
+            (Some(mean), Some(p95)) => write!(
+                f,
+                "count: {}, mean: {:?}, p95: {:?}",
+                self.count(),
+                mean,
+                p95
+            ),
+            _ => write!(f, "count: 0"),
+        }
+    }
+}
+
+/// The latency of a tcp based upstream connection, broken down by phase.
+#[derive(Debug, Default)]
+pub struct TransportMetrics {
+    /// tcp connect, including the proxy handshake if any.
+    pub connect: LatencyHistogram,
+    /// tls handshake, until the first application data written.
+    pub tls_handshake: LatencyHistogram,
+    /// from the first byte of the request written, to it flushed.
+    pub write: LatencyHistogram,
+    /// from the request flushed, to the first byte of the response read.
+    pub first_byte: LatencyHistogram,
+    /// from the first byte of the response read, to the last one before the next request.
+    pub read: LatencyHistogram,
+}
+
+impl fmt::Display for TransportMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connect: [{}], tls handshake: [{}], write: [{}], first byte: [{}], read: [{}]",
+            self.connect, self.tls_handshake, self.write, self.first_byte, self.read
+        )
+    }
+}
+
+static TRANSPORT_METRICS: Lazy<RwLock<HashMap<SocketAddr, Arc<TransportMetrics>>>> =
+    Lazy::new(Default::default);
+
+/// The transport metrics of the upstream socket address.
+pub fn transport_metrics(addr: SocketAddr) -> Arc<TransportMetrics> {
+    if let Some(metrics) = TRANSPORT_METRICS
+        .read()
+        .ok()
+        .and_then(|m| m.get(&addr).cloned())
+    {
+        return metrics;
+    }
+
+    match TRANSPORT_METRICS.write() {
+        Ok(mut m) => m.entry(addr).or_default().clone(),
+        Err(_) => Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.mean(), None);
+        assert_eq!(histogram.percentile(0.95), None);
+
+        for _ in 0..95 {
+            histogram.record(Duration::from_micros(1000));
+        }
+        for _ in 0..5 {
+            histogram.record(Duration::from_millis(100));
+        }
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(5950)));
+        assert_eq!(
+            histogram.percentile(0.95),
+            Some(Duration::from_micros(1024))
+        );
+        assert_eq!(
+            histogram.percentile(0.99),
+            Some(Duration::from_micros(131072))
+        );
+    }
+
+    #[test]
+    fn test_latency_histogram_merge() {
+        let a = LatencyHistogram::default();
+        let b = LatencyHistogram::default();
+
+        a.record(Duration::from_micros(1000));
+        b.record(Duration::from_millis(100));
+        a.merge(&b);
+
+        assert_eq!(a.count(), 2);
+        assert_eq!(a.percentile(1.0), Some(Duration::from_micros(131072)));
+
+        a.reset();
+        assert_eq!(a.count(), 0);
+        assert_eq!(a.percentile(1.0), None);
+    }
+
+    #[test]
+    fn test_transport_metrics() {
+        let addr = "10.0.0.1:853".parse().unwrap();
+        transport_metrics(addr)
+            .connect
+            .record(Duration::from_millis(10));
+        assert_eq!(transport_metrics(addr).connect.count(), 1);
+    }
+}
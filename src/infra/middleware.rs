@@ -43,12 +43,21 @@ pub trait MiddlewareDefaultHandler<TCtx, TReq, TRes, TErr>: Send + Sync {
     async fn handle(&self, ctx: &mut TCtx, req: &TReq) -> Result<TRes, TErr>;
 }
 
-#[derive(Clone)]
 pub struct Next<'a, TCtx, TReq, TRes, TErr> {
     default: &'a Arc<dyn MiddlewareDefaultHandler<TCtx, TReq, TRes, TErr>>,
     middlewares: &'a [Arc<dyn Middleware<TCtx, TReq, TRes, TErr>>],
 }
 
+// not derived, which would require the context, request and response to be Clone.
+impl<'a, TCtx, TReq, TRes, TErr> Clone for Next<'a, TCtx, TReq, TRes, TErr> {
+    fn clone(&self) -> Self {
+        Self {
+            default: self.default,
+            middlewares: self.middlewares,
+        }
+    }
+}
+
 impl<'a, TCtx: Send, TReq: Sync, TRes, TErr> Next<'a, TCtx, TReq, TRes, TErr> {
     pub(crate) fn new(
         default: &'a Arc<dyn MiddlewareDefaultHandler<TCtx, TReq, TRes, TErr>>,
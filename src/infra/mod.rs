@@ -1,4 +1,16 @@
+pub mod concurrency;
+pub mod drain;
+pub mod http;
+pub mod iface;
+pub mod ipnet;
+pub mod mac;
 pub mod mapped_file;
 pub mod mem_bytes;
+pub mod memory;
+pub mod metrics;
 pub mod middleware;
+pub mod netfilter;
 pub mod ping;
+pub mod rate_limit;
+pub mod tasks;
+pub mod tproxy;
@@ -0,0 +1,371 @@
+//! Add the ips answered to the sets of the firewall, over the netlink of netfilter, rather
+//! than spawning `ipset` or `nft` for each answer.
+
+use std::io;
+use std::net::IpAddr;
+
+/// see linux/netfilter/nfnetlink.h, linux/netfilter/ipset/ip_set.h and
+/// linux/netfilter/nf_tables.h.
+const NFNL_SUBSYS_IPSET: u16 = 6;
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 16;
+const NFNL_MSG_BATCH_END: u16 = 17;
+const IPSET_CMD_ADD: u16 = 9;
+const IPSET_PROTOCOL: u8 = 6;
+
+const IPSET_ATTR_PROTOCOL: u16 = 1;
+const IPSET_ATTR_SETNAME: u16 = 2;
+const IPSET_ATTR_DATA: u16 = 7;
+const IPSET_ATTR_IP: u16 = 1;
+const IPSET_ATTR_TIMEOUT: u16 = 6;
+const IPSET_ATTR_CADT_FLAGS: u16 = 8;
+const IPSET_ATTR_IPADDR_IPV4: u16 = 1;
+const IPSET_ATTR_IPADDR_IPV6: u16 = 2;
+/// added again, the timeout refreshed, rather than failed as existing.
+const IPSET_FLAG_EXIST: u32 = 1;
+
+const NFT_MSG_NEWSETELEM: u16 = 12;
+const NFTA_SET_ELEM_LIST_TABLE: u16 = 1;
+const NFTA_SET_ELEM_LIST_SET: u16 = 2;
+const NFTA_SET_ELEM_LIST_ELEMENTS: u16 = 3;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_SET_ELEM_KEY: u16 = 1;
+const NFTA_SET_ELEM_TIMEOUT: u16 = 4;
+const NFTA_DATA_VALUE: u16 = 1;
+
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const NLM_F_CREATE: u16 = 0x400;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_F_NET_BYTEORDER: u16 = 1 << 14;
+const NLMSG_ERROR: u16 = 2;
+
+const NFPROTO_UNSPEC: u8 = 0;
+const NFPROTO_INET: u8 = 1;
+const NFPROTO_IPV4: u8 = 2;
+const NFPROTO_IPV6: u8 = 10;
+
+/// The netlink socket of netfilter, shared by the queries, the acks read back lazily.
+pub struct Netfilter {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    socket: socket2::Socket,
+}
+
+impl Netfilter {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn open() -> io::Result<Self> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(
+            Domain::from(libc::AF_NETLINK),
+            Type::RAW,
+            Some(Protocol::from(libc::NETLINK_NETFILTER)),
+        )?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self { socket })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn open() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "netfilter is only available on linux",
+        ))
+    }
+
+    /// `ipset add [set] [ip] [timeout [seconds]] -exist`, the set must be created with
+    /// `timeout` for the timeout.
+    pub fn add_ipset(&self, set: &str, ip: IpAddr, timeout: Option<u32>) -> io::Result<()> {
+        self.send(&ipset_add(set, ip, timeout))
+    }
+
+    /// `nft add element [family] [table] [set] { [ip] [timeout [seconds]s] }`, the family is
+    /// `inet`, `ip` or `ip6`, the set must be created with `flags timeout` for the timeout.
+    pub fn add_nftset(
+        &self,
+        family: &str,
+        table: &str,
+        set: &str,
+        ip: IpAddr,
+        timeout: Option<u32>,
+    ) -> io::Result<()> {
+        let family = match family {
+            "inet" => NFPROTO_INET,
+            "ip" => NFPROTO_IPV4,
+            "ip6" => NFPROTO_IPV6,
+            _ => return Err(io::ErrorKind::InvalidInput.into()),
+        };
+        self.send(&nftset_add(family, table, set, ip, timeout))
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        self.drain_acks();
+        self.socket.send(message).map(|_| ())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn send(&self, _message: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// The acks of the messages sent before, the failures logged, e.g. the set not exists.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn drain_acks(&self) {
+        use std::io::Read;
+
+        let mut buf = [0u8; 1024];
+        while let Ok(len) = (&self.socket).read(&mut buf) {
+            if let Some(errno) = ack_error(&buf[..len]) {
+                crate::log::debug!(
+                    "netfilter rejected, {}",
+                    io::Error::from_raw_os_error(errno)
+                );
+            }
+        }
+    }
+}
+
+fn ipset_add(set: &str, ip: IpAddr, timeout: Option<u32>) -> Vec<u8> {
+    let family = match ip {
+        IpAddr::V4(_) => NFPROTO_IPV4,
+        IpAddr::V6(_) => NFPROTO_IPV6,
+    };
+
+    let mut message = Message::new(
+        NFNL_SUBSYS_IPSET << 8 | IPSET_CMD_ADD,
+        NLM_F_REQUEST | NLM_F_ACK,
+        family,
+    );
+    message.attr(IPSET_ATTR_PROTOCOL, &[IPSET_PROTOCOL]);
+    message.attr_str(IPSET_ATTR_SETNAME, set);
+
+    let data = message.begin(IPSET_ATTR_DATA);
+    let addr = message.begin(IPSET_ATTR_IP);
+    match ip {
+        IpAddr::V4(ip) => message.attr(IPSET_ATTR_IPADDR_IPV4 | NLA_F_NET_BYTEORDER, &ip.octets()),
+        IpAddr::V6(ip) => message.attr(IPSET_ATTR_IPADDR_IPV6 | NLA_F_NET_BYTEORDER, &ip.octets()),
+    }
+    message.end(addr);
+    if let Some(timeout) = timeout {
+        message.attr(
+            IPSET_ATTR_TIMEOUT | NLA_F_NET_BYTEORDER,
+            &timeout.to_be_bytes(),
+        );
+    }
+    message.attr(
+        IPSET_ATTR_CADT_FLAGS | NLA_F_NET_BYTEORDER,
+        &IPSET_FLAG_EXIST.to_be_bytes(),
+    );
+    message.end(data);
+
+    message.finish()
+}
+
+/// The element added in a batch, as nftables accepts the changes in batches only.
+fn nftset_add(family: u8, table: &str, set: &str, ip: IpAddr, timeout: Option<u32>) -> Vec<u8> {
+    let mut batch = Message::new(NFNL_MSG_BATCH_BEGIN, NLM_F_REQUEST, NFPROTO_UNSPEC)
+        .res_id(NFNL_SUBSYS_NFTABLES)
+        .finish();
+
+    let mut message = Message::new(
+        NFNL_SUBSYS_NFTABLES << 8 | NFT_MSG_NEWSETELEM,
+        NLM_F_REQUEST | NLM_F_CREATE | NLM_F_ACK,
+        family,
+    );
+    message.attr_str(NFTA_SET_ELEM_LIST_TABLE, table);
+    message.attr_str(NFTA_SET_ELEM_LIST_SET, set);
+
+    let elements = message.begin(NFTA_SET_ELEM_LIST_ELEMENTS);
+    let element = message.begin(NFTA_LIST_ELEM);
+    let key = message.begin(NFTA_SET_ELEM_KEY);
+    match ip {
+        IpAddr::V4(ip) => message.attr(NFTA_DATA_VALUE, &ip.octets()),
+        IpAddr::V6(ip) => message.attr(NFTA_DATA_VALUE, &ip.octets()),
+    }
+    message.end(key);
+    if let Some(timeout) = timeout {
+        // in milliseconds.
+        message.attr(
+            NFTA_SET_ELEM_TIMEOUT,
+            &(timeout as u64 * 1000).to_be_bytes(),
+        );
+    }
+    message.end(element);
+    message.end(elements);
+    batch.extend(message.finish());
+
+    batch.extend(
+        Message::new(NFNL_MSG_BATCH_END, NLM_F_REQUEST, NFPROTO_UNSPEC)
+            .res_id(NFNL_SUBSYS_NFTABLES)
+            .finish(),
+    );
+
+    batch
+}
+
+/// The errno of the first failed ack in the datagram, if any.
+fn ack_error(buf: &[u8]) -> Option<i32> {
+    let mut buf = buf;
+    while buf.len() >= 20 {
+        let len = u32::from_ne_bytes(buf[0..4].try_into().ok()?) as usize;
+        let kind = u16::from_ne_bytes(buf[4..6].try_into().ok()?);
+        if len < 16 || len > buf.len() {
+            return None;
+        }
+        if kind == NLMSG_ERROR {
+            let errno = i32::from_ne_bytes(buf[16..20].try_into().ok()?);
+            if errno != 0 {
+                return Some(-errno);
+            }
+        }
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    None
+}
+
+/// A netfilter message, the header followed by the attributes, see linux/netlink.h.
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(kind: u16, flags: u16, family: u8) -> Self {
+        let mut buf = Vec::with_capacity(128);
+        // nlmsghdr, the length filled in once finished.
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&kind.to_ne_bytes());
+        buf.extend_from_slice(&flags.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes()); // seq
+        buf.extend_from_slice(&0u32.to_ne_bytes()); // port id, assigned by the kernel.
+                                                    // nfgenmsg, the version and the resource id.
+        buf.extend_from_slice(&[family, 0, 0, 0]);
+        Self { buf }
+    }
+
+    /// The subsystem of the batch, in the resource id of nfgenmsg.
+    fn res_id(mut self, id: u16) -> Self {
+        self.buf[18..20].copy_from_slice(&id.to_be_bytes());
+        self
+    }
+
+    fn attr(&mut self, kind: u16, data: &[u8]) {
+        self.buf
+            .extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.pad();
+    }
+
+    /// The string, nul terminated.
+    fn attr_str(&mut self, kind: u16, s: &str) {
+        let mut data = Vec::with_capacity(s.len() + 1);
+        data.extend_from_slice(s.as_bytes());
+        data.push(0);
+        self.attr(kind, &data);
+    }
+
+    /// The nested attribute, the offset of which is to be ended.
+    fn begin(&mut self, kind: u16) -> usize {
+        let offset = self.buf.len();
+        self.buf.extend_from_slice(&0u16.to_ne_bytes());
+        self.buf
+            .extend_from_slice(&(kind | NLA_F_NESTED).to_ne_bytes());
+        offset
+    }
+
+    fn end(&mut self, offset: usize) {
+        let len = (self.buf.len() - offset) as u16;
+        self.buf[offset..offset + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    fn pad(&mut self) {
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipset_add() {
+        let message = ipset_add("vpnset", "1.2.3.4".parse().unwrap(), Some(300));
+
+        assert_eq!(message.len(), 72);
+        assert_eq!(u32::from_ne_bytes(message[0..4].try_into().unwrap()), 72);
+        assert_eq!(
+            u16::from_ne_bytes(message[4..6].try_into().unwrap()),
+            0x0609
+        );
+        assert_eq!(message[16], NFPROTO_IPV4);
+        // the set name, nul terminated and padded.
+        assert_eq!(
+            &message[28..40],
+            [&11u16.to_ne_bytes()[..], &2u16.to_ne_bytes(), b"vpnset\0\0"].concat()
+        );
+        // the data, the ip nested in.
+        assert_eq!(u16::from_ne_bytes(message[40..42].try_into().unwrap()), 32);
+        assert_eq!(&message[52..56], &[1, 2, 3, 4]);
+        assert_eq!(&message[60..64], &300u32.to_be_bytes());
+
+        let message = ipset_add("vpnset6", "::1".parse().unwrap(), None);
+        assert_eq!(message.len(), 76);
+        assert_eq!(message[16], NFPROTO_IPV6);
+    }
+
+    #[test]
+    fn test_nftset_add() {
+        let batch = nftset_add(
+            NFPROTO_INET,
+            "fw4",
+            "dst4",
+            "1.2.3.4".parse().unwrap(),
+            Some(300),
+        );
+
+        let kind =
+            |offset: usize| u16::from_ne_bytes(batch[offset + 4..offset + 6].try_into().unwrap());
+        let len = |offset: usize| {
+            u32::from_ne_bytes(batch[offset..offset + 4].try_into().unwrap()) as usize
+        };
+
+        // begin, the element, end.
+        assert_eq!(kind(0), NFNL_MSG_BATCH_BEGIN);
+        assert_eq!(&batch[18..20], &NFNL_SUBSYS_NFTABLES.to_be_bytes());
+        let element = len(0);
+        assert_eq!(kind(element), 0x0a0c);
+        assert_eq!(batch[element + 16], NFPROTO_INET);
+        let end = element + len(element);
+        assert_eq!(kind(end), NFNL_MSG_BATCH_END);
+        assert_eq!(end + len(end), batch.len());
+
+        // the key nested in the element, then the timeout.
+        let message = &batch[element..end];
+        assert_eq!(&message[52..56], &[1, 2, 3, 4]);
+        assert_eq!(&message[60..68], &300_000u64.to_be_bytes());
+        assert_eq!(message.len(), 68);
+    }
+
+    #[test]
+    fn test_ack_error() {
+        let mut ack = vec![0u8; 36];
+        ack[0..4].copy_from_slice(&36u32.to_ne_bytes());
+        ack[4..6].copy_from_slice(&NLMSG_ERROR.to_ne_bytes());
+        assert_eq!(ack_error(&ack), None);
+
+        ack[16..20].copy_from_slice(&(-2i32).to_ne_bytes());
+        assert_eq!(ack_error(&ack), Some(2));
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+use lru::LruCache;
+
+/// The clients tracked at most, the least recently seen ones are forgotten beyond it.
+const MAX_CLIENTS: usize = 65536;
+
+/// The buckets are split by the client, so that the listeners rarely wait for each other.
+const SHARDS: usize = 16;
+
+/// A token bucket for each client, refilled at the rate and holding the burst at most.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    shards: Vec<Mutex<LruCache<IpAddr, Bucket>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate.max(1) as f64,
+            burst: burst.max(1) as f64,
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(LruCache::new(
+                        NonZeroUsize::new(MAX_CLIENTS / SHARDS).unwrap(),
+                    ))
+                })
+                .collect(),
+        }
+    }
+
+    /// Take a token of the client, false if none left.
+    pub fn check(&self, ip: IpAddr, now: Instant) -> bool {
+        let key = client_key(ip);
+        let mut buckets = self.shard(&key);
+
+        // the least recently seen client is evicted once the shard is full, its bucket
+        // most likely refilled already.
+        if !buckets.contains(&key) {
+            buckets.put(
+                key,
+                Bucket {
+                    tokens: self.burst,
+                    updated: now,
+                },
+            );
+        }
+        let bucket = buckets.get_mut(&key).expect("bucket inserted");
+
+        if self.refill(bucket, now) >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn shard(&self, key: &IpAddr) -> MutexGuard<'_, LruCache<IpAddr, Bucket>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        match self.shards[hasher.finish() as usize % SHARDS].lock() {
+            Ok(buckets) => buckets,
+            Err(err) => err.into_inner(),
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+/// The ipv6 clients are limited by the /64 network, which a single host may own entirely.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let mut segments = v6.segments();
+                segments[4..].fill(0);
+                IpAddr::V6(Ipv6Addr::from(segments))
+            }
+        },
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_burst() {
+        let limiter = RateLimiter::new(10, 3);
+        let now = Instant::now();
+        let ip = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(limiter.check(ip, now));
+        }
+        assert!(!limiter.check(ip, now));
+
+        // the others are not affected.
+        assert!(limiter.check("192.0.2.2".parse().unwrap(), now));
+
+        // refilled at 10 per second.
+        assert!(!limiter.check(ip, now + Duration::from_millis(50)));
+        assert!(limiter.check(ip, now + Duration::from_millis(150)));
+        assert!(!limiter.check(ip, now + Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn test_rate_limiter_ipv6_network() {
+        let limiter = RateLimiter::new(1, 1);
+        let now = Instant::now();
+
+        assert!(limiter.check("2001:db8::1".parse().unwrap(), now));
+        assert!(!limiter.check("2001:db8::2".parse().unwrap(), now));
+        assert!(limiter.check("2001:db8:0:1::1".parse().unwrap(), now));
+
+        assert!(limiter.check("::ffff:192.0.2.1".parse().unwrap(), now));
+        assert!(!limiter.check("192.0.2.1".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn test_rate_limiter_bounded() {
+        let limiter = RateLimiter::new(1, 1);
+        let now = Instant::now();
+
+        for i in 0..MAX_CLIENTS as u32 * 2 {
+            assert!(limiter.check(IpAddr::V4(i.into()), now));
+        }
+
+        let tracked: usize = limiter.shards.iter().map(|s| s.lock().unwrap().len()).sum();
+        assert!(tracked <= MAX_CLIENTS);
+
+        // the recent clients are still limited.
+        assert!(!limiter.check(IpAddr::V4((MAX_CLIENTS as u32 * 2 - 1).into()), now));
+    }
+}
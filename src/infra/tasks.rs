@@ -0,0 +1,174 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// A group of background tasks sharing one cancellation token,
+/// so that they can be stopped deterministically on reload or shutdown.
+#[derive(Debug, Clone, Default)]
+pub struct BackgroundTasks {
+    token: CancellationToken,
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    /// the sub groups, e.g. of the listeners, joined on shutdown along with this group.
+    children: Arc<Mutex<Vec<BackgroundTasks>>>,
+}
+
+impl BackgroundTasks {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Create a sub group, which is cancelled when this group is cancelled, and joined when
+    /// this group is shut down.
+    pub fn child(&self) -> Self {
+        let child = Self {
+            token: self.token.child_token(),
+            handles: Default::default(),
+            children: Default::default(),
+        };
+
+        if let Ok(mut children) = self.children.lock() {
+            // the ones shut down on their own, e.g. the previous generation on reload.
+            children.retain(|c| !c.is_cancelled());
+            children.push(child.clone());
+        }
+
+        child
+    }
+
+    #[inline]
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Spawn a task, which is dropped as soon as the group is cancelled.
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let token = self.token.clone();
+        self.track(tokio::spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => (),
+                _ = future => (),
+            }
+        }));
+    }
+
+    /// Spawn a task that watches the token itself, e.g. to flush its buffers before exiting.
+    pub fn spawn_with_token<F, Fut>(&self, f: F)
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.track(tokio::spawn(f(self.token.clone())));
+    }
+
+    fn track(&self, handle: JoinHandle<()>) {
+        if let Ok(mut handles) = self.handles.lock() {
+            handles.retain(|h| !h.is_finished());
+            handles.push(handle);
+        }
+    }
+
+    /// Cancel all tasks, including the ones of the sub groups, and wait for them to finish.
+    pub async fn shutdown(&self) {
+        self.token.cancel();
+
+        let mut groups = vec![self.clone()];
+
+        while let Some(group) = groups.pop() {
+            let handles = group
+                .handles
+                .lock()
+                .map(|mut handles| std::mem::take(&mut *handles))
+                .unwrap_or_default();
+
+            for handle in handles {
+                let _ = handle.await;
+            }
+
+            if let Ok(mut children) = group.children.lock() {
+                groups.extend(std::mem::take(&mut *children));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use tokio::runtime;
+
+    use super::*;
+
+    #[test]
+    fn test_shutdown_background_tasks() {
+        runtime::Runtime::new().unwrap().block_on(async {
+            let tasks = BackgroundTasks::new();
+            let child = tasks.child();
+            let counter = Arc::new(AtomicUsize::new(0));
+
+            {
+                let counter = counter.clone();
+                child.spawn(async move {
+                    loop {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                    }
+                });
+            }
+
+            let flushed = Arc::new(AtomicUsize::new(0));
+            {
+                let flushed = flushed.clone();
+                child.spawn_with_token(|token| async move {
+                    token.cancelled().await;
+                    flushed.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            tasks.shutdown().await;
+            assert!(child.is_cancelled());
+            child.shutdown().await;
+
+            let count = counter.load(Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            assert_eq!(counter.load(Ordering::SeqCst), count);
+            assert_eq!(flushed.load(Ordering::SeqCst), 1);
+        })
+    }
+
+    #[test]
+    fn test_shutdown_joins_children() {
+        runtime::Runtime::new().unwrap().block_on(async {
+            let tasks = BackgroundTasks::new();
+            let grandchild = tasks.child().child();
+            let finished = Arc::new(AtomicUsize::new(0));
+
+            {
+                let finished = finished.clone();
+                grandchild.spawn_with_token(|token| async move {
+                    token.cancelled().await;
+                    // the in-flight work, finished before the shutdown returns.
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+
+            tasks.shutdown().await;
+            assert_eq!(finished.load(Ordering::SeqCst), 1);
+        })
+    }
+}
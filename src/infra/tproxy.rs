@@ -0,0 +1,106 @@
+use std::net::SocketAddr;
+
+use tokio::net::TcpStream;
+
+tokio::task_local! {
+    /// The destination the client intended to query, before redirected to us by the firewall.
+    pub static ORIGINAL_DST: SocketAddr;
+}
+
+/// The destination of the query being handled, if redirected.
+pub fn original_dst() -> Option<SocketAddr> {
+    ORIGINAL_DST.try_with(|dst| *dst).ok()
+}
+
+/// The destination of the tcp connection before redirected, by REDIRECT or TPROXY,
+/// e.g. `iptables -t nat -A PREROUTING -p tcp --dport 53 -j REDIRECT --to-ports 6053`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn tcp_original_dst(stream: &TcpStream, listener_addr: SocketAddr) -> Option<SocketAddr> {
+    use std::os::unix::io::AsRawFd;
+
+    let local = stream.local_addr().ok()?;
+
+    // REDIRECT, the conntrack knows where it was heading.
+    if let Some(dst) = so_original_dst(stream.as_raw_fd(), local).filter(|dst| *dst != local) {
+        return Some(dst);
+    }
+
+    // TPROXY, the connection is accepted on the original destination.
+    let redirected = local.port() != listener_addr.port()
+        || (!listener_addr.ip().is_unspecified() && local.ip() != listener_addr.ip());
+    redirected.then(|| local)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn tcp_original_dst(_stream: &TcpStream, _listener_addr: SocketAddr) -> Option<SocketAddr> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn so_original_dst(fd: std::os::unix::io::RawFd, local: SocketAddr) -> Option<SocketAddr> {
+    use socket2::SockAddr;
+    use std::net::IpAddr;
+
+    /// The same option of ip6tables, see linux/netfilter_ipv6/ip6_tables.h.
+    const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+    let ipv4 = match local.ip() {
+        IpAddr::V4(_) => true,
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().is_some(),
+    };
+    let (level, name) = if ipv4 {
+        (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST)
+    };
+
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut storage as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    // not redirected, or no conntrack at all.
+    if ret != 0 {
+        return None;
+    }
+
+    unsafe { SockAddr::new(storage, len) }.as_socket()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::runtime::Builder;
+
+    use super::*;
+
+    #[test]
+    fn test_original_dst() {
+        Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let dst: SocketAddr = "192.0.2.53:53".parse().unwrap();
+                assert_eq!(original_dst(), None);
+                assert_eq!(
+                    ORIGINAL_DST.scope(dst, async { original_dst() }).await,
+                    Some(dst)
+                );
+
+                // connected directly, not redirected.
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                let _client = TcpStream::connect(addr).await.unwrap();
+                let (stream, _) = listener.accept().await.unwrap();
+                assert_eq!(tcp_original_dst(&stream, addr), None);
+            })
+    }
+}
@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::Path;
+
+use clap::ValueEnum;
+
+/// The kind of the machine the configuration is generated for.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Profile {
+    /// Serve the clients of the local network behind the dnsmasq of OpenWrt, which keeps
+    /// port 53 and the dhcp.
+    Router,
+    /// Serve the applications of this machine only.
+    Desktop,
+}
+
+impl Profile {
+    /// A router if it's OpenWrt, or its dnsmasq leases the dhcp addresses; forwarding the
+    /// packets alone isn't, e.g. every docker host does.
+    pub fn detect() -> Self {
+        if Path::new("/etc/openwrt_release").exists() || Path::new(DHCP_LEASES_FILE).exists() {
+            Profile::Router
+        } else {
+            Profile::Desktop
+        }
+    }
+}
+
+/// The leases of the dnsmasq of OpenWrt, the hostnames of the clients answered.
+const DHCP_LEASES_FILE: &str = "/tmp/dhcp.leases";
+
+/// The annotated configuration of the profile, see `smartdns init`.
+pub fn generate(profile: Profile) -> String {
+    let mut conf = String::from(COMMON);
+
+    conf.push_str(match profile {
+        Profile::Router => ROUTER,
+        Profile::Desktop => DESKTOP,
+    });
+
+    conf
+}
+
+const COMMON: &str = r#"# The configuration generated by `smartdns init`, the full reference of the directives:
+#   https://github.com/mokeyish/smartdns-rs#配置
+#
+# Check it with `smartdns check -c [file]` after editing.
+
+# The upstreams queried, the fastest answer taken.
+#   server [ip][:port] [-group name] [-exclude-default-group]
+#   server-tls [ip|host][:port]
+#   server-https https://[host]/dns-query
+server-https https://cloudflare-dns.com/dns-query
+server-https https://dns.google/dns-query
+server-tls 1.1.1.1
+
+# The servers resolving the hostnames of the upstreams above, by ip address.
+bootstrap-dns 1.1.1.1
+bootstrap-dns 8.8.8.8
+
+# Answer the ips cached once expired, and refresh them in background.
+serve-expired yes
+prefetch-domain yes
+
+# Answer the fastest ips of the domain, checked by ping, then by connecting to the port.
+#   speed-check-mode [ping|tcp:port|none],...
+speed-check-mode ping,tcp:443,tcp:80
+
+# Block a domain and its subdomains, or answer an ip of your own.
+#   address /domain/[ip|#]
+# address /ads.example.com/#
+# address /nas.example.com/192.168.1.2
+
+# Resolve the domains by a group of the upstreams, e.g. of the office vpn.
+#   nameserver /domain/group
+# server 10.0.0.1 -group office -exclude-default-group
+# nameserver /corp.example.com/office
+
+"#;
+
+const ROUTER: &str = r#"# Serve the clients of the local network, on both families, over udp and tcp, behind
+# dnsmasq, which keeps port 53 and the dhcp. Forward dnsmasq to this port:
+#   uci add_list dhcp.@dnsmasq[0].server='127.0.0.1#6053'
+#   uci set dhcp.@dnsmasq[0].noresolv='1'
+#   uci commit dhcp && /etc/init.d/dnsmasq restart
+# The clients of the private networks are allowed only, see acl-enable.
+bind [::]:6053
+bind-tcp [::]:6053
+acl-enable yes
+
+# The answers cached, 0 to disable, kept small for the memory of the routers.
+cache-size 4096
+
+# Answer the hostnames of the dhcp clients, also under the local domain, e.g. laptop.lan.
+dnsmasq-lease-file /tmp/dhcp.leases
+domain lan
+expand-hosts yes
+
+log-level warn
+"#;
+
+const DESKTOP: &str = r#"# Serve the applications of this machine only, point the dns of the system to 127.0.0.1.
+bind 127.0.0.1:53
+bind-tcp 127.0.0.1:53
+bind [::1]:53
+
+# The answers cached, 0 to disable.
+cache-size 16384
+
+# Answer the family faster to connect only, if faster by more than the milliseconds.
+dualstack-ip-selection yes
+dualstack-ip-selection-threshold 15
+
+log-level info
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smartdns::dns_conf::SmartDnsConfig;
+
+    #[test]
+    fn test_generate_valid() {
+        for profile in [Profile::Router, Profile::Desktop] {
+            let path = std::env::temp_dir().join(format!("smartdns-init-{:?}.conf", profile));
+            fs::write(&path, generate(profile)).unwrap();
+
+            let cfg = SmartDnsConfig::load_from_file(&path);
+            fs::remove_file(&path).unwrap();
+
+            assert!(cfg.diagnostics.is_empty(), "{:?}", cfg.diagnostics);
+            assert!(cfg.check().is_empty());
+            assert_eq!(cfg.servers["default"].len(), 3);
+
+            // port 53 of the router kept by dnsmasq.
+            if profile == Profile::Router {
+                assert!(cfg
+                    .binds
+                    .iter()
+                    .chain(cfg.binds_tcp.iter())
+                    .flat_map(|bind| bind.addr.iter())
+                    .all(|addr| addr.port() == 6053));
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! smartdns-rs as a library, to embed the resolver and insert handling stages of its own.
+//!
+//! The queries flow through the middlewares in the order registered, each one answers
+//! or passes the query on by [`Next::run`]. The [`HandlerBuilder`] runs the stages registered
+//! before the ones of the configuration, e.g.
+//!
+//! ```no_run
+//! use smartdns::dns_conf::SmartDnsConfig;
+//! use smartdns::{DnsContext, DnsError, DnsRequest, DnsResponse, HandlerBuilder, Middleware, Next};
+//!
+//! struct Logging;
+//!
+//! #[async_trait::async_trait]
+//! impl Middleware<DnsContext, DnsRequest, DnsResponse, DnsError> for Logging {
+//!     async fn handle(
+//!         &self,
+//!         ctx: &mut DnsContext,
+//!         req: &DnsRequest,
+//!         next: Next<'_, DnsContext, DnsRequest, DnsResponse, DnsError>,
+//!     ) -> Result<DnsResponse, DnsError> {
+//!         println!("query {} from {}", req.query(), req.src());
+//!         next.run(ctx, req).await
+//!     }
+//! }
+//!
+//! let runtime = tokio::runtime::Builder::new_current_thread()
+//!     .enable_all()
+//!     .build()
+//!     .unwrap();
+//! let _guard = runtime.enter();
+//!
+//! let cfg = SmartDnsConfig::load(Some("/etc/smartdns/smartdns.conf"));
+//! let handler = HandlerBuilder::new(cfg).with(Logging).build();
+//! ```
+#![allow(dead_code)]
+
+pub mod dns;
+pub mod dns_client;
+pub mod dns_conf;
+pub mod dns_mw;
+pub mod handler;
+
+pub use dns::{DnsContext, DnsError, DnsRequest, DnsResponse, LookupSource};
+pub use dns_mw::{DnsMiddlewareBuilder, DnsMiddlewareHandler};
+pub use handler::{HandlerBuilder, Retained};
+pub use infra::middleware::{Middleware, Next};
+
+pub mod control;
+pub mod infra;
+pub mod log;
+
+// the servers and commands of the smartdns binary, not part of the stable api.
+#[cfg(feature = "sqlite")]
+#[doc(hidden)]
+pub mod audit_db;
+#[doc(hidden)]
+pub mod blocking;
+#[doc(hidden)]
+pub mod dns_https;
+#[doc(hidden)]
+pub mod dns_server;
+#[doc(hidden)]
+pub mod dns_tcp;
+#[doc(hidden)]
+pub mod dns_tls;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[doc(hidden)]
+pub mod dns_udp;
+#[doc(hidden)]
+pub mod domain_set;
+#[doc(hidden)]
+pub mod rule_explain;
+#[doc(hidden)]
+pub mod third_ext;
+#[doc(hidden)]
+pub mod upstream_stats;
+
+mod blocklist;
+mod dns_conn;
+mod dns_ecs;
+mod dns_mw_addr;
+mod dns_mw_audit;
+mod dns_mw_cache;
+mod dns_mw_chaos;
+mod dns_mw_cname;
+mod dns_mw_container;
+mod dns_mw_dualstack;
+mod dns_mw_hosts;
+mod dns_mw_ipset;
+mod dns_mw_max_reply_ip;
+mod dns_mw_mdns;
+mod dns_mw_ns;
+mod dns_mw_pin;
+mod dns_mw_qtype;
+#[cfg(feature = "script")]
+mod dns_mw_script;
+mod dns_mw_secondary;
+mod dns_mw_slo;
+mod dns_mw_spdt;
+mod dns_mw_suffix;
+#[cfg(feature = "wasm-plugin")]
+mod dns_mw_wasm;
+mod dns_mw_zone;
+mod dns_padding;
+mod dns_url;
+mod dnscrypt;
+mod dnstap;
+mod fast_ping;
+mod geoip;
+mod geosite;
+mod matcher;
+mod notify;
+mod odoh;
+mod preset_ns;
+mod proxy;
+mod speed_check;
@@ -0,0 +1,580 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::net::{TcpListener, TcpSocket, UdpSocket};
+
+#[cfg(unix)]
+use crate::upgrade::handover::{InheritedListeners, ListenerKind};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use smartdns::dns_udp;
+use smartdns::{
+    dns_conf::{Acl, BindServer, QueryLimit, RateLimit, ServerOpts, SmartDnsConfig},
+    dns_https,
+    dns_server::{MessageLimits, MiddlewareBasedRequestHandler, ServerFuture},
+    dns_tcp,
+    dns_tls::ReloadableCert,
+    infra::{iface, tasks::BackgroundTasks},
+    log::{debug, error, info, warn},
+};
+
+/// The protocol a listener answers with, the tls and https ones handshaking first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerProto {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+impl ListenerProto {
+    fn is_tls(&self) -> bool {
+        matches!(self, ListenerProto::Tls | ListenerProto::Https)
+    }
+}
+
+impl std::fmt::Display for ListenerProto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ListenerProto::Udp => "UDP",
+            ListenerProto::Tcp => "TCP",
+            ListenerProto::Tls => "TLS",
+            ListenerProto::Https => "HTTPS",
+        })
+    }
+}
+
+/// A listening socket of the configuration, along with everything it's built with, so that a
+/// reload binds again only the listeners changed, the others answering on.
+#[derive(Debug, Clone, PartialEq)]
+struct ListenerSpec {
+    proto: ListenerProto,
+    addr: SocketAddr,
+    /// the udp sockets sharing the address with SO_REUSEPORT, see `num-workers`.
+    worker: usize,
+    workers: usize,
+    bind: BindServer,
+    /// the acl of the listener, the global one if it has none.
+    acl: Option<Acl>,
+    /// the inflight limit of the listener, queueing as the global one.
+    query_limit: Option<QueryLimit>,
+    /// the limits shared by all the listeners.
+    limits: (Option<RateLimit>, Option<QueryLimit>),
+    edns_packet_max: u16,
+    /// the messages received, checked before parsed.
+    message_limits: MessageLimits,
+    /// the certificate files of the tls and https listeners.
+    cert: Option<(PathBuf, PathBuf)>,
+}
+
+/// The listeners of the configuration, as bound by the specs.
+fn specs(cfg: &SmartDnsConfig) -> Vec<ListenerSpec> {
+    let workers = if cfg!(unix) { cfg.num_workers() } else { 1 };
+    let limits = (cfg.rate_limit.clone(), cfg.query_limit);
+    let cert = cfg
+        .bind_cert_file
+        .clone()
+        .zip(cfg.bind_cert_key_file.clone());
+
+    [
+        (ListenerProto::Udp, &cfg.binds),
+        (ListenerProto::Tcp, &cfg.binds_tcp),
+        (ListenerProto::Tls, &cfg.binds_tls),
+        (ListenerProto::Https, &cfg.binds_https),
+    ]
+    .into_iter()
+    .flat_map(|(proto, binds)| binds.iter().map(move |bind| (proto, bind)))
+    .flat_map(|(proto, bind)| {
+        // each udp address is repeated for the workers.
+        let workers = if proto == ListenerProto::Udp {
+            workers
+        } else {
+            1
+        };
+        bind.addr.iter().flat_map(move |addr| {
+            (0..workers).map(move |worker| (proto, bind, *addr, worker, workers))
+        })
+    })
+    .map(|(proto, bind, addr, worker, workers)| ListenerSpec {
+        proto,
+        addr,
+        worker,
+        workers,
+        bind: bind.clone(),
+        acl: cfg
+            .acl_enable
+            .then(|| bind.acl.clone().unwrap_or_else(|| cfg.acl.clone())),
+        query_limit: bind.max_inflight.map(|n| QueryLimit {
+            max_inflight: n,
+            ..cfg.query_limit.unwrap_or_else(|| QueryLimit::new(n))
+        }),
+        limits: limits.clone(),
+        edns_packet_max: cfg.edns_packet_max(),
+        // the udp payload size advertised, the largest datagram taken.
+        message_limits: match proto {
+            ListenerProto::Udp => MessageLimits {
+                max_message_size: cfg.edns_packet_max() as usize,
+                ..Default::default()
+            },
+            _ => Default::default(),
+        },
+        cert: proto.is_tls().then(|| cert.clone()).flatten(),
+    })
+    .collect()
+}
+
+/// A listener bound, stopped along with its tasks.
+struct Listener {
+    spec: ListenerSpec,
+    tasks: BackgroundTasks,
+    #[cfg(unix)]
+    fd: RawFd,
+    /// the udp socket answered by trust-dns, where no pktinfo, aborted on drop.
+    server: Option<ServerFuture<MiddlewareBasedRequestHandler>>,
+}
+
+/// The certificate of the tls listeners, watched until replaced.
+struct LoadedCert {
+    files: (PathBuf, PathBuf),
+    cert: Arc<ReloadableCert>,
+    tasks: BackgroundTasks,
+}
+
+/// The listeners running, bound again on reload only if their specs changed, so that the
+/// cached answers and the connections of the others are kept.
+pub struct Listeners {
+    /// the handler shared by the listeners, before the options of each.
+    base: MiddlewareBasedRequestHandler,
+    limits: (Option<RateLimit>, Option<QueryLimit>),
+    cert: Option<LoadedCert>,
+    desired: Vec<ListenerSpec>,
+    running: Vec<Listener>,
+    tasks: BackgroundTasks,
+    /// the listening sockets handed over by the predecessor when upgrading, taken once.
+    #[cfg(unix)]
+    inherited: Option<InheritedListeners>,
+}
+
+impl Listeners {
+    pub fn new(base: MiddlewareBasedRequestHandler, tasks: &BackgroundTasks) -> Self {
+        Self {
+            base,
+            limits: Default::default(),
+            cert: None,
+            desired: vec![],
+            running: vec![],
+            tasks: tasks.clone(),
+            #[cfg(unix)]
+            inherited: Some(InheritedListeners::from_env()),
+        }
+    }
+
+    /// Bind the listeners of the configuration, stopping the ones no longer configured, false
+    /// if any couldn't be bound, see `sync`.
+    pub async fn update(&mut self, cfg: &SmartDnsConfig) -> bool {
+        let limits = (cfg.rate_limit.clone(), cfg.query_limit);
+        if limits != self.limits {
+            // the listeners are all bound again, as their specs changed along.
+            self.base = self
+                .base
+                .clone()
+                .with_rate_limit(limits.0.clone())
+                .with_query_limit(limits.1);
+            self.limits = limits;
+        }
+
+        let mut desired = specs(cfg);
+
+        let needs_cert = desired.iter().any(|spec| spec.proto.is_tls());
+        self.update_cert(needs_cert.then(|| {
+            cfg.bind_cert_file
+                .clone()
+                .zip(cfg.bind_cert_key_file.clone())
+        }))
+        .await;
+
+        if self.cert.is_none() {
+            desired.retain(|spec| {
+                if spec.proto.is_tls() {
+                    warn!(
+                        "skip {} on {:?}, no certificate loaded",
+                        spec.proto, spec.addr
+                    );
+                }
+                !spec.proto.is_tls()
+            });
+        }
+
+        self.desired = desired;
+        self.sync().await
+    }
+
+    /// Load the certificate of the files, unless already, none if no tls listener.
+    async fn update_cert(&mut self, files: Option<Option<(PathBuf, PathBuf)>>) {
+        let files = match files {
+            Some(Some(files)) => files,
+            Some(None) => {
+                error!("bind-tls and bind-https require bind-cert-file and bind-cert-key-file");
+                return self.stop_cert().await;
+            }
+            None => return self.stop_cert().await,
+        };
+
+        if matches!(self.cert.as_ref(), Some(loaded) if loaded.files == files) {
+            return;
+        }
+
+        self.stop_cert().await;
+
+        match ReloadableCert::load(&files.0, &files.1) {
+            Ok(cert) => {
+                let cert = Arc::new(cert);
+                let tasks = self.tasks.child();
+                cert.spawn_watcher(&tasks);
+                self.cert = Some(LoadedCert { files, cert, tasks });
+            }
+            Err(err) => error!("load tls certificate {:?} failed, {}", files.0, err),
+        }
+    }
+
+    async fn stop_cert(&mut self) {
+        if let Some(loaded) = self.cert.take() {
+            loaded.tasks.shutdown().await;
+        }
+    }
+
+    /// Whether all the listeners of the configuration are bound.
+    pub fn is_synced(&self) -> bool {
+        self.desired
+            .iter()
+            .all(|spec| self.running.iter().any(|listener| listener.spec == *spec))
+    }
+
+    /// Stop the listeners no longer configured, then bind the ones not yet, false if any
+    /// couldn't be bound, retried by the next call.
+    pub async fn sync(&mut self) -> bool {
+        let desired = &self.desired;
+        let (running, stale): (Vec<_>, Vec<_>) = std::mem::take(&mut self.running)
+            .into_iter()
+            .partition(|listener| desired.contains(&listener.spec));
+        self.running = running;
+
+        // stopped first, their addresses bound again by the changed ones.
+        for listener in stale {
+            info!(
+                "stop listening for {} on {:?}",
+                listener.spec.proto, listener.spec.addr
+            );
+            listener.tasks.shutdown().await;
+            drop(listener.server);
+        }
+
+        let mut synced = true;
+        for spec in self.desired.clone() {
+            if self.running.iter().any(|listener| listener.spec == spec) {
+                continue;
+            }
+
+            match self.start(spec.clone()).await {
+                Ok(listener) => self.running.push(listener),
+                Err(err) => {
+                    error!(
+                        "could not listen for {} on {:?}, {}",
+                        spec.proto, spec.addr, err
+                    );
+                    synced = false;
+                }
+            }
+        }
+
+        // close the inherited sockets no longer configured.
+        #[cfg(unix)]
+        drop(self.inherited.take());
+
+        synced
+    }
+
+    /// The listening sockets, handed over to the successor when upgrading.
+    #[cfg(unix)]
+    pub fn raw_fds(&self) -> Vec<(ListenerKind, RawFd)> {
+        self.running
+            .iter()
+            .map(|listener| match listener.spec.proto {
+                ListenerProto::Udp => (ListenerKind::Udp, listener.fd),
+                _ => (ListenerKind::Tcp, listener.fd),
+            })
+            .collect()
+    }
+
+    async fn start(&mut self, spec: ListenerSpec) -> io::Result<Listener> {
+        let handler = self
+            .base
+            .clone()
+            .with_acl(spec.acl.clone())
+            .with_limits(spec.message_limits)
+            .with_listener_query_limit(spec.query_limit)
+            .with_server_opts(ServerOpts {
+                acl: spec.bind.acl.clone().map(Arc::new),
+                ..spec.bind.opts.clone()
+            });
+
+        match spec.proto {
+            ListenerProto::Udp => self.start_udp(spec, handler).await,
+            _ => self.start_tcp(spec, handler).await,
+        }
+    }
+
+    async fn start_udp(
+        &mut self,
+        spec: ListenerSpec,
+        handler: MiddlewareBasedRequestHandler,
+    ) -> io::Result<Listener> {
+        #[cfg(unix)]
+        let inherited = self.inherited.as_mut().and_then(|i| i.take_udp(spec.addr));
+        #[cfg(not(unix))]
+        let inherited: Option<std::net::UdpSocket> = None;
+
+        let socket = match inherited {
+            Some(socket) => {
+                debug!("inherited UDP {:?}", spec.addr);
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)?
+            }
+            None => {
+                debug!("binding UDP to {:?}", spec.addr);
+                bind_udp(
+                    spec.addr,
+                    spec.bind.interface.as_deref(),
+                    spec.workers > 1,
+                    spec.bind.transparent,
+                )
+                .await?
+            }
+        };
+
+        let local_addr = socket.local_addr()?;
+        info!("listening for UDP on {:?}", local_addr);
+
+        let tasks = self.tasks.child();
+        #[cfg(unix)]
+        let fd = socket.as_raw_fd();
+
+        // answer from the address the queries arrived on, rather than the one routed to the client,
+        // the messages checked before parsed.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let server = {
+            dns_udp::spawn_listener(
+                socket,
+                spec.edns_packet_max,
+                spec.bind.transparent,
+                spec.message_limits,
+                handler,
+                &tasks,
+            )?;
+            None
+        };
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let server = {
+            let mut server = ServerFuture::new(handler);
+            server.register_socket(socket);
+            Some(server)
+        };
+
+        Ok(Listener {
+            spec,
+            tasks,
+            #[cfg(unix)]
+            fd,
+            server,
+        })
+    }
+
+    async fn start_tcp(
+        &mut self,
+        spec: ListenerSpec,
+        handler: MiddlewareBasedRequestHandler,
+    ) -> io::Result<Listener> {
+        let cert = self.cert.as_ref().map(|loaded| loaded.cert.clone());
+        let acceptor = match (cert.as_ref(), spec.proto) {
+            (Some(cert), ListenerProto::Tls) => Some(cert.acceptor(&spec.bind.tls)?),
+            (Some(cert), ListenerProto::Https) => Some(cert.https_acceptor(&spec.bind.tls)?),
+            (None, proto) if proto.is_tls() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no certificate loaded",
+                ))
+            }
+            _ => None,
+        };
+
+        #[cfg(unix)]
+        let inherited = self.inherited.as_mut().and_then(|i| i.take_tcp(spec.addr));
+        #[cfg(not(unix))]
+        let inherited: Option<std::net::TcpListener> = None;
+
+        let listener = match inherited {
+            Some(listener) => {
+                info!("inherited TCP {:?}", spec.addr);
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => {
+                info!("binding TCP to {:?}", spec.addr);
+                bind_tcp(
+                    spec.addr,
+                    spec.bind.interface.as_deref(),
+                    spec.bind.transparent,
+                )
+                .await?
+            }
+        };
+
+        let local_addr = listener.local_addr()?;
+        info!("listening for {} on {:?}", spec.proto, local_addr);
+
+        let tasks = self.tasks.child();
+        #[cfg(unix)]
+        let fd = listener.as_raw_fd();
+
+        match (acceptor, cert) {
+            (Some(acceptor), Some(cert)) if spec.proto == ListenerProto::Https => {
+                // HTTP/3 is served over quic on the same port, advertised by Alt-Svc.
+                #[cfg(feature = "http3")]
+                let h3_port = (!spec.bind.tls.no_http3)
+                    .then(|| {
+                        cert.quic_server_config(&spec.bind.tls)
+                            .and_then(|config| {
+                                dns_https::spawn_h3_listener(
+                                    local_addr,
+                                    config,
+                                    spec.bind.tcp,
+                                    spec.message_limits,
+                                    handler.clone(),
+                                    &tasks,
+                                )
+                            })
+                            .map_err(|err| warn!("skip HTTP/3 on {:?}, {}", local_addr, err))
+                            .ok()
+                    })
+                    .flatten();
+                #[cfg(not(feature = "http3"))]
+                let h3_port = {
+                    let _ = cert;
+                    None
+                };
+
+                dns_https::spawn_listener(
+                    listener,
+                    spec.bind.tcp,
+                    acceptor,
+                    h3_port,
+                    spec.message_limits,
+                    handler,
+                    &tasks,
+                )
+            }
+            (Some(acceptor), _) => dns_tcp::spawn_tls_listener(
+                listener,
+                spec.bind.tcp,
+                acceptor,
+                spec.message_limits,
+                handler,
+                &tasks,
+            ),
+            (None, _) => dns_tcp::spawn_listener(
+                listener,
+                spec.bind.tcp,
+                spec.message_limits,
+                handler,
+                &tasks,
+            ),
+        }
+
+        Ok(Listener {
+            spec,
+            tasks,
+            #[cfg(unix)]
+            fd,
+            server: None,
+        })
+    }
+}
+
+/// Bind the udp socket, answering only on the network interface if any.
+async fn bind_udp(
+    addr: SocketAddr,
+    interface: Option<&str>,
+    reuse_port: bool,
+    transparent: bool,
+) -> io::Result<UdpSocket> {
+    let socket = if reuse_port {
+        bind_udp_reuse_port(addr)?
+    } else {
+        UdpSocket::bind(addr).await?
+    };
+    if let Some(name) = interface {
+        iface::bind_device(&socket, name)?;
+    }
+    if transparent {
+        iface::set_transparent(&socket, addr.is_ipv6())?;
+    }
+    Ok(socket)
+}
+
+/// Bind the udp socket with SO_REUSEPORT, so that the workers share the address.
+#[cfg(unix)]
+fn bind_udp_reuse_port(addr: SocketAddr) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(not(unix))]
+fn bind_udp_reuse_port(addr: SocketAddr) -> io::Result<UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("SO_REUSEPORT is not supported on this platform, {}", addr),
+    ))
+}
+
+/// Bind the tcp listener, accepting only on the network interface if any.
+async fn bind_tcp(
+    addr: SocketAddr,
+    interface: Option<&str>,
+    transparent: bool,
+) -> io::Result<TcpListener> {
+    let name = match interface {
+        Some(name) => name,
+        None => {
+            let listener = TcpListener::bind(addr).await?;
+            if transparent {
+                iface::set_transparent(&listener, addr.is_ipv6())?;
+            }
+            return Ok(listener);
+        }
+    };
+
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    iface::bind_device(&socket, name)?;
+    if transparent {
+        iface::set_transparent(&socket, addr.is_ipv6())?;
+    }
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
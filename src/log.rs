@@ -1,4 +1,12 @@
-use std::{env, fmt};
+use std::{
+    env, fmt, fs,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use once_cell::sync::OnceCell;
 
 use time::OffsetDateTime;
 use tracing::{Event, Subscriber};
@@ -6,26 +14,120 @@ use tracing_subscriber::{
     fmt::{format, FmtContext, FormatEvent, FormatFields, FormattedFields},
     prelude::__tracing_subscriber_SubscriberExt,
     registry::LookupSpan,
+    reload,
     util::SubscriberInitExt,
+    EnvFilter, Registry,
 };
 
+use crate::control::ControlServer;
+use crate::infra::mapped_file::MappedFile;
+
 pub use tracing::{debug, error, info, trace, warn};
 
+/// The file the logs are written to once configured, stdout until then.
+static LOG_FILE: Mutex<Option<MappedFile>> = Mutex::new(None);
+
+/// The filter of the levels, replaced by `smartdns log-level` while running.
+static FILTER: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
 pub fn logger(level: tracing::Level) {
     // Setup tracing for logging based on input
-    let filter = tracing_subscriber::EnvFilter::builder()
-        .with_default_directive(tracing::Level::WARN.into())
-        .parse(all_trust_dns(level))
-        .expect("failed to configure tracing/logging");
+    let (filter, handle) = reload::Layer::new(env_filter(level));
+    let _ = FILTER.set(handle);
 
-    let formatter = tracing_subscriber::fmt::layer().event_format(TdnsFormatter { level });
+    let formatter = tracing_subscriber::fmt::layer()
+        .event_format(TdnsFormatter { level })
+        .with_writer(|| LogWriter);
 
     tracing_subscriber::registry()
-        .with(formatter)
         .with(filter)
+        .with(formatter)
         .init();
 }
 
+fn env_filter(level: tracing::Level) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(tracing::Level::WARN.into())
+        .parse(all_trust_dns(level))
+        .expect("failed to configure tracing/logging")
+}
+
+/// Change the level of the running logger, e.g. to debug an intermittent failure without
+/// restarting.
+pub fn set_level(level: tracing::Level) -> Result<(), String> {
+    let handle = FILTER
+        .get()
+        .ok_or_else(|| "no logger to change".to_string())?;
+    handle
+        .reload(env_filter(level))
+        .map_err(|err| format!("change log level failed, {}", err))?;
+    info!("log level changed to {}", level);
+    Ok(())
+}
+
+/// Answer `log-level [level]` on the control socket, `log-level reset` for the level started
+/// with, see `smartdns log-level`.
+pub fn register_level_command(control: &ControlServer, default: tracing::Level) {
+    control.register("log-level", move |args| {
+        let level = match args {
+            "reset" => default,
+            level => tracing::Level::from_str(level).map_err(|err| err.to_string())?,
+        };
+        set_level(level)?;
+        Ok(format!("Log level changed to {}\n", level))
+    });
+}
+
+/// Write the logs to the file rather than stdout, rotated once it reaches the size, the last
+/// num ones kept, so that no logrotate is required, e.g. on the routers. Applied again on
+/// reload, the same file kept open, none back to stdout.
+pub fn log_to_file(path: Option<&Path>, size: u64, num: usize) {
+    if let Some(dir) = path.and_then(|path| path.parent()) {
+        if let Err(err) = fs::create_dir_all(dir) {
+            warn!("create log directory {:?} failed, {}", dir, err);
+        }
+    }
+
+    let mut log_file = LOG_FILE.lock().unwrap_or_else(|err| err.into_inner());
+    match (log_file.as_mut(), path) {
+        (Some(file), Some(path)) if file.path() == path => {
+            file.set_size(size);
+            file.set_num(Some(num));
+        }
+        (_, Some(path)) => *log_file = Some(MappedFile::open(path, size, Some(num))),
+        (_, None) => *log_file = None,
+    }
+}
+
+/// Reopen the log file, e.g. on SIGUSR1 once an external rotator moved it.
+pub fn reopen_log_file() {
+    let mut log_file = LOG_FILE.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(file) = log_file.as_mut() {
+        let _ = file.reopen();
+    }
+}
+
+/// Writes to the log file if configured, else stdout.
+struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut log_file = LOG_FILE.lock().unwrap_or_else(|err| err.into_inner());
+        match log_file.as_mut() {
+            Some(file) => file.write(buf),
+            None => io::stdout().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut log_file = LOG_FILE.lock().unwrap_or_else(|err| err.into_inner());
+        match log_file.as_mut() {
+            Some(file) => file.flush(),
+            None => io::stdout().flush(),
+        }
+    }
+}
+
 fn all_trust_dns(level: impl ToString) -> String {
     format!(
         "named={level},smartdns={level},{env}",
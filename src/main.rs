@@ -1,49 +1,196 @@
 #![allow(dead_code)]
 
 use cli::*;
-use std::{path::PathBuf, sync::Arc, time::Duration};
-use tokio::{
-    net::{TcpListener, UdpSocket},
-    runtime, signal,
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
+use tokio::{runtime, signal};
 
 mod cli;
-mod dns;
-mod dns_client;
-mod dns_conf;
-mod dns_mw;
-mod dns_mw_addr;
-mod dns_mw_audit;
-mod dns_mw_cache;
-mod dns_mw_ns;
-mod dns_mw_spdt;
-mod dns_mw_zone;
-mod dns_server;
-mod dns_url;
-mod fast_ping;
-mod infra;
-mod log;
-mod matcher;
-mod preset_ns;
+mod init;
+mod listeners;
 mod service;
-mod third_ext;
-
-use dns_mw::DnsMiddlewareBuilder;
-use dns_mw_addr::AddressMiddleware;
-use dns_mw_audit::DnsAuditMiddleware;
-use dns_mw_cache::DnsCacheMiddleware;
-use dns_mw_ns::NameServerMiddleware;
-use dns_mw_spdt::DnsSpeedTestMiddleware;
-use dns_mw_zone::DnsZoneMiddleware;
-use dns_server::{MiddlewareBasedRequestHandler, ServerFuture};
-use infra::middleware;
-use log::logger;
+mod upgrade;
 
-use crate::log::{debug, info};
-use crate::{
-    dns_client::DnsClient, dns_conf::SmartDnsConfig, matcher::DomainNameServerGroupMatcher,
+use smartdns::{
+    blocking, control, dns_mw, dns_server, domain_set, infra, log, rule_explain, third_ext,
+    upstream_stats,
 };
 
+use control::ControlServer;
+use dns_mw::DnsMiddlewareHandler;
+use dns_server::{MiddlewareBasedRequestHandler, ReloadableHandler};
+use infra::drain::Drain;
+use infra::tasks::BackgroundTasks;
+use listeners::Listeners;
+use log::logger;
+use smartdns::dns_conf::SmartDnsConfig;
+use smartdns::handler::{HandlerBuilder, Retained};
+
+use crate::log::{debug, error, info, warn};
+use crate::third_ext::FutureTimeoutExt;
+
+/// The middlewares of the configuration, their background tasks spawned into the group
+/// stopped once replaced on reload.
+fn build_handler(
+    cfg: SmartDnsConfig,
+    retained: &Retained,
+    control: &ControlServer,
+    tasks: &BackgroundTasks,
+) -> DnsMiddlewareHandler {
+    HandlerBuilder::new(cfg)
+        .with_retained(retained)
+        .with_control(control)
+        .with_tasks(tasks)
+        .build()
+}
+
+/// Reload the configuration on SIGHUP, or once its files changed, e.g. regenerated by the
+/// web ui of the router. The middlewares, the upstreams included, are built again and swapped
+/// at once, the cached answers kept, the queries in flight finishing with the previous ones.
+/// Only the listeners changed are bound again.
+struct Reloader {
+    conf_file: Option<PathBuf>,
+    /// the directives of the command line.
+    overrides: Vec<String>,
+    /// the modification times of the files loaded.
+    modified: Vec<(PathBuf, Option<SystemTime>)>,
+    handler: ReloadableHandler,
+    retained: Retained,
+    listeners: Listeners,
+    control: ControlServer,
+    tasks: BackgroundTasks,
+    /// the background tasks of the current middlewares.
+    generation: BackgroundTasks,
+    drain_timeout: Duration,
+}
+
+impl Reloader {
+    fn new(
+        cfg: &SmartDnsConfig,
+        handler: ReloadableHandler,
+        retained: Retained,
+        listeners: Listeners,
+        control: &ControlServer,
+        tasks: &BackgroundTasks,
+        generation: BackgroundTasks,
+    ) -> Self {
+        Self {
+            conf_file: cfg.conf_file.clone(),
+            overrides: cfg.overrides.clone(),
+            modified: modified(cfg),
+            handler,
+            retained,
+            listeners,
+            control: control.clone(),
+            tasks: tasks.clone(),
+            generation,
+            drain_timeout: cfg.drain_timeout(),
+        }
+    }
+
+    /// Whether any file of the configuration changed since loaded.
+    fn is_modified(&self) -> bool {
+        self.modified
+            .iter()
+            .any(|(path, modified)| modified_at(path) != *modified)
+    }
+
+    fn load(&self) -> Option<SmartDnsConfig> {
+        let conf_file = self.conf_file.as_ref()?;
+        info!("reloading configuration from: {:?}", conf_file);
+        SmartDnsConfig::try_load_with(Some(conf_file), &self.overrides)
+            .map_err(|err| error!("reload {:?} failed, {}", conf_file, err))
+            .ok()
+    }
+
+    /// Reload once the files changed, else bind the listeners failed to bind last time.
+    async fn watch(&mut self) {
+        if self.is_modified() {
+            self.reload().await;
+        } else if !self.listeners.is_synced() {
+            self.listeners.sync().await;
+        }
+    }
+
+    async fn reload(&mut self) {
+        let cfg = match self.load() {
+            Some(cfg) => cfg,
+            None => return,
+        };
+
+        // not retried until changed again, the listeners failed to bind are on the next watch.
+        self.modified = modified(&cfg);
+
+        self.retained.resize(&cfg).await;
+
+        log::log_to_file(cfg.log_file.as_deref(), cfg.log_size(), cfg.log_num());
+
+        // the listeners changed are bound again, the others answering on.
+        if !self.listeners.update(&cfg).await {
+            warn!(
+                "some listeners failed to bind, retried in {:?}",
+                CONF_WATCH_INTERVAL
+            );
+        }
+
+        self.drain_timeout = cfg.drain_timeout();
+
+        let generation = self.tasks.child();
+        self.handler.replace(build_handler(
+            cfg,
+            &self.retained,
+            &self.control,
+            &generation,
+        ));
+
+        // the previous middlewares stopped once the queries in flight are answered.
+        let previous = std::mem::replace(&mut self.generation, generation);
+        let drain_timeout = self.drain_timeout;
+        self.tasks.spawn(async move {
+            tokio::time::sleep(drain_timeout).await;
+            previous.shutdown().await;
+        });
+
+        info!("configuration reloaded");
+    }
+}
+
+fn modified(cfg: &SmartDnsConfig) -> Vec<(PathBuf, Option<SystemTime>)> {
+    cfg.conf_files
+        .iter()
+        .chain(cfg.dnsmasq_files.iter())
+        .map(|path| (path.clone(), modified_at(path)))
+        .collect()
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Start the new process with the listening sockets, and answer along with it for the drain
+/// timeout, false if it couldn't be started.
+#[cfg(unix)]
+async fn hand_over(
+    exe_path: &Path,
+    listeners: &[(upgrade::handover::ListenerKind, std::os::unix::io::RawFd)],
+    drain_timeout: Duration,
+) -> bool {
+    match upgrade::handover::spawn_successor(exe_path, listeners) {
+        Ok(pid) => {
+            info!("handed over listeners to {} (pid: {})", NAME, pid);
+            tokio::time::sleep(drain_timeout).await;
+            true
+        }
+        Err(err) => {
+            error!("failed to hand over listeners, {}", err);
+            false
+        }
+    }
+}
+
 fn banner() {
     info!("");
     info!(r#"     _____                      _       _____  _   _  _____ "#);
@@ -58,6 +205,12 @@ fn banner() {
 /// The app name
 const NAME: &'static str = "Smart-DNS";
 
+/// How often the configuration files are checked for changes.
+const CONF_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The time to wait for background tasks to stop.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
 /// The default configuration.
 const DEFAULT_CONF: &'static str = include_str!("../etc/smartdns/smartdns.conf");
 
@@ -83,9 +236,28 @@ fn main() -> windows_service::Result<()> {
 
 fn run_command(cli: Cli) {
     match cli.command {
-        Commands::Run { conf, debug } => {
-            run_server(conf, debug);
+        Commands::Run {
+            conf,
+            debug,
+            overrides,
+        } => {
+            run_server(conf, debug, overrides);
+        }
+        Commands::Init { profile } => {
+            print!(
+                "{}",
+                init::generate(profile.unwrap_or_else(init::Profile::detect))
+            )
         }
+        Commands::Check { conf } => check_config(conf),
+        Commands::Config {
+            command:
+                ConfigCommands::Dump {
+                    conf,
+                    json,
+                    overrides,
+                },
+        } => dump_config(conf, json, overrides),
         Commands::Service {
             command: service_command,
         } => {
@@ -100,19 +272,227 @@ fn run_command(cli: Cli) {
                 Status => status(),
             }
         }
+        Commands::Upgrade {
+            version,
+            pid_file,
+            conf,
+        } => {
+            let pid_file = pid_file.unwrap_or_else(|| SmartDnsConfig::load(conf).pid_file());
+            if let Err(err) = upgrade::upgrade(version, pid_file) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        Commands::Blocking { command } => match command {
+            BlockingCommands::Pause {
+                client,
+                domain,
+                duration,
+                conf,
+            } => {
+                let domain = domain.map(|d| {
+                    let mut d = d
+                        .parse::<trust_dns_client::rr::domain::Name>()
+                        .unwrap_or_else(|e| {
+                            eprintln!("Invalid domain {}, {}", d, e);
+                            std::process::exit(1);
+                        });
+                    d.set_fqdn(true);
+                    d.into()
+                });
+                let o = blocking::BlockingOverride::new(client, domain, duration);
+                control_command(conf, &format!("blocking-pause {}", o))
+            }
+            BlockingCommands::Resume { client, conf } => {
+                let command = match client {
+                    Some(client) => format!("blocking-resume {}", client),
+                    None => "blocking-resume".to_string(),
+                };
+                control_command(conf, &command)
+            }
+        },
+        Commands::LogLevel { level, reset, conf } => {
+            let command = match level.filter(|_| !reset) {
+                Some(level) => format!("log-level {}", level),
+                None => "log-level reset".to_string(),
+            };
+            control_command(conf, &command)
+        }
+        Commands::Status { conf } => control_command(conf, "status"),
+        Commands::Upstream {
+            command: UpstreamCommands::Stats,
+        } => upstream_stats::print(PathBuf::from(upstream_stats::STATS_FILE)),
+        Commands::Stats { command } => print_stats(command),
+        Commands::Rules {
+            command:
+                RulesCommands::Explain {
+                    domain,
+                    record_type,
+                    client,
+                    conf,
+                },
+        } => {
+            let cfg = SmartDnsConfig::load(conf);
+            println!("{} {}", domain, record_type);
+            for explanation in rule_explain::explain(&cfg, &domain, record_type, client) {
+                println!("  {}", explanation);
+            }
+        }
+        Commands::Rules {
+            command: RulesCommands::Compile { input, output },
+        } => {
+            let output = output.unwrap_or_else(|| input.with_extension("bin"));
+            if output == input {
+                eprintln!("The output would overwrite {}", input.display());
+                std::process::exit(1);
+            }
+            match domain_set::compile(&input, &output) {
+                Ok(count) => println!("Compiled {} domains into {}", count, output.display()),
+                Err(err) => {
+                    eprintln!("Compile {} failed, {}", input.display(), err);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn print_stats(command: StatsCommands) {
+    use smartdns::audit_db::{self, TopOf};
+
+    let (of, args) = match command {
+        StatsCommands::TopDomains(args) => (TopOf::Domains, args),
+        StatsCommands::TopClients(args) => (TopOf::Clients, args),
+    };
+    let cfg = SmartDnsConfig::load(args.conf);
+    if let Err(err) = audit_db::print_top(cfg.audit_file(), of, args.since, args.limit) {
+        eprintln!("{}", err);
+        std::process::exit(1);
     }
 }
 
-fn run_server(conf: Option<PathBuf>, debug: bool) {
-    logger(if debug {
+#[cfg(not(feature = "sqlite"))]
+fn print_stats(_: StatsCommands) {
+    eprintln!("The statistics are not available, built without the sqlite feature");
+    std::process::exit(1);
+}
+
+/// Send the command to the running server of the configuration, exiting with 1 if it failed or
+/// no server answered.
+fn control_command(conf: Option<PathBuf>, command: &str) {
+    let path = SmartDnsConfig::load(conf).control_socket();
+
+    match control::request(&path, command) {
+        Ok(Ok(answer)) => print!("{}", answer),
+        Ok(Err(err)) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("No server answering on {}, {}", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Print the errors of the configuration, exiting with 1 if any.
+fn check_config(conf: Option<PathBuf>) {
+    let path = match conf.or_else(SmartDnsConfig::find_conf_file) {
+        Some(path) => path,
+        None => {
+            eprintln!("No configuration file found");
+            std::process::exit(1);
+        }
+    };
+
+    let cfg = match SmartDnsConfig::try_load_from_file(&path) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            eprintln!("{}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    let diagnostics = cfg
+        .diagnostics
+        .iter()
+        .cloned()
+        .chain(cfg.check())
+        .collect::<Vec<_>>();
+
+    for diagnostic in diagnostics.iter() {
+        eprintln!("{}", diagnostic);
+    }
+
+    if diagnostics.is_empty() {
+        println!("configuration ok");
+    } else {
+        eprintln!("{} error(s) found in configuration", diagnostics.len());
+        std::process::exit(1);
+    }
+}
+
+/// Print the settings in effect, then the directives applied in order, located by file and line.
+fn dump_config(conf: Option<PathBuf>, json: bool, overrides: ConfOverrides) {
+    let cfg = SmartDnsConfig::load_with(conf, &overrides.directives());
+    let settings = cfg.effective_settings();
+
+    if json {
+        let value = serde_json::json!({
+            "settings": settings
+                .iter()
+                .map(|(name, value)| serde_json::json!({ "name": name, "value": value }))
+                .collect::<Vec<_>>(),
+            "directives": cfg
+                .directives
+                .iter()
+                .map(|directive| {
+                    serde_json::json!({
+                        "file": directive.file.as_ref().map(|file| file.display().to_string()),
+                        "line": directive.line,
+                        "name": directive.name,
+                        "options": directive.options,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&value).unwrap());
+        return;
+    }
+
+    println!("# the settings in effect, the defaults included.");
+    for (name, value) in settings {
+        println!("{} {}", name, value);
+    }
+
+    println!();
+    println!("# the directives applied, in order.");
+    for directive in cfg.directives.iter() {
+        match directive.file.as_ref() {
+            Some(file) => println!("{}  # {}:{}", directive, file.display(), directive.line),
+            None => println!("{}  # command line", directive),
+        }
+    }
+}
+
+fn run_server(conf: Option<PathBuf>, debug: bool, overrides: ConfOverrides) {
+    let log_level = if debug {
         tracing::Level::DEBUG
     } else {
-        tracing::Level::INFO
-    });
+        overrides.log_level.unwrap_or(tracing::Level::INFO)
+    };
+    logger(log_level);
 
     info!("Smart-DNS 🐋 {} starting", version());
 
-    let cfg = SmartDnsConfig::load(conf);
+    // remember the executable path before it gets replaced by upgrading.
+    #[cfg(unix)]
+    let exe_path = std::env::current_exe().expect("failed to get current exe path");
+
+    let cfg = SmartDnsConfig::load_with(conf, &overrides.directives());
+
+    log::log_to_file(cfg.log_file.as_deref(), cfg.log_size(), cfg.log_num());
 
     info!(r#"whoami 👉 "{}""#, cfg.server_name);
 
@@ -128,93 +508,77 @@ fn run_server(conf: Option<PathBuf>, debug: bool) {
 
     let runtime = runtime::Builder::new_multi_thread()
         .enable_all()
-        .worker_threads(4)
+        .worker_threads(cfg.num_workers().max(4))
         .thread_name("smartdns-runtime")
         .build()
         .expect("failed to initialize Tokio Runtime");
 
-    let udp_socket_addrs = cfg.binds.clone().into_iter().map(|s| s.addr).flatten();
-    let tcp_socket_addrs = cfg.binds_tcp.clone().into_iter().map(|s| s.addr).flatten();
-
-    // build handle pipeline.
-    let middleware = {
-        let _guard = runtime.enter();
-        let dns_client = Arc::new(DnsClient::new(
-            DomainNameServerGroupMatcher::create(&cfg),
-            cfg.servers.clone(),
-            Default::default(),
-        ));
-
-        let mut middleware_builder = DnsMiddlewareBuilder::new();
-
-        // check if audit enabled.
-        if cfg.audit_enable && cfg.audit_file.is_some() {
-            middleware_builder = middleware_builder.with(DnsAuditMiddleware::new(
-                cfg.audit_file.as_ref().unwrap(),
-                cfg.audit_size(),
-                cfg.audit_num(),
-            ));
-        }
+    if !cfg!(unix) && cfg.num_workers() > 1 {
+        warn!("num-workers is not supported on this platform");
+    }
 
-        middleware_builder = middleware_builder.with(DnsZoneMiddleware);
+    // the background tasks of all subsystems, stopped on shutdown.
+    let tasks = BackgroundTasks::new();
 
-        if cfg.address_rules.len() > 0 {
-            middleware_builder = middleware_builder.with(AddressMiddleware::new(&cfg));
+    // the commands of the cli, e.g. `smartdns log-level debug`.
+    let control = ControlServer::new();
+    log::register_level_command(&control, log_level);
+    control.register_status("messages", || dns_server::DROPPED_MESSAGES.to_string());
+    #[cfg(unix)]
+    {
+        let _guard = runtime.enter();
+        let path = cfg.control_socket();
+        if let Err(err) = control.spawn(&path, &tasks) {
+            warn!("listen on control socket {:?} failed, {}", path, err);
         }
+    }
 
-        // check if cache enabled.
-        if cfg.cache_size() > 0 {
-            middleware_builder =
-                middleware_builder.with(DnsCacheMiddleware::new(&cfg, dns_client.clone()));
-        }
+    // the queries in flight, finished before stopping.
+    let drain = Arc::new(Drain::new());
 
-        // check if speed_check enabled.
-        if !cfg.speed_check_mode.is_empty() {
-            middleware_builder = middleware_builder.with(DnsSpeedTestMiddleware);
-        }
+    // the cached answers and the blocking paused, kept over the reloads.
+    let retained = Retained::new(&cfg);
+    retained.register_commands(&control);
 
-        middleware_builder = middleware_builder.with(NameServerMiddleware::new(&cfg));
+    // the background tasks of the middlewares, replaced on reload.
+    let generation = tasks.child();
 
-        MiddlewareBasedRequestHandler::new(middleware_builder.build(cfg, dns_client.clone()))
+    // build handle pipeline.
+    let middleware = {
+        let _guard = runtime.enter();
+        MiddlewareBasedRequestHandler::new(build_handler(
+            cfg.clone(),
+            &retained,
+            &control,
+            &generation,
+        ))
+        .with_drain(drain.clone())
     };
 
-    let mut server = ServerFuture::new(middleware);
-
-    // load udp the listeners
-    for udp_socket in udp_socket_addrs {
-        debug!("binding UDP to {:?}", udp_socket);
-        let udp_socket = runtime
-            .block_on(UdpSocket::bind(udp_socket))
-            .unwrap_or_else(|_| panic!("could not bind to udp: {}", udp_socket));
-
-        info!(
-            "listening for UDP on {:?}",
-            udp_socket
-                .local_addr()
-                .expect("could not lookup local address")
-        );
-
-        let _guard = runtime.enter();
-        server.register_socket(udp_socket);
+    // the listeners of the configuration, bound again on reload only if changed.
+    let mut listeners = Listeners::new(middleware.clone(), &tasks);
+    if !runtime.block_on(listeners.update(&cfg)) {
+        panic!("could not bind the listeners");
     }
 
-    // and TCP as necessary
-    for tcp_listener in tcp_socket_addrs {
-        info!("binding TCP to {:?}", tcp_listener);
-        let tcp_listener = runtime
-            .block_on(TcpListener::bind(tcp_listener))
-            .unwrap_or_else(|_| panic!("could not bind to tcp: {}", tcp_listener));
-
-        info!(
-            "listening for TCP on {:?}",
-            tcp_listener
-                .local_addr()
-                .expect("could not lookup local address")
-        );
-
-        let _guard = runtime.enter();
-        server.register_listener(tcp_listener, Duration::from_secs(5));
-    }
+    let mut reloader = Reloader::new(
+        &cfg,
+        middleware.reloadable(),
+        retained,
+        listeners,
+        &control,
+        &tasks,
+        generation,
+    );
+
+    #[cfg(unix)]
+    let pid_file = {
+        let pid_file = cfg.pid_file();
+        if let Err(err) = upgrade::write_pid_file(pid_file.as_path()) {
+            warn!("write pid file {:?} failed, {}", pid_file, err);
+        }
+        pid_file
+    };
 
     // config complete, starting!
 
@@ -225,10 +589,76 @@ fn run_server(conf: Option<PathBuf>, debug: bool) {
     info!("Server starting up");
 
     runtime.block_on(async {
-        signal::ctrl_c().await.unwrap();
+        #[cfg(unix)]
+        {
+            use signal::unix::{signal as unix_signal, SignalKind};
+            let mut upgrade_signal =
+                unix_signal(SignalKind::user_defined2()).expect("failed to listen SIGUSR2");
+            let mut terminate_signal =
+                unix_signal(SignalKind::terminate()).expect("failed to listen SIGTERM");
+            let mut reload_signal =
+                unix_signal(SignalKind::hangup()).expect("failed to listen SIGHUP");
+            let mut reopen_signal =
+                unix_signal(SignalKind::user_defined1()).expect("failed to listen SIGUSR1");
+            let mut watch = tokio::time::interval(CONF_WATCH_INTERVAL);
+
+            loop {
+                tokio::select! {
+                    _ = signal::ctrl_c() => break,
+                    _ = terminate_signal.recv() => break,
+                    _ = upgrade_signal.recv() => {
+                        // the binary has been replaced, start the new one with our sockets.
+                        let listeners = reloader.listeners.raw_fds();
+                        let drain_timeout = reloader.drain_timeout;
+                        if !hand_over(exe_path.as_path(), &listeners, drain_timeout).await {
+                            signal::ctrl_c().await.unwrap();
+                        }
+                        break;
+                    }
+                    _ = reload_signal.recv() => reloader.reload().await,
+                    _ = reopen_signal.recv() => {
+                        // the log file moved by an external rotator, e.g. logrotate.
+                        log::reopen_log_file();
+                    }
+                    _ = watch.tick() => reloader.watch().await,
+                }
+            }
+        }
+
+        #[cfg(not(unix))]
+        {
+            let mut watch = tokio::time::interval(CONF_WATCH_INTERVAL);
+
+            loop {
+                tokio::select! {
+                    _ = signal::ctrl_c() => break,
+                    _ = watch.tick() => reloader.watch().await,
+                }
+            }
+        }
+
+        // turn away the new queries, and let the in-flight ones finish.
+        let drain_timeout = reloader.drain_timeout;
+        info!("draining {} queries in flight", drain.inflight());
+        if drain.drain().timeout(drain_timeout).await.is_err() {
+            warn!(
+                "{} queries still in flight after {:?}",
+                drain.inflight(),
+                drain_timeout
+            );
+        }
+
+        // stop the background tasks.
+        if tasks.shutdown().timeout(SHUTDOWN_TIMEOUT).await.is_err() {
+            warn!("background tasks did not stop in {:?}", SHUTDOWN_TIMEOUT);
+        }
+
         // we're exiting for some reason...
         info!("{} {} shutdown", NAME, version());
     });
 
+    #[cfg(unix)]
+    upgrade::remove_pid_file(pid_file.as_path());
+
     drop(runtime);
 }
@@ -1,17 +1,86 @@
-use crate::dns_conf::{DomainAddress, DomainOrDomainSet, SmartDnsConfig};
+use crate::dns_conf::{
+    AddressRuleItem, DomainAddress, DomainOrDomainSet, DomainRule, DomainSets, FamilySets,
+    ForceTransport, ForwardRuleItem, HttpsRecordRule, NftSet, RecordTypeFilter, SmartDnsConfig,
+};
+use crate::domain_set::DomainSet;
+use crate::log::error;
+use regex::{RegexSet, RegexSetBuilder};
 use std::collections::HashMap;
 use std::fmt::Debug;
-use trust_dns_client::rr::LowerName;
+use std::sync::Arc;
+use std::time::Duration;
+use trust_dns_client::rr::{LowerName, Name};
 
-#[derive(Debug, Default)]
-pub struct DomainMatcher<T: Debug>(HashMap<LowerName, T>);
+#[derive(Debug)]
+pub struct DomainMatcher<T: Debug> {
+    domains: HashMap<LowerName, T>,
+    full: HashMap<LowerName, T>,
+    /// the domain sets referenced, shared with the config rather than copied into each matcher.
+    sets: Vec<(Arc<DomainSet>, T)>,
+    /// the keywords and regular expressions of the rules and the sets, matched all at once.
+    patterns: RegexSet,
+    pattern_values: Vec<T>,
+}
+
+impl<T: Debug> Default for DomainMatcher<T> {
+    fn default() -> Self {
+        Self {
+            domains: Default::default(),
+            full: Default::default(),
+            sets: Default::default(),
+            patterns: RegexSet::empty(),
+            pattern_values: Default::default(),
+        }
+    }
+}
 
 impl<T: Debug> DomainMatcher<T> {
+    /// The most specific rule: the full names, then the domains and their subdomains,
+    /// the domain rules over the domain sets of the same level, then the patterns.
     pub fn find(&self, domain: &LowerName) -> Option<&T> {
+        if let Some(v) = self.full.get(domain) {
+            return Some(v);
+        }
+        if let Some((_, v)) = self
+            .sets
+            .iter()
+            .rev()
+            .find(|(set, _)| set.contains_full(domain))
+        {
+            return Some(v);
+        }
+
+        if let Some(v) = self.find_domain(domain) {
+            return Some(v);
+        }
+
+        if self.pattern_values.is_empty() {
+            return None;
+        }
+
+        // the later rules override the earlier ones.
+        let name = domain.to_string();
+        self.patterns
+            .matches(name.trim_end_matches('.'))
+            .iter()
+            .last()
+            .map(|idx| &self.pattern_values[idx])
+    }
+
+    fn find_domain(&self, domain: &LowerName) -> Option<&T> {
         let mut domain = domain.to_owned();
 
         loop {
-            if let Some(v) = self.0.get(&domain) {
+            if let Some(v) = self.domains.get(&domain) {
+                return Some(v);
+            }
+            // the later rules override the earlier ones.
+            if let Some((_, v)) = self
+                .sets
+                .iter()
+                .rev()
+                .find(|(set, _)| set.contains_domain(&domain))
+            {
                 return Some(v);
             }
             if domain.is_root() {
@@ -23,33 +92,151 @@ impl<T: Debug> DomainMatcher<T> {
 
         None
     }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+            && self.full.is_empty()
+            && self.sets.is_empty()
+            && self.pattern_values.is_empty()
+    }
 }
 
-pub type DomainAddressMatcher = DomainMatcher<DomainAddress>;
+impl<T: Debug + Clone> DomainMatcher<T> {
+    /// The matcher of the rules, without the patterns if they failed to compile, which is told
+    /// on loading the config, see `check_patterns`.
+    fn from_domains<'a, I>(rules: I, domain_sets: &DomainSets) -> Self
+    where
+        I: IntoIterator<Item = (&'a DomainOrDomainSet, T)>,
+    {
+        Self::try_from_domains(rules, domain_sets).unwrap_or_else(|(mut matcher, err)| {
+            error!("compile domain patterns failed, {}", err);
+            matcher.pattern_values.clear();
+            matcher
+        })
+    }
 
-impl DomainMatcher<DomainAddress> {
-    pub fn create(cfg: &SmartDnsConfig) -> DomainMatcher<DomainAddress> {
-        let mut keys = vec![];
-        let mut values = vec![];
+    fn try_from_domains<'a, I>(rules: I, domain_sets: &DomainSets) -> Result<Self, (Self, String)>
+    where
+        I: IntoIterator<Item = (&'a DomainOrDomainSet, T)>,
+    {
+        let mut matcher = Self::default();
+        let mut patterns = vec![];
 
-        for rule in cfg.address_rules.iter() {
-            match &rule.domain {
+        for (domain, value) in rules {
+            match domain {
                 DomainOrDomainSet::Domain(domain) => {
-                    keys.push(domain.to_owned());
-                    values.push(rule.address);
+                    matcher.domains.insert(domain.to_owned(), value);
+                }
+                DomainOrDomainSet::Full(domain) => {
+                    matcher.full.insert(domain.to_owned(), value);
+                }
+                DomainOrDomainSet::Keyword(keyword) => {
+                    patterns.push(regex::escape(keyword));
+                    matcher.pattern_values.push(value);
+                }
+                DomainOrDomainSet::Regex(regex) => {
+                    patterns.push(regex.to_owned());
+                    matcher.pattern_values.push(value);
                 }
                 DomainOrDomainSet::DomainSet(set_name) => {
-                    if let Some(set) = cfg.domain_sets.get(set_name) {
-                        for domain in set.iter() {
-                            keys.push(domain.to_owned());
-                            values.push(rule.address);
+                    if let Some(set) = domain_sets.get(set_name) {
+                        for keyword in set.keywords.iter() {
+                            patterns.push(regex::escape(keyword));
+                            matcher.pattern_values.push(value.clone());
+                        }
+                        for regex in set.regexes.iter() {
+                            patterns.push(regex.to_owned());
+                            matcher.pattern_values.push(value.clone());
                         }
+                        matcher.sets.push((set.clone(), value));
                     }
                 }
             }
         }
 
-        DomainMatcher(create_map(keys, values))
+        if !patterns.is_empty() {
+            match RegexSetBuilder::new(patterns)
+                .case_insensitive(true)
+                .build()
+            {
+                Ok(set) => matcher.patterns = set,
+                // each one is valid, but together they may exceed the size limit.
+                Err(err) => return Err((matcher, err.to_string())),
+            }
+        }
+
+        Ok(matcher)
+    }
+}
+
+/// The keywords and regular expressions of each kind of the rules compiled as the matchers do,
+/// the ones failed told, e.g. beyond the size limit of the set together.
+pub fn check_patterns(cfg: &SmartDnsConfig) -> Vec<String> {
+    fn domains<'a, R, F>(rules: &'a [R], f: F) -> Vec<&'a DomainOrDomainSet>
+    where
+        F: Fn(&'a R) -> &'a DomainOrDomainSet,
+    {
+        rules.iter().map(f).collect()
+    }
+
+    let mut kinds = vec![
+        ("address", domains(&cfg.address_rules, |r| &r.domain)),
+        ("nameserver", domains(&cfg.forward_rules, |r| &r.domain)),
+        ("cname", domains(&cfg.cname_rules, |r| &r.domain)),
+        (
+            "https-record",
+            domains(&cfg.https_record_rules, |r| &r.domain),
+        ),
+        ("query type", domains(&cfg.query_type_rules, |r| &r.domain)),
+        ("ipset", domains(&cfg.ipset_rules, |r| &r.domain)),
+        ("nftset", domains(&cfg.nftset_rules, |r| &r.domain)),
+        ("domain-rules", domains(&cfg.domain_rules, |r| &r.domain)),
+    ];
+    for group in cfg.conf_groups.values() {
+        kinds.push(("address", domains(&group.address_rules, |r| &r.domain)));
+        kinds.push(("nameserver", domains(&group.forward_rules, |r| &r.domain)));
+    }
+
+    let block_sets = cfg
+        .block_sets()
+        .map(|name| DomainOrDomainSet::DomainSet(name.to_owned()))
+        .collect::<Vec<_>>();
+    kinds.extend(block_sets.iter().map(|set| ("block set", vec![set])));
+
+    kinds
+        .into_iter()
+        .filter_map(|(kind, domains)| {
+            DomainMatcher::<()>::try_from_domains(
+                domains.into_iter().map(|domain| (domain, ())),
+                &cfg.domain_sets,
+            )
+            .err()
+            .map(|(_, err)| {
+                format!(
+                    "the patterns of the {} rules failed to compile, {}",
+                    kind, err
+                )
+            })
+        })
+        .collect()
+}
+
+pub type DomainAddressMatcher = DomainMatcher<DomainAddress>;
+
+impl DomainMatcher<DomainAddress> {
+    pub fn create(cfg: &SmartDnsConfig) -> DomainMatcher<DomainAddress> {
+        Self::from_rules(&cfg.address_rules, &cfg.domain_sets)
+    }
+
+    pub fn from_rules(
+        rules: &[AddressRuleItem],
+        domain_sets: &DomainSets,
+    ) -> DomainMatcher<DomainAddress> {
+        Self::from_domains(
+            rules.iter().map(|rule| (&rule.domain, rule.address)),
+            domain_sets,
+        )
     }
 }
 
@@ -57,33 +244,313 @@ pub type DomainNameServerGroupMatcher = DomainMatcher<String>;
 
 impl DomainMatcher<String> {
     pub fn create(cfg: &SmartDnsConfig) -> DomainMatcher<String> {
-        let mut keys = vec![];
-        let mut values = vec![];
+        Self::from_rules(&cfg.forward_rules, &cfg.domain_sets)
+    }
 
-        for rule in cfg.forward_rules.iter() {
-            match &rule.domain {
-                DomainOrDomainSet::Domain(domain) => {
-                    keys.push(domain.to_owned());
-                    values.push(rule.server_group.to_owned());
-                }
-                DomainOrDomainSet::DomainSet(set_name) => {
-                    if let Some(set) = cfg.domain_sets.get(set_name) {
-                        for domain in set.iter() {
-                            keys.push(domain.to_owned());
-                            values.push(rule.server_group.to_owned());
-                        }
-                    }
-                }
-            }
-        }
-        DomainMatcher(create_map(keys, values))
+    pub fn from_rules(
+        rules: &[ForwardRuleItem],
+        domain_sets: &DomainSets,
+    ) -> DomainMatcher<String> {
+        Self::from_domains(
+            rules.iter().map(|rule| {
+                // "-" means ignoring the rule, that's using the default group.
+                let server_group = match rule.server_group.as_str() {
+                    "-" => "default".to_string(),
+                    group => group.to_string(),
+                };
+                (&rule.domain, server_group)
+            }),
+            domain_sets,
+        )
+    }
+}
+
+pub type DomainCNameMatcher = DomainMatcher<Name>;
+
+impl DomainMatcher<Name> {
+    pub fn create(cfg: &SmartDnsConfig) -> DomainMatcher<Name> {
+        Self::from_domains(
+            cfg.cname_rules
+                .iter()
+                .map(|rule| (&rule.domain, rule.cname.clone())),
+            &cfg.domain_sets,
+        )
     }
 }
 
-fn create_map<K: std::hash::Hash + std::cmp::Eq, V>(keys: Vec<K>, values: Vec<V>) -> HashMap<K, V> {
-    let mut map = HashMap::new();
-    for (k, v) in keys.into_iter().zip(values) {
-        map.insert(k, v);
+pub type DomainHttpsRecordMatcher = DomainMatcher<HttpsRecordRule>;
+
+impl DomainMatcher<HttpsRecordRule> {
+    pub fn create(cfg: &SmartDnsConfig) -> DomainMatcher<HttpsRecordRule> {
+        Self::from_domains(
+            cfg.https_record_rules
+                .iter()
+                .map(|rule| (&rule.domain, rule.rule)),
+            &cfg.domain_sets,
+        )
+    }
+}
+
+pub type DomainQueryTypeMatcher = DomainMatcher<RecordTypeFilter>;
+
+impl DomainMatcher<RecordTypeFilter> {
+    pub fn create(cfg: &SmartDnsConfig) -> DomainMatcher<RecordTypeFilter> {
+        Self::from_domains(
+            cfg.query_type_rules
+                .iter()
+                .map(|rule| (&rule.domain, rule.types.clone())),
+            &cfg.domain_sets,
+        )
+    }
+}
+
+pub type DomainIpSetMatcher = DomainMatcher<FamilySets<String>>;
+
+impl DomainMatcher<FamilySets<String>> {
+    pub fn create(cfg: &SmartDnsConfig) -> DomainMatcher<FamilySets<String>> {
+        Self::from_domains(
+            cfg.ipset_rules
+                .iter()
+                .map(|rule| (&rule.domain, rule.sets.clone())),
+            &cfg.domain_sets,
+        )
+    }
+}
+
+pub type DomainNftSetMatcher = DomainMatcher<FamilySets<NftSet>>;
+
+impl DomainMatcher<FamilySets<NftSet>> {
+    pub fn create(cfg: &SmartDnsConfig) -> DomainMatcher<FamilySets<NftSet>> {
+        Self::from_domains(
+            cfg.nftset_rules
+                .iter()
+                .map(|rule| (&rule.domain, rule.sets.clone())),
+            &cfg.domain_sets,
+        )
+    }
+}
+
+pub type DomainForceTransportMatcher = DomainMatcher<ForceTransport>;
+
+impl DomainMatcher<ForceTransport> {
+    pub fn create(cfg: &SmartDnsConfig) -> DomainMatcher<ForceTransport> {
+        Self::from_domains(
+            cfg.forward_rules
+                .iter()
+                .filter_map(|rule| rule.force_transport.map(|t| (&rule.domain, t))),
+            &cfg.domain_sets,
+        )
+    }
+}
+
+pub type DomainPinResultMatcher = DomainMatcher<Duration>;
+
+impl DomainMatcher<Duration> {
+    pub fn create(cfg: &SmartDnsConfig) -> DomainMatcher<Duration> {
+        Self::from_domains(
+            cfg.forward_rules
+                .iter()
+                .filter_map(|rule| rule.pin_result.map(|p| (&rule.domain, p))),
+            &cfg.domain_sets,
+        )
+    }
+}
+
+/// The index of the rule matching, by the same precedence as the other matchers, to tell which
+/// one of the rules applies, see `smartdns rules explain`.
+pub type DomainRuleIndexMatcher = DomainMatcher<usize>;
+
+impl DomainMatcher<usize> {
+    pub fn from_indexes<'a, I>(domains: I, domain_sets: &DomainSets) -> DomainMatcher<usize>
+    where
+        I: IntoIterator<Item = &'a DomainOrDomainSet>,
+    {
+        Self::from_domains(
+            domains
+                .into_iter()
+                .enumerate()
+                .map(|(i, domain)| (domain, i)),
+            domain_sets,
+        )
+    }
+}
+
+pub type DomainRuleMatcher = DomainMatcher<Arc<DomainRule>>;
+
+impl DomainMatcher<Arc<DomainRule>> {
+    pub fn create(cfg: &SmartDnsConfig) -> DomainMatcher<Arc<DomainRule>> {
+        Self::from_domains(
+            cfg.domain_rules
+                .iter()
+                .map(|rule| (&rule.domain, Arc::new(rule.rule.clone()))),
+            &cfg.domain_sets,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_resolver::Name;
+
+    use super::*;
+
+    fn name(s: &str) -> LowerName {
+        Name::from_str(s).unwrap().into()
+    }
+
+    #[test]
+    fn test_nameserver_group_matcher() {
+        let mut cfg = SmartDnsConfig::new();
+        cfg.forward_rules.push(ForwardRuleItem {
+            domain: DomainOrDomainSet::from_str("corp.com").unwrap(),
+            server_group: "office".to_string(),
+            force_transport: None,
+            pin_result: None,
+        });
+        cfg.forward_rules.push(ForwardRuleItem {
+            domain: DomainOrDomainSet::from_str("www.corp.com").unwrap(),
+            server_group: "-".to_string(),
+            force_transport: Some(ForceTransport::Tcp),
+            pin_result: None,
+        });
+
+        let matcher = DomainNameServerGroupMatcher::create(&cfg);
+
+        assert_eq!(
+            matcher.find(&name("corp.com.")),
+            Some(&"office".to_string())
+        );
+        assert_eq!(
+            matcher.find(&name("mail.corp.com.")),
+            Some(&"office".to_string())
+        );
+        assert_eq!(
+            matcher.find(&name("www.corp.com.")),
+            Some(&"default".to_string())
+        );
+        assert_eq!(matcher.find(&name("example.com.")), None);
+
+        let matcher = DomainForceTransportMatcher::create(&cfg);
+
+        assert_eq!(
+            matcher.find(&name("www.corp.com.")),
+            Some(&ForceTransport::Tcp)
+        );
+        assert_eq!(matcher.find(&name("mail.corp.com.")), None);
+    }
+
+    #[test]
+    fn test_domain_set_matcher() {
+        let mut cfg = SmartDnsConfig::new();
+        let mut ads = DomainSet::default();
+        ads.insert("ads.com");
+        ads.insert("tracker.net");
+        cfg.domain_sets.insert("ads".to_string(), Arc::new(ads));
+        cfg.address_rules.push(AddressRuleItem {
+            domain: DomainOrDomainSet::from_str("domain-set:ads").unwrap(),
+            address: DomainAddress::SOA,
+        });
+        cfg.address_rules.push(AddressRuleItem {
+            domain: DomainOrDomainSet::from_str("cdn.ads.com").unwrap(),
+            address: DomainAddress::IGN,
+        });
+        cfg.address_rules.push(AddressRuleItem {
+            domain: DomainOrDomainSet::from_str("domain-set:missing").unwrap(),
+            address: DomainAddress::SOA,
+        });
+
+        let matcher = DomainAddressMatcher::create(&cfg);
+
+        assert_eq!(
+            matcher.find(&name("x.tracker.net.")),
+            Some(&DomainAddress::SOA)
+        );
+        assert_eq!(
+            matcher.find(&name("img.cdn.ads.com.")),
+            Some(&DomainAddress::IGN)
+        );
+        assert_eq!(matcher.find(&name("example.com.")), None);
+
+        // shared with the config, not copied.
+        assert_eq!(Arc::strong_count(&cfg.domain_sets["ads"]), 2);
+    }
+
+    #[test]
+    fn test_domain_pattern_matcher() {
+        let mut cfg = SmartDnsConfig::new();
+        let mut set = DomainSet::default();
+        set.insert("keyword:tracker");
+        set.insert("full:ads.net");
+        cfg.domain_sets.insert("ads".to_string(), Arc::new(set));
+
+        for (domain, address) in [
+            ("domain-set:ads", DomainAddress::SOA),
+            ("regexp:^ad[0-9]+\\.", DomainAddress::SOA),
+            ("full:example.com", DomainAddress::IGN),
+            ("example.com", DomainAddress::SOAv6),
+        ] {
+            cfg.address_rules.push(AddressRuleItem {
+                domain: DomainOrDomainSet::from_str(domain).unwrap(),
+                address,
+            });
+        }
+
+        let matcher = DomainAddressMatcher::create(&cfg);
+
+        assert_eq!(
+            matcher.find(&name("AD12.example.org.")),
+            Some(&DomainAddress::SOA)
+        );
+        assert_eq!(matcher.find(&name("bad1.example.org.")), None);
+        assert_eq!(
+            matcher.find(&name("cdn.tracker.example.org.")),
+            Some(&DomainAddress::SOA)
+        );
+        assert_eq!(matcher.find(&name("ads.net.")), Some(&DomainAddress::SOA));
+        assert_eq!(matcher.find(&name("www.ads.net.")), None);
+        assert_eq!(
+            matcher.find(&name("example.com.")),
+            Some(&DomainAddress::IGN)
+        );
+        assert_eq!(
+            matcher.find(&name("www.example.com.")),
+            Some(&DomainAddress::SOAv6)
+        );
+        // the domain rules are more specific than the patterns.
+        assert_eq!(
+            matcher.find(&name("ad1.example.com.")),
+            Some(&DomainAddress::SOAv6)
+        );
+    }
+
+    #[test]
+    fn test_check_patterns() {
+        let mut cfg = SmartDnsConfig::new();
+        cfg.address_rules.push(AddressRuleItem {
+            domain: DomainOrDomainSet::from_str("keyword:ads").unwrap(),
+            address: DomainAddress::SOA,
+        });
+        cfg.address_rules.push(AddressRuleItem {
+            domain: DomainOrDomainSet::from_str("ads.com").unwrap(),
+            address: DomainAddress::SOA,
+        });
+        assert!(check_patterns(&cfg).is_empty());
+
+        // valid one by one as parsed, standing for a set beyond the size limit together.
+        cfg.address_rules.push(AddressRuleItem {
+            domain: DomainOrDomainSet::Regex("(".to_string()),
+            address: DomainAddress::SOA,
+        });
+
+        let errors = check_patterns(&cfg);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("address rules"), "{}", errors[0]);
+
+        // the domains are still matched without the patterns.
+        let matcher = DomainAddressMatcher::create(&cfg);
+        assert_eq!(matcher.find(&name("ads.com.")), Some(&DomainAddress::SOA));
+        assert_eq!(matcher.find(&name("ads.net.")), None);
     }
-    map
 }
@@ -0,0 +1,34 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::log::{debug, warn};
+
+/// Tell the operators of the events worth acting on, e.g. a latency slo breached, by running
+/// the command of `notify-command` with the event and the message as the arguments.
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    command: Option<PathBuf>,
+}
+
+impl Notifier {
+    pub fn new(command: Option<PathBuf>) -> Self {
+        Self { command }
+    }
+
+    /// Run the command in background, nothing if none configured.
+    pub fn notify(&self, event: &str, message: &str) {
+        let command = match self.command.as_ref() {
+            Some(command) => command.clone(),
+            None => return,
+        };
+        let (event, message) = (event.to_string(), message.to_string());
+
+        tokio::task::spawn_blocking(move || {
+            match Command::new(&command).arg(&event).arg(&message).status() {
+                Ok(status) if status.success() => debug!("notified {} by {:?}", event, command),
+                Ok(status) => warn!("notify {} by {:?} failed, {}", event, command, status),
+                Err(err) => warn!("notify {} by {:?} failed, {}", event, command, err),
+            }
+        });
+    }
+}
@@ -0,0 +1,543 @@
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use hyper::header::{ACCEPT, CONTENT_TYPE as CONTENT_TYPE_HEADER};
+use hyper::{Body, Request, StatusCode};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hmac;
+use ring::rand::SystemRandom;
+use trust_dns_proto::op::Message;
+use trust_dns_proto::serialize::binary::{BinDecodable, BinEncodable};
+use trust_dns_proto::xfer::{DnsRequest, DnsResponse};
+use trust_dns_resolver::error::ResolveError;
+use url::Url;
+
+use crate::infra::http::{read_body, HttpClient};
+use crate::log::debug;
+
+const ODOH_VERSION: u16 = 0x0001;
+/// The only HPKE suite supported: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-128-GCM.
+const KEM_X25519_SHA256: u16 = 0x0020;
+const KDF_SHA256: u16 = 0x0001;
+const AEAD_AES_128_GCM: u16 = 0x0001;
+
+const KEY_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HASH_LEN: usize = 32;
+
+const QUERY_TYPE: u8 = 0x01;
+const RESPONSE_TYPE: u8 = 0x02;
+
+const CONTENT_TYPE: &str = "application/oblivious-dns-message";
+const CONFIGS_PATH: &str = "/.well-known/odohconfigs";
+/// The config of the target is fetched again after this duration, or once the target rejects the key.
+const CONFIG_TTL: Duration = Duration::from_secs(3600);
+/// The longest body read, the largest dns message with the encryption overhead fits in.
+const MAX_BODY_LEN: usize = 128 * 1024;
+
+/// An Oblivious DoH (RFC 9230) client, the queries are encrypted to the target,
+/// and sent through the relay, so that neither sees both the client ip and the query.
+pub struct OdohClient {
+    target: Url,
+    relay: Option<Url>,
+    http: HttpClient,
+    config: RwLock<Option<(Instant, Arc<OdohConfig>)>>,
+}
+
+impl OdohClient {
+    /// The client sending the queries over the http client, which connects to the target and
+    /// the relay as the other upstreams, see `DnsClient`.
+    pub fn new(target: Url, relay: Option<Url>, http: HttpClient) -> Self {
+        Self {
+            target,
+            relay,
+            http,
+            config: Default::default(),
+        }
+    }
+
+    pub async fn exchange(&self, request: DnsRequest) -> Result<DnsResponse, ResolveError> {
+        let config = self.config().await?;
+        let query = request.to_vec()?;
+
+        let (message, context) = encrypt_query(&config, &query)?;
+
+        let request = Request::post(self.query_url().as_str())
+            .header(CONTENT_TYPE_HEADER, CONTENT_TYPE)
+            .header(ACCEPT, CONTENT_TYPE)
+            .body(Body::from(message))
+            .map_err(|e| ResolveError::from(format!("odoh request failed, {}", e)))?;
+
+        let res = self
+            .http
+            .request(request)
+            .await
+            .map_err(|e| ResolveError::from(format!("odoh request failed, {}", e)))?;
+
+        if res.status() == StatusCode::UNAUTHORIZED {
+            // the target rotated its key, fetch the config again on the next query.
+            if let Ok(mut config) = self.config.write() {
+                *config = None;
+            }
+        }
+
+        if !res.status().is_success() {
+            return Err(ResolveError::from(format!(
+                "odoh request failed, {}",
+                res.status()
+            )));
+        }
+
+        let body = read_body(res, MAX_BODY_LEN)
+            .await
+            .map_err(|e| ResolveError::from(format!("odoh response failed, {}", e)))?;
+
+        let response = context.decrypt_response(&body)?;
+
+        Ok(DnsResponse::from(Message::from_bytes(&response)?))
+    }
+
+    /// The relay forwards the query to the target by the `targethost` and `targetpath` parameters.
+    fn query_url(&self) -> Url {
+        match self.relay.as_ref() {
+            Some(relay) => {
+                let mut url = relay.clone();
+                url.query_pairs_mut()
+                    .append_pair("targethost", self.target.host_str().unwrap_or_default())
+                    .append_pair("targetpath", self.target.path());
+                url
+            }
+            None => self.target.clone(),
+        }
+    }
+
+    async fn config(&self) -> Result<Arc<OdohConfig>, ResolveError> {
+        if let Some((fetched, config)) = self.config.read().ok().and_then(|c| c.clone()) {
+            if fetched.elapsed() < CONFIG_TTL {
+                return Ok(config);
+            }
+        }
+
+        let mut url = self.target.clone();
+        url.set_path(CONFIGS_PATH);
+        url.set_query(None);
+
+        let failed = |e: &dyn fmt::Display| format!("fetch odoh configs failed, {}", e);
+
+        let request = Request::get(url.as_str())
+            .body(Body::empty())
+            .map_err(|e| failed(&e))?;
+
+        let res = self.http.request(request).await.map_err(|e| failed(&e))?;
+        if !res.status().is_success() {
+            return Err(ResolveError::from(failed(&res.status())));
+        }
+
+        let body = read_body(res, MAX_BODY_LEN).await.map_err(|e| failed(&e))?;
+
+        let config = Arc::new(OdohConfig::parse(&body)?);
+        debug!(
+            "odoh config of {} fetched",
+            self.target.host_str().unwrap_or_default()
+        );
+
+        if let Ok(mut c) = self.config.write() {
+            *c = Some((Instant::now(), config.clone()));
+        }
+
+        Ok(config)
+    }
+}
+
+impl fmt::Debug for OdohClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OdohClient")
+            .field("target", &self.target.as_str())
+            .field("relay", &self.relay.as_ref().map(|r| r.as_str()))
+            .finish()
+    }
+}
+
+/// The public key of the target, published at `/.well-known/odohconfigs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OdohConfig {
+    /// the serialized `ObliviousDoHConfigContents`, the key id is derived from.
+    contents: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+impl OdohConfig {
+    /// The first config of the supported version and suite in the `ObliviousDoHConfigs`.
+    fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let mut configs = read_lp16(&mut &bytes[..])?;
+
+        while !configs.is_empty() {
+            let version = read_u16(&mut configs)?;
+            let contents = read_lp16(&mut configs)?;
+
+            if version != ODOH_VERSION {
+                continue;
+            }
+
+            let mut rest = contents;
+            let suite = (
+                read_u16(&mut rest)?,
+                read_u16(&mut rest)?,
+                read_u16(&mut rest)?,
+            );
+            let public_key = read_lp16(&mut rest)?;
+
+            if suite == (KEM_X25519_SHA256, KDF_SHA256, AEAD_AES_128_GCM) && public_key.len() == 32
+            {
+                return Ok(Self {
+                    contents: contents.to_vec(),
+                    public_key: public_key.to_vec(),
+                });
+            }
+        }
+
+        Err("no odoh config of the supported version and suite".to_string())
+    }
+
+    fn key_id(&self) -> Vec<u8> {
+        expand(&extract(&[], &self.contents), &[b"odoh key id"], HASH_LEN)
+    }
+}
+
+/// Encrypt the query to the target, the context is kept to decrypt the response.
+fn encrypt_query(config: &OdohConfig, query: &[u8]) -> Result<(Vec<u8>, QueryContext), String> {
+    // the query is padded already, see `edns-padding`.
+    let mut plaintext = vec![];
+    put_lp16(&mut plaintext, query);
+    put_lp16(&mut plaintext, &[]);
+
+    let (enc, context) = HpkeContext::setup_sender(&config.public_key, b"odoh query")?;
+
+    let key_id = config.key_id();
+    let mut aad = vec![QUERY_TYPE];
+    put_lp16(&mut aad, &key_id);
+
+    let mut encrypted = enc;
+    encrypted.extend_from_slice(&context.seal(&aad, &plaintext)?);
+
+    let mut message = vec![QUERY_TYPE];
+    put_lp16(&mut message, &key_id);
+    put_lp16(&mut message, &encrypted);
+
+    Ok((message, QueryContext { context, plaintext }))
+}
+
+struct QueryContext {
+    context: HpkeContext,
+    plaintext: Vec<u8>,
+}
+
+impl QueryContext {
+    /// The response is encrypted by the key derived from the query context and the response nonce.
+    fn decrypt_response(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        let mut rest = message;
+
+        if read_u8(&mut rest)? != RESPONSE_TYPE {
+            return Err("not an odoh response".to_string());
+        }
+
+        let response_nonce = read_lp16(&mut rest)?;
+        let encrypted = read_lp16(&mut rest)?;
+
+        let secret = self.context.export(b"odoh response", KEY_LEN);
+
+        let mut salt = self.plaintext.clone();
+        put_lp16(&mut salt, response_nonce);
+
+        let prk = extract(&salt, &secret);
+        let key = expand(&prk, &[b"odoh key"], KEY_LEN);
+        let nonce = expand(&prk, &[b"odoh nonce"], NONCE_LEN);
+
+        let mut aad = vec![RESPONSE_TYPE];
+        put_lp16(&mut aad, response_nonce);
+
+        let plaintext = open(&key, &nonce, &aad, encrypted)?;
+
+        read_lp16(&mut &plaintext[..]).map(|dns| dns.to_vec())
+    }
+}
+
+/// The HPKE (RFC 9180) context of the base mode sender, only one message is sealed.
+struct HpkeContext {
+    key: Vec<u8>,
+    base_nonce: Vec<u8>,
+    exporter_secret: Vec<u8>,
+}
+
+impl HpkeContext {
+    /// Encapsulate an ephemeral key to the recipient, the encapsulated key is returned with the context.
+    fn setup_sender(pk_r: &[u8], info: &[u8]) -> Result<(Vec<u8>, Self), String> {
+        let rng = SystemRandom::new();
+        let sk_e = EphemeralPrivateKey::generate(&X25519, &rng).map_err(|_| "x25519 failed")?;
+        let enc = sk_e
+            .compute_public_key()
+            .map_err(|_| "x25519 failed")?
+            .as_ref()
+            .to_vec();
+
+        let dh = agreement::agree_ephemeral(
+            sk_e,
+            &UnparsedPublicKey::new(&X25519, pk_r),
+            "invalid odoh public key",
+            |dh| Ok(dh.to_vec()),
+        )?;
+
+        let context = Self::from_dh(&dh, &enc, pk_r, info);
+        Ok((enc, context))
+    }
+
+    fn from_dh(dh: &[u8], enc: &[u8], pk_r: &[u8], info: &[u8]) -> Self {
+        let kem_suite = [b"KEM".as_ref(), &KEM_X25519_SHA256.to_be_bytes()].concat();
+
+        let eae_prk = labeled_extract(&kem_suite, &[], b"eae_prk", dh);
+        let kem_context = [enc, pk_r].concat();
+        let shared_secret = labeled_expand(
+            &kem_suite,
+            &eae_prk,
+            b"shared_secret",
+            &kem_context,
+            HASH_LEN,
+        );
+
+        Self::key_schedule(&shared_secret, info)
+    }
+
+    fn key_schedule(shared_secret: &[u8], info: &[u8]) -> Self {
+        let suite = hpke_suite();
+
+        let mut context = vec![0u8]; // mode_base
+        context.extend_from_slice(&labeled_extract(&suite, &[], b"psk_id_hash", &[]));
+        context.extend_from_slice(&labeled_extract(&suite, &[], b"info_hash", info));
+
+        let secret = labeled_extract(&suite, shared_secret, b"secret", &[]);
+
+        Self {
+            key: labeled_expand(&suite, &secret, b"key", &context, KEY_LEN),
+            base_nonce: labeled_expand(&suite, &secret, b"base_nonce", &context, NONCE_LEN),
+            exporter_secret: labeled_expand(&suite, &secret, b"exp", &context, HASH_LEN),
+        }
+    }
+
+    /// Seal the first message, whose nonce is the base nonce itself.
+    fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let key = LessSafeKey::new(
+            UnboundKey::new(&AES_128_GCM, &self.key).map_err(|_| "invalid aead key")?,
+        );
+        let nonce =
+            Nonce::try_assume_unique_for_key(&self.base_nonce).map_err(|_| "invalid nonce")?;
+
+        let mut in_out = plaintext.to_vec();
+        key.seal_in_place_append_tag(nonce, Aad::from(aad), &mut in_out)
+            .map_err(|_| "seal failed")?;
+        Ok(in_out)
+    }
+
+    fn export(&self, exporter_context: &[u8], len: usize) -> Vec<u8> {
+        labeled_expand(
+            &hpke_suite(),
+            &self.exporter_secret,
+            b"sec",
+            exporter_context,
+            len,
+        )
+    }
+}
+
+fn hpke_suite() -> Vec<u8> {
+    [
+        b"HPKE".as_ref(),
+        &KEM_X25519_SHA256.to_be_bytes(),
+        &KDF_SHA256.to_be_bytes(),
+        &AEAD_AES_128_GCM.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn open(key: &[u8], nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+    let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, key).map_err(|_| "invalid aead key")?);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| "invalid nonce")?;
+
+    let mut in_out = ciphertext.to_vec();
+    let plaintext = key
+        .open_in_place(nonce, Aad::from(aad), &mut in_out)
+        .map_err(|_| "odoh response decryption failed")?;
+    Ok(plaintext.to_vec())
+}
+
+fn labeled_extract(suite: &[u8], salt: &[u8], label: &[u8], ikm: &[u8]) -> Vec<u8> {
+    extract(salt, &[b"HPKE-v1".as_ref(), suite, label, ikm].concat())
+}
+
+fn labeled_expand(suite: &[u8], prk: &[u8], label: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    expand(
+        prk,
+        &[&(len as u16).to_be_bytes(), b"HPKE-v1", suite, label, info],
+        len,
+    )
+}
+
+/// HKDF-Extract (RFC 5869) with SHA256, the empty salt is as good as the zeros.
+fn extract(salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, salt);
+    hmac::sign(&key, ikm).as_ref().to_vec()
+}
+
+/// HKDF-Expand (RFC 5869) with SHA256, the info is given in parts.
+fn expand(prk: &[u8], info: &[&[u8]], len: usize) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, prk);
+    let mut okm = Vec::with_capacity(len + HASH_LEN);
+    let mut block = vec![];
+    let mut counter = 1u8;
+
+    while okm.len() < len {
+        let mut ctx = hmac::Context::with_key(&key);
+        ctx.update(&block);
+        for part in info {
+            ctx.update(part);
+        }
+        ctx.update(&[counter]);
+        block = ctx.sign().as_ref().to_vec();
+        okm.extend_from_slice(&block);
+        counter += 1;
+    }
+
+    okm.truncate(len);
+    okm
+}
+
+fn read_u8(bytes: &mut &[u8]) -> Result<u8, String> {
+    let (first, rest) = bytes
+        .split_first()
+        .ok_or("unexpected end of odoh message")?;
+    *bytes = rest;
+    Ok(*first)
+}
+
+fn read_u16(bytes: &mut &[u8]) -> Result<u16, String> {
+    Ok(u16::from_be_bytes([read_u8(bytes)?, read_u8(bytes)?]))
+}
+
+fn read_lp16<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let len = read_u16(bytes)? as usize;
+    if bytes.len() < len {
+        return Err("unexpected end of odoh message".to_string());
+    }
+    let (value, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(value)
+}
+
+fn put_lp16(buf: &mut Vec<u8>, value: &[u8]) {
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// RFC 9180, Appendix A.1.1, the dh is computed from skEm and pkRm.
+    fn vector_context() -> HpkeContext {
+        HpkeContext::from_dh(
+            &hex("b3b5c19eab3f088ac18f23f774ff6414ba4fde45404d10085efc3e4dc9c72e35"),
+            &hex("37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431"),
+            &hex("3948cfe0ad1ddb695d780e59077195da6c56506b027329794ab02bca80815c4d"),
+            &hex("4f6465206f6e2061204772656369616e2055726e"),
+        )
+    }
+
+    #[test]
+    fn test_hpke_base_vector() {
+        let context = vector_context();
+
+        assert_eq!(context.key, hex("4531685d41d65f03dc48f6b8302c05b0"));
+        assert_eq!(context.base_nonce, hex("56d890e5accaaf011cff4b7d"));
+        assert_eq!(
+            context.exporter_secret,
+            hex("45ff1c2e220db587171952c0592d5f5ebe103f1561a2614e38f2ffd47e99e3f8")
+        );
+
+        let ciphertext = context
+            .seal(b"Count-0", b"Beauty is truth, truth beauty")
+            .unwrap();
+        assert_eq!(
+            ciphertext,
+            hex("f938558b5d72f1a23810b4be2ab4f84331acc02fc97babc53a52ae8218a355a96d8770ac83d07bea87e13c512a")
+        );
+    }
+
+    #[test]
+    fn test_decrypt_response() {
+        let query = QueryContext {
+            context: vector_context(),
+            plaintext: hex("0003abcdef0000"),
+        };
+
+        let response = hex("020010000102030405060708090a0b0c0d0e0f00172f1f6ba3499c073d03deaa9b15d1a8463948100d7954c2");
+        assert_eq!(query.decrypt_response(&response).unwrap(), hex("123456"));
+
+        let mut tampered = response.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(query.decrypt_response(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let public_key = [7u8; 32];
+
+        let mut contents = vec![];
+        contents.extend_from_slice(&KEM_X25519_SHA256.to_be_bytes());
+        contents.extend_from_slice(&KDF_SHA256.to_be_bytes());
+        contents.extend_from_slice(&AEAD_AES_128_GCM.to_be_bytes());
+        put_lp16(&mut contents, &public_key);
+
+        let mut configs = vec![];
+        // an unknown version first, skipped.
+        configs.extend_from_slice(&0xff06u16.to_be_bytes());
+        put_lp16(&mut configs, &[1, 2, 3]);
+        configs.extend_from_slice(&ODOH_VERSION.to_be_bytes());
+        put_lp16(&mut configs, &contents);
+
+        let mut bytes = vec![];
+        put_lp16(&mut bytes, &configs);
+
+        let config = OdohConfig::parse(&bytes).unwrap();
+        assert_eq!(config.public_key, public_key);
+        assert_eq!(config.contents, contents);
+        assert_eq!(config.key_id().len(), 32);
+
+        assert!(OdohConfig::parse(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_query() {
+        let config = OdohConfig {
+            contents: vec![],
+            public_key: hex("3948cfe0ad1ddb695d780e59077195da6c56506b027329794ab02bca80815c4d"),
+        };
+
+        let (message, context) = encrypt_query(&config, &[0xab, 0xcd]).unwrap();
+        assert_eq!(context.plaintext, vec![0, 2, 0xab, 0xcd, 0, 0]);
+
+        let mut rest = &message[..];
+        assert_eq!(read_u8(&mut rest).unwrap(), QUERY_TYPE);
+        assert_eq!(read_lp16(&mut rest).unwrap(), config.key_id());
+        // the encapsulated key, then the plaintext sealed with the tag.
+        assert_eq!(read_lp16(&mut rest).unwrap().len(), 32 + 6 + 16);
+        assert!(rest.is_empty());
+    }
+}
@@ -0,0 +1,604 @@
+//! Explain the resolution of a query offline, by the rules of the configuration, see
+//! `smartdns rules explain www.example.com A --client 192.168.3.7`.
+//!
+//! The stages are evaluated in the order of the middlewares, by the same matching of each, so
+//! that the rule told is the one applying. The blocklists are of their cached copies, the hosts
+//! files and the leases as they are now; the blocking paused isn't known offline.
+use std::net::IpAddr;
+
+use trust_dns_client::rr::{LowerName, Name, RData, RecordType};
+
+use crate::blocking::BlockingOverrides;
+use crate::blocklist::{self, Blocklists};
+use crate::dns_conf::{
+    ClientMatch, DomainAddress, DomainOrDomainSet, DomainRuleItem, ServerOpts, SmartDnsConfig,
+    SpeedCheckMode,
+};
+use crate::dns_mw_addr::{self, AddressMiddleware, AddressOrigin};
+use crate::dns_mw_hosts::Hosts;
+use crate::dns_mw_mdns::DnsMdnsMiddleware;
+use crate::dns_mw_zone::DnsZoneMiddleware;
+use crate::matcher::DomainRuleIndexMatcher;
+
+/// What a stage does to the query, and the rule deciding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub stage: &'static str,
+    pub outcome: String,
+    /// the rule and how it matches, or why the default applies.
+    pub reason: String,
+}
+
+impl Explanation {
+    fn new(stage: &'static str, outcome: String, reason: String) -> Self {
+        Self {
+            stage,
+            outcome,
+            reason,
+        }
+    }
+}
+
+impl std::fmt::Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<16} {}, {}", self.stage, self.outcome, self.reason)
+    }
+}
+
+/// The stages the query of the client goes through, until the one answering it.
+pub fn explain(
+    cfg: &SmartDnsConfig,
+    name: &Name,
+    record_type: RecordType,
+    client: Option<IpAddr>,
+) -> Vec<Explanation> {
+    let name = LowerName::from(name);
+    let mut explanations = vec![];
+
+    let (opts, reason) = policy(cfg, client);
+    explanations.push(Explanation::new("policy", describe_opts(&opts), reason));
+
+    if cfg.deny_query_types.contains(&record_type) {
+        explanations.push(Explanation::new(
+            "query-type",
+            "answered NOTIMP".to_string(),
+            format!("deny-query-type {}", record_type),
+        ));
+        return explanations;
+    }
+
+    if let Some(rule) = find(&cfg.query_type_rules, |r| &r.domain, cfg, &name) {
+        if !rule.types.accepts(record_type) {
+            explanations.push(Explanation::new(
+                "query-type",
+                "answered REFUSED".to_string(),
+                format!("query-type {}", describe_domain(&rule.domain)),
+            ));
+            return explanations;
+        }
+    }
+
+    let domain_rule = find(&cfg.domain_rules, |r| &r.domain, cfg, &name);
+    if let Some(item) = domain_rule {
+        explanations.push(Explanation::new(
+            "domain-rules",
+            format!("{:?}", item.rule),
+            format!("domain-rules {}", describe_domain(&item.domain)),
+        ));
+    }
+
+    let local = LocalSources::new(cfg);
+
+    // the single-label names under the local domain, by the local sources only.
+    let expanded = cfg
+        .domain
+        .as_ref()
+        .and_then(|domain| Some((domain, expand(domain, &name, record_type)?)));
+    if let Some((domain, expanded)) = expanded {
+        let mut expanded_explanations = vec![];
+        if local.explain(
+            &mut expanded_explanations,
+            cfg,
+            &opts,
+            domain_rule,
+            &expanded,
+            record_type,
+        ) {
+            explanations.push(Explanation::new(
+                "suffix",
+                format!("resolved as {}", expanded),
+                format!("domain {}", domain),
+            ));
+            explanations.extend(expanded_explanations);
+            return explanations;
+        }
+    }
+
+    if local.explain(
+        &mut explanations,
+        cfg,
+        &opts,
+        domain_rule,
+        &name,
+        record_type,
+    ) {
+        return explanations;
+    }
+
+    if let Some(domain) = local.mdns.domain_of(&name) {
+        explanations.push(Explanation::new(
+            "mdns",
+            "resolved by multicast on the LAN".to_string(),
+            format!("mdns-domain {}", domain),
+        ));
+        return explanations;
+    }
+
+    if let Some(rule) = find(&cfg.cname_rules, |r| &r.domain, cfg, &name) {
+        explanations.push(Explanation::new(
+            "cname",
+            format!("resolved as {}", rule.cname),
+            format!("cname {}", describe_domain(&rule.domain)),
+        ));
+    }
+
+    let (group, reason) = server_group(cfg, &opts, domain_rule.map(|item| &item.rule), &name);
+    let servers = cfg
+        .servers
+        .get(&group)
+        .map(|servers| {
+            servers
+                .iter()
+                .map(|server| server.url.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .unwrap_or_default();
+    explanations.push(Explanation::new(
+        "nameserver",
+        format!("resolved by group {} [{}]", group, servers),
+        reason,
+    ));
+
+    let (modes, reason) = if opts.no_speed_check {
+        (vec![], "-no-speed-check".to_string())
+    } else {
+        match domain_rule {
+            Some(item) if !item.rule.speed_check_mode.is_empty() => (
+                item.rule.speed_check_mode.clone(),
+                format!("domain-rules {}", describe_domain(&item.domain)),
+            ),
+            _ => {
+                let conf_group = opts.conf_group.as_deref();
+                let reason = match conf_group.and_then(|group| cfg.conf_groups.get(group)) {
+                    Some(group) if !group.speed_check_mode.is_empty() => {
+                        format!("speed-check-mode of conf-group {}", conf_group.unwrap())
+                    }
+                    _ => "speed-check-mode".to_string(),
+                };
+                (cfg.speed_check_mode(conf_group).to_vec(), reason)
+            }
+        }
+    };
+    // probed until `none`, as the speed check and the fastest-ip fan-out do.
+    let outcome = match modes.split(|mode| *mode == SpeedCheckMode::None).next() {
+        Some([]) | None => "disabled".to_string(),
+        Some(modes) => format!("{:?}", modes),
+    };
+    explanations.push(Explanation::new("speed-check", outcome, reason));
+
+    explanations
+}
+
+/// The name under the local domain, if a single-label A or AAAA query expanded.
+fn expand(domain: &Name, name: &LowerName, record_type: RecordType) -> Option<LowerName> {
+    if name.num_labels() != 1 || !matches!(record_type, RecordType::A | RecordType::AAAA) {
+        return None;
+    }
+    Name::from(name.clone())
+        .append_domain(domain)
+        .ok()
+        .map(LowerName::from)
+}
+
+/// The stages answering from the configuration and the local files, before the cache, as the
+/// middlewares of them match.
+struct LocalSources {
+    zone: DnsZoneMiddleware,
+    hosts: Hosts,
+    address: AddressMiddleware,
+    mdns: DnsMdnsMiddleware,
+}
+
+impl LocalSources {
+    fn new(cfg: &SmartDnsConfig) -> Self {
+        Self {
+            zone: DnsZoneMiddleware::new(cfg),
+            hosts: Hosts::load(cfg),
+            address: AddressMiddleware::new(
+                cfg,
+                BlockingOverrides::new(),
+                Blocklists::cached(&cfg.blocklists, blocklist::CACHE_DIR),
+            ),
+            mdns: DnsMdnsMiddleware::new(&cfg.mdns_domains()),
+        }
+    }
+
+    /// Explain the local records, the hosts, then the address rules, true if answered.
+    fn explain(
+        &self,
+        explanations: &mut Vec<Explanation>,
+        cfg: &SmartDnsConfig,
+        opts: &ServerOpts,
+        domain_rule: Option<&DomainRuleItem>,
+        name: &LowerName,
+        record_type: RecordType,
+    ) -> bool {
+        let records = self.zone.lookup(name, record_type);
+        if !records.is_empty() {
+            explanations.push(Explanation::new(
+                "zone",
+                format!("answered {}", describe_rdatas(records)),
+                format!("the local {} records", record_type),
+            ));
+            return true;
+        }
+
+        if let Some(rdatas) = self.hosts.lookup(name, record_type) {
+            let outcome = match rdatas.is_empty() {
+                true => "answered NODATA, the host known of the other type".to_string(),
+                false => format!("answered {}", describe_rdatas(rdatas.iter())),
+            };
+            explanations.push(Explanation::new(
+                "hosts",
+                outcome,
+                "the hosts files and the leases".to_string(),
+            ));
+            return true;
+        }
+
+        if !matches!(record_type, RecordType::A | RecordType::AAAA) {
+            return false;
+        }
+
+        if opts.force_aaaa_soa && record_type == RecordType::AAAA {
+            explanations.push(Explanation::new(
+                "address",
+                "AAAA filtered, answered SOA".to_string(),
+                "-force-aaaa-soa".to_string(),
+            ));
+            return true;
+        }
+
+        let (address, origin) =
+            match self
+                .address
+                .match_address(opts, domain_rule.map(|item| &item.rule), name)
+            {
+                Some(address) => address,
+                None => return false,
+            };
+        let reason = describe_origin(&origin, cfg, domain_rule, name);
+
+        let skipped = if opts.no_rule_addr {
+            Some("-no-rule-addr")
+        } else if opts.no_rule_soa && dns_mw_addr::is_soa(&address) {
+            Some("-no-rule-soa")
+        } else {
+            None
+        };
+
+        match (skipped, describe_address(&address, record_type, cfg)) {
+            (Some(option), _) => explanations.push(Explanation::new(
+                "address",
+                format!("skipped by {}", option),
+                reason,
+            )),
+            (None, Some(outcome)) => {
+                explanations.push(Explanation::new("address", outcome, reason));
+                return true;
+            }
+            (None, None) => explanations.push(Explanation::new(
+                "address",
+                "passed through".to_string(),
+                reason,
+            )),
+        }
+
+        false
+    }
+}
+
+/// The policy of the first listener, as the offline query has none, with the client rule
+/// applied over it.
+fn policy(cfg: &SmartDnsConfig, client: Option<IpAddr>) -> (ServerOpts, String) {
+    let (listener, reason) = match cfg.binds.first() {
+        Some(bind) => (bind.opts.clone(), format!("the listener {:?}", bind.addr)),
+        None => (ServerOpts::default(), "the defaults".to_string()),
+    };
+
+    if let Some(rule) = client.and_then(|ip| cfg.client_rule(ip, None)) {
+        let client = match &rule.client {
+            ClientMatch::Net(net) => net.to_string(),
+            ClientMatch::Mac(mac) => format!("{:?}", mac),
+        };
+        return (
+            listener.with_client(&rule.opts),
+            format!("client-rules {} over {}", client, reason),
+        );
+    }
+
+    (listener, format!("{}, no client rule", reason))
+}
+
+/// The server group, by the nameserver rules of the conf-group, the domain rule and the global
+/// ones, then the group of the policy, the geoip route, or the default one.
+fn server_group(
+    cfg: &SmartDnsConfig,
+    opts: &ServerOpts,
+    domain_rule: Option<&crate::dns_conf::DomainRule>,
+    name: &LowerName,
+) -> (String, String) {
+    // "-" means the default group, as of the nameserver rules.
+    let group_of = |group: &str| match group {
+        "-" => "default".to_string(),
+        group => group.to_string(),
+    };
+
+    let by_rule = (!opts.no_rule_nameserver)
+        .then(|| {
+            opts.conf_group
+                .as_ref()
+                .and_then(|conf_group| {
+                    let rules = &cfg.conf_groups.get(conf_group)?.forward_rules;
+                    find(rules, |r| &r.domain, cfg, name).map(|rule| {
+                        (
+                            group_of(&rule.server_group),
+                            format!(
+                                "nameserver {} of conf-group {}",
+                                describe_domain(&rule.domain),
+                                conf_group
+                            ),
+                        )
+                    })
+                })
+                .or_else(|| {
+                    domain_rule
+                        .and_then(|rule| rule.nameserver.as_deref())
+                        .map(|group| (group_of(group), "domain-rules -nameserver".to_string()))
+                })
+                .or_else(|| {
+                    find(&cfg.forward_rules, |r| &r.domain, cfg, name).map(|rule| {
+                        (
+                            group_of(&rule.server_group),
+                            format!("nameserver {}", describe_domain(&rule.domain)),
+                        )
+                    })
+                })
+        })
+        .flatten();
+
+    if let Some(group) = by_rule {
+        return group;
+    }
+
+    if let Some(group) = opts.group.as_ref() {
+        return (group.to_owned(), "-group of the policy".to_string());
+    }
+
+    if let Some(route) = cfg.geoip_route.as_ref() {
+        return (
+            route.group.to_owned(),
+            format!(
+                "geoip-route, by {} if its ips are of {}, otherwise by {}",
+                route.group, route.country, route.otherwise
+            ),
+        );
+    }
+
+    ("default".to_string(), "no nameserver rule".to_string())
+}
+
+/// The rule matching the domain, of the precedence the middlewares match with.
+fn find<'a, R>(
+    rules: &'a [R],
+    domain: fn(&R) -> &DomainOrDomainSet,
+    cfg: &SmartDnsConfig,
+    name: &LowerName,
+) -> Option<&'a R> {
+    DomainRuleIndexMatcher::from_indexes(rules.iter().map(domain), &cfg.domain_sets)
+        .find(name)
+        .map(|idx| &rules[*idx])
+}
+
+/// How the domains of a rule match, e.g. `/example.com/, the domain and its subdomains`.
+fn describe_domain(domain: &DomainOrDomainSet) -> String {
+    match domain {
+        DomainOrDomainSet::Domain(domain) => {
+            format!("/{}/, the domain and its subdomains", domain)
+        }
+        DomainOrDomainSet::Full(domain) => format!("/full:{}/, the full name", domain),
+        DomainOrDomainSet::Keyword(keyword) => format!("/keyword:{}/, the keyword", keyword),
+        DomainOrDomainSet::Regex(regex) => format!("/regexp:{}/, the pattern", regex),
+        DomainOrDomainSet::DomainSet(set) => format!("/domain-set:{}/, the domain set", set),
+    }
+}
+
+fn describe_opts(opts: &ServerOpts) -> String {
+    let mut parts = vec![];
+    if let Some(group) = opts.group.as_ref() {
+        parts.push(format!("-group {}", group));
+    }
+    if let Some(group) = opts.conf_group.as_ref() {
+        parts.push(format!("-conf-group {}", group));
+    }
+    for set in opts.block_sets.iter() {
+        parts.push(format!("-block-set {}", set));
+    }
+    for (flag, option) in [
+        (opts.no_rule_addr, "-no-rule-addr"),
+        (opts.no_rule_nameserver, "-no-rule-nameserver"),
+        (opts.no_rule_soa, "-no-rule-soa"),
+        (opts.no_speed_check, "-no-speed-check"),
+        (opts.no_cache, "-no-cache"),
+        (opts.force_aaaa_soa, "-force-aaaa-soa"),
+    ] {
+        if flag {
+            parts.push(option.to_string());
+        }
+    }
+
+    if parts.is_empty() {
+        "the defaults".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+/// The rule of the address, e.g. `address /ads.example.com/, the domain and its subdomains`.
+fn describe_origin(
+    origin: &AddressOrigin,
+    cfg: &SmartDnsConfig,
+    domain_rule: Option<&DomainRuleItem>,
+    name: &LowerName,
+) -> String {
+    match origin {
+        AddressOrigin::ConfGroup(group) => cfg
+            .conf_groups
+            .get(group)
+            .and_then(|conf_group| find(&conf_group.address_rules, |r| &r.domain, cfg, name))
+            .map(|rule| {
+                format!(
+                    "address {} of conf-group {}",
+                    describe_domain(&rule.domain),
+                    group
+                )
+            })
+            .unwrap_or_else(|| format!("address of conf-group {}", group)),
+        AddressOrigin::DomainRule => match domain_rule {
+            Some(item) => format!("domain-rules {}", describe_domain(&item.domain)),
+            None => "domain-rules".to_string(),
+        },
+        AddressOrigin::BlockSet(set) => format!("the blocked domain set {}", set),
+        AddressOrigin::Rule => find(&cfg.address_rules, |r| &r.domain, cfg, name)
+            .map(|rule| format!("address {}", describe_domain(&rule.domain)))
+            .unwrap_or_else(|| "address".to_string()),
+        AddressOrigin::Blocklist => "the blocklists, of their cached copies".to_string(),
+    }
+}
+
+/// The answer of the address rule, as the address middleware, none to pass the query through.
+fn describe_address(
+    address: &DomainAddress,
+    record_type: RecordType,
+    cfg: &SmartDnsConfig,
+) -> Option<String> {
+    if let Some(mode) = dns_mw_addr::blocking_mode(address, record_type, cfg.blocking_mode) {
+        return Some(match address {
+            DomainAddress::SOAv4 | DomainAddress::SOAv6 => {
+                format!("{} filtered, answered {:?}", record_type, mode)
+            }
+            _ => format!("blocked, answered {:?}", mode),
+        });
+    }
+
+    match dns_mw_addr::address_rdata(address, record_type)? {
+        RData::A(ip) => Some(format!("answered {}", ip)),
+        RData::AAAA(ip) => Some(format!("answered {}", ip)),
+        _ => Some("answered SOA, the address of the other family only".to_string()),
+    }
+}
+
+fn describe_rdatas<'a, I: IntoIterator<Item = &'a RData>>(rdatas: I) -> String {
+    rdatas
+        .into_iter()
+        .map(|rdata| rdata.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn stages(cfg: &SmartDnsConfig, name: &str, client: Option<&str>) -> Vec<String> {
+        explain(
+            cfg,
+            &Name::from_str(name).unwrap(),
+            RecordType::A,
+            client.map(|ip| ip.parse().unwrap()),
+        )
+        .into_iter()
+        .map(|explanation| format!("{} {}", explanation.stage, explanation.outcome))
+        .collect()
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut cfg = SmartDnsConfig::load_from_file("tests/test_confs/f_explain.conf");
+        // the default listener of the config loaded has no policy.
+        cfg.binds.clear();
+
+        assert_eq!(
+            stages(&cfg, "www.ads.example.com", None),
+            vec!["policy the defaults", "address blocked, answered NoData"]
+        );
+        assert_eq!(
+            stages(&cfg, "nas.example.com", None)[1],
+            "address answered 192.168.1.2"
+        );
+        assert!(stages(&cfg, "www.corp.example.com", None)[1]
+            .starts_with("nameserver resolved by group office"));
+
+        // the single-label names under the local domain, if answered locally.
+        assert_eq!(
+            stages(&cfg, "nas", None),
+            vec![
+                "policy the defaults",
+                "suffix resolved as nas.example.com.",
+                "address answered 192.168.1.2"
+            ]
+        );
+        assert!(stages(&cfg, "printer", None)[1].starts_with("nameserver"));
+        assert_eq!(
+            stages(&cfg, "printer.local", None)[1],
+            "mdns resolved by multicast on the LAN"
+        );
+
+        // the local records of the type, the others passed on.
+        let txt = explain(
+            &cfg,
+            &Name::from_str("nas.example.com").unwrap(),
+            RecordType::TXT,
+            None,
+        );
+        assert_eq!(txt[1].stage, "zone");
+
+        // the speed check of the domain rule, none probed after none.
+        let speed_check = |name| {
+            stages(&cfg, name, None)
+                .into_iter()
+                .find(|stage| stage.starts_with("speed-check"))
+        };
+        assert_eq!(
+            speed_check("www.example.com").as_deref(),
+            Some("speed-check [Ping]")
+        );
+        assert_eq!(
+            speed_check("video.example.com").as_deref(),
+            Some("speed-check disabled")
+        );
+        assert_eq!(
+            speed_check("cdn.example.com").as_deref(),
+            Some("speed-check [Tcp(443)]")
+        );
+
+        // the address rules skipped for the clients of the subnet.
+        let stages = stages(&cfg, "ads.example.com", Some("192.168.3.7"));
+        assert_eq!(stages[0], "policy -no-rule-addr");
+        assert_eq!(stages[1], "address skipped by -no-rule-addr");
+        assert!(stages[2].starts_with("nameserver resolved by group default"));
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use once_cell::sync::OnceCell;
+use rand::random;
+use surge_ping::{Client, Config, PingIdentifier, PingSequence, ICMP};
+use tokio::net::TcpStream;
+
+use crate::dns_conf::SpeedCheckMode;
+use crate::log::debug;
+use crate::third_ext::FutureTimeoutExt;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// The latencies are remembered for a while, so that the ips shared by the domains, e.g. of
+/// a CDN, aren't probed for every query.
+const RTT_TTL: Duration = Duration::from_secs(60);
+/// The expired latencies are purged once this many remembered.
+const MAX_RTTS: usize = 4096;
+
+/// Probe the latency of the ips by the speed check modes, the modes tried in order until
+/// one reaches the ip, e.g. `ping,tcp:80` falls back to tcp where icmp is filtered.
+#[derive(Default)]
+pub struct SpeedChecker {
+    rtts: Mutex<HashMap<(SpeedCheckMode, IpAddr), (Option<Duration>, Instant)>>,
+    icmp_v4: OnceCell<Option<Client>>,
+    icmp_v6: OnceCell<Option<Client>>,
+}
+
+impl SpeedChecker {
+    /// The latency of each ip, in the order given, none if unreachable by all the modes.
+    pub async fn check(&self, ips: &[IpAddr], modes: &[SpeedCheckMode]) -> Vec<Option<Duration>> {
+        future::join_all(ips.iter().map(|ip| self.check_ip(*ip, modes))).await
+    }
+
+    /// The ip reached first and its latency, without waiting for the others, the latencies
+    /// remembered raced as if probed again.
+    pub async fn first(
+        &self,
+        ips: &[IpAddr],
+        modes: &[SpeedCheckMode],
+    ) -> Option<(IpAddr, Duration)> {
+        let checks = ips.iter().map(|ip| {
+            Box::pin(async move {
+                let start = Instant::now();
+                let rtt = self.check_ip(*ip, modes).await.ok_or(())?;
+                if let Some(remaining) = rtt.checked_sub(start.elapsed()) {
+                    tokio::time::sleep(remaining).await;
+                }
+                Ok::<_, ()>((*ip, rtt))
+            })
+        });
+
+        future::select_ok(checks).await.ok().map(|(first, _)| first)
+    }
+
+    async fn check_ip(&self, ip: IpAddr, modes: &[SpeedCheckMode]) -> Option<Duration> {
+        for mode in modes
+            .iter()
+            .take_while(|mode| **mode != SpeedCheckMode::None)
+        {
+            let cached = self
+                .rtts
+                .lock()
+                .ok()
+                .and_then(|rtts| rtts.get(&(*mode, ip)).copied())
+                .filter(|(_, at)| at.elapsed() < RTT_TTL);
+
+            let rtt = match cached {
+                Some((rtt, _)) => rtt,
+                None => {
+                    let rtt = self.probe(ip, *mode).await;
+                    if let Ok(mut rtts) = self.rtts.lock() {
+                        if rtts.len() >= MAX_RTTS {
+                            rtts.retain(|_, (_, at)| at.elapsed() < RTT_TTL);
+                        }
+                        rtts.insert((*mode, ip), (rtt, Instant::now()));
+                    }
+                    rtt
+                }
+            };
+
+            if rtt.is_some() {
+                return rtt;
+            }
+        }
+
+        None
+    }
+
+    async fn probe(&self, ip: IpAddr, mode: SpeedCheckMode) -> Option<Duration> {
+        match mode {
+            SpeedCheckMode::Ping => self.ping(ip).await,
+            SpeedCheckMode::Tcp(port) => {
+                let start = Instant::now();
+                match TcpStream::connect(SocketAddr::new(ip, port))
+                    .timeout(PROBE_TIMEOUT)
+                    .await
+                {
+                    Ok(Ok(_)) => Some(start.elapsed()),
+                    _ => None,
+                }
+            }
+            SpeedCheckMode::None => None,
+        }
+    }
+
+    async fn ping(&self, ip: IpAddr) -> Option<Duration> {
+        let (client, kind) = match ip {
+            IpAddr::V4(_) => (&self.icmp_v4, ICMP::V4),
+            IpAddr::V6(_) => (&self.icmp_v6, ICMP::V6),
+        };
+
+        // unavailable without the privilege of icmp sockets, the next mode is tried then.
+        let client = client
+            .get_or_init(|| {
+                Client::new(&Config::builder().kind(kind).build())
+                    .map_err(|err| debug!("icmp {:?} unavailable, {}", kind, err))
+                    .ok()
+            })
+            .as_ref()?;
+
+        let mut pinger = client.pinger(ip, PingIdentifier(random())).await;
+        pinger.timeout(PROBE_TIMEOUT);
+        pinger
+            .ping(PingSequence(0), &[0; 56])
+            .await
+            .ok()
+            .map(|(_, rtt)| rtt)
+    }
+}
+
+/// The records sorted by the latencies of their ips, the fastest first and the unreachable
+/// ones dropped unless none reachable, the records without ip, e.g. CNAME, kept in front.
+pub fn sort_by_rtt<R: Clone>(
+    records: &[R],
+    ip_of: impl Fn(&R) -> Option<IpAddr>,
+    rtts: &HashMap<IpAddr, Option<Duration>>,
+) -> Vec<R> {
+    let (mut with_ip, without_ip): (Vec<_>, Vec<_>) =
+        records.iter().partition(|record| ip_of(record).is_some());
+
+    let rtt_of = |record: &&R| ip_of(record).and_then(|ip| rtts.get(&ip).copied().flatten());
+
+    if with_ip.iter().any(|record| rtt_of(record).is_some()) {
+        with_ip.retain(|record| rtt_of(record).is_some());
+    }
+    // stable, the upstream order kept among the equally fast.
+    with_ip.sort_by_key(|record| rtt_of(record).unwrap_or(Duration::MAX));
+
+    without_ip.into_iter().chain(with_ip).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sort_by_rtt() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let ms = |ms| Some(Duration::from_millis(ms));
+
+        let records = vec!["cname", "10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4"];
+        let ip_of = |r: &&str| r.parse::<IpAddr>().ok();
+
+        let rtts = HashMap::from([
+            (ip("10.0.0.1"), ms(30)),
+            (ip("10.0.0.2"), None),
+            (ip("10.0.0.3"), ms(10)),
+            (ip("10.0.0.4"), ms(30)),
+        ]);
+        assert_eq!(
+            sort_by_rtt(&records, ip_of, &rtts),
+            vec!["cname", "10.0.0.3", "10.0.0.1", "10.0.0.4"]
+        );
+
+        // none reachable, all kept.
+        let rtts = HashMap::from([(ip("10.0.0.1"), None)]);
+        assert_eq!(sort_by_rtt(&records, ip_of, &rtts), records);
+    }
+
+    #[test]
+    fn test_speed_check_none() {
+        let checker = SpeedChecker::default();
+        let rtts = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(checker.check(
+                &["127.0.0.1".parse().unwrap()],
+                &[SpeedCheckMode::None, SpeedCheckMode::Tcp(80)],
+            ));
+        assert_eq!(rtts, vec![None]);
+    }
+}
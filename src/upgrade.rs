@@ -0,0 +1,360 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+const RELEASE_URL: &'static str = "https://github.com/mokeyish/smartdns-rs/releases";
+
+/// The hex encoded ed25519 public key used to verify release artifacts, provided at build time.
+const RELEASE_PUBLIC_KEY: Option<&'static str> = option_env!("SMARTDNS_RELEASE_PUBLIC_KEY");
+
+/// The target triple this binary was built for, see build.rs
+const TARGET: &'static str = env!("TARGET");
+
+#[cfg(windows)]
+const BIN_NAME: &'static str = "smartdns.exe";
+#[cfg(not(windows))]
+const BIN_NAME: &'static str = "smartdns";
+
+/// Download the release artifact, verify its signature, swap the binary and ask the running server to restart.
+pub fn upgrade(version: Option<String>, pid_file: PathBuf) -> Result<(), String> {
+    let public_key = RELEASE_PUBLIC_KEY
+        .and_then(decode_hex)
+        .ok_or("This build has no release public key, refusing to upgrade")?;
+
+    let archive_name = archive_name();
+
+    let base_url = match version {
+        Some(version) => format!("{}/download/{}", RELEASE_URL, version),
+        None => format!("{}/latest/download", RELEASE_URL),
+    };
+
+    let archive_url = format!("{}/{}", base_url, archive_name);
+
+    println!("Downloading {}", archive_url);
+    let archive = download(archive_url.as_str())
+        .map_err(|e| format!("Download {} failed, {}", archive_url, e))?;
+
+    let signature_url = format!("{}.sig", archive_url);
+    let signature = download(signature_url.as_str())
+        .map_err(|e| format!("Download {} failed, {}", signature_url, e))?;
+
+    verify(&public_key, &archive, &signature)
+        .map_err(|_| "Signature verification failed, refusing to upgrade")?;
+
+    let binary = extract_binary(archive_name.as_str(), &archive)
+        .map_err(|e| format!("Extract {} from {} failed, {}", BIN_NAME, archive_name, e))?;
+
+    let current_exe =
+        env::current_exe().map_err(|e| format!("Failed to get current exe path, {}", e))?;
+
+    swap_binary(current_exe.as_path(), &binary)
+        .map_err(|e| format!("Replace {:?} failed, {}", current_exe, e))?;
+
+    println!(
+        "Successfully upgraded `{}` at {:?}",
+        crate::NAME,
+        current_exe
+    );
+
+    restart(pid_file);
+
+    Ok(())
+}
+
+fn archive_name() -> String {
+    if cfg!(any(windows, target_os = "macos")) {
+        format!("smartdns-{}.zip", TARGET)
+    } else {
+        format!("smartdns-{}.tar.gz", TARGET)
+    }
+}
+
+fn download(url: &str) -> Result<Vec<u8>, reqwest::Error> {
+    use reqwest::blocking as http;
+    let bytes = http::get(url)?.error_for_status()?.bytes()?;
+    Ok(bytes.to_vec())
+}
+
+fn verify(
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), ring::error::Unspecified> {
+    UnparsedPublicKey::new(&ED25519, public_key).verify(message, signature)
+}
+
+fn extract_binary(archive_name: &str, archive: &[u8]) -> io::Result<Vec<u8>> {
+    let mut binary = vec![];
+
+    if archive_name.ends_with(".zip") {
+        let mut archive = zip::ZipArchive::new(Cursor::new(archive))?;
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            if Path::new(file.name()).file_name() == Some(OsStr::new(BIN_NAME)) {
+                file.read_to_end(&mut binary)?;
+                return Ok(binary);
+            }
+        }
+    } else {
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(Cursor::new(archive)));
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.path()?.file_name() == Some(OsStr::new(BIN_NAME)) {
+                entry.read_to_end(&mut binary)?;
+                return Ok(binary);
+            }
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::NotFound, "binary not found"))
+}
+
+fn swap_binary(exe: &Path, binary: &[u8]) -> io::Result<()> {
+    let new_path = exe.with_extension("new");
+    let old_path = exe.with_extension("old");
+
+    fs::write(new_path.as_path(), binary)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(new_path.as_path(), fs::Permissions::from_mode(0o755))?;
+    }
+
+    if old_path.exists() {
+        // the previous backup may still be locked on windows.
+        let _ = fs::remove_file(old_path.as_path());
+    }
+
+    // a running executable can be renamed but not overwritten.
+    fs::rename(exe, old_path.as_path())?;
+
+    if let Err(err) = fs::rename(new_path.as_path(), exe) {
+        fs::rename(old_path.as_path(), exe)?;
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn restart(pid_file: PathBuf) {
+    match read_pid_file(pid_file.as_path()) {
+        Some(pid) if unsafe { libc::kill(pid as libc::pid_t, libc::SIGUSR2) } == 0 => {
+            println!(
+                "Graceful restart of `{}` (pid: {}) requested",
+                crate::NAME,
+                pid
+            )
+        }
+        _ => println!(
+            "No running `{}` found by {:?}, start it to use the new version",
+            crate::NAME,
+            pid_file
+        ),
+    }
+}
+
+#[cfg(not(unix))]
+fn restart(_pid_file: PathBuf) {
+    println!(
+        "Restart `{}` with `smartdns service restart` to use the new version",
+        crate::NAME
+    );
+}
+
+pub fn write_pid_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    fs::write(path, std::process::id().to_string())
+}
+
+pub fn read_pid_file<P: AsRef<Path>>(path: P) -> Option<u32> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+}
+
+/// Remove the pid file, unless it was taken over by a successor.
+pub fn remove_pid_file<P: AsRef<Path>>(path: P) {
+    if read_pid_file(path.as_ref()) == Some(std::process::id()) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    // sliced by bytes below, so that a multi-byte char must not get there.
+    if s.len() % 2 != 0 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Handing over the listening sockets to a new process.
+#[cfg(unix)]
+pub mod handover {
+    use std::env;
+    use std::io;
+    use std::net::{SocketAddr, TcpListener, UdpSocket};
+    use std::os::unix::io::{FromRawFd, RawFd};
+    use std::path::Path;
+    use std::process::Command;
+
+    use crate::log::warn;
+
+    const ENV_LISTEN_FDS: &'static str = "SMARTDNS_LISTEN_FDS";
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ListenerKind {
+        Udp,
+        Tcp,
+    }
+
+    /// The sockets inherited from the predecessor, those not taken are closed on drop.
+    #[derive(Debug, Default)]
+    pub struct InheritedListeners {
+        udp: Vec<UdpSocket>,
+        tcp: Vec<TcpListener>,
+    }
+
+    impl InheritedListeners {
+        pub fn from_env() -> Self {
+            let mut listeners = Self::default();
+
+            let fds = match env::var(ENV_LISTEN_FDS) {
+                Ok(fds) => fds,
+                Err(_) => return listeners,
+            };
+
+            env::remove_var(ENV_LISTEN_FDS);
+
+            for item in fds.split(',').filter(|s| !s.is_empty()) {
+                match parse_item(item) {
+                    Some((ListenerKind::Udp, fd)) => {
+                        listeners.udp.push(unsafe { UdpSocket::from_raw_fd(fd) })
+                    }
+                    Some((ListenerKind::Tcp, fd)) => {
+                        listeners.tcp.push(unsafe { TcpListener::from_raw_fd(fd) })
+                    }
+                    None => warn!("invalid inherited listener: {}", item),
+                }
+            }
+
+            listeners
+        }
+
+        pub fn take_udp(&mut self, addr: SocketAddr) -> Option<UdpSocket> {
+            let idx = self
+                .udp
+                .iter()
+                .position(|s| matches!(s.local_addr(), Ok(a) if a == addr))?;
+            Some(self.udp.swap_remove(idx))
+        }
+
+        pub fn take_tcp(&mut self, addr: SocketAddr) -> Option<TcpListener> {
+            let idx = self
+                .tcp
+                .iter()
+                .position(|s| matches!(s.local_addr(), Ok(a) if a == addr))?;
+            Some(self.tcp.swap_remove(idx))
+        }
+    }
+
+    /// Start the (upgraded) executable with the same arguments, passing the listening sockets to it.
+    pub fn spawn_successor(exe: &Path, listeners: &[(ListenerKind, RawFd)]) -> io::Result<u32> {
+        let mut fds = vec![];
+
+        for (kind, fd) in listeners {
+            // keep the fd open across exec.
+            unsafe {
+                let flags = libc::fcntl(*fd, libc::F_GETFD);
+                if flags < 0 || libc::fcntl(*fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            fds.push(format!(
+                "{}:{}",
+                match kind {
+                    ListenerKind::Udp => "udp",
+                    ListenerKind::Tcp => "tcp",
+                },
+                fd
+            ));
+        }
+
+        Command::new(exe)
+            .args(env::args_os().skip(1))
+            .env(ENV_LISTEN_FDS, fds.join(","))
+            .spawn()
+            .map(|child| child.id())
+    }
+
+    fn parse_item(s: &str) -> Option<(ListenerKind, RawFd)> {
+        let (kind, fd) = s.split_once(':')?;
+        let kind = match kind {
+            "udp" => ListenerKind::Udp,
+            "tcp" => ListenerKind::Tcp,
+            _ => return None,
+        };
+        Some((kind, fd.parse().ok()?))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_item() {
+            assert_eq!(parse_item("udp:3"), Some((ListenerKind::Udp, 3)));
+            assert_eq!(parse_item("tcp:12"), Some((ListenerKind::Tcp, 12)));
+            assert_eq!(parse_item("quic:3"), None);
+            assert_eq!(parse_item("udp"), None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert_eq!(decode_hex("0f0"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("é0"), None);
+        assert_eq!(decode_hex("+f"), None);
+    }
+
+    #[test]
+    fn test_archive_name() {
+        assert!(archive_name().starts_with("smartdns-"));
+        assert!(archive_name().contains(TARGET));
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        use ring::rand::SystemRandom;
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+
+        let archive = b"smartdns archive";
+        let signature = key_pair.sign(archive);
+
+        assert!(verify(key_pair.public_key().as_ref(), archive, signature.as_ref()).is_ok());
+        assert!(verify(
+            key_pair.public_key().as_ref(),
+            b"tampered",
+            signature.as_ref()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use cfg_if::cfg_if;
+
+use crate::dns_client::{DnsClient, UpstreamStats};
+use crate::infra::tasks::BackgroundTasks;
+use crate::log::debug;
+
+cfg_if! {
+    if #[cfg(target_os = "android")] {
+        pub const STATS_FILE: &'static str = "/data/data/com.termux/files/usr/var/run/smartdns.upstreams";
+    } else {
+        pub const STATS_FILE: &'static str = "/var/run/smartdns.upstreams";
+    }
+}
+
+/// The statistics are written to the file in this interval.
+const WRITE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Write the statistics of the upstreams of all server groups periodically,
+/// picked up by the `upstream stats` command, along with their latency broken down by the
+/// transport phase, printed in `status` by the function returned.
+pub fn spawn_writer<P: AsRef<Path>>(
+    client: &Arc<DnsClient>,
+    path: P,
+    tasks: &BackgroundTasks,
+) -> impl Fn() -> String + Send + Sync + 'static {
+    let client = Arc::downgrade(client);
+    let path = path.as_ref().to_owned();
+    let status = Arc::new(RwLock::new(String::new()));
+    let snapshot = status.clone();
+
+    tasks.spawn(async move {
+        let mut interval = tokio::time::interval(WRITE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let client = match client.upgrade() {
+                Some(client) => client,
+                None => break,
+            };
+
+            let mut rows = vec![];
+            let mut transports = String::new();
+            for (group, force_transport, server_group) in client.created_server_groups().await {
+                let group = match force_transport {
+                    Some(t) => format!("{} ({:?})", group, t),
+                    None => group,
+                };
+                for upstream in server_group
+                    .upstreams()
+                    .iter()
+                    .chain(server_group.fallbacks())
+                {
+                    rows.push((group.clone(), upstream.stats()));
+                    let _ = writeln!(transports, "upstream {}", upstream);
+                }
+            }
+            rows.sort_by(|a, b| a.0.cmp(&b.0));
+
+            if let Ok(mut status) = status.write() {
+                *status = transports;
+            }
+
+            if let Err(err) = fs::write(&path, format_stats(&rows, SystemTime::now())) {
+                debug!("write upstream stats {} failed, {}", path.display(), err);
+            }
+        }
+    });
+
+    move || snapshot.read().map(|s| s.clone()).unwrap_or_default()
+}
+
+fn format_stats(rows: &[(String, UpstreamStats)], now: SystemTime) -> String {
+    let ms = |d: Option<Duration>| {
+        d.map(|d| format!("{:.1}ms", d.as_secs_f64() * 1000.0))
+            .unwrap_or_else(|| "-".to_string())
+    };
+
+    let mut out = format!(
+        "{:<16} {:<40} {:>8} {:>8} {:>8} {:>10} {:>10}  {}\n",
+        "GROUP", "UPSTREAM", "QUERIES", "ERRORS", "TIMEOUTS", "AVG", "P95", "LAST ERROR"
+    );
+
+    for (group, stats) in rows {
+        let last_error = match stats.last_error.as_ref() {
+            Some((at, err)) => format!(
+                "{}s ago, {}",
+                now.duration_since(*at).unwrap_or_default().as_secs(),
+                err
+            ),
+            None => "-".to_string(),
+        };
+
+        let _ = writeln!(
+            out,
+            "{:<16} {:<40} {:>8} {:>8} {:>8} {:>10} {:>10}  {}",
+            group,
+            stats.name,
+            stats.queries,
+            stats.errors,
+            stats.timeouts,
+            ms(stats.mean),
+            ms(stats.p95),
+            last_error
+        );
+    }
+
+    out
+}
+
+/// Print the statistics last written by the running server.
+pub fn print(path: PathBuf) {
+    let content = fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "Read {} failed, {}, is the server running?",
+            path.display(),
+            e
+        )
+    });
+
+    print!("{}", content);
+
+    let age = fs::metadata(&path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|m| m.elapsed().ok())
+        .unwrap_or_default();
+
+    if age > WRITE_INTERVAL * 3 {
+        println!(
+            "\nThe statistics were not updated for {}s, is the server running?",
+            age.as_secs()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_stats() {
+        let now = SystemTime::now();
+        let rows = vec![(
+            "default".to_string(),
+            UpstreamStats {
+                name: "udp://8.8.8.8".to_string(),
+                queries: 120,
+                errors: 3,
+                timeouts: 2,
+                mean: Some(Duration::from_micros(12_345)),
+                p95: Some(Duration::from_millis(32)),
+                last_error: Some((
+                    now - Duration::from_secs(5),
+                    "request timed out".to_string(),
+                )),
+            },
+        )];
+
+        let out = format_stats(&rows, now);
+        let lines = out.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("GROUP"));
+
+        let cols = lines[1].split_whitespace().collect::<Vec<_>>();
+        assert_eq!(
+            cols[..7],
+            [
+                "default",
+                "udp://8.8.8.8",
+                "120",
+                "3",
+                "2",
+                "12.3ms",
+                "32.0ms"
+            ]
+        );
+        assert!(lines[1].ends_with("5s ago, request timed out"));
+    }
+}